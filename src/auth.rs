@@ -0,0 +1,512 @@
+// tonic::Status is what interceptors and services have to return, however large
+#![allow(clippy::result_large_err)]
+
+use crate::config;
+use crate::error::Error;
+use crate::orderbook::{self, Exchange, OutTick};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+use tracing::info;
+
+/// Clients allowed to use the gRPC services, and what each may do.
+///
+/// ```toml
+/// # Optional: also accept JWTs whose `sub` claim names one of the clients below.
+/// [jwt]
+/// algorithm = "RS256"
+/// public_key = "jwt.pem"
+///
+/// [clients.dashboard]
+/// tokens = ["d4shb0ard"]
+/// symbols = ["ETH/BTC"]
+/// exchanges = ["binance", "kraken"]
+/// max_streams = 2
+///
+/// [clients.oncall]
+/// tokens = ["0nc4ll"]
+/// admin = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AuthFile {
+    jwt: Option<JwtConfig>,
+    clients: BTreeMap<String, ClientConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JwtConfig {
+    /// `HS256` by default.
+    #[serde(default = "default_algorithm")]
+    algorithm: Algorithm,
+
+    /// Shared secret of the HMAC algorithms.
+    secret: Option<String>,
+
+    /// PEM encoded public key of the RSA and EC algorithms.
+    public_key: Option<PathBuf>,
+
+    /// Only accept tokens issued by it when set.
+    issuer: Option<String>,
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::HS256
+}
+
+/// What a client may do. Everything which is not limited is allowed.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClientConfig {
+    /// Static API tokens, sent as `authorization: Bearer <token>`.
+    tokens: Vec<String>,
+
+    /// Symbols the client may stream, all when unset.
+    symbols: Option<Vec<String>>,
+
+    /// Exchanges whose levels the client receives, all when unset.
+    exchanges: Option<Vec<Exchange>>,
+
+    /// Most streams the client may have open at once, of any streaming RPC, unlimited when unset.
+    max_streams: Option<usize>,
+
+    /// May use the `Admin` service.
+    admin: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+/// Authenticated caller, added to the extensions of every request.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Client {
+    name: String,
+    config: ClientConfig,
+}
+
+impl Client {
+    /// Caller of a server without an auth file, which may do anything.
+    fn anonymous() -> Client {
+        Client {
+            name: "anonymous".to_string(),
+            config: ClientConfig { admin: true, ..Default::default() },
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the client the interceptor added to the request.
+    pub(crate) fn of<T>(request: &Request<T>) -> Result<Client, Status> {
+        request.extensions().get::<Client>()
+            .cloned()
+            .ok_or_else(|| Status::unauthenticated("not authenticated"))
+    }
+
+    pub(crate) fn check_symbol(&self, symbol: &str) -> Result<(), Status> {
+        match &self.config.symbols {
+            Some(symbols) if !symbols.iter().any(|s| s == symbol) =>
+                Err(Status::permission_denied(format!("{} may not read {}", self.name, symbol))),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_admin(&self) -> Result<(), Status> {
+        match self.config.admin {
+            true => Ok(()),
+            false => Err(Status::permission_denied(format!("{} may not use the Admin service", self.name))),
+        }
+    }
+
+    /// Checks the client may read what the exchange publishes, or what all exchanges do together for
+    /// `None`, which only clients seeing every exchange may.
+    pub(crate) fn check_exchange(&self, exchange: Option<&Exchange>) -> Result<(), Status> {
+        match (exchange, &self.config.exchanges) {
+            (Some(exchange), _) if self.sees(exchange) => Ok(()),
+            (Some(exchange), _) => Err(Status::permission_denied(format!("{} may not read {}", self.name, exchange))),
+            (None, Some(_)) => Err(Status::permission_denied(format!("{} may not read every exchange", self.name))),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Whether the client may see the levels and the status of the exchange.
+    pub(crate) fn sees(&self, exchange: &Exchange) -> bool {
+        match &self.config.exchanges {
+            Some(exchanges) => exchanges.contains(exchange),
+            None => true,
+        }
+    }
+
+    /// Drops the levels of the exchanges the client may not see.
+    pub(crate) fn filter(&self, out_tick: OutTick) -> OutTick {
+        match &self.config.exchanges {
+            Some(exchanges) => out_tick.retain_exchanges(exchanges),
+            None => out_tick,
+        }
+    }
+}
+
+/// Contents of the auth file, ready to check tokens against.
+struct Clients {
+    clients: BTreeMap<String, ClientConfig>,
+    jwt: Option<(DecodingKey, Validation)>,
+}
+
+impl Clients {
+    fn load(path: &Path) -> Result<Clients, Error> {
+        let file: AuthFile = config::read(path)?;
+
+        let mut clients = file.clients;
+        for (name, client) in clients.iter_mut() {
+            if let Some(symbols) = &client.symbols {
+                let symbols = symbols.iter()
+                    .map(|s| orderbook::parse_symbol(s))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| Error::BadConfig(format!("clients.{}.symbols: {}", name, e)))?;
+                client.symbols = Some(symbols);
+            }
+        }
+
+        let jwt = match file.jwt {
+            Some(jwt) => Some(decoding_key(&jwt)?),
+            None => None,
+        };
+
+        Ok(Clients { clients, jwt })
+    }
+
+    fn authenticate(&self, token: &str) -> Result<Client, Status> {
+        let by_token = self.clients.iter()
+            .find(|(_, client)| client.tokens.iter().any(|t| constant_time_eq(t.as_bytes(), token.as_bytes())));
+        if let Some((name, config)) = by_token {
+            return Ok(Client { name: name.clone(), config: config.clone() });
+        }
+
+        let (key, validation) = match &self.jwt {
+            Some(jwt) => jwt,
+            None => return Err(Status::unauthenticated("invalid token")),
+        };
+        let claims = jsonwebtoken::decode::<Claims>(token, key, validation)
+            .map_err(|e| Status::unauthenticated(format!("invalid token: {}", e)))?
+            .claims;
+
+        match self.clients.get(&claims.sub) {
+            Some(config) => Ok(Client { name: claims.sub, config: config.clone() }),
+            None => Err(Status::permission_denied(format!("unknown client: {}", claims.sub))),
+        }
+    }
+}
+
+fn decoding_key(jwt: &JwtConfig) -> Result<(DecodingKey, Validation), Error> {
+    let bad = |e: String| Error::BadConfig(format!("jwt: {}", e));
+
+    let key = match (jwt.algorithm, &jwt.secret, &jwt.public_key) {
+        (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512, Some(secret), _) =>
+            DecodingKey::from_secret(secret.as_bytes()),
+        (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512, None, _) =>
+            return Err(bad(format!("{:?} needs a secret", jwt.algorithm))),
+        (Algorithm::ES256 | Algorithm::ES384, _, Some(path)) =>
+            DecodingKey::from_ec_pem(&read_key(path)?).map_err(|e| bad(e.to_string()))?,
+        (Algorithm::EdDSA, _, Some(path)) =>
+            DecodingKey::from_ed_pem(&read_key(path)?).map_err(|e| bad(e.to_string()))?,
+        (_, _, Some(path)) =>
+            DecodingKey::from_rsa_pem(&read_key(path)?).map_err(|e| bad(e.to_string()))?,
+        (_, _, None) =>
+            return Err(bad(format!("{:?} needs a public_key", jwt.algorithm))),
+    };
+
+    let mut validation = Validation::new(jwt.algorithm);
+    if let Some(issuer) = &jwt.issuer {
+        validation.set_issuer(&[issuer]);
+    }
+
+    Ok((key, validation))
+}
+
+fn read_key(path: &Path) -> Result<Vec<u8>, Error> {
+    std::fs::read(path).map_err(|e| Error::BadConfig(format!("{}: {}", path.display(), e)))
+}
+
+/// Compares tokens in a time which does not depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks the bearer token of every request against the auth file, if any.
+#[derive(Clone)]
+pub(crate) struct Auth {
+    path: Option<PathBuf>,
+    clients: Arc<RwLock<Option<Clients>>>,
+    streams: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Auth {
+    pub(crate) fn load(path: Option<PathBuf>) -> Result<Auth, Error> {
+        let clients = match &path {
+            Some(path) => Some(Clients::load(path)?),
+            None => None,
+        };
+
+        Ok(Auth {
+            path,
+            clients: Arc::new(RwLock::new(clients)),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Reads the auth file again. The previous clients stay in effect if it cannot be read.
+    pub(crate) fn reload(&self) -> Result<usize, Error> {
+        let path = self.path.as_ref()
+            .ok_or_else(|| Error::BadConfig("no auth file was given".to_string()))?;
+        let clients = Clients::load(path)?;
+        let n = clients.clients.len();
+
+        *self.clients.write().unwrap_or_else(PoisonError::into_inner) = Some(clients);
+        info!(clients = n, path = %path.display(), "reloaded auth file");
+        Ok(n)
+    }
+
+    fn authenticate(&self, metadata: &MetadataMap) -> Result<Client, Status> {
+        let clients = self.clients.read().unwrap_or_else(PoisonError::into_inner);
+        let clients = match &*clients {
+            Some(clients) => clients,
+            None => return Ok(Client::anonymous()),
+        };
+
+        let token = metadata.get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+
+        clients.authenticate(token.trim())
+    }
+
+    /// Counts a stream against the limit of the client until the returned guard is dropped.
+    pub(crate) fn open_stream(&self, client: &Client) -> Result<StreamGuard, Status> {
+        let mut streams = self.streams.lock().unwrap_or_else(PoisonError::into_inner);
+        let open = streams.entry(client.name.clone()).or_insert(0);
+        if let Some(max) = client.config.max_streams {
+            if *open >= max {
+                return Err(Status::permission_denied(
+                    format!("{} may have at most {} stream(s) open", client.name, max)));
+            }
+        }
+        *open += 1;
+
+        Ok(StreamGuard { streams: self.streams.clone(), name: client.name.clone() })
+    }
+}
+
+impl Interceptor for Auth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let client = self.authenticate(request.metadata())?;
+        request.extensions_mut().insert(client);
+        Ok(request)
+    }
+}
+
+pub(crate) struct StreamGuard {
+    streams: Arc<Mutex<HashMap<String, usize>>>,
+    name: String,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Ok(mut streams) = self.streams.lock() {
+            if let Some(open) = streams.get_mut(&self.name) {
+                *open = open.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auth::*;
+    use crate::orderbook::{Level, Side};
+    use jsonwebtoken::{EncodingKey, Header};
+    use rust_decimal_macros::dec;
+    use serde::Serialize;
+    use tonic::Code;
+
+    const AUTH_FILE: &str = r#"
+        [jwt]
+        secret = "s3cr3t"
+
+        [clients.dashboard]
+        tokens = ["d4shb0ard"]
+        symbols = ["eth/btc"]
+        exchanges = ["kraken"]
+        max_streams = 1
+
+        [clients.oncall]
+        admin = true
+    "#;
+
+    #[derive(Serialize)]
+    struct TestClaims {
+        sub: String,
+        exp: usize,
+    }
+
+    fn auth(contents: &str) -> (Auth, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.toml");
+        std::fs::write(&path, contents).unwrap();
+        (Auth::load(Some(path)).unwrap(), dir)
+    }
+
+    fn bearer(token: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        metadata
+    }
+
+    fn jwt(sub: &str, secret: &str) -> String {
+        let claims = TestClaims { sub: sub.to_string(), exp: 4102444800 };
+        jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn should_allow_anyone_without_auth_file() {
+        let auth = Auth::load(None).unwrap();
+
+        let client = auth.authenticate(&MetadataMap::new()).unwrap();
+
+        assert_eq!(client, Client::anonymous());
+        assert!(client.check_admin().is_ok());
+    }
+
+    #[test]
+    fn should_authenticate_token() {
+        /*
+         * Given
+         */
+        let (auth, _dir) = auth(AUTH_FILE);
+
+        /*
+         * When
+         */
+        let dashboard = auth.authenticate(&bearer("d4shb0ard")).unwrap();
+        let oncall = auth.authenticate(&bearer(&jwt("oncall", "s3cr3t"))).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(dashboard.name, "dashboard");
+        assert!(dashboard.check_symbol("ETH/BTC").is_ok());
+        assert_eq!(dashboard.check_symbol("BTC/USD").unwrap_err().code(), Code::PermissionDenied);
+        assert_eq!(dashboard.check_admin().unwrap_err().code(), Code::PermissionDenied);
+
+        assert_eq!(oncall.name, "oncall");
+        assert!(oncall.check_symbol("BTC/USD").is_ok());
+        assert!(oncall.check_admin().is_ok());
+    }
+
+    #[test]
+    fn should_reject_token() {
+        let (auth, _dir) = auth(AUTH_FILE);
+
+        let code = |metadata: &MetadataMap| auth.authenticate(metadata).unwrap_err().code();
+
+        assert_eq!(code(&MetadataMap::new()), Code::Unauthenticated);
+        assert_eq!(code(&bearer("d4shb0ard!")), Code::Unauthenticated);
+        assert_eq!(code(&bearer(&jwt("oncall", "guessed"))), Code::Unauthenticated);
+        assert_eq!(code(&bearer(&jwt("intruder", "s3cr3t"))), Code::PermissionDenied);
+    }
+
+    #[test]
+    fn should_limit_streams() {
+        /*
+         * Given
+         */
+        let (auth, _dir) = auth(AUTH_FILE);
+        let dashboard = auth.authenticate(&bearer("d4shb0ard")).unwrap();
+
+        /*
+         * When
+         */
+        let first = auth.open_stream(&dashboard);
+        let second = auth.open_stream(&dashboard);
+        drop(first);
+        let third = auth.open_stream(&dashboard);
+
+        /*
+         * Then
+         */
+        assert_eq!(second.err().unwrap().code(), Code::PermissionDenied);
+        assert!(third.is_ok());
+    }
+
+    #[test]
+    fn should_filter_exchanges() {
+        /*
+         * Given
+         */
+        let (auth, _dir) = auth(AUTH_FILE);
+        let dashboard = auth.authenticate(&bearer("d4shb0ard")).unwrap();
+        let out_tick = OutTick {
+            spread: dec!(1),
+            bids: vec![
+                Level::new(Side::Bid, dec!(10), dec!(1), Exchange::Binance),
+                Level::new(Side::Bid, dec!(9), dec!(1), Exchange::Kraken),
+            ],
+            asks: vec![
+                Level::new(Side::Ask, dec!(11), dec!(1), Exchange::Kraken),
+                Level::new(Side::Ask, dec!(12), dec!(1), Exchange::Binance),
+            ],
+        };
+
+        /*
+         * When
+         */
+        let out_tick = dashboard.filter(out_tick);
+
+        /*
+         * Then
+         */
+        assert_eq!(out_tick, OutTick {
+            spread: dec!(2),
+            bids: vec![Level::new(Side::Bid, dec!(9), dec!(1), Exchange::Kraken)],
+            asks: vec![Level::new(Side::Ask, dec!(11), dec!(1), Exchange::Kraken)],
+        });
+        assert!(dashboard.check_exchange(Some(&Exchange::Kraken)).is_ok());
+        assert_eq!(dashboard.check_exchange(Some(&Exchange::Binance)).unwrap_err().code(), Code::PermissionDenied);
+        assert_eq!(dashboard.check_exchange(None).unwrap_err().code(), Code::PermissionDenied);
+        assert!(Client::anonymous().check_exchange(None).is_ok());
+    }
+
+    #[test]
+    fn should_reload() {
+        /*
+         * Given
+         */
+        let (auth, dir) = auth(AUTH_FILE);
+        std::fs::write(dir.path().join("auth.toml"), "[clients.dashboard]\ntokens = [\"r0t4ted\"]").unwrap();
+
+        /*
+         * When
+         */
+        let reloaded = auth.reload();
+
+        /*
+         * Then
+         */
+        assert_eq!(reloaded.unwrap(), 1);
+        assert!(auth.authenticate(&bearer("d4shb0ard")).is_err());
+        assert!(auth.authenticate(&bearer("r0t4ted")).is_ok());
+
+        std::fs::write(dir.path().join("auth.toml"), "[clients.dashboard]\ntoken = \"typo\"").unwrap();
+        assert!(auth.reload().is_err());
+        assert!(auth.authenticate(&bearer("r0t4ted")).is_ok());
+    }
+}
@@ -1,69 +1,529 @@
-use crate::error::Error;
-use crate::orderbook::{self, Exchange, InTick, ToLevel, ToLevels, ToTick};
-use crate::websocket;
-use log::{debug, info};
+use crate::config::{InstrumentKind, VenueConfig};
+use crate::error::{self, Error};
+use crate::orderbook::{self, Exchange, FullBook, InTick};
+use crate::venue::Parsed;
+use crate::record::Tape;
+use crate::trade::{self, TradeSide};
+use crate::websocket::{self, Ack};
+use crate::{metrics, rest};
+use chrono::{TimeZone, Utc};
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace, warn};
 use tungstenite::Message;
 
-const BINANCE_WS_URL: &str = "wss://stream.binance.com:9443/ws";
+pub(crate) const BINANCE_WS_URL: &str = "wss://stream.binance.com:9443/ws";
+
+/// USDⓈ-M futures, whose symbols without an expiry are perpetuals.
+pub(crate) const BINANCE_FUTURES_WS_URL: &str = "wss://fstream.binance.com/ws";
+
+pub(crate) const BINANCE_US_WS_URL: &str = "wss://stream.binance.us:9443/ws";
+
+/// Levels of the snapshot the changes of the whole book are synced against.
+const SNAPSHOT_LIMIT: usize = 1000;
+
+/// Most changes kept while waiting for the snapshot.
+const MAX_PENDING: usize = 1000;
 
 #[derive(Debug, Deserialize, PartialEq)]
-struct Event {
+#[serde(untagged)]
+enum Event {
+    Depth(Depth),
+
+    Update(DepthUpdate),
+
+    AggTrade(AggTrade),
+
+    BookTicker(BookTicker),
+
+    /// Response to a request which failed.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "code": 2,
+    ///   "msg": "Invalid request: unknown variant",
+    ///   "id": 1
+    /// }
+    /// ```
+    Error {
+        code: i64,
+        msg: String,
+    },
+
+    /// Response to a request, e.g. `{"result":null,"id":1}` once a stream is subscribed.
+    Response {
+        result: Option<serde_json::Value>,
+        id: u64,
+    },
+}
+
+/// Top levels of the book, as streamed by the spot partial depth streams and returned by the REST
+/// snapshots of every variant.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "lastUpdateId": 160,
+///   "bids": [["0.0024", "10"]],
+///   "asks": [["0.0026", "100"]]
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct Depth {
     #[serde(rename = "lastUpdateId")]
-    last_update_id: usize,
+    last_update_id: u64,
+    bids: Vec<Level>,
+    asks: Vec<Level>,
+}
+
+/// Changes to the book between two update IDs, as streamed by the diff depth streams. Futures also
+/// stream their partial depths this way, holding the top levels.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "e": "depthUpdate",
+///   "E": 123456789,
+///   "T": 123456788,
+///   "s": "BTCUSDT",
+///   "U": 157,
+///   "u": 160,
+///   "pu": 149,
+///   "b": [["0.0024", "10"]],
+///   "a": [["0.0026", "100"]]
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct DepthUpdate {
+    /// Event time, milliseconds since epoch
+    #[serde(rename = "E")]
+    event_time: i64,
+
+    #[serde(rename = "s")]
+    symbol: String,
+
+    #[serde(rename = "U")]
+    first_update_id: u64,
+
+    #[serde(rename = "u")]
+    final_update_id: u64,
+
+    /// Final update ID of the previous event. Only futures send it.
+    #[serde(rename = "pu")]
+    previous_final_update_id: Option<u64>,
+
+    /// Amount left at each price, 0 once the level is gone
+    #[serde(rename = "b")]
     bids: Vec<Level>,
+
+    #[serde(rename = "a")]
     asks: Vec<Level>,
 }
 
+/// Fills of a single taker order at a single price, as streamed by the aggregate trade streams of
+/// every variant.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "e": "aggTrade",
+///   "E": 123456789,
+///   "s": "BNBBTC",
+///   "a": 12345,
+///   "p": "0.001",
+///   "q": "100",
+///   "f": 100,
+///   "l": 105,
+///   "T": 123456785,
+///   "m": true
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct AggTrade {
+    #[serde(rename = "s")]
+    symbol: String,
+
+    #[serde(rename = "a")]
+    aggregate_trade_id: u64,
+
+    #[serde(rename = "p")]
+    price: Decimal,
+
+    #[serde(rename = "q")]
+    quantity: Decimal,
+
+    /// Trade time, milliseconds since epoch
+    #[serde(rename = "T")]
+    trade_time: i64,
+
+    /// Whether the buyer was the maker, i.e. the taker sold
+    #[serde(rename = "m")]
+    buyer_is_maker: bool,
+}
+
+impl AggTrade {
+    /// Converts a `binance::AggTrade` into a `trade::Trade`. `None` if its time is out of range.
+    fn to_trade(&self, exchange: Exchange) -> Option<trade::Trade> {
+        Some(trade::Trade {
+            exchange,
+            price: self.price,
+            amount: self.quantity,
+            side: if self.buyer_is_maker { TradeSide::Sell } else { TradeSide::Buy },
+            trade_id: Some(self.aggregate_trade_id.to_string()),
+            time: Utc.timestamp_millis_opt(self.trade_time).single()?,
+        })
+    }
+}
+
+/// Best bid and offer, as streamed by the book ticker streams of every variant whenever either
+/// changes. Futures also send the event and transaction times.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "u": 400900217,
+///   "s": "BNBUSDT",
+///   "b": "25.35190000",
+///   "B": "31.21000000",
+///   "a": "25.36520000",
+///   "A": "40.66000000"
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct BookTicker {
+    #[serde(rename = "u")]
+    update_id: u64,
+
+    #[serde(rename = "s")]
+    symbol: String,
+
+    /// Event time, milliseconds since epoch. Only futures send it.
+    #[serde(rename = "E")]
+    event_time: Option<i64>,
+
+    #[serde(rename = "b")]
+    bid_price: Decimal,
+
+    #[serde(rename = "B")]
+    bid_quantity: Decimal,
+
+    #[serde(rename = "a")]
+    ask_price: Decimal,
+
+    #[serde(rename = "A")]
+    ask_quantity: Decimal,
+}
+
+/// Request. Subscribes to further streams on the connection.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "method": "SUBSCRIBE",
+///   "params": ["btcusdt@aggTrade"],
+///   "id": 1
+/// }
+/// ```
+#[derive(Debug, Serialize, PartialEq)]
+struct Request {
+    method: String,
+    params: Vec<String>,
+    id: u64,
+}
+
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 struct Level {
     price: Decimal,
     amount: Decimal,
 }
 
-impl ToLevel for Level {
-    /// Converts a `binance::Level` into a `orderbook::Level`.
-    fn to_level(&self, side: orderbook::Side) -> orderbook::Level {
-        orderbook::Level::new(side, self.price, self.amount, Exchange::Binance)
+/// Book of a connection. In diff mode it is kept in full as changes touch any level of it, with the
+/// update ID it is at.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Depths {
+    exchange: Exchange,
+    diff: bool,
+
+    /// Amount at each price.
+    book: FullBook<Decimal>,
+
+    /// `None` until the snapshot arrived.
+    last_update_id: Option<u64>,
+
+    /// Whether a change was applied since the snapshot, from which on they must follow on exactly.
+    synced: bool,
+
+    /// Changes received before the snapshot, applied once it arrives.
+    pending: Vec<DepthUpdate>,
+}
+
+impl Depths {
+    pub(crate) fn new(exchange: Exchange, diff: bool) -> Depths {
+        Depths {
+            exchange,
+            diff,
+            book: FullBook::default(),
+            last_update_id: None,
+            synced: false,
+            pending: vec![],
+        }
+    }
+
+    /// Replaces the book with the snapshot, then applies the changes received before it.
+    fn reset(&mut self, snapshot: Depth, symbol: &str, payload: &str) -> Result<(), Error> {
+        self.book.bids = snapshot.bids.into_iter().map(|l| (l.price, l.amount)).collect();
+        self.book.asks = snapshot.asks.into_iter().map(|l| (l.price, l.amount)).collect();
+        self.last_update_id = Some(snapshot.last_update_id);
+        self.synced = false;
+        for update in std::mem::take(&mut self.pending) {
+            self.apply(update, symbol, payload)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the changes the book is not at yet. Fails, dropping the book, if changes were missed
+    /// since: the first one applied after the snapshot must straddle its update ID, later ones follow
+    /// on from the previous one, which futures tell by `pu`. Futures update IDs skip, so their first
+    /// change may also end at the snapshot's update ID, or follow on from it by `pu`.
+    fn apply(&mut self, update: DepthUpdate, symbol: &str, payload: &str) -> Result<(), Error> {
+        let last = match self.last_update_id {
+            Some(last) => last,
+            None => {
+                if self.pending.len() == MAX_PENDING {
+                    warn!("no snapshot yet, dropping the oldest change");
+                    self.pending.remove(0);
+                }
+                self.pending.push(update);
+                return Ok(());
+            },
+        };
+        let stale = match update.previous_final_update_id {
+            Some(_) => update.final_update_id < last,
+            None => update.final_update_id <= last,
+        };
+        if stale {
+            trace!(final_update_id = update.final_update_id, "stale change");
+            return Ok(());
+        }
+
+        let (expected, got) = match (self.synced, update.previous_final_update_id) {
+            (true, Some(previous)) => (last, previous),
+            (true, None) => (last + 1, update.first_update_id),
+            (false, Some(previous)) if previous == last => (last, previous),
+            (false, Some(_)) => (last, update.first_update_id.max(last)),
+            (false, None) => (last + 1, update.first_update_id.max(last + 1)),
+        };
+        if expected != got {
+            // changes wait for the snapshot of the next subscription
+            *self = Depths::new(self.exchange, self.diff);
+            return Err(Error::SequenceGap {
+                exchange: self.exchange,
+                symbol: symbol.to_string(),
+                expected,
+                got,
+                payload: error::excerpt(payload),
+            });
+        }
+
+        for (levels, changes) in [(&mut self.book.bids, update.bids), (&mut self.book.asks, update.asks)] {
+            for Level { price, amount } in changes {
+                match amount.is_zero() {
+                    true => levels.remove(&price),
+                    false => levels.insert(price, amount),
+                };
+            }
+        }
+        self.last_update_id = Some(update.final_update_id);
+        self.synced = true;
+        Ok(())
+    }
+
+    fn to_tick(&self) -> InTick {
+        self.book.to_tick(self.exchange, |side, price, amount| self.level(side, price, *amount))
+    }
+
+    /// Converts the best levels, best first, into `orderbook::Level`s.
+    fn levels(&self, side: orderbook::Side, levels: impl Iterator<Item = (Decimal, Decimal)>) -> Vec<orderbook::Level> {
+        levels.take(orderbook::MAX_DEPTH)
+            .map(|(price, amount)| self.level(side.clone(), price, amount))
+            .collect()
+    }
+
+    fn level(&self, side: orderbook::Side, price: Decimal, amount: Decimal) -> orderbook::Level {
+        orderbook::Level::new(side, price, amount, self.exchange).with_kind(kind(&self.exchange))
+    }
+
+    /// The best bid and offer, which replace the book. An empty side is left out.
+    fn to_bbo_tick(&self, ticker: BookTicker) -> InTick {
+        let bid = (!ticker.bid_quantity.is_zero()).then_some((ticker.bid_price, ticker.bid_quantity));
+        let ask = (!ticker.ask_quantity.is_zero()).then_some((ticker.ask_price, ticker.ask_quantity));
+        InTick {
+            exchange: self.exchange,
+            bids: self.levels(orderbook::Side::Bid, bid.into_iter()),
+            asks: self.levels(orderbook::Side::Ask, ask.into_iter()),
+        }
+    }
+
+    /// The top levels of a partial depth, which replace the book.
+    fn to_partial_tick(&self, bids: Vec<Level>, asks: Vec<Level>) -> InTick {
+        InTick {
+            exchange: self.exchange,
+            bids: self.levels(orderbook::Side::Bid, bids.into_iter().map(|l| (l.price, l.amount))),
+            asks: self.levels(orderbook::Side::Ask, asks.into_iter().map(|l| (l.price, l.amount))),
+        }
     }
 }
 
-impl ToTick for Event {
-    /// Converts the `Event` into a `Option<InTick>`. Only keep the top ten levels of bids and asks.
-    fn maybe_to_tick(&self) -> Option<InTick> {
-        let bids = self.bids.to_levels(orderbook::Side::Bid, 10);
-        let asks = self.asks.to_levels(orderbook::Side::Ask, 10);
+/// Binance futures books are perpetuals, the others spot.
+fn kind(exchange: &Exchange) -> InstrumentKind {
+    match exchange {
+        Exchange::BinanceFutures => InstrumentKind::Perpetual,
+        _ => InstrumentKind::Spot,
+    }
+}
 
-        Some(InTick { exchange: Exchange::Binance, bids, asks })
+/// REST API the snapshots are fetched from in diff mode.
+pub(crate) fn rest_url(exchange: &Exchange) -> &'static str {
+    match exchange {
+        Exchange::BinanceFutures => "https://fapi.binance.com",
+        Exchange::BinanceUs => "https://api.binance.us",
+        _ => "https://api.binance.com",
     }
 }
 
-pub(crate) async fn connect(symbol: &String) -> Result<websocket::WsStream, Error> {
-    let depth = 10;
-    let symbol = symbol.to_lowercase().replace("/", "");
-    let url = format!("{}/{}@depth{}@100ms", BINANCE_WS_URL, symbol, depth);
-    Ok(websocket::connect(url.as_str()).await?)
+fn depth_path(exchange: &Exchange) -> &'static str {
+    match exchange {
+        Exchange::BinanceFutures => "/fapi/v1/depth",
+        _ => "/api/v3/depth",
+    }
 }
 
-pub(crate) fn parse(msg: Message) -> Result<Option<InTick>, Error> {
-    let e = match msg {
-        Message::Binary(x) => { info!("binary {:?}", x); None },
-        Message::Text(x) => {
-            let e= deserialize(x)?;
-            debug!("{:?}", e);
-            Some(e)
+/// Binance only streams 5, 10 or 20 levels, so the depth is rounded up to the next of these, unless
+/// `diff` asks for the changes of the whole book, which are synced against a snapshot fetched from
+/// the REST API once the stream is open, or `bbo` for the best bid and offer alone.
+/// Binance neither confirms nor refuses the stream named in the URL, so the first depth counts as
+/// confirmation and an unknown pair runs into the timeout. The aggregate trades are subscribed on the
+/// same connection once it is confirmed.
+pub(crate) async fn connect(
+    exchange: Exchange,
+    url: &str,
+    symbol: &str,
+    config: &VenueConfig,
+    tape: &Tape,
+) -> Result<(websocket::WsStream, Vec<Message>), Error>
+{
+    let pair = symbol.to_lowercase().replace('/', "");
+    let url = match (config.diff, config.bbo) {
+        (true, _) => format!("{}/{}@depth@100ms", url, pair),
+        (false, true) => format!("{}/{}@bookTicker", url, pair),
+        (false, false) => {
+            let depth = [5, 10, 20].into_iter().find(|d| *d >= config.depth).unwrap_or(20);
+            format!("{}/{}@depth{}@100ms", url, pair, depth)
         },
-        Message::Ping(x) => { info!("Ping {:?}", x); None },
-        Message::Pong(x) => { info!("Pong {:?}", x); None },
-        Message::Close(x) => { info!("Close {:?}", x); None },
-        Message::Frame(x) => { info!("Frame {:?}", x); None },
     };
-    Ok(e.map(|e| e.maybe_to_tick()).flatten())
+    let mut ws_stream = websocket::connect(exchange, symbol, url.as_str(), tape).await?;
+    let mut backlog = websocket::await_ack(&mut ws_stream, exchange, symbol, &url, ack, tape).await?;
+    if config.trades {
+        websocket::subscribe(&mut ws_stream, exchange, symbol, &url, trade_subscription(exchange, symbol, &pair)?, tape).await?;
+    }
+
+    if config.diff {
+        let rest_url = config.rest_url.as_deref().unwrap_or_else(|| rest_url(&exchange)).trim_end_matches('/');
+        let snapshot_url = format!("{}{}?symbol={}&limit={}", rest_url, depth_path(&exchange), pair.to_uppercase(), SNAPSHOT_LIMIT);
+        let snapshot = Message::Text(rest::get(exchange, symbol, &snapshot_url).await?);
+        tape.message(&snapshot);
+        backlog.push(snapshot);
+    }
+    Ok((ws_stream, backlog))
+}
+
+fn ack(text: &str) -> Ack {
+    match deserialize(text) {
+        Ok(Event::Depth(_) | Event::Update(_) | Event::BookTicker(_)) => Ack::Subscribed,
+        _ => Ack::Pending,
+    }
+}
+
+fn trade_subscription(exchange: Exchange, symbol: &str, pair: &str) -> Result<String, Error> {
+    let request = Request { method: "SUBSCRIBE".to_string(), params: vec![format!("{}@aggTrade", pair)], id: 1 };
+    let payload = format!("{:?}", request);
+    serde_json::to_string(&request).map_err(|e| Error::protocol(exchange, symbol, e, &payload))
+}
+
+/// Partial depths replace the book. In diff mode, the snapshot replaces the book held in `depths` and
+/// changes apply to it as long as they follow on. The best bid and offer replace the book as well.
+/// Spot partial depths and book tickers carry no timestamp, so no message age is observed for them.
+pub(crate) fn parse(msg: Message, symbol: &str, depths: &mut Depths) -> Result<Parsed, Error> {
+    let exchange = depths.exchange;
+    metrics::message_received(exchange, &msg);
+    let x = match msg {
+        Message::Text(x) => x,
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); return Ok(Parsed::Nothing) },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); return Ok(Parsed::Nothing) },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); return Ok(Parsed::Nothing) },
+        Message::Close(x) => { info!(frame = ?x, "close"); return Ok(Parsed::Nothing) },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); return Ok(Parsed::Nothing) },
+    };
+    trace!(%x, "text message");
+
+    let e = deserialize(&x).map_err(|e| metrics::parse_error(exchange, symbol, e, &x))?;
+    Ok(match e {
+        Event::Depth(depth) if depths.diff => {
+            trace!(last_update_id = depth.last_update_id, "snapshot");
+            depths.reset(depth, symbol, &x)?;
+            Parsed::Tick(depths.to_tick())
+        },
+        Event::Depth(depth) => {
+            trace!(last_update_id = depth.last_update_id, "depth");
+            Parsed::Tick(depths.to_partial_tick(depth.bids, depth.asks))
+        },
+        Event::Update(update) => {
+            trace!(symbol = %update.symbol, first_update_id = update.first_update_id, final_update_id = update.final_update_id, "depth update");
+            if let Some(ts) = Utc.timestamp_millis_opt(update.event_time).single() {
+                metrics::message_age(exchange, ts);
+            }
+            match depths.diff {
+                true => {
+                    depths.apply(update, symbol, &x)?;
+                    match depths.last_update_id {
+                        Some(_) => Parsed::Tick(depths.to_tick()),
+                        None => Parsed::Nothing,
+                    }
+                },
+                false => Parsed::Tick(depths.to_partial_tick(update.bids, update.asks)),
+            }
+        },
+        Event::AggTrade(agg_trade) => {
+            trace!(symbol = %agg_trade.symbol, id = agg_trade.aggregate_trade_id, price = %agg_trade.price, "aggregate trade");
+            match agg_trade.to_trade(exchange) {
+                Some(trade) => {
+                    metrics::message_age(exchange, trade.time);
+                    Parsed::Trades(vec![trade])
+                },
+                None => Parsed::Nothing,
+            }
+        },
+        Event::BookTicker(ticker) => {
+            trace!(symbol = %ticker.symbol, update_id = ticker.update_id, "book ticker");
+            if let Some(ts) = ticker.event_time.and_then(|event_time| Utc.timestamp_millis_opt(event_time).single()) {
+                metrics::message_age(exchange, ts);
+            }
+            Parsed::Snapshot(depths.to_bbo_tick(ticker))
+        },
+        Event::Error { code, msg } => {
+            info!(code, %msg, "error");
+            Parsed::Error(msg)
+        },
+        Event::Response { id, .. } => {
+            info!(id, "response");
+            Parsed::Nothing
+        },
+    })
 }
 
-fn deserialize(s: String) -> serde_json::Result<Event> {
-    Ok(serde_json::from_str(&s)?)
+fn deserialize(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
 }
 
 #[cfg(test)]
@@ -71,15 +531,19 @@ mod test {
     use rust_decimal_macros::dec;
     use crate::binance::*;
 
+    fn perpetual(side: orderbook::Side, price: Decimal, amount: Decimal) -> orderbook::Level {
+        orderbook::Level::new(side, price, amount, Exchange::BinanceFutures).with_kind(InstrumentKind::Perpetual)
+    }
+
     #[test]
-    fn should_deserialize_event() -> Result<(), Error> {
+    fn should_deserialize_event() -> serde_json::Result<()> {
         assert_eq!(deserialize(r#"
         {
            "lastUpdateId":5244166729,
            "bids":[["0.06900300","14.80480000"],["0.06900100","0.85230000"]],
            "asks":[["0.06900400","12.04200000"],["0.06900500","2.85830000"]]
-        }"#.to_string())?,
-                   Event{
+        }"#)?,
+                   Event::Depth(Depth {
                        last_update_id: 5244166729,
                        bids: vec![
                            Level { price: dec!(0.06900300), amount: dec!(14.80480000) },
@@ -89,8 +553,237 @@ mod test {
                            Level { price: dec!(0.06900400), amount: dec!(12.04200000) },
                            Level { price: dec!(0.06900500), amount: dec!(2.85830000) },
                        ]
-                   }
+                   })
+        );
+        assert_eq!(deserialize(include_str!("../tests/fixtures/binance_futures/depth_update_next.json"))?,
+                   Event::Update(DepthUpdate {
+                       event_time: 1656000000200,
+                       symbol: "BTCUSDT".to_string(),
+                       first_update_id: 1006,
+                       final_update_id: 1010,
+                       previous_final_update_id: Some(1005),
+                       bids: vec![],
+                       asks: vec![Level { price: dec!(20000.30), amount: dec!(0.500) }],
+                   })
         );
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn should_ack_with_first_depth() {
+        /*
+         * Given
+         */
+        let depth = include_str!("../tests/fixtures/binance/depth.json");
+
+        /*
+         * When
+         */
+        let (depth, other) = (ack(depth), ack(r#"{"result":null,"id":1}"#));
+
+        /*
+         * Then
+         */
+        assert_eq!(depth, Ack::Subscribed);
+        assert_eq!(other, Ack::Pending);
+    }
+
+    #[test]
+    fn should_pass_on_aggregate_trades() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::new(Exchange::Binance, false);
+
+        /*
+         * When
+         */
+        let trade = parse(Message::Text(include_str!("../tests/fixtures/binance/agg_trade.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+        let subscribed = parse(Message::Text(r#"{"result":null,"id":1}"#.to_string()), "ETH/BTC", &mut depths).unwrap();
+        let error = parse(Message::Text(r#"{"code":2,"msg":"Invalid request","id":1}"#.to_string()), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(trade, Parsed::Trades(vec![trade::Trade {
+            exchange: Exchange::Binance,
+            price: dec!(0.06900500),
+            amount: dec!(1.25),
+            side: TradeSide::Sell,
+            trade_id: Some("26129".to_string()),
+            time: Utc.timestamp_millis_opt(1656000001123).unwrap(),
+        }]));
+        assert_eq!(subscribed, Parsed::Nothing);
+        assert_eq!(error, Parsed::Error("Invalid request".to_string()));
+        assert_eq!(trade_subscription(Exchange::Binance, "ETH/BTC", "ethbtc").unwrap(), r#"{"method":"SUBSCRIBE","params":["ethbtc@aggTrade"],"id":1}"#);
+    }
+
+    #[test]
+    fn should_replace_with_best_bid_and_offer() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::new(Exchange::Binance, false);
+        let mut futures = Depths::new(Exchange::BinanceFutures, false);
+        let book_ticker = include_str!("../tests/fixtures/binance/book_ticker.json");
+
+        /*
+         * When
+         */
+        let tick = parse(Message::Text(book_ticker.to_string()), "ETH/BTC", &mut depths).unwrap();
+        let perpetual_tick = parse(Message::Text(
+            r#"{"e":"bookTicker","u":1010,"E":1656000000200,"T":1656000000199,"s":"BTCUSDT","b":"20000.00","B":"3.000","a":"20000.20","A":"0"}"#.to_string()),
+            "BTC/USDT", &mut futures).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(tick, Parsed::Snapshot(InTick {
+            exchange: Exchange::Binance,
+            bids: vec![orderbook::Level::new(orderbook::Side::Bid, dec!(0.06900300), dec!(14.80480000), Exchange::Binance)],
+            asks: vec![orderbook::Level::new(orderbook::Side::Ask, dec!(0.06900400), dec!(12.04200000), Exchange::Binance)],
+        }));
+        assert_eq!(perpetual_tick, Parsed::Snapshot(InTick {
+            exchange: Exchange::BinanceFutures,
+            bids: vec![perpetual(orderbook::Side::Bid, dec!(20000.00), dec!(3.000))],
+            asks: vec![],
+        }));
+        assert_eq!(ack(book_ticker), Ack::Subscribed);
+    }
+
+    #[test]
+    fn should_replace_with_partial_depths() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::new(Exchange::BinanceFutures, false);
+
+        /*
+         * When
+         */
+        let tick = parse(Message::Text(include_str!("../tests/fixtures/binance_futures/depth_update_next.json").to_string()), "BTC/USDT", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(tick, Parsed::Tick(InTick {
+            exchange: Exchange::BinanceFutures,
+            bids: vec![],
+            asks: vec![perpetual(orderbook::Side::Ask, dec!(20000.30), dec!(0.500))],
+        }));
+    }
+
+    #[test]
+    fn should_apply_pending_changes_to_snapshot() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::new(Exchange::BinanceFutures, true);
+        parse(Message::Text(include_str!("../tests/fixtures/binance_futures/depth_update.json").to_string()), "BTC/USDT", &mut depths).unwrap();
+        parse(Message::Text(include_str!("../tests/fixtures/binance_futures/depth_snapshot.json").to_string()), "BTC/USDT", &mut depths).unwrap();
+
+        /*
+         * When
+         */
+        let tick = parse(Message::Text(include_str!("../tests/fixtures/binance_futures/depth_update_next.json").to_string()), "BTC/USDT", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(tick, Parsed::Tick(InTick {
+            exchange: Exchange::BinanceFutures,
+            bids: vec![
+                perpetual(orderbook::Side::Bid, dec!(20000.00), dec!(3.000)),
+                perpetual(orderbook::Side::Bid, dec!(19999.90), dec!(4.000)),
+            ],
+            asks: vec![
+                perpetual(orderbook::Side::Ask, dec!(20000.20), dec!(1.250)),
+                perpetual(orderbook::Side::Ask, dec!(20000.30), dec!(0.500)),
+                perpetual(orderbook::Side::Ask, dec!(20000.50), dec!(0.800)),
+            ],
+        }));
+        assert_eq!(depths.last_update_id, Some(1010));
+    }
+
+    #[test]
+    fn should_sync_futures_on_change_ending_at_snapshot() {
+        /*
+         * Given
+         */
+        let snapshot = include_str!("../tests/fixtures/binance_futures/depth_snapshot.json");
+        let mut depths = Depths::new(Exchange::BinanceFutures, true);
+        let mut following = Depths::new(Exchange::BinanceFutures, true);
+        parse(Message::Text(snapshot.to_string()), "BTC/USDT", &mut depths).unwrap();
+        parse(Message::Text(snapshot.to_string()), "BTC/USDT", &mut following).unwrap();
+        let skipping = include_str!("../tests/fixtures/binance_futures/depth_update_skipping.json");
+
+        /*
+         * When
+         */
+        parse(Message::Text(include_str!("../tests/fixtures/binance_futures/depth_update_at_snapshot.json").to_string()), "BTC/USDT", &mut depths).unwrap();
+        let tick = parse(Message::Text(skipping.to_string()), "BTC/USDT", &mut depths);
+        let following_tick = parse(Message::Text(skipping.to_string()), "BTC/USDT", &mut following);
+
+        /*
+         * Then
+         */
+        assert_eq!(tick.unwrap(), Parsed::Tick(InTick {
+            exchange: Exchange::BinanceFutures,
+            bids: vec![
+                perpetual(orderbook::Side::Bid, dec!(20000.00), dec!(3.000)),
+                perpetual(orderbook::Side::Bid, dec!(19999.90), dec!(4.000)),
+            ],
+            asks: vec![
+                perpetual(orderbook::Side::Ask, dec!(20000.20), dec!(1.250)),
+                perpetual(orderbook::Side::Ask, dec!(20000.30), dec!(0.500)),
+                perpetual(orderbook::Side::Ask, dec!(20000.50), dec!(0.800)),
+            ],
+        }));
+        assert_eq!(depths.last_update_id, Some(1012));
+        assert!(matches!(following_tick, Ok(Parsed::Tick(_))), "{:?}", following_tick);
+    }
+
+    #[test]
+    fn should_fail_on_previous_update_id_gap() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::new(Exchange::BinanceFutures, true);
+        parse(Message::Text(include_str!("../tests/fixtures/binance_futures/depth_update.json").to_string()), "BTC/USDT", &mut depths).unwrap();
+        parse(Message::Text(include_str!("../tests/fixtures/binance_futures/depth_snapshot.json").to_string()), "BTC/USDT", &mut depths).unwrap();
+        let gap = include_str!("../tests/fixtures/binance_futures/depth_update_next.json").replace(r#""pu":1005"#, r#""pu":1003"#);
+
+        /*
+         * When
+         */
+        let parsed = parse(Message::Text(gap), "BTC/USDT", &mut depths);
+
+        /*
+         * Then
+         */
+        assert!(matches!(parsed, Err(Error::SequenceGap { expected: 1005, got: 1003, .. })), "{:?}", parsed);
+    }
+
+    #[test]
+    fn should_fail_on_spot_update_id_gap() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::new(Exchange::Binance, true);
+        parse(Message::Text(include_str!("../tests/fixtures/binance/depth.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+        let update = |first: u64, last: u64| Message::Text(format!(
+            r#"{{"e":"depthUpdate","E":1656000000100,"s":"ETHBTC","U":{},"u":{},"b":[],"a":[["0.06900400","1.0"]]}}"#, first, last));
+
+        /*
+         * When
+         */
+        let straddling = parse(update(5244166720, 5244166730), "ETH/BTC", &mut depths);
+        let gap = parse(update(5244166732, 5244166735), "ETH/BTC", &mut depths);
+
+        /*
+         * Then
+         */
+        assert!(matches!(straddling, Ok(Parsed::Tick(_))), "{:?}", straddling);
+        assert!(matches!(gap, Err(Error::SequenceGap { expected: 5244166731, got: 5244166732, .. })), "{:?}", gap);
+    }
+}
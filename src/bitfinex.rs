@@ -0,0 +1,599 @@
+use crate::config::Precision;
+use crate::error::{self, Error};
+use crate::orderbook::{Exchange, FullBook, InTick, ToLevel};
+use crate::venue::{Parsed, TradingStatus};
+use crate::record::Tape;
+use crate::websocket::{self, Ack};
+use crate::{metrics, orderbook};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::{debug, info, trace};
+use tungstenite::protocol::Message;
+
+pub(crate) const BITFINEX_WS_URL: &str = "wss://api-pub.bitfinex.com/ws/2";
+
+/// Flag of the `conf` request which adds a checksum message after every book update.
+const CHECKSUM_FLAG: u32 = 131072;
+
+/// Levels per side the checksum of a book is computed over.
+const CHECKSUM_LEVELS: usize = 25;
+
+/// Sent for a restart of the trading engine, and once it is done.
+const MAINTENANCE_STARTED: u32 = 20060;
+const MAINTENANCE_ENDED: u32 = 20061;
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Event {
+    Control(Control),
+
+    Channel(ChannelMessage),
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Control {
+    /// Publication: Sent on connection, and for a restart or maintenance of the platform.
+    ///
+    /// **Examples of payload**
+    /// ```json
+    /// {
+    ///   "event": "info",
+    ///   "version": 2,
+    ///   "serverId": "35d6e9d7-5d0a-4d4d-9b4e-7c5cfd0ef34b",
+    ///   "platform": {
+    ///     "status": 1
+    ///   }
+    /// }
+    ///
+    /// {
+    ///   "event": "info",
+    ///   "code": 20060,
+    ///   "msg": "Entering in Maintenance mode. Please pause any activity and resume after receiving the info message 20061."
+    /// }
+    /// ```
+    Info {
+        version: Option<u8>,
+
+        platform: Option<Platform>,
+
+        /// 20051: reconnect, 20060: maintenance started, 20061: maintenance ended
+        code: Option<u32>,
+
+        msg: Option<String>,
+    },
+
+    /// Response. Confirms the flags of a `conf` request.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "event": "conf",
+    ///   "status": "OK",
+    ///   "flags": 131072
+    /// }
+    /// ```
+    Conf {
+        /// OK|FAILED
+        status: String,
+
+        flags: Option<u32>,
+    },
+
+    /// Response. Confirms a subscription, and numbers the channel its messages are sent on.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "event": "subscribed",
+    ///   "channel": "book",
+    ///   "chanId": 17082,
+    ///   "symbol": "tETHBTC",
+    ///   "prec": "P0",
+    ///   "freq": "F0",
+    ///   "len": "25",
+    ///   "pair": "ETHBTC"
+    /// }
+    /// ```
+    Subscribed {
+        /// book|trades|ticker|candles|status
+        channel: String,
+
+        /// Channel ID, which the messages of the subscription start with
+        #[serde(rename = "chanId")]
+        chan_id: u64,
+
+        symbol: Option<String>,
+
+        /// Precision of a book, P0|P1|P2|P3|P4, or R0 for a raw book
+        prec: Option<String>,
+
+        /// Levels per side of a book
+        len: Option<String>,
+    },
+
+    Unsubscribed {
+        #[serde(rename = "chanId")]
+        chan_id: u64,
+
+        status: String,
+    },
+
+    /// Response. Sent for a request which failed, e.g. a subscription to an unknown symbol.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "event": "error",
+    ///   "msg": "symbol: invalid",
+    ///   "code": 10300,
+    ///   "channel": "book",
+    ///   "symbol": "tETHXYZ"
+    /// }
+    /// ```
+    Error {
+        msg: String,
+
+        code: u32,
+    },
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Platform {
+    /// 1 for operative, 0 for maintenance
+    status: u8,
+}
+
+/// Request. Sets flags of the connection, or subscribes to a channel.
+///
+/// **Examples of payload**
+/// ```json
+/// {
+///   "event": "conf",
+///   "flags": 131072
+/// }
+///
+/// {
+///   "event": "subscribe",
+///   "channel": "book",
+///   "symbol": "tETHBTC",
+///   "prec": "P0",
+///   "freq": "F0",
+///   "len": "25"
+/// }
+/// ```
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Request {
+    Conf {
+        flags: u32,
+    },
+
+    Subscribe {
+        channel: String,
+
+        symbol: String,
+
+        prec: String,
+
+        /// F0 for realtime, F1 for every 2 seconds
+        freq: String,
+
+        /// 1|25|100|250
+        len: String,
+    },
+}
+
+/// Publication: Messages of a channel, arrays starting with the channel ID.
+///
+/// **Examples of payload**
+/// ```json
+/// [17082, [[0.069, 3, 4.2], [0.06901, 2, -2]]]
+///
+/// [17082, [0.069, 0, 1]]
+///
+/// [17082, "cs", 1047641315]
+///
+/// [17082, "hb"]
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum ChannelMessage {
+    Heartbeat(u64, Heartbeat),
+
+    /// CRC32 of the book after the previous update, as a signed 32-bit integer. Only sent once
+    /// requested with `conf`.
+    Checksum(u64, ChecksumTag, i32),
+
+    /// A single level which changed.
+    Update(u64, Level),
+
+    /// The whole book, bids first, sent on subscription.
+    Snapshot(u64, Vec<Level>),
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+enum Heartbeat {
+    #[serde(rename = "hb")]
+    Hb,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+enum ChecksumTag {
+    #[serde(rename = "cs")]
+    Cs,
+}
+
+/// Level of a precision book. Its side is told by the sign of the amount.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct Level {
+    price: Decimal,
+
+    /// Number of orders at the price. 0 removes the level, whose amount is then 1 for a bid and -1
+    /// for an ask
+    count: u64,
+
+    /// Positive for a bid, negative for an ask
+    amount: Decimal,
+}
+
+impl Level {
+    fn side(&self) -> orderbook::Side {
+        match self.amount.is_sign_positive() {
+            true => orderbook::Side::Bid,
+            false => orderbook::Side::Ask,
+        }
+    }
+}
+
+impl ToLevel for Level {
+    /// Converts a `bitfinex::Level` into a `orderbook::Level`, of amount 0 if it was removed.
+    fn to_level(&self, side: orderbook::Side) -> orderbook::Level {
+        let amount = match self.count {
+            0 => Decimal::ZERO,
+            _ => self.amount.abs(),
+        };
+        orderbook::Level::new(side, self.price, amount, Exchange::Bitfinex)
+    }
+}
+
+/// Books of the channels subscribed on a connection, by channel ID. Kept in full so that checksums
+/// can be validated, and the levels beyond the top are known once it is consumed.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Channels {
+    books: BTreeMap<u64, Depths>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Depths {
+    book: FullBook<Level>,
+}
+
+impl Depths {
+    fn apply(&mut self, level: &Level) {
+        let levels = self.book.side_mut(level.side());
+        match level.count {
+            0 => levels.remove(&level.price),
+            _ => levels.insert(level.price, level.clone()),
+        };
+    }
+
+    /// CRC32 of the best `CHECKSUM_LEVELS` bids and asks, alternating from the best bid as
+    /// `bid price:bid amount:ask price:ask amount:...`, asks keeping their negative amount.
+    fn checksum(&self) -> i32 {
+        let bids: Vec<&Level> = self.book.bids.values().rev().take(CHECKSUM_LEVELS).collect();
+        let asks: Vec<&Level> = self.book.asks.values().take(CHECKSUM_LEVELS).collect();
+
+        let mut fields = vec![];
+        for i in 0..CHECKSUM_LEVELS {
+            for side in [&bids, &asks] {
+                if let Some(level) = side.get(i) {
+                    fields.push(format!("{}:{}", level.price, level.amount));
+                }
+            }
+        }
+        crc32fast::hash(fields.join(":").as_bytes()) as i32
+    }
+
+    fn to_tick(&self) -> InTick {
+        self.book.to_tick(Exchange::Bitfinex, |side, _, level| level.to_level(side))
+    }
+}
+
+/// Returns the WebSocket once the subscription is confirmed, with the messages received until then.
+/// Bitfinex streams 25 or 100 levels, so the depth is rounded up to either.
+pub(crate) async fn connect(
+    url: &str,
+    symbol: &str,
+    depth: usize,
+    precision: Precision,
+    checksum: bool,
+    tape: &Tape,
+) -> Result<(websocket::WsStream, Vec<Message>), Error>
+{
+    let mut ws_stream = websocket::connect(Exchange::Bitfinex, symbol, url, tape).await?;
+    if checksum {
+        websocket::subscribe(&mut ws_stream, Exchange::Bitfinex, symbol, url, serialize(symbol, Request::Conf { flags: CHECKSUM_FLAG })?, tape).await?;
+    }
+    websocket::subscribe(&mut ws_stream, Exchange::Bitfinex, symbol, url, subscription(symbol, depth, precision)?, tape).await?;
+    let backlog = websocket::await_ack(&mut ws_stream, Exchange::Bitfinex, symbol, url, ack, tape).await?;
+    Ok((ws_stream, backlog))
+}
+
+/// Bitfinex answers an unknown symbol with an `error` event, and unknown flags with a failed `conf`.
+fn ack(text: &str) -> Ack {
+    match deserialize(text) {
+        Ok(Event::Control(Control::Subscribed { .. })) => Ack::Subscribed,
+        Ok(Event::Control(Control::Error { msg, code })) => Ack::Rejected(format!("{} ({})", msg, code)),
+        Ok(Event::Control(Control::Conf { status, flags })) if status != "OK" =>
+            Ack::Rejected(format!("conf {:?}: {}", flags, status)),
+        _ => Ack::Pending,
+    }
+}
+
+fn subscription(symbol: &str, depth: usize, precision: Precision) -> Result<String, Error> {
+    let len = if depth <= 25 { "25" } else { "100" };
+    serialize(symbol, Request::Subscribe {
+        channel: "book".to_string(),
+        symbol: trading_pair(symbol),
+        prec: precision.to_string(),
+        freq: "F0".to_string(),
+        len: len.to_string(),
+    })
+}
+
+fn serialize(symbol: &str, request: Request) -> Result<String, Error> {
+    let payload = format!("{:?}", request);
+    serde_json::to_string(&request).map_err(|e| Error::protocol(Exchange::Bitfinex, symbol, e, &payload))
+}
+
+/// Bitfinex names trading pairs with a `t` prefix, e.g. `tETHBTC`, separating currencies of more
+/// than 3 letters with a colon, e.g. `tTESTBTC:TESTUSD`.
+fn trading_pair(symbol: &str) -> String {
+    let symbol = symbol.to_uppercase();
+    match symbol.split_once('/') {
+        Some((base, quote)) if base.len() > 3 || quote.len() > 3 => format!("t{}:{}", base, quote),
+        Some((base, quote)) => format!("t{}{}", base, quote),
+        None => format!("t{}", symbol),
+    }
+}
+
+/// Routes channel messages to the book of their channel, and passes on the whole book once a snapshot
+/// replaced it or an update changed a single level of it. A checksum which does not match the book
+/// fails the message, as the book can no longer be trusted until the venue subscribes again.
+pub(crate) fn parse(msg: Message, symbol: &str, channels: &mut Channels) -> Result<Parsed, Error> {
+    metrics::message_received(Exchange::Bitfinex, &msg);
+    let x = match msg {
+        Message::Text(x) => x,
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); return Ok(Parsed::Nothing) },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); return Ok(Parsed::Nothing) },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); return Ok(Parsed::Nothing) },
+        Message::Close(x) => { info!(frame = ?x, "close"); return Ok(Parsed::Nothing) },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); return Ok(Parsed::Nothing) },
+    };
+    trace!(%x, "text message");
+
+    let e = deserialize(&x).map_err(|e| metrics::parse_error(Exchange::Bitfinex, symbol, e, &x))?;
+    let message = match e {
+        Event::Control(control) => return Ok(on_control(control, channels)),
+        Event::Channel(message) => message,
+    };
+
+    let chan_id = match &message {
+        ChannelMessage::Heartbeat(chan_id, _)
+        | ChannelMessage::Checksum(chan_id, _, _)
+        | ChannelMessage::Update(chan_id, _)
+        | ChannelMessage::Snapshot(chan_id, _) => *chan_id,
+    };
+    let depths = match channels.books.get_mut(&chan_id) {
+        Some(depths) => depths,
+        None => {
+            debug!(chan_id, "message of an unknown channel");
+            return Ok(Parsed::Nothing);
+        },
+    };
+
+    Ok(match message {
+        ChannelMessage::Heartbeat(..) => {
+            trace!(chan_id, "heartbeat");
+            Parsed::Nothing
+        },
+        ChannelMessage::Checksum(_, _, expected) => {
+            let computed = depths.checksum();
+            if computed != expected {
+                return Err(Error::ChecksumMismatch {
+                    exchange: Exchange::Bitfinex,
+                    symbol: symbol.to_string(),
+                    expected: expected as i64,
+                    computed: computed as i64,
+                    payload: error::excerpt(&x),
+                });
+            }
+            Parsed::Nothing
+        },
+        ChannelMessage::Update(_, level) => {
+            depths.apply(&level);
+            Parsed::Tick(depths.to_tick())
+        },
+        ChannelMessage::Snapshot(_, levels) => {
+            *depths = Depths::default();
+            levels.iter().for_each(|level| depths.apply(level));
+            Parsed::Snapshot(depths.to_tick())
+        },
+    })
+}
+
+fn on_control(control: Control, channels: &mut Channels) -> Parsed {
+    match control {
+        Control::Subscribed { chan_id, ref channel, .. } if channel == "book" => {
+            info!(event = ?control, "subscribed");
+            channels.books.insert(chan_id, Depths::default());
+            Parsed::Nothing
+        },
+        Control::Unsubscribed { chan_id, .. } => {
+            info!(event = ?control, "unsubscribed");
+            channels.books.remove(&chan_id);
+            Parsed::Nothing
+        },
+        Control::Error { msg, code } => Parsed::Error(format!("{} ({})", msg, code)),
+        Control::Conf { status, flags } if status != "OK" => Parsed::Error(format!("conf {:?}: {}", flags, status)),
+        Control::Info { platform: Some(Platform { status: 0 }), msg, .. } | Control::Info { code: Some(MAINTENANCE_STARTED), msg, .. } =>
+            Parsed::Status(TradingStatus::Maintenance, msg),
+        Control::Info { platform: Some(Platform { status: 1 }), msg, .. } | Control::Info { code: Some(MAINTENANCE_ENDED), msg, .. } =>
+            Parsed::Status(TradingStatus::Online, msg),
+        control => {
+            info!(event = ?control, "control message");
+            Parsed::Nothing
+        },
+    }
+}
+
+fn deserialize(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use crate::bitfinex::*;
+
+    fn level(price: Decimal, count: u64, amount: Decimal) -> Level {
+        Level { price, count, amount }
+    }
+
+    fn subscribed() -> Channels {
+        let mut channels = Channels::default();
+        parse(Message::Text(include_str!("../tests/fixtures/bitfinex/subscribed.json").to_string()), "ETH/BTC", &mut channels).unwrap();
+        channels
+    }
+
+    #[test]
+    fn should_deserialize_snapshot() -> serde_json::Result<()> {
+        assert_eq!(deserialize(include_str!("../tests/fixtures/bitfinex/book_snapshot.json"))?,
+                   Event::Channel(ChannelMessage::Snapshot(17082, vec![
+                       level(dec!(0.069), 3, dec!(4.2)),
+                       level(dec!(0.06899), 1, dec!(1.5)),
+                       level(dec!(0.06901), 2, dec!(-2)),
+                       level(dec!(0.06902), 4, dec!(-7.25)),
+                   ]))
+        );
+        assert_eq!(deserialize(include_str!("../tests/fixtures/bitfinex/book_update.json"))?,
+                   Event::Channel(ChannelMessage::Update(17082, level(dec!(0.069), 0, dec!(1)))));
+        assert_eq!(deserialize(include_str!("../tests/fixtures/bitfinex/checksum.json"))?,
+                   Event::Channel(ChannelMessage::Checksum(17082, ChecksumTag::Cs, 1047641315)));
+        assert_eq!(deserialize(r#"[17082,"hb"]"#)?, Event::Channel(ChannelMessage::Heartbeat(17082, Heartbeat::Hb)));
+        Ok(())
+    }
+
+    #[test]
+    fn should_deserialize_control_messages() -> serde_json::Result<()> {
+        assert_eq!(deserialize(include_str!("../tests/fixtures/bitfinex/subscribed.json"))?,
+                   Event::Control(Control::Subscribed {
+                       channel: "book".to_string(),
+                       chan_id: 17082,
+                       symbol: Some("tETHBTC".to_string()),
+                       prec: Some("P0".to_string()),
+                       len: Some("25".to_string()),
+                   })
+        );
+        assert_eq!(deserialize(include_str!("../tests/fixtures/bitfinex/info.json"))?,
+                   Event::Control(Control::Info { version: Some(2), platform: Some(Platform { status: 1 }), code: None, msg: None }));
+        assert_eq!(deserialize(include_str!("../tests/fixtures/bitfinex/error.json"))?,
+                   Event::Control(Control::Error { msg: "symbol: invalid".to_string(), code: 10300 }));
+        Ok(())
+    }
+
+    #[test]
+    fn should_serialize_requests() {
+        assert_eq!(subscription("eth/btc", 10, Precision::P0).unwrap(),
+                   r#"{"event":"subscribe","channel":"book","symbol":"tETHBTC","prec":"P0","freq":"F0","len":"25"}"#);
+        assert_eq!(subscription("TEST/USDT", 50, Precision::P2).unwrap(),
+                   r#"{"event":"subscribe","channel":"book","symbol":"tTEST:USDT","prec":"P2","freq":"F0","len":"100"}"#);
+        assert_eq!(serialize("ETH/BTC", Request::Conf { flags: CHECKSUM_FLAG }).unwrap(), r#"{"event":"conf","flags":131072}"#);
+    }
+
+    #[test]
+    fn should_ack_subscription() {
+        assert_eq!(ack(include_str!("../tests/fixtures/bitfinex/info.json")), Ack::Pending);
+        assert_eq!(ack(r#"{"event":"conf","status":"OK","flags":131072}"#), Ack::Pending);
+        assert_eq!(ack(include_str!("../tests/fixtures/bitfinex/subscribed.json")), Ack::Subscribed);
+        assert_eq!(ack(include_str!("../tests/fixtures/bitfinex/error.json")), Ack::Rejected("symbol: invalid (10300)".to_string()));
+    }
+
+    #[test]
+    fn should_convert_snapshot_and_delete() {
+        /*
+         * Given
+         */
+        let mut channels = subscribed();
+
+        /*
+         * When
+         */
+        let snapshot = parse(Message::Text(include_str!("../tests/fixtures/bitfinex/book_snapshot.json").to_string()), "ETH/BTC", &mut channels).unwrap();
+        let update = parse(Message::Text(include_str!("../tests/fixtures/bitfinex/book_update.json").to_string()), "ETH/BTC", &mut channels).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(snapshot, Parsed::Snapshot(InTick {
+            exchange: Exchange::Bitfinex,
+            bids: vec![
+                orderbook::Level::new(orderbook::Side::Bid, dec!(0.069), dec!(4.2), Exchange::Bitfinex),
+                orderbook::Level::new(orderbook::Side::Bid, dec!(0.06899), dec!(1.5), Exchange::Bitfinex),
+            ],
+            asks: vec![
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.06901), dec!(2), Exchange::Bitfinex),
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.06902), dec!(7.25), Exchange::Bitfinex),
+            ],
+        }));
+        assert_eq!(update, Parsed::Tick(InTick {
+            exchange: Exchange::Bitfinex,
+            bids: vec![orderbook::Level::new(orderbook::Side::Bid, dec!(0.06899), dec!(1.5), Exchange::Bitfinex)],
+            asks: vec![
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.06901), dec!(2), Exchange::Bitfinex),
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.06902), dec!(7.25), Exchange::Bitfinex),
+            ],
+        }));
+    }
+
+    #[test]
+    fn should_ignore_other_channels() {
+        let mut channels = subscribed();
+        let other = include_str!("../tests/fixtures/bitfinex/book_snapshot.json").replace("17082", "17083");
+        assert_eq!(parse(Message::Text(other.clone()), "ETH/BTC", &mut channels).unwrap(), Parsed::Nothing);
+        assert_eq!(parse(Message::Text(other), "ETH/BTC", &mut Channels::default()).unwrap(), Parsed::Nothing);
+    }
+
+    #[test]
+    fn should_validate_checksum() {
+        /*
+         * Given
+         */
+        let mut channels = subscribed();
+        parse(Message::Text(include_str!("../tests/fixtures/bitfinex/book_snapshot.json").to_string()), "ETH/BTC", &mut channels).unwrap();
+
+        /*
+         * When
+         */
+        let valid = parse(Message::Text(include_str!("../tests/fixtures/bitfinex/checksum.json").to_string()), "ETH/BTC", &mut channels);
+        let invalid = parse(Message::Text(include_str!("../tests/fixtures/bitfinex/checksum_bad.json").to_string()), "ETH/BTC", &mut channels);
+
+        /*
+         * Then
+         */
+        assert_eq!(valid.unwrap(), Parsed::Nothing);
+        assert!(matches!(invalid, Err(Error::ChecksumMismatch { expected: 1047641316, computed: 1047641315, .. })), "{:?}", invalid);
+    }
+
+    #[test]
+    fn should_report_maintenance() {
+        let mut channels = Channels::default();
+        let started = r#"{"event":"info","code":20060,"msg":"Entering in Maintenance mode."}"#;
+        assert_eq!(parse(Message::Text(started.to_string()), "ETH/BTC", &mut channels).unwrap(),
+                   Parsed::Status(TradingStatus::Maintenance, Some("Entering in Maintenance mode.".to_string())));
+        assert_eq!(parse(Message::Text(include_str!("../tests/fixtures/bitfinex/info.json").to_string()), "ETH/BTC", &mut channels).unwrap(),
+                   Parsed::Status(TradingStatus::Online, None));
+    }
+}
@@ -1,14 +1,17 @@
 use chrono::{DateTime, Utc};
 use crate::error::Error;
 use crate::orderbook::{self, Exchange, InTick, ToLevel, ToLevels, ToTick};
-use crate::websocket;
-use futures::SinkExt;
-use log::{debug, info};
+use crate::venue::Parsed;
+use crate::record::Tape;
+use crate::trade::{self, TradeSide};
+use crate::websocket::{self, Ack};
+use crate::metrics;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace};
 use tungstenite::protocol::Message;
 
-const BITSTAMP_WS_URL: &str = "wss://ws.bitstamp.net";
+pub(crate) const BITSTAMP_WS_URL: &str = "wss://ws.bitstamp.net";
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(tag = "event")]
@@ -16,6 +19,9 @@ enum Event {
     #[serde(rename = "data")]
     Data{data: InData, channel: Channel},
 
+    #[serde(rename = "trade")]
+    Trade{data: InTrade, channel: Channel},
+
     #[serde(rename = "bts:subscribe")]
     Subscribe{data: OutSubscription},
 
@@ -33,12 +39,12 @@ enum Event {
 }
 
 impl ToTick for Event {
-    /// Converts the `Event` into a `Option<InTick>`. Only keep the top ten levels of bids and asks.
+    /// Converts the `Event` into a `Option<InTick>`. Only keep the top `MAX_DEPTH` levels of bids and asks.
     fn maybe_to_tick(&self) -> Option<InTick> {
         match self {
             Event::Data { data, .. } => {
-                let bids = data.bids.to_levels(orderbook::Side::Bid, 10);
-                let asks = data.asks.to_levels(orderbook::Side::Ask, 10);
+                let bids = data.bids.to_levels(orderbook::Side::Bid, orderbook::MAX_DEPTH);
+                let asks = data.asks.to_levels(orderbook::Side::Ask, orderbook::MAX_DEPTH);
 
                 Some(InTick { exchange: Exchange::Bitstamp, bids, asks })
             },
@@ -64,6 +70,58 @@ struct InData {
     asks: Vec<Level>,
 }
 
+/// Trade published on the `live_trades` channel.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "data": {
+///     "id": 238452123,
+///     "timestamp": "1656000001",
+///     "amount": 0.5,
+///     "amount_str": "0.50000000",
+///     "price": 0.069005,
+///     "price_str": "0.06900500",
+///     "type": 1,
+///     "microtimestamp": "1656000001123456",
+///     "buy_order_id": 1505210573627393,
+///     "sell_order_id": 1505210573627392
+///   },
+///   "channel": "live_trades_ethbtc",
+///   "event": "trade"
+/// }
+/// ```
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct InTrade {
+    id: u64,
+
+    #[serde(with = "microtimestamp")]
+    microtimestamp: DateTime<Utc>,
+
+    /// Exact amount, `amount` being a float
+    amount_str: Decimal,
+
+    price_str: Decimal,
+
+    /// 0 when the taker bought, 1 when it sold
+    #[serde(rename = "type")]
+    kind: u8,
+}
+
+impl InTrade {
+    /// Converts a `bitstamp::InTrade` into a `trade::Trade`.
+    fn to_trade(&self) -> trade::Trade {
+        trade::Trade {
+            exchange: Exchange::Bitstamp,
+            price: self.price_str,
+            amount: self.amount_str,
+            side: if self.kind == 0 { TradeSide::Buy } else { TradeSide::Sell },
+            trade_id: Some(self.id.to_string()),
+            time: self.microtimestamp,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 struct InSubscription {}
 
@@ -88,52 +146,77 @@ impl ToLevel for Level {
 
 type Channel = String;
 
-pub(crate) async fn connect(symbol: &String) -> Result<websocket::WsStream, Error> {
-    let mut ws_stream = websocket::connect(BITSTAMP_WS_URL).await?;
-    subscribe(&mut ws_stream, symbol).await?;
-    Ok(ws_stream)
+/// Returns the WebSocket once the subscription is confirmed, with the messages received until then.
+/// The trades are subscribed once the book is, so that the confirmation awaited is the one of the book.
+pub(crate) async fn connect(url: &str, symbol: &str, trades: bool, tape: &Tape) -> Result<(websocket::WsStream, Vec<Message>), Error> {
+    let mut ws_stream = websocket::connect(Exchange::Bitstamp, symbol, url, tape).await?;
+    websocket::subscribe(&mut ws_stream, Exchange::Bitstamp, symbol, url, subscription(symbol, "order_book")?, tape).await?;
+    let backlog = websocket::await_ack(&mut ws_stream, Exchange::Bitstamp, symbol, url, ack, tape).await?;
+    if trades {
+        websocket::subscribe(&mut ws_stream, Exchange::Bitstamp, symbol, url, subscription(symbol, "live_trades")?, tape).await?;
+    }
+    Ok((ws_stream, backlog))
+}
+
+/// Bitstamp answers an unknown pair with `bts:error`.
+fn ack(text: &str) -> Ack {
+    match deserialize(text) {
+        Ok(Event::SubscriptionSucceeded { .. }) => Ack::Subscribed,
+        Ok(Event::Error { data, .. }) => Ack::Rejected(data.message),
+        _ => Ack::Pending,
+    }
 }
 
-pub(crate) fn parse(msg: Message) -> Result<Option<InTick>, Error> {
+pub(crate) fn parse(msg: Message, symbol: &str) -> Result<Parsed, Error> {
+    metrics::message_received(Exchange::Bitstamp, &msg);
     let e = match msg {
-        Message::Binary(x) => { info!("binary {:?}", x); None },
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); None },
         Message::Text(x) => {
-            debug!("{:?}", x);
-
-            let e= deserialize(x)?;
-            match e {
-                Event::Data{..} => debug!("{:?}", e),
-                _ => info!("{:?}", e),
+            trace!(%x, "text message");
+
+            let e= deserialize(&x).map_err(|e| metrics::parse_error(Exchange::Bitstamp, symbol, e, &x))?;
+            match &e {
+                Event::Data{ data, .. } => {
+                    trace!(microtimestamp = %data.microtimestamp, "order book");
+                    metrics::message_age(Exchange::Bitstamp, data.microtimestamp);
+                },
+                Event::Trade{ data, .. } => {
+                    trace!(id = data.id, price = %data.price_str, amount = %data.amount_str, "trade");
+                    metrics::message_age(Exchange::Bitstamp, data.microtimestamp);
+                },
+                _ => info!(event = ?e, "control message"),
             }
 
             Some(e)
         },
-        Message::Ping(x) => { info!("Ping {:?}", x); None },
-        Message::Pong(x) => { info!("Pong {:?}", x); None },
-        Message::Close(x) => { info!("Close {:?}", x); None },
-        Message::Frame(x) => { info!("Frame {:?}", x); None },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); None },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); None },
+        Message::Close(x) => { info!(frame = ?x, "close"); None },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); None },
     };
-    Ok(e.map(|e| e.maybe_to_tick()).flatten())
+    match e {
+        Some(Event::Error { data, .. }) => return Ok(Parsed::Error(data.message)),
+        Some(Event::Trade { data, .. }) => return Ok(Parsed::Trades(vec![data.to_trade()])),
+        _ => {},
+    }
+    Ok(e.and_then(|e| e.maybe_to_tick()).into())
 }
 
-async fn subscribe (
-    rx: &mut websocket::WsStream,
-    symbol: &String,
-) -> Result<(), Error>
-{
-    let symbol = symbol.to_lowercase().replace("/", "");
-    let channel = format!("order_book_{}", symbol);
-    let msg = serialize(Event::Subscribe{ data: OutSubscription { channel } })?;
-    rx.send(Message::Text(msg)).await?;
-    Ok(())
+/// Bitstamp names channels after their kind and the pair, e.g. `order_book_ethbtc`.
+fn subscription(symbol: &str, kind: &str) -> Result<String, Error> {
+    let pair = symbol.to_lowercase().replace("/", "");
+    let channel = format!("{}_{}", kind, pair);
+    let sub = Event::Subscribe{ data: OutSubscription { channel } };
+    let payload = format!("{:?}", sub);
+    serialize(sub).map_err(|e| Error::protocol(Exchange::Bitstamp, symbol, e, &payload))
 }
 
-fn deserialize(s: String) -> serde_json::Result<Event> {
-    Ok(serde_json::from_str(&s)?)
+fn deserialize(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
 }
 
 fn serialize(e: Event) -> serde_json::Result<String> {
-    Ok(serde_json::to_string(&e)?)
+    serde_json::to_string(&e)
 }
 
 mod timestamp {
@@ -183,7 +266,7 @@ mod test {
     use crate::bitstamp::*;
 
     #[test]
-    fn should_deserialize_event_data() -> Result<(), Error> {
+    fn should_deserialize_event_data() -> serde_json::Result<()> {
         assert_eq!(deserialize("{\
                        \"data\":{\
                            \"timestamp\":\"1652103479\",\
@@ -193,7 +276,7 @@ mod test {
                        },\
                        \"channel\":\"order_book_ethbtc\",\
                        \"event\":\"data\"\
-                   }".to_string())?,
+                   }")?,
                    Event::Data{
                        data: InData {
                            timestamp: Utc.timestamp(1652103479, 0),
@@ -213,12 +296,12 @@ mod test {
     }
 
     #[test]
-    fn should_deserialize_subscription_succeeded() -> Result<(), Error> {
+    fn should_deserialize_subscription_succeeded() -> serde_json::Result<()> {
         assert_eq!(deserialize("{\
                        \"data\":{},\
                        \"channel\":\"order_book_ethbtc\",\
                        \"event\":\"bts:subscription_succeeded\"
-                   }".to_string())?,
+                   }")?,
                    Event::SubscriptionSucceeded{
                        data: InSubscription{},
                        channel: "order_book_ethbtc".to_string(),
@@ -227,7 +310,7 @@ mod test {
     }
 
     #[test]
-    fn should_deserialize_error() -> Result<(), Error> {
+    fn should_deserialize_error() -> serde_json::Result<()> {
         assert_eq!(deserialize("{\
                        \"event\":\"bts:error\",\
                        \"channel\":\"\",\
@@ -235,7 +318,7 @@ mod test {
                            \"code\":null,\
                            \"message\":\"Incorrect JSON format.\"\
                        }\
-                   }".to_string())?,
+                   }")?,
                    Event::Error{
                        data: InError{ code: None, message: "Incorrect JSON format.".to_string() },
                        channel: "".to_string(),
@@ -244,7 +327,7 @@ mod test {
     }
 
     #[test]
-    fn should_serialize_subscribe() -> Result<(), Error> {
+    fn should_serialize_subscribe() -> serde_json::Result<()> {
         assert_eq!(serialize(Event::Subscribe{
             data: OutSubscription { channel: "order_book_ethbtc".to_string() }
         })?,
@@ -252,5 +335,73 @@ mod test {
         );
         Ok(())
     }
-}
 
+    #[test]
+    fn should_pass_on_trade() -> Result<(), Error> {
+        /*
+         * Given
+         */
+        let msg = Message::Text(include_str!("../tests/fixtures/bitstamp/trade.json").to_string());
+
+        /*
+         * When
+         */
+        let parsed = parse(msg, "ETH/BTC")?;
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Trades(vec![trade::Trade {
+            exchange: Exchange::Bitstamp,
+            price: dec!(0.06900500),
+            amount: dec!(0.5),
+            side: TradeSide::Sell,
+            trade_id: Some("238452123".to_string()),
+            time: Utc.timestamp_nanos(1656000001123456000),
+        }]));
+        assert_eq!(subscription("ETH/BTC", "live_trades")?,
+                   "{\"event\":\"bts:subscribe\",\"data\":{\"channel\":\"live_trades_ethbtc\"}}".to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_ack_subscription() {
+        /*
+         * Given
+         */
+        let subscribed = include_str!("../tests/fixtures/bitstamp/subscription_succeeded.json");
+        let rejected = include_str!("../tests/fixtures/bitstamp/error.json");
+
+        /*
+         * When
+         */
+        let (subscribed, rejected) = (ack(subscribed), ack(rejected));
+
+        /*
+         * Then
+         */
+        assert_eq!(subscribed, Ack::Subscribed);
+        assert_eq!(rejected, Ack::Rejected("Bad subscription string.".to_string()));
+    }
+
+    #[test]
+    fn should_report_error_after_subscription() -> Result<(), Error> {
+        /*
+         * Given
+         */
+        let msg = Message::Text(include_str!("../tests/fixtures/bitstamp/error.json").to_string());
+
+        /*
+         * When
+         */
+        let parsed = parse(msg, "ETH/BTC")?;
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Error("Bad subscription string.".to_string()));
+
+        Ok(())
+    }
+}
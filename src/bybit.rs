@@ -0,0 +1,504 @@
+use crate::error::{self, Error};
+use crate::orderbook::{Exchange, FullBook, InTick, ToLevel};
+use crate::venue::Parsed;
+use crate::record::Tape;
+use crate::websocket::{self, Ack};
+use crate::{metrics, orderbook};
+use chrono::{TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, info, trace};
+use tungstenite::protocol::Message;
+
+/// Spot markets. USDT and USDC perpetuals are streamed from `wss://stream.bybit.com/v5/public/linear`
+/// in the same format, which a venue `url` selects.
+pub(crate) const BYBIT_WS_URL: &str = "wss://stream.bybit.com/v5/public/spot";
+
+/// Bybit drops connections on which the client sent nothing for a while, and recommends a ping
+/// every 20 seconds.
+pub(crate) const PING_EVERY: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Event {
+    Topic(Topic),
+
+    Response(Response),
+}
+
+/// Response to a request of the client, such as a subscription or a ping.
+///
+/// **Examples of payload**
+///
+/// ```json
+/// {
+///   "success": true,
+///   "ret_msg": "subscribe",
+///   "conn_id": "2324d924-aa4d-45b0-a858-7b8be29ab52b",
+///   "req_id": "10001",
+///   "op": "subscribe"
+/// }
+///
+/// {
+///   "success": false,
+///   "ret_msg": "error:handler not found,topic:orderbook.50.ETHXYZ",
+///   "conn_id": "2324d924-aa4d-45b0-a858-7b8be29ab52b",
+///   "op": "subscribe"
+/// }
+///
+/// {
+///   "req_id": "",
+///   "op": "pong",
+///   "args": ["1675418560633"],
+///   "conn_id": "cfcb4ocsvfriu23r3er0-1b"
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct Response {
+    /// subscribe|unsubscribe|ping|pong
+    op: String,
+
+    /// Whether the request succeeded. Missing from the `pong` of the linear endpoint
+    success: Option<bool>,
+
+    /// `pong`, the topic subscribed or the error
+    ret_msg: Option<String>,
+
+    conn_id: String,
+
+    /// Optional - client originated ID reflected in response message
+    req_id: Option<String>,
+
+    /// Server time in milliseconds, for a `pong`
+    args: Option<Vec<String>>,
+}
+
+/// Request. Subscribes to topics or pings the server.
+///
+/// **Example of payload**
+///
+/// ```json
+/// {
+///   "op": "subscribe",
+///   "args": ["orderbook.50.BTCUSDT"]
+/// }
+///
+/// {
+///   "op": "ping"
+/// }
+/// ```
+#[derive(Debug, Serialize, PartialEq)]
+struct Request {
+    op: Op,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    args: Vec<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Op {
+    Subscribe,
+    Ping,
+}
+
+/// Publication: Order book levels of an `orderbook.{depth}.{symbol}` topic. On subscription a
+/// snapshot is pushed, followed by deltas of the levels which changed. Another snapshot may follow at
+/// any time, e.g. after a restart of the service, and replaces the book.
+///
+/// **Example of payload**
+///
+/// ```json
+/// {
+///   "topic": "orderbook.50.BTCUSDT",
+///   "type": "snapshot",
+///   "ts": 1672304484978,
+///   "data": {
+///     "s": "BTCUSDT",
+///     "b": [
+///       ["16493.50", "0.006"],
+///       ["16493.00", "0.100"]
+///     ],
+///     "a": [
+///       ["16611.00", "0.029"],
+///       ["16612.00", "0.213"]
+///     ],
+///     "u": 18521288,
+///     "seq": 7961638724
+///   },
+///   "cts": 1672304484976
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct Topic {
+    topic: String,
+
+    #[serde(rename = "type")]
+    kind: Kind,
+
+    /// Time the message was generated at, milliseconds since epoch
+    ts: i64,
+
+    data: Book,
+
+    /// Time of the matching engine, milliseconds since epoch
+    cts: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Snapshot,
+    Delta,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Book {
+    /// Symbol name
+    s: String,
+
+    /// Bids, descending from best bid. A size of 0 removes the level in a delta
+    b: Vec<Level>,
+
+    /// Asks, ascending from best ask. A size of 0 removes the level in a delta
+    a: Vec<Level>,
+
+    /// Update ID, one more with every message. 1 for a snapshot sent as the service restarted
+    u: u64,
+
+    /// Cross sequence
+    seq: u64,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct Level {
+    price: Decimal,
+    size: Decimal,
+}
+
+impl ToLevel for Level {
+    /// Converts a `bybit::Level` into a `orderbook::Level`.
+    fn to_level(&self, side: orderbook::Side) -> orderbook::Level {
+        orderbook::Level::new(side, self.price, self.size, Exchange::Bybit)
+    }
+}
+
+/// Book of a connection, kept in full, as a delta only sends the levels which changed and the merge may
+/// need those beyond the top once it is consumed.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Depths {
+    book: FullBook<Level>,
+
+    /// Update ID of the last message of the connection, which the next delta must follow.
+    last: Option<u64>,
+}
+
+impl Depths {
+    /// Replaces the book by a snapshot, or applies the levels of a delta, a size of 0 removing the level.
+    fn apply(&mut self, snapshot: bool, book: Book) {
+        if snapshot {
+            self.book.clear();
+        }
+        self.last = Some(book.u);
+
+        for (levels, side) in [(&mut self.book.bids, book.b), (&mut self.book.asks, book.a)] {
+            for level in side {
+                match level.size.is_zero() {
+                    true => levels.remove(&level.price),
+                    false => levels.insert(level.price, level),
+                };
+            }
+        }
+    }
+
+    fn to_tick(&self) -> InTick {
+        self.book.to_tick(Exchange::Bybit, |side, _, level| level.to_level(side))
+    }
+}
+
+/// Returns the WebSocket once the subscription is confirmed, with the messages received until then.
+/// Bybit only streams 1, 50, 200 or, for derivatives, 500 levels, so the depth is rounded up to 50 or 200.
+pub(crate) async fn connect(url: &str, symbol: &str, depth: usize, tape: &Tape) -> Result<(websocket::WsStream, Vec<Message>), Error> {
+    let mut ws_stream = websocket::connect(Exchange::Bybit, symbol, url, tape).await?;
+    websocket::subscribe(&mut ws_stream, Exchange::Bybit, symbol, url, subscription(symbol, depth)?, tape).await?;
+    let backlog = websocket::await_ack(&mut ws_stream, Exchange::Bybit, symbol, url, ack, tape).await?;
+    Ok((ws_stream, backlog))
+}
+
+/// Bybit answers every subscription, with `success` false for an unknown topic.
+fn ack(text: &str) -> Ack {
+    match deserialize(text) {
+        Ok(Event::Response(Response { op, success: Some(success), ret_msg, .. })) if op == "subscribe" => match success {
+            true => Ack::Subscribed,
+            false => Ack::Rejected(ret_msg.unwrap_or(op)),
+        },
+        _ => Ack::Pending,
+    }
+}
+
+fn subscription(symbol: &str, depth: usize) -> Result<String, Error> {
+    let depth = if depth <= 50 { 50 } else { 200 };
+    let sub = Request { op: Op::Subscribe, args: vec![format!("orderbook.{}.{}", depth, market(symbol))] };
+    let payload = format!("{:?}", sub);
+    serde_json::to_string(&sub).map_err(|e| Error::protocol(Exchange::Bybit, symbol, e, &payload))
+}
+
+/// Application level ping, sent every `PING_EVERY`.
+pub(crate) fn ping() -> Message {
+    // cannot fail, as the request holds nothing but names
+    Message::Text(serde_json::to_string(&Request { op: Op::Ping, args: vec![] }).unwrap_or_default())
+}
+
+/// Bybit names markets without a separator, e.g. `BTCUSDT`.
+fn market(symbol: &str) -> String {
+    symbol.to_uppercase().replace('/', "")
+}
+
+/// Applies every book message to `depths`, which holds the book of the connection, and passes on the
+/// whole book. A delta which does not follow the update ID of the previous message fails, as the book
+/// can no longer be trusted until the venue subscribes again.
+pub(crate) fn parse(msg: Message, symbol: &str, depths: &mut Depths) -> Result<Parsed, Error> {
+    metrics::message_received(Exchange::Bybit, &msg);
+    let e = match msg {
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); None },
+        Message::Text(x) => {
+            trace!(%x, "text message");
+            let e = deserialize(&x).map_err(|e| metrics::parse_error(Exchange::Bybit, symbol, e, &x))?;
+            if let Event::Topic(Topic { kind: Kind::Delta, data, .. }) = &e {
+                match depths.last {
+                    // a snapshot sent as the service restarted may come as a delta
+                    _ if data.u == 1 => {},
+                    Some(last) if data.u == last + 1 => {},
+                    last => return Err(Error::SequenceGap {
+                        exchange: Exchange::Bybit,
+                        symbol: symbol.to_string(),
+                        expected: last.map_or(0, |last| last + 1),
+                        got: data.u,
+                        payload: error::excerpt(&x),
+                    }),
+                }
+            }
+            Some(e)
+        },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); None },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); None },
+        Message::Close(x) => { info!(frame = ?x, "close"); None },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); None },
+    };
+
+    Ok(match e {
+        Some(Event::Topic(Topic { kind, ts, data, .. })) => {
+            trace!(?kind, u = data.u, seq = data.seq, "book");
+            match kind {
+                Kind::Delta if data.u != 1 => {
+                    if let Some(ts) = Utc.timestamp_millis_opt(ts).single() {
+                        metrics::message_age(Exchange::Bybit, ts);
+                    }
+                    depths.apply(false, data);
+                    Parsed::Tick(depths.to_tick())
+                },
+                _ => {
+                    depths.apply(true, data);
+                    Parsed::Snapshot(depths.to_tick())
+                },
+            }
+        },
+        Some(Event::Response(Response { success: Some(false), ret_msg, op, .. })) => Parsed::Error(ret_msg.unwrap_or(op)),
+        Some(Event::Response(Response { op, .. })) if op == "pong" || op == "ping" => {
+            trace!("pong");
+            Parsed::Nothing
+        },
+        Some(Event::Response(response)) => {
+            info!(event = ?response, "control message");
+            Parsed::Nothing
+        },
+        None => Parsed::Nothing,
+    })
+}
+
+fn deserialize(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use crate::bybit::*;
+    use crate::orderbook::Exchanges;
+
+    fn level(price: Decimal, size: Decimal) -> Level {
+        Level { price, size }
+    }
+
+    #[test]
+    fn should_deserialize_snapshot() -> serde_json::Result<()> {
+        assert_eq!(deserialize(include_str!("../tests/fixtures/bybit/orderbook_snapshot.json"))?,
+                   Event::Topic(Topic {
+                       topic: "orderbook.50.ETHBTC".to_string(),
+                       kind: Kind::Snapshot,
+                       ts: 1656000000100,
+                       data: Book {
+                           s: "ETHBTC".to_string(),
+                           b: vec![level(dec!(0.069000), dec!(4.2)), level(dec!(0.068990), dec!(1.5))],
+                           a: vec![level(dec!(0.069010), dec!(2.0)), level(dec!(0.069020), dec!(7.25))],
+                           u: 500,
+                           seq: 7961638724,
+                       },
+                       cts: Some(1656000000098),
+                   })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_deserialize_responses() -> serde_json::Result<()> {
+        assert_eq!(deserialize(include_str!("../tests/fixtures/bybit/subscribed.json"))?,
+                   Event::Response(Response {
+                       op: "subscribe".to_string(),
+                       success: Some(true),
+                       ret_msg: Some("".to_string()),
+                       conn_id: "cfcb4ocsvfriu23r3er0-1b".to_string(),
+                       req_id: None,
+                       args: None,
+                   })
+        );
+        assert_eq!(deserialize(r#"{"req_id":"","op":"pong","args":["1675418560633"],"conn_id":"cfcb4ocsvfriu23r3er0-1b"}"#)?,
+                   Event::Response(Response {
+                       op: "pong".to_string(),
+                       success: None,
+                       ret_msg: None,
+                       conn_id: "cfcb4ocsvfriu23r3er0-1b".to_string(),
+                       req_id: Some("".to_string()),
+                       args: Some(vec!["1675418560633".to_string()]),
+                   })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_serialize_requests() {
+        assert_eq!(subscription("eth/btc", 10).unwrap(), r#"{"op":"subscribe","args":["orderbook.50.ETHBTC"]}"#);
+        assert_eq!(subscription("eth/btc", 100).unwrap(), r#"{"op":"subscribe","args":["orderbook.200.ETHBTC"]}"#);
+        assert_eq!(ping(), Message::Text(r#"{"op":"ping"}"#.to_string()));
+    }
+
+    #[test]
+    fn should_ack_subscription() {
+        assert_eq!(ack(include_str!("../tests/fixtures/bybit/subscribed.json")), Ack::Subscribed);
+        assert_eq!(ack(include_str!("../tests/fixtures/bybit/subscription_error.json")),
+                   Ack::Rejected("error:handler not found,topic:orderbook.50.ETHXYZ".to_string()));
+        assert_eq!(ack(include_str!("../tests/fixtures/bybit/orderbook_snapshot.json")), Ack::Pending);
+    }
+
+    #[test]
+    fn should_convert_snapshot_and_delta() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+
+        /*
+         * When
+         */
+        let snapshot = parse(Message::Text(include_str!("../tests/fixtures/bybit/orderbook_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+        let delta = parse(Message::Text(include_str!("../tests/fixtures/bybit/orderbook_delta.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert!(matches!(snapshot, Parsed::Snapshot(InTick { exchange: Exchange::Bybit, ref bids, .. }) if bids.len() == 2));
+        assert_eq!(delta, Parsed::Tick(InTick {
+            exchange: Exchange::Bybit,
+            bids: vec![
+                orderbook::Level::new(orderbook::Side::Bid, dec!(0.068990), dec!(1.5), Exchange::Bybit),
+                orderbook::Level::new(orderbook::Side::Bid, dec!(0.068980), dec!(3.1), Exchange::Bybit),
+            ],
+            asks: vec![
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.069010), dec!(1.4), Exchange::Bybit),
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.069020), dec!(7.25), Exchange::Bybit),
+            ],
+        }));
+        assert_eq!(depths.last, Some(501));
+    }
+
+    #[test]
+    fn should_merge_levels_beyond_venue_depth_once_top_deleted() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        let mut exchanges = Exchanges::new();
+        exchanges.set_venue(Exchange::Bybit, 1, dec!(0));
+        if let Parsed::Snapshot(t) = parse(Message::Text(include_str!("../tests/fixtures/bybit/orderbook_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap() {
+            exchanges.replace(t);
+        }
+
+        /*
+         * When
+         */
+        if let Parsed::Tick(t) = parse(Message::Text(include_str!("../tests/fixtures/bybit/orderbook_delta.json").to_string()), "ETH/BTC", &mut depths).unwrap() {
+            exchanges.update(t);
+        }
+
+        /*
+         * Then
+         */
+        assert_eq!(exchanges.to_tick().bids, vec![
+            orderbook::Level::new(orderbook::Side::Bid, dec!(0.068990), dec!(1.5), Exchange::Bybit),
+        ]);
+    }
+
+    #[test]
+    fn should_fail_on_update_id_gap() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        let delta = include_str!("../tests/fixtures/bybit/orderbook_delta.json");
+
+        /*
+         * When
+         */
+        let before_snapshot = parse(Message::Text(delta.to_string()), "ETH/BTC", &mut depths);
+        parse(Message::Text(include_str!("../tests/fixtures/bybit/orderbook_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+        parse(Message::Text(delta.to_string()), "ETH/BTC", &mut depths).unwrap();
+        let repeated = parse(Message::Text(delta.to_string()), "ETH/BTC", &mut depths);
+
+        /*
+         * Then
+         */
+        assert!(matches!(before_snapshot, Err(Error::SequenceGap { expected: 0, got: 501, .. })), "{:?}", before_snapshot);
+        assert!(matches!(repeated, Err(Error::SequenceGap { expected: 502, got: 501, .. })), "{:?}", repeated);
+    }
+
+    #[test]
+    fn should_reset_on_restart_snapshot() {
+        /*
+         * Given
+         */
+        let mut depths = Depths { last: Some(501), ..Depths::default() };
+        let restarted = include_str!("../tests/fixtures/bybit/orderbook_delta.json")
+            .replace(r#""u":501"#, r#""u":1"#);
+
+        /*
+         * When
+         */
+        let parsed = parse(Message::Text(restarted), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert!(matches!(parsed, Parsed::Snapshot(_)), "{:?}", parsed);
+        assert_eq!(depths.last, Some(1));
+    }
+
+    #[test]
+    fn should_report_errors_and_ignore_pongs() {
+        let mut depths = Depths::default();
+        assert_eq!(parse(Message::Text(include_str!("../tests/fixtures/bybit/subscription_error.json").to_string()), "ETH/BTC", &mut depths).unwrap(),
+                   Parsed::Error("error:handler not found,topic:orderbook.50.ETHXYZ".to_string()));
+        assert_eq!(parse(Message::Text(r#"{"success":true,"ret_msg":"pong","conn_id":"0970e817","op":"ping"}"#.to_string()), "ETH/BTC", &mut depths).unwrap(),
+                   Parsed::Nothing);
+    }
+}
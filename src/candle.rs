@@ -0,0 +1,313 @@
+use crate::orderbook::Exchange;
+use crate::trade::Trade;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, VecDeque};
+use tokio::sync::broadcast;
+use tracing::trace;
+
+/// Candles held for a `Candles` stream which falls behind, beyond which it skips the oldest.
+const CANDLES_CAPACITY: usize = 1024;
+
+/// Time after the end of its interval a candle is closed at, unless something of a later interval
+/// arrived before, so that trades which took a little longer to come in still count.
+const CLOSE_AFTER_SECS: i64 = 2;
+
+/// What the prices of a series of candles are taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Source {
+    /// Prices and amounts of the trades, at the time the exchange matched them.
+    Trades,
+
+    /// Middle of the best bid and offer whenever the book changes. Nothing is traded.
+    Mid,
+}
+
+/// Identifies a series of candles of a symbol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct SeriesKey {
+    pub(crate) source: Source,
+
+    /// `None` for the consolidated series, of the exchanges merged into the book.
+    pub(crate) exchange: Option<Exchange>,
+
+    pub(crate) interval_secs: u64,
+}
+
+/// Open, high, low and close of the prices of an interval, with the amount traded.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Candle {
+    /// Start of the interval, a multiple of it since epoch.
+    pub(crate) start: DateTime<Utc>,
+
+    pub(crate) open: Decimal,
+    pub(crate) high: Decimal,
+    pub(crate) low: Decimal,
+    pub(crate) close: Decimal,
+
+    /// Amount traded, 0 for mid prices.
+    pub(crate) volume: Decimal,
+
+    /// Trades, or mid prices, which made the candle.
+    pub(crate) count: u64,
+
+    /// Whether the interval is over, after which the candle no longer changes.
+    pub(crate) closed: bool,
+}
+
+impl Candle {
+    fn new(start: DateTime<Utc>, price: Decimal, volume: Decimal) -> Candle {
+        Candle { start, open: price, high: price, low: price, close: price, volume, count: 1, closed: false }
+    }
+
+    fn add(&mut self, price: Decimal, volume: Decimal) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += volume;
+        self.count += 1;
+    }
+}
+
+/// Candle being built and the latest closed ones of a series, oldest first. Intervals without trades
+/// or book changes have no candle.
+#[derive(Debug, Default)]
+struct Series {
+    current: Option<Candle>,
+    history: VecDeque<Candle>,
+}
+
+/// Builds the candles of a symbol at every interval, from the trades and the mid prices of every
+/// exchange and consolidated. The latest `history` closed candles of each series are kept for the
+/// `Candles` streams to start with, and every change is sent to them.
+#[derive(Debug)]
+pub(crate) struct Engine {
+    intervals_secs: Vec<u64>,
+    history: usize,
+    series: BTreeMap<SeriesKey, Series>,
+    tx: broadcast::Sender<(SeriesKey, Candle)>,
+}
+
+impl Engine {
+    pub(crate) fn new(intervals_secs: Vec<u64>, history: usize) -> Engine {
+        Engine { intervals_secs, history, series: BTreeMap::new(), tx: broadcast::channel(CANDLES_CAPACITY).0 }
+    }
+
+    pub(crate) fn intervals_secs(&self) -> &[u64] {
+        &self.intervals_secs
+    }
+
+    /// Changes of every series from now on, without the candles built so far.
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<(SeriesKey, Candle)> {
+        self.tx.subscribe()
+    }
+
+    /// Latest `count` closed candles of the series, oldest first, followed by the one being built.
+    pub(crate) fn backfill(&self, key: &SeriesKey, count: usize) -> Vec<Candle> {
+        match self.series.get(key) {
+            Some(series) => {
+                let skip = series.history.len().saturating_sub(count);
+                series.history.iter().skip(skip).chain(series.current.iter()).cloned().collect()
+            },
+            None => vec![],
+        }
+    }
+
+    /// Adds the trade to the series of its exchange, and to the consolidated ones if its exchange is
+    /// merged into the book.
+    pub(crate) fn on_trade(&mut self, trade: &Trade, merged: bool) {
+        self.add(Source::Trades, Some(trade.exchange), trade.price, trade.amount, trade.time);
+        if merged {
+            self.add(Source::Trades, None, trade.price, trade.amount, trade.time);
+        }
+    }
+
+    /// Adds the mid price of the best bid and offer of the exchange, or across the merged ones for `None`.
+    pub(crate) fn on_mid(&mut self, exchange: Option<Exchange>, price: Decimal, at: DateTime<Utc>) {
+        self.add(Source::Mid, exchange, price, Decimal::ZERO, at);
+    }
+
+    /// Closes the candles whose interval ended `CLOSE_AFTER_SECS` before `now`.
+    pub(crate) fn close_until(&mut self, now: DateTime<Utc>) {
+        for (key, series) in self.series.iter_mut() {
+            let ended = series.current.as_ref()
+                .is_some_and(|candle| candle.start + Duration::seconds(key.interval_secs as i64 + CLOSE_AFTER_SECS) <= now);
+            if ended {
+                close(key, series, self.history, &self.tx);
+            }
+        }
+    }
+
+    fn add(&mut self, source: Source, exchange: Option<Exchange>, price: Decimal, volume: Decimal, at: DateTime<Utc>) {
+        for interval_secs in self.intervals_secs.iter().copied() {
+            let key = SeriesKey { source, exchange, interval_secs };
+            let start = start_of(at, interval_secs);
+            let series = self.series.entry(key).or_default();
+
+            let current_start = series.current.as_ref().map(|candle| candle.start)
+                .or_else(|| series.history.back().map(|candle| candle.start));
+            match current_start {
+                Some(current_start) if start < current_start
+                    || (start == current_start && series.current.is_none()) => {
+                    trace!(?key, %at, "late for a closed candle, dropped");
+                    continue;
+                },
+                Some(current_start) if start > current_start => close(&key, series, self.history, &self.tx),
+                _ => {},
+            }
+
+            let candle = match &mut series.current {
+                Some(candle) => {
+                    candle.add(price, volume);
+                    candle
+                },
+                None => series.current.insert(Candle::new(start, price, volume)),
+            };
+            // no stream being open is fine
+            let _ = self.tx.send((key, candle.clone()));
+        }
+    }
+}
+
+/// Closes the candle being built, if any, and keeps it in the history of the series.
+fn close(key: &SeriesKey, series: &mut Series, history: usize, tx: &broadcast::Sender<(SeriesKey, Candle)>) {
+    if let Some(mut candle) = series.current.take() {
+        candle.closed = true;
+        let _ = tx.send((*key, candle.clone()));
+        if series.history.len() == history {
+            series.history.pop_front();
+        }
+        series.history.push_back(candle);
+    }
+}
+
+/// Start of the interval the time falls into. Intervals are at most a week long, so the time is never
+/// left as is.
+fn start_of(at: DateTime<Utc>, interval_secs: u64) -> DateTime<Utc> {
+    at.duration_trunc(Duration::seconds(interval_secs as i64)).unwrap_or(at)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use crate::candle::*;
+    use crate::trade::TradeSide;
+    use chrono::TimeZone;
+
+    fn trade(exchange: Exchange, price: Decimal, amount: Decimal, ms: i64) -> Trade {
+        Trade { exchange, price, amount, side: TradeSide::Buy, trade_id: None, time: Utc.timestamp_millis(ms) }
+    }
+
+    fn key(exchange: Option<Exchange>, interval_secs: u64) -> SeriesKey {
+        SeriesKey { source: Source::Trades, exchange, interval_secs }
+    }
+
+    #[test]
+    fn should_build_candles_of_trades() {
+        /*
+         * Given
+         */
+        let mut engine = Engine::new(vec![60], 10);
+        let mut rx = engine.subscribe();
+
+        /*
+         * When
+         */
+        engine.on_trade(&trade(Exchange::Kraken, dec!(10), dec!(1), 1656000000000), true);
+        engine.on_trade(&trade(Exchange::Coinbase, dec!(12), dec!(2), 1656000010000), true);
+        engine.on_trade(&trade(Exchange::Kraken, dec!(9), dec!(0.5), 1656000050000), true);
+        engine.on_trade(&trade(Exchange::Kraken, dec!(11), dec!(1), 1656000061000), true);
+
+        /*
+         * Then
+         */
+        assert_eq!(engine.backfill(&key(None, 60), 10), vec![
+            Candle {
+                start: Utc.timestamp_millis(1656000000000),
+                open: dec!(10), high: dec!(12), low: dec!(9), close: dec!(9), volume: dec!(3.5), count: 3, closed: true,
+            },
+            Candle {
+                start: Utc.timestamp_millis(1656000060000),
+                open: dec!(11), high: dec!(11), low: dec!(11), close: dec!(11), volume: dec!(1), count: 1, closed: false,
+            },
+        ]);
+        assert_eq!(engine.backfill(&key(Some(Exchange::Kraken), 60), 10)[0].volume, dec!(1.5));
+        assert_eq!(engine.backfill(&key(Some(Exchange::Coinbase), 60), 10).len(), 1);
+        let (first_key, first) = rx.try_recv().unwrap();
+        assert_eq!((first_key, first.count), (key(Some(Exchange::Kraken), 60), 1));
+    }
+
+    #[test]
+    fn should_leave_unmerged_trades_out_of_consolidated_candles() {
+        /*
+         * Given
+         */
+        let mut engine = Engine::new(vec![60], 10);
+
+        /*
+         * When
+         */
+        engine.on_trade(&trade(Exchange::BinanceFutures, dec!(20000), dec!(1), 1656000000000), false);
+
+        /*
+         * Then
+         */
+        assert_eq!(engine.backfill(&key(None, 60), 10), vec![]);
+        assert_eq!(engine.backfill(&key(Some(Exchange::BinanceFutures), 60), 10).len(), 1);
+    }
+
+    #[test]
+    fn should_close_candles_over_and_drop_late_trades() {
+        /*
+         * Given
+         */
+        let mut engine = Engine::new(vec![60, 300], 2);
+        for minute in 0..4 {
+            engine.on_trade(&trade(Exchange::Kraken, dec!(10), dec!(1), 1656000000000 + minute * 60000), true);
+        }
+
+        /*
+         * When
+         */
+        engine.close_until(Utc.timestamp_millis(1656000000000 + 4 * 60000 + 1000));
+        let still_open = engine.backfill(&key(None, 60), 10);
+        engine.close_until(Utc.timestamp_millis(1656000000000 + 4 * 60000 + 2000));
+        engine.on_trade(&trade(Exchange::Kraken, dec!(8), dec!(1), 1656000000000 + 3 * 60000), true);
+
+        /*
+         * Then
+         */
+        assert!(!still_open.last().unwrap().closed);
+        let minutes = engine.backfill(&key(None, 60), 10);
+        assert_eq!(minutes.iter().map(|c| (c.start.timestamp(), c.closed)).collect::<Vec<_>>(),
+                   vec![(1656000120, true), (1656000180, true)]);
+        assert_eq!(minutes[1].low, dec!(10));
+        assert_eq!(engine.backfill(&key(None, 300), 10)[0].count, 5);
+        assert_eq!(engine.backfill(&key(None, 60), 1).len(), 1);
+    }
+
+    #[test]
+    fn should_build_candles_of_mid_prices() {
+        /*
+         * Given
+         */
+        let mut engine = Engine::new(vec![60], 10);
+        let mid = SeriesKey { source: Source::Mid, exchange: None, interval_secs: 60 };
+
+        /*
+         * When
+         */
+        engine.on_mid(None, dec!(0.0690035), Utc.timestamp_millis(1656000000000));
+        engine.on_mid(None, dec!(0.0690040), Utc.timestamp_millis(1656000001000));
+
+        /*
+         * Then
+         */
+        assert_eq!(engine.backfill(&mid, 10), vec![Candle {
+            start: Utc.timestamp_millis(1656000000000),
+            open: dec!(0.0690035), high: dec!(0.0690040), low: dec!(0.0690035), close: dec!(0.0690040),
+            volume: dec!(0), count: 2, closed: false,
+        }]);
+        assert_eq!(engine.backfill(&key(None, 60), 10), vec![]);
+    }
+}
@@ -4,6 +4,10 @@ use proto::orderbook_aggregator_client::OrderbookAggregatorClient;
 use rust_decimal::Decimal;
 use rust_decimal::prelude::FromPrimitive;
 use rust_decimal_macros::dec;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
 
 mod proto {
     tonic::include_proto!("orderbook");
@@ -14,19 +18,54 @@ mod proto {
 struct Cli {
     #[clap(short, long, help = "(Optional) Port number of the gRPC server. Default: 50051")]
     port: Option<usize>,
+
+    #[clap(long, conflicts_with = "port", help = "(Optional) URI of the gRPC server, https:// for TLS. Default: http://[::1]:50051")]
+    server: Option<String>,
+
+    #[clap(long, help = "(Optional) PEM CA certificate to verify the server with")]
+    ca: Option<PathBuf>,
+
+    #[clap(long, requires = "key", help = "(Optional) PEM client certificate, for servers requiring mutual TLS")]
+    cert: Option<PathBuf>,
+
+    #[clap(long, requires = "cert", help = "(Optional) PEM private key of --cert")]
+    key: Option<PathBuf>,
+
+    #[clap(long, help = "(Optional) Name to verify the server certificate against. Default: the host of --server")]
+    domain: Option<String>,
+
+    #[clap(long, env = "ORDERLY_TOKEN", hide_env_values = true, help = "(Optional) API token or JWT sent as bearer token")]
+    token: Option<String>,
+
+    #[clap(short, long, help = "(Optional) Symbol to stream, e.g. ETH/USD. Default: the first symbol of the server")]
+    symbol: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+    tracing_subscriber::fmt().with_env_filter(tracing_subscriber::EnvFilter::from_default_env()).init();
 
     let args = Cli::parse();
     let port: usize = args.port.unwrap_or(50051);
-    let addr = format!("http://[::1]:{}", port);
+    let server = args.server.clone().unwrap_or(format!("http://[::1]:{}", port));
 
-    let mut client = OrderbookAggregatorClient::connect(addr).await?;
+    let mut endpoint = Channel::from_shared(server.clone())?;
+    if server.starts_with("https://") {
+        endpoint = endpoint.tls_config(tls_config(&args)?)?;
+    }
 
-    let request = tonic::Request::new(proto::Empty {});
+    let authorization: Option<MetadataValue<Ascii>> = match &args.token {
+        Some(token) => Some(format!("Bearer {}", token).parse()?),
+        None => None,
+    };
+    let mut client = OrderbookAggregatorClient::new(endpoint.connect().await?);
+
+    let mut request = tonic::Request::new(proto::SummaryRequest {
+        symbol: args.symbol.unwrap_or_default(),
+    });
+    if let Some(authorization) = authorization {
+        request.metadata_mut().insert("authorization", authorization);
+    }
 
     // let response = client.check(request).await?;
     // info!("{:?}", response);
@@ -76,7 +115,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         ask5, ask6, ask7, ask8, ask9
     ];
 
-    pb_spread.set_prefix(format!("[Spread]"));
+    pb_spread.set_prefix("[Spread]");
     pb_spread.set_style(spinner_style.clone());
     pb_bids.iter()
         .enumerate()
@@ -95,26 +134,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // listening to stream
     while let Some(res) = response.message().await? {
-        let proto::Summary{spread, bids, asks} = res;
+        let proto::Summary{spread, bids, asks, venues} = res;
 
-        // set spread
-        let mut spread = Decimal::from_f64(spread).unwrap();
+        // set spread, followed by the exchanges which are not live
+        let mut spread = Decimal::from_f64(spread).unwrap_or_default();
         spread.rescale(8);
-        spread_percentage(spread, asks.first())
-            .map(|perc|
-                pb_spread.set_message(format!("{} ({}%)", spread, perc))
-            );
+        if let Some(perc) = spread_percentage(spread, asks.first()) {
+            pb_spread.set_message(format!("{} ({}%){}", spread, perc, not_live(&venues)));
+        }
 
-        let bid_max_len = bids.iter().map(|l| l.amount as u64).max();
-        let ask_max_len = asks.iter().map(|l| l.amount as u64).max();
+        let bid_max_len = bids.iter().take(pb_bids.len()).map(|l| l.amount as u64).max();
+        let ask_max_len = asks.iter().take(pb_asks.len()).map(|l| l.amount as u64).max();
 
-        // set bids
-        bids.iter().rev().enumerate().for_each(|(i, level)|
+        // set bids, of which the server may publish more than there are bars
+        bids.iter().take(pb_bids.len()).rev().enumerate().for_each(|(i, level)|
             pb_bids[i].set_level(bid_max_len, level)
         );
 
         // set asks
-        asks.iter().enumerate().for_each(|(i, level)|
+        asks.iter().take(pb_asks.len()).enumerate().for_each(|(i, level)|
             pb_asks[i].set_level(ask_max_len, level)
         );
     }
@@ -122,6 +160,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn tls_config(args: &Cli) -> Result<ClientTlsConfig, std::io::Error> {
+    let mut tls = ClientTlsConfig::new();
+    if let Some(ca) = &args.ca {
+        tls = tls.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+    }
+    if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+        tls = tls.identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+    }
+    if let Some(domain) = &args.domain {
+        tls = tls.domain_name(domain);
+    }
+    Ok(tls)
+}
+
+/// Lists the exchanges which are not live with their health, e.g. ` [kraken: Disconnected]`.
+fn not_live(venues: &HashMap<String, i32>) -> String {
+    let mut not_live: Vec<String> = venues.iter()
+        .filter(|(_, health)| **health != proto::Health::Live as i32)
+        .map(|(exchange, health)| match proto::Health::from_i32(*health) {
+            Some(health) => format!("{}: {:?}", exchange, health),
+            None => format!("{}: {}", exchange, health),
+        })
+        .collect();
+    not_live.sort();
+
+    match not_live.is_empty() {
+        true => String::new(),
+        false => format!(" [{}]", not_live.join(", ")),
+    }
+}
+
 trait SetLevel {
     fn set_level(&self, max_len: Option<u64>, level: &proto::Level);
 }
@@ -129,11 +198,13 @@ trait SetLevel {
 impl SetLevel for ProgressBar {
     fn set_level(&self, max_len: Option<u64>, level: &proto::Level) {
         // set len
-        max_len.map(|len| self.set_length(len));
+        if let Some(len) = max_len {
+            self.set_length(len);
+        }
 
         // set message
-        let mut price = Decimal::from_f64(level.price).unwrap();
-        let mut amount = Decimal::from_f64(level.amount).unwrap();
+        let mut price = Decimal::from_f64(level.price).unwrap_or_default();
+        let mut amount = Decimal::from_f64(level.amount).unwrap_or_default();
         price.rescale(8);
         amount.rescale(8);
         let msg = format!("{} {} {}", price, amount, level.exchange);
@@ -146,10 +217,13 @@ impl SetLevel for ProgressBar {
     }
 }
 
+/// `None` without a best ask, or with one at a price which cannot be divided by.
 fn spread_percentage(spread: Decimal, best_ask: Option<&proto::Level>) -> Option<Decimal> {
     best_ask
-        .map(|l| {
-            let mut perc = spread /  Decimal::from_f64(l.price).unwrap() * dec!(100);
+        .and_then(|l| Decimal::from_f64(l.price))
+        .and_then(|price| spread.checked_div(price))
+        .map(|ratio| {
+            let mut perc = ratio * dec!(100);
             perc.rescale(4);
             perc
         })
@@ -1,14 +1,17 @@
 use chrono::{DateTime, Utc};
-use futures::SinkExt;
 use crate::error::Error;
 use crate::orderbook::{self, Exchange, InTick, ToLevel, ToLevels, ToTick};
-use crate::websocket;
-use log::{debug, info};
+use crate::venue::{Parsed, TradingStatus};
+use crate::record::Tape;
+use crate::trade::{self, TradeSide};
+use crate::websocket::{self, Ack};
+use crate::metrics;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace};
 use tungstenite::Message;
 
-const COINBASE_WS_URL: &str = "wss://ws-feed.exchange.coinbase.com";
+pub(crate) const COINBASE_WS_URL: &str = "wss://ws-feed.exchange.coinbase.com";
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase")]
@@ -25,6 +28,10 @@ enum Event {
     /// ```
     Error {
         message: String,
+
+        /// Details of the message, e.g. `ETH-XYZ is not a valid product`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
     },
 
     /// To begin receiving feed messages, you must first send a subscribe message to the server indicating which channels and products to receive. This message is mandatory—you are disconnected if no subscribe has been received within 5 seconds.
@@ -204,6 +211,7 @@ enum Event {
     },
 
     /// The `ticker` channel provides real-time price updates every time a match happens. It batches updates in case of cascading matches, greatly reducing bandwidth requirements.
+    /// The best bid and offer come along with their sizes.
     /// ```json
     /// // Ticker messsage
     /// {
@@ -217,7 +225,9 @@ enum Event {
     ///    "high_24h":"40662.06",
     ///    "volume_30d":"160.65999711",
     ///    "best_bid":"40552.26",
+    ///    "best_bid_size":"0.05000000",
     ///    "best_ask":"40553.84",
+    ///    "best_ask_size":"0.11000000",
     ///    "side":"sell",
     ///    "time":"2022-03-16T18:42:08.145773Z",
     ///    "trade_id":131414,
@@ -234,7 +244,11 @@ enum Event {
         high_24h: Decimal, // 40662.06",
         volume_30d: Decimal, // 160.65999711",
         best_bid: Decimal, // 40552.26",
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        best_bid_size: Option<Decimal>, // 0.05000000",
         best_ask: Decimal, // 40553.84",
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        best_ask_size: Option<Decimal>, // 0.11000000",
         side: Side, // "sell",
 
         #[serde(with = "timestamp")]
@@ -243,6 +257,29 @@ enum Event {
         last_size: Decimal, // "0.00002465"
     },
 
+    /// The `matches` channel sends a message for every trade. The `side` is the one of the maker order,
+    /// `buy` meaning that a sell order took it.
+    /// ```json
+    /// {
+    ///   "type": "match",
+    ///   "trade_id": 10,
+    ///   "sequence": 50,
+    ///   "maker_order_id": "ac928c66-ca53-498f-9c13-a110027a60e8",
+    ///   "taker_order_id": "132fb6ae-456b-4654-b4e0-d681ac05cea1",
+    ///   "time": "2014-11-07T08:19:27.028459Z",
+    ///   "product_id": "BTC-USD",
+    ///   "size": "5.23512",
+    ///   "price": "400.23",
+    ///   "side": "sell"
+    /// }
+    /// ```
+    Match(Match),
+
+    /// Latest trade, sent once the `matches` channel is subscribed. It happened before the
+    /// subscription, so it is not passed on.
+    #[serde(rename = "last_match")]
+    LastMatch(Match),
+
     /// The level2 channel sends a message with the type `snapshot` and the corresponding `product_id`. The properties `bids` and `asks` are arrays of `[price, size]` tuples and represent the entire order book.
     /// ```json
     /// {
@@ -310,6 +347,41 @@ impl ToLevel for Change {
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct Match {
+    trade_id: u64,
+    sequence: u64,
+    maker_order_id: String,
+    taker_order_id: String,
+
+    #[serde(with = "timestamp")]
+    time: DateTime<Utc>,
+
+    product_id: String,
+    size: Decimal,
+    price: Decimal,
+
+    /// Side of the maker
+    side: Side,
+}
+
+impl Match {
+    /// Converts a `coinbase::Match` into a `trade::Trade`, whose side is the one of the taker.
+    fn to_trade(&self) -> trade::Trade {
+        trade::Trade {
+            exchange: Exchange::Coinbase,
+            price: self.price,
+            amount: self.size,
+            side: match self.side {
+                Side::Buy => TradeSide::Sell,
+                Side::Sell => TradeSide::Buy,
+            },
+            trade_id: Some(self.trade_id.to_string()),
+            time: self.time,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 #[serde(rename_all = "lowercase")]
 enum Side {
@@ -340,6 +412,9 @@ struct ChannelConfig {
     product_ids: Vec<String>,
 }
 
+/// Fields missing from the `status` channel default rather than fail the whole message, as it lists
+/// every product of the exchange.
+///
 /// ```json
 ///     {
 ///       "id": "BTC-USD",
@@ -360,7 +435,8 @@ struct ChannelConfig {
 ///       "fx_stablecoin": false
 ///     }
 /// ```
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
 struct Product {
     id: String,
     base_currency: String, // "BTC"
@@ -380,6 +456,20 @@ struct Product {
     fx_stablecoin: bool, // false
 }
 
+impl Product {
+    /// Coinbase reports `online`, `offline`, `internal` or `delisted`; online products may still be
+    /// restricted by one of the `*_only` flags.
+    fn trading_status(&self) -> TradingStatus {
+        match self.status.as_str() {
+            "online" if self.cancel_only => TradingStatus::CancelOnly,
+            "online" if self.limit_only => TradingStatus::LimitOnly,
+            "online" if self.post_only => TradingStatus::PostOnly,
+            "online" => TradingStatus::Online,
+            _ => TradingStatus::Offline,
+        }
+    }
+}
+
 /// ```json
 ///     {
 ///       "id": "USD",
@@ -392,7 +482,8 @@ struct Product {
 ///       "details": {}
 ///     }
 /// ```
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
 struct Currency {
     id: String, // "USD"
     name: String, // "United States Dollar"
@@ -404,16 +495,48 @@ struct Currency {
     details: CurrencyDetails, // {}
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
 struct CurrencyDetails {}
 
+impl Event {
+    /// Message of an `error`, followed by its reason if it gave one.
+    fn error_reason(&self) -> Option<String> {
+        match self {
+            Event::Error { message, reason: Some(reason) } => Some(format!("{}: {}", message, reason)),
+            Event::Error { message, reason: None } => Some(message.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl Event {
+    /// Best bid and offer of a `ticker`, `None` unless it gave their sizes. An empty side is left out.
+    fn maybe_to_bbo_tick(&self) -> Option<InTick> {
+        match self {
+            Event::Ticker { best_bid, best_bid_size: Some(bid_size), best_ask, best_ask_size: Some(ask_size), .. } => {
+                let bids = match bid_size.is_zero() {
+                    true => vec![],
+                    false => vec![orderbook::Level::new(orderbook::Side::Bid, *best_bid, *bid_size, Exchange::Coinbase)],
+                };
+                let asks = match ask_size.is_zero() {
+                    true => vec![],
+                    false => vec![orderbook::Level::new(orderbook::Side::Ask, *best_ask, *ask_size, Exchange::Coinbase)],
+                };
+                Some(InTick { exchange: Exchange::Coinbase, bids, asks })
+            },
+            _ => None,
+        }
+    }
+}
+
 impl ToTick for Event {
-    /// Converts the `Event` into a `Option<InTick>`. Only keep the top ten levels of bids and asks.
+    /// Converts the `Event` into a `Option<InTick>`. Only keep the top `MAX_DEPTH` levels of bids and asks.
     fn maybe_to_tick(&self) -> Option<InTick> {
         match self {
             Event::Snapshot { bids, asks, .. } => {
-                let bids = bids.to_levels(orderbook::Side::Bid, 10);
-                let asks = asks.to_levels(orderbook::Side::Ask, 10);
+                let bids = bids.to_levels(orderbook::Side::Bid, orderbook::MAX_DEPTH);
+                let asks = asks.to_levels(orderbook::Side::Ask, orderbook::MAX_DEPTH);
 
                 Some(InTick { exchange: Exchange::Coinbase, bids, asks })
             }
@@ -421,11 +544,11 @@ impl ToTick for Event {
                 let bids = changes.iter()
                     .filter(|c| c.side == Side::Buy)
                     .cloned().collect::<Vec<Change>>()
-                    .to_levels(orderbook::Side::Bid, 10);
+                    .to_levels(orderbook::Side::Bid, orderbook::MAX_DEPTH);
                 let asks = changes.iter()
                     .filter(|c| c.side == Side::Sell)
                     .cloned().collect::<Vec<Change>>()
-                    .to_levels(orderbook::Side::Ask, 10);
+                    .to_levels(orderbook::Side::Ask, orderbook::MAX_DEPTH);
 
                 Some(InTick { exchange: Exchange::Coinbase, bids, asks })
             }
@@ -434,60 +557,107 @@ impl ToTick for Event {
     }
 }
 
-pub(crate) async fn connect(symbol: &String) -> Result<websocket::WsStream, Error> {
-    let mut ws_stream = websocket::connect(COINBASE_WS_URL).await?;
-    subscribe(&mut ws_stream, symbol).await?;
-    Ok(ws_stream)
+/// Returns the WebSocket once the subscription is confirmed, with the messages received until then.
+pub(crate) async fn connect(url: &str, symbol: &str, bbo: bool, trades: bool, tape: &Tape) -> Result<(websocket::WsStream, Vec<Message>), Error> {
+    let mut ws_stream = websocket::connect(Exchange::Coinbase, symbol, url, tape).await?;
+    websocket::subscribe(&mut ws_stream, Exchange::Coinbase, symbol, url, subscription(symbol, bbo, trades)?, tape).await?;
+    let backlog = websocket::await_ack(&mut ws_stream, Exchange::Coinbase, symbol, url, ack, tape).await?;
+    Ok((ws_stream, backlog))
+}
+
+/// Coinbase answers an unknown product with an `error`, whose `reason` names the product.
+fn ack(text: &str) -> Ack {
+    match deserialize(text) {
+        Ok(Event::Subscriptions { .. }) => Ack::Subscribed,
+        Ok(e) => e.error_reason().map_or(Ack::Pending, Ack::Rejected),
+        Err(_) => Ack::Pending,
+    }
+}
+
+/// Coinbase names `ETH/BTC` as `ETH-BTC`.
+fn product_id(symbol: &str) -> String {
+    symbol.to_uppercase().replace("/", "-")
 }
 
-async fn subscribe (
-    rx: &mut websocket::WsStream,
-    symbol: &String,
-) -> Result<(), Error>
-{
-    let symbol = symbol.to_uppercase().replace("/", "-");
+/// The trades are subscribed with the book, as the `matches` channel, which is confirmed along. With
+/// `bbo`, the `ticker` is subscribed instead of the book, which only ticks on matches.
+fn subscription(symbol: &str, bbo: bool, trades: bool) -> Result<String, Error> {
+    let book = if bbo { "ticker" } else { "level2" };
+    let mut channels = vec![
+        Channel::Channel(book.to_string()),
+        Channel::Channel("heartbeat".to_string()),
+        Channel::Channel("status".to_string()),
+    ];
+    if trades {
+        channels.push(Channel::Channel("matches".to_string()));
+    }
     let sub = Event::Subscribe{
-        product_ids: Some(vec![ symbol ]),
-        channels: vec![
-            Channel::Channel("level2".to_string()),
-            Channel::Channel("heartbeat".to_string()),
-        ]
+        product_ids: Some(vec![ product_id(symbol) ]),
+        channels,
     };
-    let msg = serialize(sub)?;
-    rx.send(Message::Text(msg)).await?;
-    Ok(())
+    let payload = format!("{:?}", sub);
+    serialize(sub).map_err(|e| Error::protocol(Exchange::Coinbase, symbol, e, &payload))
 }
 
-pub(crate) fn parse(msg: Message) -> Result<Option<InTick>, Error> {
+/// The `status` channel reports every product, so only the one of `symbol` is taken from it.
+pub(crate) fn parse(msg: Message, symbol: &str) -> Result<Parsed, Error> {
+    metrics::message_received(Exchange::Coinbase, &msg);
     let e = match msg {
-        Message::Binary(x) => { info!("binary {:?}", x); None },
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); None },
         Message::Text(x) => {
-            debug!("{:?}", x);
-
-            let e= deserialize(x)?;
-            match e {
-                Event::Ticker { .. } => debug!("{:?}", e),
-                Event::Snapshot { .. } => debug!("{:?}", e),
-                Event::L2Update { .. } => debug!("{:?}", e),
-                _ => info!("{:?}", e),
+            trace!(%x, "text message");
+
+            let e= deserialize(&x).map_err(|e| metrics::parse_error(Exchange::Coinbase, symbol, e, &x))?;
+            match &e {
+                Event::Ticker { sequence, time, .. } => {
+                    trace!(sequence, "ticker");
+                    metrics::message_age(Exchange::Coinbase, *time);
+                },
+                Event::Match(m) => {
+                    trace!(trade_id = m.trade_id, price = %m.price, size = %m.size, "match");
+                    metrics::message_age(Exchange::Coinbase, m.time);
+                },
+                Event::Snapshot { bids, asks, .. } => debug!(bids = bids.len(), asks = asks.len(), "snapshot"),
+                Event::L2Update { time, changes, .. } => {
+                    trace!(%time, changes = changes.len(), "l2update");
+                    metrics::message_age(Exchange::Coinbase, *time);
+                },
+                _ => info!(event = ?e, "control message"),
             }
 
             Some(e)
         },
-        Message::Ping(x) => { info!("Ping {:?}", x); None },
-        Message::Pong(x) => { info!("Pong {:?}", x); None },
-        Message::Close(x) => { info!("Close {:?}", x); None },
-        Message::Frame(x) => { info!("Frame {:?}", x); None },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); None },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); None },
+        Message::Close(x) => { info!(frame = ?x, "close"); None },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); None },
     };
-    Ok(e.map(|e| e.maybe_to_tick()).flatten())
+    if let Some(reason) = e.as_ref().and_then(Event::error_reason) {
+        return Ok(Parsed::Error(reason));
+    }
+    if let Some(Event::Match(m)) = &e {
+        return Ok(Parsed::Trades(vec![m.to_trade()]));
+    }
+    // the best bid and offer replace the book, as nothing else is subscribed with them
+    if let Some(Event::Ticker { .. }) = &e {
+        return Ok(e.and_then(|e| e.maybe_to_bbo_tick()).map_or(Parsed::Nothing, Parsed::Snapshot));
+    }
+    if let Some(Event::Status { products, .. }) = &e {
+        let product_id = product_id(symbol);
+        return Ok(match products.iter().find(|p| p.id == product_id) {
+            Some(product) => Parsed::Status(product.trading_status(), product.status_message.clone()),
+            None => Parsed::Nothing,
+        });
+    }
+    Ok(e.and_then(|e| e.maybe_to_tick()).into())
 }
 
-fn deserialize(s: String) -> serde_json::Result<Event> {
-    Ok(serde_json::from_str(&s)?)
+fn deserialize(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
 }
 
 fn serialize(e: Event) -> serde_json::Result<String> {
-    Ok(serde_json::to_string(&e)?)
+    serde_json::to_string(&e)
 }
 
 mod timestamp {
@@ -516,7 +686,7 @@ mod test {
     use crate::coinbase::*;
 
     #[test]
-    fn should_deserialize_subscriptions() -> Result<(), Error> {
+    fn should_deserialize_subscriptions() -> serde_json::Result<()> {
         assert_eq!(deserialize(r#"
         {
             "type": "subscriptions",
@@ -544,7 +714,7 @@ mod test {
                     ]
                 }
             ]
-        }"#.to_string())?,
+        }"#)?,
                    Event::Subscriptions{
                        channels: vec![
                            Channel::Config(ChannelConfig{
@@ -576,7 +746,7 @@ mod test {
     }
 
     #[test]
-    fn should_deserialize_heartbeat() -> Result<(), Error> {
+    fn should_deserialize_heartbeat() -> serde_json::Result<()> {
         assert_eq!(deserialize(r#"
         {
             "type": "heartbeat",
@@ -584,7 +754,7 @@ mod test {
             "last_trade_id": 20,
             "product_id": "BTC-USD",
             "time": "2014-11-07T08:19:28.464459Z"
-        }"#.to_string())?,
+        }"#)?,
                    Event::Heartbeat{
                        sequence: 90,
                        last_trade_id: 20,
@@ -596,14 +766,14 @@ mod test {
     }
 
     #[test]
-    fn should_deserialize_snapshot() -> Result<(), Error> {
+    fn should_deserialize_snapshot() -> serde_json::Result<()> {
         assert_eq!(deserialize(r#"
         {
             "type": "snapshot",
             "product_id": "BTC-USD",
             "bids": [["10101.10", "0.45054140"]],
             "asks": [["10102.55", "0.57753524"]]
-        }"#.to_string())?,
+        }"#)?,
                    Event::Snapshot{
                        product_id: "BTC-USD".to_string(),
                        bids: vec![
@@ -618,7 +788,7 @@ mod test {
     }
 
     #[test]
-    fn should_deserialize_l2udpate() -> Result<(), Error> {
+    fn should_deserialize_l2udpate() -> serde_json::Result<()> {
         assert_eq!(deserialize(r#"
         {
             "type": "l2update",
@@ -631,7 +801,7 @@ mod test {
                     "0.162567"
                 ]
             ]
-        }"#.to_string())?,
+        }"#)?,
                    Event::L2Update {
                        product_id: "BTC-USD".to_string(),
                        time: DateTime::from_str("2019-08-14T20:42:27.265Z").unwrap(),
@@ -648,7 +818,7 @@ mod test {
     }
 
     #[test]
-    fn should_serialize() -> Result<(), Error> {
+    fn should_serialize() -> serde_json::Result<()> {
         let mut serialized = r#"
         {
             "type": "subscribe",
@@ -693,7 +863,7 @@ mod test {
     }
 
     #[test]
-    fn should_convert_to_tick() -> Result<(), Error> {
+    fn should_convert_to_tick() -> serde_json::Result<()> {
         /*
          * Given
          */
@@ -763,4 +933,103 @@ mod test {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn should_ack_subscription() {
+        /*
+         * Given
+         */
+        let subscribed = include_str!("../tests/fixtures/coinbase/subscriptions.json");
+        let rejected = include_str!("../tests/fixtures/coinbase/error.json");
+
+        /*
+         * When
+         */
+        let (subscribed, rejected) = (ack(subscribed), ack(rejected));
+
+        /*
+         * Then
+         */
+        assert_eq!(subscribed, Ack::Subscribed);
+        assert_eq!(rejected, Ack::Rejected("Failed to subscribe: ETH-XYZ is not a valid product".to_string()));
+    }
+
+    #[test]
+    fn should_pass_on_match_as_taker_trade() -> Result<(), Error> {
+        /*
+         * Given
+         */
+        let msg = Message::Text(include_str!("../tests/fixtures/coinbase/match.json").to_string());
+        let last_match = Message::Text(include_str!("../tests/fixtures/coinbase/match.json").replace("\"match\"", "\"last_match\""));
+
+        /*
+         * When
+         */
+        let parsed = parse(msg, "ETH/BTC")?;
+        let last_match = parse(last_match, "ETH/BTC")?;
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Trades(vec![trade::Trade {
+            exchange: Exchange::Coinbase,
+            price: dec!(0.06900500),
+            amount: dec!(0.25),
+            side: TradeSide::Buy,
+            trade_id: Some("39502561".to_string()),
+            time: "2022-06-23T16:00:01.123456Z".parse().unwrap(),
+        }]));
+        assert_eq!(last_match, Parsed::Nothing);
+        assert!(subscription("ETH/BTC", false, true).unwrap().ends_with(r#""status","matches"]}"#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_replace_with_ticker() -> Result<(), Error> {
+        /*
+         * Given
+         */
+        let ticker = include_str!("../tests/fixtures/coinbase/ticker.json");
+        let no_sizes = ticker.replace(r#""best_bid_size":"1.25000000","#, "");
+
+        /*
+         * When
+         */
+        let parsed = parse(Message::Text(ticker.to_string()), "ETH/BTC")?;
+        let no_sizes = parse(Message::Text(no_sizes), "ETH/BTC")?;
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Snapshot(InTick {
+            exchange: Exchange::Coinbase,
+            bids: vec![orderbook::Level::new(orderbook::Side::Bid, dec!(0.06900250), dec!(1.25), Exchange::Coinbase)],
+            asks: vec![orderbook::Level::new(orderbook::Side::Ask, dec!(0.06900380), dec!(0.5), Exchange::Coinbase)],
+        }));
+        assert_eq!(no_sizes, Parsed::Nothing);
+        assert!(subscription("ETH/BTC", true, false).unwrap().contains(r#""channels":["ticker","heartbeat","status"]"#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_error_after_subscription() -> Result<(), Error> {
+        /*
+         * Given
+         */
+        let msg = Message::Text(include_str!("../tests/fixtures/coinbase/error.json").to_string());
+
+        /*
+         * When
+         */
+        let parsed = parse(msg, "ETH/BTC")?;
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Error("Failed to subscribe: ETH-XYZ is not a valid product".to_string()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,766 @@
+use crate::error::Error;
+use crate::orderbook::{self, Exchange};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::fmt;
+use std::str::FromStr;
+
+/// Server settings, read from a TOML or YAML file. Every field is optional and command line
+/// options take precedence over the file.
+///
+/// ```toml
+/// symbols = ["ETH/BTC", "BTC/USD"]
+/// depth = 10
+/// listen = "0.0.0.0:50051"
+///
+/// [venues.kraken]
+/// depth = 25
+/// fee = 0.0026
+/// stale_after_secs = 30
+///
+/// [venues.coinbase]
+/// enabled = false
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Currency pairs subscribed on every enabled exchange. The first one is streamed to clients
+    /// which do not ask for a symbol.
+    pub symbols: Vec<String>,
+
+    /// Number of levels per side published in the merged orderbook.
+    pub depth: usize,
+
+    /// Kind of instrument merged into the orderbooks, spot unless set. Books of venues subscribed to
+    /// another kind are left out of the merge.
+    pub kind: InstrumentKind,
+
+    /// Adjusts the prices of each exchange by its taker `fee` in the merged orderbook, which is then
+    /// ordered by what a taker pays or gets. Prices are published as quoted unless set.
+    pub fee_adjusted: bool,
+
+    /// Address the gRPC server listens on.
+    pub listen: SocketAddr,
+
+    /// Serves gRPC over TLS when set.
+    pub tls: Option<TlsConfig>,
+
+    /// Clients allowed to use the gRPC services, and what each may do. Anyone may do anything when
+    /// unset. The file is read again on the `reload-auth` console command and on SIGHUP.
+    pub auth_file: Option<PathBuf>,
+
+    /// Also accept console commands on a Unix socket at this path.
+    pub console_socket: Option<PathBuf>,
+
+    /// Serves Prometheus metrics over HTTP at `/metrics` on this address when set.
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Records the raw messages of every venue below this directory when set, see `record`.
+    pub record: Option<PathBuf>,
+
+    /// Plays a recording back instead of connecting to the exchanges when set.
+    pub replay: Option<ReplayConfig>,
+
+    /// Writes the books to CSV or Parquet files when set, see `export`.
+    pub export: Option<ExportConfig>,
+
+    /// Builds candles of the trades and mid prices for the `Candles` RPC when set, see `candle`.
+    pub candles: Option<CandlesConfig>,
+
+    pub venues: Venues,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            symbols: vec!["ETH/BTC".to_string()],
+            depth: 10,
+            kind: InstrumentKind::Spot,
+            fee_adjusted: false,
+            listen: SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 50051)),
+            tls: None,
+            auth_file: None,
+            console_socket: None,
+            metrics_listen: None,
+            record: None,
+            replay: None,
+            export: None,
+            candles: None,
+            venues: Venues::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings from a TOML or YAML file, see `read`.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        read(path)
+    }
+
+    /// Checks the values which cannot be told apart by their type alone, and normalizes the symbols.
+    pub(crate) fn validate(&mut self) -> Result<(), Error> {
+        if self.symbols.is_empty() {
+            return Err(Error::BadConfig("at least one symbol is required".to_string()));
+        }
+        self.symbols = self.symbols.iter()
+            .map(|s| orderbook::parse_symbol(s))
+            .collect::<Result<_, _>>()
+            .map_err(Error::BadSymbol)?;
+
+        if self.depth == 0 {
+            return Err(Error::BadConfig("depth must be a positive number".to_string()));
+        }
+
+        if self.record.is_some() && self.replay.is_some() {
+            return Err(Error::BadConfig("record and replay cannot be combined".to_string()));
+        }
+        if self.export.as_ref().is_some_and(|export| export.interval_ms == Some(0)) {
+            return Err(Error::BadConfig("export.interval_ms must be a positive number".to_string()));
+        }
+        if let Some(candles) = &mut self.candles {
+            if candles.intervals_secs.is_empty() || candles.intervals_secs.iter().any(|secs| *secs == 0 || *secs > MAX_CANDLE_INTERVAL_SECS) {
+                return Err(Error::BadConfig(format!(
+                    "candles.intervals_secs must be between 1 and {} seconds, got: {:?}", MAX_CANDLE_INTERVAL_SECS, candles.intervals_secs)));
+            }
+            if candles.history == 0 {
+                return Err(Error::BadConfig("candles.history must be a positive number".to_string()));
+            }
+            candles.intervals_secs.sort_unstable();
+            candles.intervals_secs.dedup();
+        }
+
+        for exchange in Exchange::ALL {
+            let kind = self.venues.kind(&exchange);
+            let venue = self.venues.get_mut(&exchange);
+            if venue.depth == 0 || venue.depth > orderbook::MAX_DEPTH {
+                return Err(Error::BadConfig(format!(
+                    "venues.{}.depth must be between 1 and {}, got: {}", exchange, orderbook::MAX_DEPTH, venue.depth)));
+            }
+            if venue.fee < dec!(0) || venue.fee >= dec!(1) {
+                return Err(Error::BadConfig(format!(
+                    "venues.{}.fee must be a fraction between 0 and 1, got: {}", exchange, venue.fee)));
+            }
+            venue.precision = std::mem::take(&mut venue.precision).into_iter()
+                .map(|(symbol, precision)| orderbook::parse_symbol(&symbol).map(|symbol| (symbol, precision)))
+                .collect::<Result<_, _>>()
+                .map_err(Error::BadSymbol)?;
+            match exchange {
+                Exchange::Deribit => {},
+                Exchange::BinanceFutures if kind != InstrumentKind::Perpetual => return Err(Error::BadConfig(format!(
+                    "venues.{}.kind must be perpetual, only perpetuals are streamed from Binance futures", exchange))),
+                Exchange::BinanceFutures => {},
+                _ if kind != InstrumentKind::Spot => return Err(Error::BadConfig(format!(
+                    "venues.{}.kind must be spot, only Deribit lists perpetuals and futures", exchange))),
+                _ => {},
+            }
+            if (kind == InstrumentKind::Future) != venue.expiry.is_some() {
+                return Err(Error::BadConfig(format!(
+                    "venues.{}.expiry must be set for futures, and only for them", exchange)));
+            }
+            if venue.diff && ![Exchange::Binance, Exchange::BinanceFutures, Exchange::BinanceUs].contains(&exchange) {
+                return Err(Error::BadConfig(format!(
+                    "venues.{}.diff is not supported, only Binance streams both top levels and changes", exchange)));
+            }
+            if venue.trades && !TRADE_CHANNELS.contains(&exchange) {
+                return Err(Error::BadConfig(format!(
+                    "venues.{}.trades is not supported, trades are streamed from Binance, Bitstamp, Kraken, Coinbase and Gemini", exchange)));
+            }
+            if venue.bbo && !BBO_CHANNELS.contains(&exchange) {
+                return Err(Error::BadConfig(format!(
+                    "venues.{}.bbo is not supported, only Binance, Kraken and Coinbase stream their best bid and offer", exchange)));
+            }
+            if venue.bbo && venue.diff {
+                return Err(Error::BadConfig(format!("venues.{}.bbo and diff cannot be combined", exchange)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Longest interval of candles, a week.
+const MAX_CANDLE_INTERVAL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Exchanges whose trades can be subscribed.
+const TRADE_CHANNELS: [Exchange; 7] = [
+    Exchange::Binance, Exchange::BinanceFutures, Exchange::BinanceUs, Exchange::Bitstamp, Exchange::Kraken,
+    Exchange::Coinbase, Exchange::Gemini,
+];
+
+/// Exchanges whose best bid and offer can be subscribed on their own.
+const BBO_CHANNELS: [Exchange; 5] = [
+    Exchange::Binance, Exchange::BinanceFutures, Exchange::BinanceUs, Exchange::Kraken, Exchange::Coinbase,
+];
+
+/// Reads the file as YAML if its extension is `yaml` or `yml`, as TOML otherwise.
+pub(crate) fn read<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let s = std::fs::read_to_string(path)
+        .map_err(|e| Error::BadConfig(format!("{}: {}", path.display(), e)))?;
+
+    let t = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&s).map_err(|e| e.to_string()),
+        _ => toml::from_str(&s).map_err(|e| e.to_string()),
+    };
+
+    t.map_err(|e| Error::BadConfig(format!("{}: {}", path.display(), e)))
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain of the server.
+    pub cert: PathBuf,
+
+    /// PEM encoded private key of the server.
+    pub key: PathBuf,
+
+    /// PEM encoded CA certificate. When set, clients must present a certificate signed by it.
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplayConfig {
+    /// Directory written by `record`. Symbols and exchanges are taken from it, those disabled
+    /// under `venues` are skipped.
+    pub dir: PathBuf,
+
+    #[serde(default)]
+    pub speed: Speed,
+
+    /// Stops the server once everything was played, e.g. to export a recording offline.
+    #[serde(default)]
+    pub exit: bool,
+}
+
+/// Pace of a replay relative to the recording.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "SpeedValue")]
+pub enum Speed {
+    /// Times the pace of the recording, e.g. 1 for the original pace or 0.5 for half of it.
+    Times(f64),
+
+    /// As fast as the aggregator takes the messages.
+    Max,
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed::Times(1.0)
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    /// Parses `max` or a positive factor such as `10`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("max") {
+            return Ok(Speed::Max);
+        }
+        match s.parse::<f64>() {
+            Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(Speed::Times(factor)),
+            _ => Err(format!("speed must be a positive number or max, got: {}", s)),
+        }
+    }
+}
+
+/// A speed as written in the file, a number or `max`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpeedValue {
+    Number(f64),
+    Text(String),
+}
+
+impl TryFrom<SpeedValue> for Speed {
+    type Error = String;
+
+    fn try_from(value: SpeedValue) -> Result<Self, Self::Error> {
+        match value {
+            SpeedValue::Number(factor) => factor.to_string().parse(),
+            SpeedValue::Text(s) => s.parse(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportConfig {
+    /// Directory the files are written to, created if missing.
+    pub dir: PathBuf,
+
+    #[serde(default)]
+    pub format: ExportFormat,
+
+    #[serde(default)]
+    pub layout: ExportLayout,
+
+    /// Samples the books every this many milliseconds. Writes every change when unset.
+    pub interval_ms: Option<u64>,
+
+    /// Also writes the book of every venue as quoted by it, next to the merged one.
+    #[serde(default)]
+    pub venues: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CandlesConfig {
+    /// Lengths of the candles built, each aligned to multiples of it since epoch.
+    pub intervals_secs: Vec<u64>,
+
+    /// Closed candles kept per exchange, interval and source, sent first to new `Candles` streams.
+    pub history: usize,
+}
+
+impl Default for CandlesConfig {
+    fn default() -> Self {
+        CandlesConfig {
+            intervals_secs: vec![60],
+            history: 1440,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(format!("format must be csv or parquet, got: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportLayout {
+    /// A row per level.
+    #[default]
+    Long,
+
+    /// A row per book, with columns for every level up to the depth.
+    Wide,
+}
+
+impl FromStr for ExportLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "long" => Ok(ExportLayout::Long),
+            "wide" => Ok(ExportLayout::Wide),
+            _ => Err(format!("layout must be long or wide, got: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Venues {
+    pub bitstamp: VenueConfig,
+    pub binance: VenueConfig,
+    pub kraken: VenueConfig,
+    pub coinbase: VenueConfig,
+    pub okx: VenueConfig,
+    pub bybit: VenueConfig,
+    pub bitfinex: VenueConfig,
+    pub gemini: VenueConfig,
+    pub kucoin: VenueConfig,
+    pub deribit: VenueConfig,
+    pub binance_futures: VenueConfig,
+    pub binance_us: VenueConfig,
+}
+
+impl Venues {
+    pub(crate) fn get(&self, exchange: &Exchange) -> &VenueConfig {
+        match exchange {
+            Exchange::Bitstamp => &self.bitstamp,
+            Exchange::Binance => &self.binance,
+            Exchange::Kraken => &self.kraken,
+            Exchange::Coinbase => &self.coinbase,
+            Exchange::Okx => &self.okx,
+            Exchange::Bybit => &self.bybit,
+            Exchange::Bitfinex => &self.bitfinex,
+            Exchange::Gemini => &self.gemini,
+            Exchange::Kucoin => &self.kucoin,
+            Exchange::Deribit => &self.deribit,
+            Exchange::BinanceFutures => &self.binance_futures,
+            Exchange::BinanceUs => &self.binance_us,
+        }
+    }
+
+    /// Kind of instrument subscribed on the exchange: as set, or else perpetual for Binance futures and
+    /// spot for the others.
+    pub(crate) fn kind(&self, exchange: &Exchange) -> InstrumentKind {
+        self.get(exchange).kind.unwrap_or(match exchange {
+            Exchange::BinanceFutures => InstrumentKind::Perpetual,
+            _ => InstrumentKind::Spot,
+        })
+    }
+
+    fn get_mut(&mut self, exchange: &Exchange) -> &mut VenueConfig {
+        match exchange {
+            Exchange::Bitstamp => &mut self.bitstamp,
+            Exchange::Binance => &mut self.binance,
+            Exchange::Kraken => &mut self.kraken,
+            Exchange::Coinbase => &mut self.coinbase,
+            Exchange::Okx => &mut self.okx,
+            Exchange::Bybit => &mut self.bybit,
+            Exchange::Bitfinex => &mut self.bitfinex,
+            Exchange::Gemini => &mut self.gemini,
+            Exchange::Kucoin => &mut self.kucoin,
+            Exchange::Deribit => &mut self.deribit,
+            Exchange::BinanceFutures => &mut self.binance_futures,
+            Exchange::BinanceUs => &mut self.binance_us,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VenueConfig {
+    /// Connects to the exchange at startup. It can still be enabled later from the console.
+    pub enabled: bool,
+
+    /// WebSocket endpoint, e.g. of a sandbox or a local mock server. Defaults to the public endpoint
+    /// of the exchange. For KuCoin, the REST endpoint which hands out the WebSocket one.
+    pub url: Option<String>,
+
+    /// Number of levels per side kept from the orderbook of the exchange.
+    pub depth: usize,
+
+    /// Taker fee as a fraction, e.g. `0.001` for 10 bps. Prices of the exchange are adjusted by it
+    /// in the merged orderbook if `fee_adjusted` is set.
+    pub fee: Decimal,
+
+    /// Drops the orderbook of the exchange from the merge and subscribes again once no tick was
+    /// received for this many seconds. Never by default, as quiet markets may not tick for a while.
+    pub stale_after_secs: Option<u64>,
+
+    /// Precision of the book of each symbol. Only Bitfinex groups its levels by precision, at `P0`
+    /// unless set.
+    pub precision: BTreeMap<String, Precision>,
+
+    /// Asks the exchange to send checksums of its book, which are validated. A mismatch resubscribes.
+    /// Only Bitfinex makes them optional, OKX always sends them.
+    pub checksum: bool,
+
+    /// Kind of instrument subscribed for each symbol, see `Venues::kind`. Only Deribit lists other
+    /// kinds than spot, and Binance futures are only streamed for perpetuals.
+    pub kind: Option<InstrumentKind>,
+
+    /// Expiry of the futures subscribed, as named by the exchange, e.g. `27DEC24`.
+    pub expiry: Option<String>,
+
+    /// Streams the changes of the whole book, synced against a snapshot of the REST API, instead of
+    /// its top levels. Only Binance offers both.
+    pub diff: bool,
+
+    /// REST endpoint the snapshots are fetched from, e.g. of a local mock server. Defaults to the
+    /// public endpoint of the exchange.
+    pub rest_url: Option<String>,
+
+    /// Also subscribes the public trades, streamed by the `Trades` RPC. Only Binance, Bitstamp, Kraken
+    /// and Coinbase have a trade channel. Gemini sends its trades with the book anyway.
+    pub trades: bool,
+
+    /// Subscribes only the best bid and offer instead of the book, a lighter channel sent with less
+    /// delay, e.g. for the `Bbo` RPC. Only Binance, Kraken and Coinbase have one.
+    pub bbo: bool,
+}
+
+impl Default for VenueConfig {
+    fn default() -> Self {
+        VenueConfig {
+            enabled: true,
+            url: None,
+            depth: 10,
+            fee: dec!(0),
+            stale_after_secs: None,
+            precision: BTreeMap::new(),
+            checksum: false,
+            kind: None,
+            expiry: None,
+            diff: false,
+            rest_url: None,
+            trades: false,
+            bbo: false,
+        }
+    }
+}
+
+impl VenueConfig {
+    pub(crate) fn precision(&self, symbol: &str) -> Precision {
+        self.precision.get(symbol).copied().unwrap_or_default()
+    }
+}
+
+/// Significant figures of the prices of a Bitfinex book, from 5 at `P0` down to 1 at `P4`. Levels
+/// whose prices round to the same value are grouped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Precision {
+    #[default]
+    P0,
+    P1,
+    P2,
+    P3,
+    P4,
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Kind of instrument a book is quoted for. Books of different kinds are never merged, as their prices
+/// differ by the basis and the funding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstrumentKind {
+    #[default]
+    Spot,
+
+    /// Swap without expiry, kept close to the spot price by funding.
+    Perpetual,
+
+    /// Settled at its expiry.
+    Future,
+}
+
+impl fmt::Display for InstrumentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstrumentKind::Spot => write!(f, "spot"),
+            InstrumentKind::Perpetual => write!(f, "perpetual"),
+            InstrumentKind::Future => write!(f, "future"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::*;
+
+    #[test]
+    fn should_read_toml() {
+        /*
+         * Given
+         */
+        let s = r#"
+            symbols = ["eth/usd", "BTC/USD"]
+            listen = "0.0.0.0:50052"
+            metrics_listen = "127.0.0.1:9100"
+            kind = "perpetual"
+            fee_adjusted = true
+            record = "/var/lib/orderly/feeds"
+
+            [tls]
+            cert = "server.pem"
+            key = "server.key"
+
+            [export]
+            dir = "books"
+            format = "parquet"
+            interval_ms = 1000
+
+            [candles]
+            intervals_secs = [300, 60]
+
+            [venues.kraken]
+            url = "ws://localhost:8080"
+            depth = 25
+            fee = 0.0026
+            stale_after_secs = 30
+            bbo = true
+
+            [venues.coinbase]
+            enabled = false
+            trades = true
+
+            [venues.bitfinex]
+            checksum = true
+            precision = { "eth/usd" = "P2" }
+
+            [venues.deribit]
+            kind = "future"
+            expiry = "27DEC24"
+
+            [venues.binance_futures]
+            diff = true
+            rest_url = "http://localhost:8081"
+        "#;
+
+        /*
+         * When
+         */
+        let mut config: Config = toml::from_str(s).unwrap();
+        config.validate().unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(config, Config {
+            symbols: vec!["ETH/USD".to_string(), "BTC/USD".to_string()],
+            depth: 10,
+            kind: InstrumentKind::Perpetual,
+            fee_adjusted: true,
+            listen: "0.0.0.0:50052".parse().unwrap(),
+            tls: Some(TlsConfig {
+                cert: PathBuf::from("server.pem"),
+                key: PathBuf::from("server.key"),
+                client_ca: None,
+            }),
+            auth_file: None,
+            console_socket: None,
+            metrics_listen: Some("127.0.0.1:9100".parse().unwrap()),
+            record: Some(PathBuf::from("/var/lib/orderly/feeds")),
+            replay: None,
+            export: Some(ExportConfig {
+                dir: PathBuf::from("books"),
+                format: ExportFormat::Parquet,
+                layout: ExportLayout::Long,
+                interval_ms: Some(1000),
+                venues: false,
+            }),
+            candles: Some(CandlesConfig { intervals_secs: vec![60, 300], history: 1440 }),
+            venues: Venues {
+                kraken: VenueConfig {
+                    url: Some("ws://localhost:8080".to_string()),
+                    depth: 25,
+                    fee: dec!(0.0026),
+                    stale_after_secs: Some(30),
+                    bbo: true,
+                    ..Default::default()
+                },
+                coinbase: VenueConfig { enabled: false, trades: true, ..Default::default() },
+                bitfinex: VenueConfig {
+                    precision: BTreeMap::from([("ETH/USD".to_string(), Precision::P2)]),
+                    checksum: true,
+                    ..Default::default()
+                },
+                deribit: VenueConfig {
+                    kind: Some(InstrumentKind::Future),
+                    expiry: Some("27DEC24".to_string()),
+                    ..Default::default()
+                },
+                binance_futures: VenueConfig {
+                    diff: true,
+                    rest_url: Some("http://localhost:8081".to_string()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        });
+        assert_eq!(config.venues.kind(&Exchange::BinanceFutures), InstrumentKind::Perpetual);
+        assert_eq!(config.venues.kind(&Exchange::Deribit), InstrumentKind::Future);
+    }
+
+    #[test]
+    fn should_read_yaml() {
+        /*
+         * Given
+         */
+        let s = "
+            symbols: [ETH/BTC]
+            depth: 5
+            venues:
+              binance:
+                fee: 0.001
+        ";
+
+        /*
+         * When
+         */
+        let config: Config = serde_yaml::from_str(s).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(config.depth, 5);
+        assert_eq!(config.venues.binance.fee, dec!(0.001));
+        assert_eq!(config.venues.kraken, VenueConfig::default());
+    }
+
+    #[test]
+    fn should_read_replay_speed() {
+        /*
+         * Given
+         */
+        let speeds = ["", "speed = 10", "speed = 0.5", "speed = \"max\""];
+
+        /*
+         * When
+         */
+        let speeds: Vec<Speed> = speeds.iter()
+            .map(|speed| toml::from_str::<Config>(&format!("[replay]\ndir = \"feeds\"\n{}", speed)).unwrap())
+            .map(|config| config.replay.unwrap().speed)
+            .collect();
+
+        /*
+         * Then
+         */
+        assert_eq!(speeds, vec![Speed::Times(1.0), Speed::Times(10.0), Speed::Times(0.5), Speed::Max]);
+        assert_eq!("MAX".parse(), Ok(Speed::Max));
+        assert!("-1".parse::<Speed>().is_err());
+        assert!("fast".parse::<Speed>().is_err());
+    }
+
+    #[test]
+    fn should_read_example() {
+        let mut config: Config = toml::from_str(include_str!("../orderly.example.toml")).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn should_reject_bad_config() {
+        assert!(toml::from_str::<Config>("symbol = \"ETH/BTC\"").is_err());
+        assert!(toml::from_str::<Config>("[venues.ftx]").is_err());
+        assert!(toml::from_str::<Config>("listen = \"localhost\"").is_err());
+        assert!(toml::from_str::<Config>("[replay]\ndir = \"feeds\"\nspeed = 0").is_err());
+        assert!(toml::from_str::<Config>("[venues.bitfinex.precision]\n\"ETH/BTC\" = \"P5\"").is_err());
+
+        let invalid = [
+            "symbols = []",
+            "symbols = [\"ETHBTC\"]",
+            "depth = 0",
+            "[venues.binance]\ndepth = 1000",
+            "[venues.kraken]\nfee = 1.5",
+            "record = \"feeds\"\n[replay]\ndir = \"feeds\"",
+            "[export]\ndir = \"books\"\ninterval_ms = 0",
+            "[candles]\nintervals_secs = []",
+            "[candles]\nintervals_secs = [0]",
+            "[candles]\nintervals_secs = [60]\nhistory = 0",
+            "[venues.bitfinex.precision]\nETHBTC = \"P1\"",
+            "[venues.kraken]\nkind = \"perpetual\"",
+            "[venues.deribit]\nkind = \"future\"",
+            "[venues.binance_futures]\nkind = \"spot\"",
+            "[venues.deribit]\nexpiry = \"27DEC24\"",
+            "[venues.kraken]\ndiff = true",
+            "[venues.okx]\ntrades = true",
+            "[venues.bitstamp]\nbbo = true",
+            "[venues.binance]\nbbo = true\ndiff = true",
+        ];
+        for s in invalid {
+            let mut config: Config = toml::from_str(s).unwrap();
+            assert!(config.validate().is_err(), "{}", s);
+        }
+    }
+}
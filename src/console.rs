@@ -0,0 +1,326 @@
+use crate::orderbook::{self, Exchange, Level, OutTick};
+use crate::venue::VenueState;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+/// Operator commands, one per line.
+///
+/// ```text
+/// status
+/// book <exchange> [symbol]
+/// disable <exchange>
+/// enable <exchange>
+/// add-symbol <symbol>
+/// remove-symbol <symbol>
+/// resubscribe <exchange>
+/// depth <n>
+/// raw <exchange> <json>
+/// reload-auth
+/// exit
+/// ```
+///
+/// The same commands back the `Admin` gRPC service.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    /// Lists every subscription with its connection state.
+    Status,
+
+    /// Shows the orderbook currently held for the exchange, by default for the first symbol.
+    Book(Exchange, Option<String>),
+
+    /// Disconnects from the exchange for every symbol.
+    Disable(Exchange),
+
+    /// Connects to the exchange for every symbol.
+    Enable(Exchange),
+
+    /// Connects to every enabled exchange for the symbol.
+    AddSymbol(String),
+
+    /// Disconnects every exchange from the symbol and drops its orderbook.
+    RemoveSymbol(String),
+
+    /// Reconnects to the exchange and subscribes again.
+    Resubscribe(Exchange),
+
+    /// Sets the number of levels per side published in the merged orderbook.
+    Depth(usize),
+
+    /// Sends a JSON message as-is to every connection to the exchange.
+    Raw(Exchange, String),
+
+    /// Reads the auth file again.
+    ReloadAuth,
+
+    /// Closes all connections and stops the server.
+    Exit,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+
+        match (name, args) {
+            ("status", "") => Ok(Command::Status),
+            ("book", args) => match args.split_once(char::is_whitespace) {
+                Some((ex, symbol)) => Ok(Command::Book(exchange(ex)?, Some(orderbook::parse_symbol(symbol)?))),
+                None => Ok(Command::Book(exchange(args)?, None)),
+            },
+            ("disable", ex) => Ok(Command::Disable(exchange(ex)?)),
+            ("enable", ex) => Ok(Command::Enable(exchange(ex)?)),
+            ("add-symbol", symbol) => Ok(Command::AddSymbol(orderbook::parse_symbol(symbol)?)),
+            ("remove-symbol", symbol) => Ok(Command::RemoveSymbol(orderbook::parse_symbol(symbol)?)),
+            ("resubscribe", ex) => Ok(Command::Resubscribe(exchange(ex)?)),
+            ("depth", n) => n.parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .map(Command::Depth)
+                .ok_or_else(|| format!("depth must be a positive number, got: {:?}", n)),
+            ("raw", args) => {
+                let (ex, json) = args.split_once(char::is_whitespace)
+                    .ok_or_else(|| "usage: raw <exchange> <json>".to_string())?;
+                let json = json.trim();
+                serde_json::from_str::<serde_json::Value>(json)
+                    .map_err(|e| format!("invalid json: {}", e))?;
+                Ok(Command::Raw(exchange(ex)?, json.to_string()))
+            },
+            ("reload-auth", "") => Ok(Command::ReloadAuth),
+            ("exit", "") | ("/exit", "") => Ok(Command::Exit),
+            _ => Err(format!("unknown command: {:?}", line)),
+        }
+    }
+}
+
+fn exchange(s: &str) -> Result<Exchange, String> {
+    match s {
+        "" => Err("missing exchange".to_string()),
+        s => s.parse(),
+    }
+}
+
+/// Reply to a `Command`, written back as a single line of JSON.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Response {
+    Status {
+        exchanges: Vec<Exchange>,
+        symbols: Vec<String>,
+        depth: usize,
+        venues: Vec<VenueState>,
+    },
+
+    Book {
+        exchange: Exchange,
+        symbol: String,
+        spread: Decimal,
+        bids: Vec<Level>,
+        asks: Vec<Level>,
+    },
+
+    Ok {
+        message: String,
+    },
+
+    Error {
+        message: String,
+    },
+}
+
+impl Response {
+    pub(crate) fn book(exchange: Exchange, symbol: String, out_tick: OutTick) -> Response {
+        Response::Book {
+            exchange,
+            symbol,
+            spread: out_tick.spread,
+            bids: out_tick.bids,
+            asks: out_tick.asks,
+        }
+    }
+
+    pub(crate) fn ok(message: impl Into<String>) -> Response {
+        Response::Ok { message: message.into() }
+    }
+
+    pub(crate) fn error(message: impl Into<String>) -> Response {
+        Response::Error { message: message.into() }
+    }
+}
+
+/// A parsed `Command` together with the channel on which to send its `Response`.
+pub(crate) type Request = (Command, oneshot::Sender<Response>);
+
+/// Reads commands from stdin and, if given, from connections on a Unix socket at `socket`,
+/// and forwards them to `tx_requests`.
+///
+/// Closing stdin only stops reading from it, so the server keeps running headless.
+pub(crate) fn spawn(socket: Option<PathBuf>, tx_requests: mpsc::Sender<Request>) {
+    let tx = tx_requests.clone();
+    tokio::spawn(async move {
+        let stdin = BufReader::new(tokio::io::stdin());
+        serve(stdin, tokio::io::stdout(), tx).await;
+        info!("stdin closed, running headless");
+    });
+
+    if let Some(path) = socket {
+        tokio::spawn(async move {
+            if let Err(e) = listen(&path, tx_requests).await {
+                error!(path = %path.display(), error = %e, "console socket failed");
+            }
+        });
+    }
+}
+
+async fn listen(path: &PathBuf, tx: mpsc::Sender<Request>) -> std::io::Result<()> {
+    // a socket file left behind by a previous run would make bind fail
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    info!(path = %path.display(), "console listening");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let (reader, writer) = stream.into_split();
+            serve(BufReader::new(reader), writer, tx).await;
+        });
+    }
+}
+
+/// Answers every line of `reader` on `writer` until either side is closed.
+async fn serve<R, W>(reader: R, mut writer: W, tx: mpsc::Sender<Request>)
+    where R: AsyncBufRead + Unpin,
+          W: AsyncWrite + Unpin,
+{
+    let mut lines = reader.lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match line.parse::<Command>() {
+            Ok(command) => request(&tx, command).await,
+            Err(message) => Response::error(message),
+        };
+
+        let mut json = serde_json::to_string(&response)
+            .unwrap_or_else(|e| format!("{{\"type\":\"error\",\"message\":\"{}\"}}", e));
+        json.push('\n');
+        if writer.write_all(json.as_bytes()).await.is_err() || writer.flush().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends the command to the `Connector` and waits for its response.
+pub(crate) async fn request(tx: &mpsc::Sender<Request>, command: Command) -> Response {
+    let (tx_response, rx_response) = oneshot::channel();
+    if tx.send((command, tx_response)).await.is_err() {
+        return Response::error("server is shutting down");
+    }
+    rx_response.await.unwrap_or_else(|_| Response::error("server is shutting down"))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::console::*;
+    use crate::orderbook::Side;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn should_parse_commands() {
+        assert_eq!("status".parse(), Ok(Command::Status));
+        assert_eq!("book kraken".parse(), Ok(Command::Book(Exchange::Kraken, None)));
+        assert_eq!("book kraken xbt/usd".parse(), Ok(Command::Book(Exchange::Kraken, Some("XBT/USD".to_string()))));
+        assert_eq!("add-symbol eth/usd".parse(), Ok(Command::AddSymbol("ETH/USD".to_string())));
+        assert_eq!("remove-symbol ETH/USD".parse(), Ok(Command::RemoveSymbol("ETH/USD".to_string())));
+        assert_eq!("disable Binance".parse(), Ok(Command::Disable(Exchange::Binance)));
+        assert_eq!(" enable  bitstamp ".parse(), Ok(Command::Enable(Exchange::Bitstamp)));
+        assert_eq!("resubscribe coinbase".parse(), Ok(Command::Resubscribe(Exchange::Coinbase)));
+        assert_eq!("depth 25".parse(), Ok(Command::Depth(25)));
+        assert_eq!(r#"raw coinbase {"type": "unsubscribe", "channels": ["heartbeat"]}"#.parse(),
+                   Ok(Command::Raw(Exchange::Coinbase,
+                                   r#"{"type": "unsubscribe", "channels": ["heartbeat"]}"#.to_string())));
+        assert_eq!("reload-auth".parse(), Ok(Command::ReloadAuth));
+        assert_eq!("exit".parse(), Ok(Command::Exit));
+    }
+
+    #[test]
+    fn should_reject_bad_commands() {
+        assert!("".parse::<Command>().is_err());
+        assert!("book".parse::<Command>().is_err());
+        assert!("book ftx".parse::<Command>().is_err());
+        assert!("book kraken ethbtc".parse::<Command>().is_err());
+        assert!("add-symbol".parse::<Command>().is_err());
+        assert!("add-symbol ETH/".parse::<Command>().is_err());
+        assert!("depth 0".parse::<Command>().is_err());
+        assert!("depth ten".parse::<Command>().is_err());
+        assert!("raw kraken".parse::<Command>().is_err());
+        assert!("raw kraken {not json".parse::<Command>().is_err());
+        assert!("status now".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn should_serialize_response() {
+        /*
+         * Given
+         */
+        let response = Response::book(Exchange::Kraken, "ETH/BTC".to_string(), OutTick {
+            spread: dec!(0.5),
+            bids: vec![Level::new(Side::Bid, dec!(10), dec!(1.5), Exchange::Kraken)],
+            asks: vec![Level::new(Side::Ask, dec!(10.5), dec!(2), Exchange::Kraken)],
+        });
+
+        /*
+         * When
+         */
+        let json = serde_json::to_string(&response).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(json, concat!(
+            r#"{"type":"book","exchange":"kraken","symbol":"ETH/BTC","spread":"0.5","#,
+            r#""bids":[{"side":"bid","price":"10","amount":"1.5","exchange":"kraken","kind":"spot"}],"#,
+            r#""asks":[{"side":"ask","price":"10.5","amount":"2","exchange":"kraken","kind":"spot"}]}"#,
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_answer_each_line() {
+        /*
+         * Given
+         */
+        let (tx, mut rx) = mpsc::channel::<Request>(10);
+        tokio::spawn(async move {
+            while let Some((command, tx_response)) = rx.recv().await {
+                let _ = tx_response.send(Response::ok(format!("{:?}", command)));
+            }
+        });
+        let input: &[u8] = b"status\n\nbogus\ndepth 5\n";
+        let mut output: Vec<u8> = vec![];
+
+        /*
+         * When
+         */
+        serve(input, &mut output, tx).await;
+
+        /*
+         * Then
+         */
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "{\"type\":\"ok\",\"message\":\"Status\"}\n",
+            "{\"type\":\"error\",\"message\":\"unknown command: \\\"bogus\\\"\"}\n",
+            "{\"type\":\"ok\",\"message\":\"Depth(5)\"}\n",
+        ));
+    }
+}
@@ -0,0 +1,514 @@
+use crate::config::InstrumentKind;
+use crate::error::{self, Error};
+use crate::orderbook::{self, Exchange, FullBook, InTick};
+use crate::venue::Parsed;
+use crate::record::Tape;
+use crate::websocket::{self, Ack};
+use crate::metrics;
+use chrono::{TimeZone, Utc};
+use futures::SinkExt;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace};
+use tungstenite::protocol::Message;
+
+pub(crate) const DERIBIT_WS_URL: &str = "wss://www.deribit.com/ws/api/v2";
+
+/// Seconds between the heartbeats the exchange is asked for, the least it accepts being 10. Every
+/// other one is a `test_request`, which closes the connection unless answered.
+const HEARTBEAT_SECS: u64 = 30;
+
+/// Changes of the whole book are grouped over 100ms, as only authorized connections may get them raw.
+const INTERVAL: &str = "100ms";
+
+const SUBSCRIBE_ID: u64 = 1;
+const HEARTBEAT_ID: u64 = 2;
+const TEST_ID: u64 = 3;
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Event {
+    Notification(Notification),
+
+    Response(Response),
+}
+
+/// JSON-RPC notification, sent without being asked for.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+enum Notification {
+    /// Publication: A book of the subscribed channel, whole or the changes to it.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "jsonrpc": "2.0",
+    ///   "method": "subscription",
+    ///   "params": {
+    ///     "channel": "book.BTC-PERPETUAL.100ms",
+    ///     "data": {
+    ///       "type": "change",
+    ///       "timestamp": 1554373911330,
+    ///       "instrument_name": "BTC-PERPETUAL",
+    ///       "prev_change_id": 297217,
+    ///       "change_id": 297218,
+    ///       "bids": [["delete", 5041.94, 0], ["change", 5042.34, 10]],
+    ///       "asks": [["new", 5042.64, 40]]
+    ///     }
+    ///   }
+    /// }
+    /// ```
+    Subscription {
+        channel: String,
+
+        data: Book,
+    },
+
+    /// Publication: Sent every `HEARTBEAT_SECS` once asked for, alternately as a plain heartbeat and as
+    /// a `test_request`, which must be answered with a `public/test` request.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "jsonrpc": "2.0",
+    ///   "method": "heartbeat",
+    ///   "params": {
+    ///     "type": "test_request"
+    ///   }
+    /// }
+    /// ```
+    Heartbeat {
+        #[serde(rename = "type")]
+        kind: HeartbeatKind,
+    },
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum HeartbeatKind {
+    Heartbeat,
+    TestRequest,
+}
+
+/// Response to a JSON-RPC request of the same ID, holding either its result or its error.
+///
+/// **Examples of payload**
+/// ```json
+/// {
+///   "jsonrpc": "2.0",
+///   "id": 1,
+///   "result": ["book.BTC-PERPETUAL.100ms"],
+///   "usIn": 1656000000000000,
+///   "usOut": 1656000000000150,
+///   "usDiff": 150,
+///   "testnet": false
+/// }
+///
+/// {
+///   "jsonrpc": "2.0",
+///   "id": 1,
+///   "error": {
+///     "message": "Invalid params",
+///     "data": { "reason": "invalid channel", "param": "channels" },
+///     "code": -32602
+///   }
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct Response {
+    id: u64,
+
+    result: Option<serde_json::Value>,
+
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct RpcError {
+    code: i64,
+
+    message: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Book {
+    #[serde(rename = "type")]
+    kind: BookKind,
+
+    /// Milliseconds since epoch
+    timestamp: i64,
+
+    instrument_name: String,
+
+    /// Change ID of the previous message, which this one follows on. Missing from snapshots.
+    prev_change_id: Option<u64>,
+
+    change_id: u64,
+
+    bids: Vec<Change>,
+
+    asks: Vec<Change>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum BookKind {
+    Snapshot,
+    Change,
+}
+
+/// What happened at a price, with the amount left there.
+#[derive(Debug, Deserialize, PartialEq)]
+struct Change(Action, Decimal, Decimal);
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    New,
+    Change,
+    Delete,
+}
+
+/// JSON-RPC request.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "jsonrpc": "2.0",
+///   "id": 1,
+///   "method": "public/subscribe",
+///   "params": {
+///     "channels": ["book.BTC-PERPETUAL.100ms"]
+///   }
+/// }
+/// ```
+#[derive(Debug, Serialize, PartialEq)]
+struct Request {
+    jsonrpc: &'static str,
+
+    id: u64,
+
+    #[serde(flatten)]
+    method: Method,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "method", content = "params")]
+enum Method {
+    #[serde(rename = "public/subscribe")]
+    Subscribe {
+        channels: Vec<String>,
+    },
+
+    /// Asks for a heartbeat every `interval` seconds.
+    #[serde(rename = "public/set_heartbeat")]
+    SetHeartbeat {
+        interval: u64,
+    },
+
+    /// Answers a `test_request`.
+    #[serde(rename = "public/test")]
+    Test {},
+}
+
+impl Request {
+    fn new(id: u64, method: Method) -> Request {
+        Request { jsonrpc: "2.0", id, method }
+    }
+
+    fn to_text(&self, symbol: &str) -> Result<String, Error> {
+        serde_json::to_string(self).map_err(|e| Error::protocol(Exchange::Deribit, symbol, e, &format!("{:?}", self)))
+    }
+}
+
+/// Book of a connection, kept in full as changes touch any level of it, with the change ID it is at.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Depths {
+    /// Amount at each price.
+    book: FullBook<Decimal>,
+
+    /// `None` until the snapshot arrived.
+    change_id: Option<u64>,
+
+    kind: InstrumentKind,
+}
+
+impl Depths {
+    /// Applies the book. Fails if it does not follow on from the one the depths are at.
+    fn apply(&mut self, book: Book, symbol: &str, payload: &str) -> Result<(), Error> {
+        match (book.kind, self.change_id) {
+            (BookKind::Snapshot, _) => {
+                self.book.clear();
+                self.kind = kind(&book.instrument_name);
+            },
+            (BookKind::Change, None) => {
+                debug!(change_id = book.change_id, "change before the snapshot");
+                return Ok(());
+            },
+            (BookKind::Change, Some(change_id)) if book.prev_change_id != Some(change_id) => {
+                return Err(Error::SequenceGap {
+                    exchange: Exchange::Deribit,
+                    symbol: symbol.to_string(),
+                    expected: change_id,
+                    got: book.prev_change_id.unwrap_or_default(),
+                    payload: error::excerpt(payload),
+                });
+            },
+            (BookKind::Change, Some(_)) => {},
+        }
+
+        for (levels, changes) in [(&mut self.book.bids, book.bids), (&mut self.book.asks, book.asks)] {
+            for Change(action, price, amount) in changes {
+                match action {
+                    Action::Delete => levels.remove(&price),
+                    Action::New | Action::Change => levels.insert(price, amount),
+                };
+            }
+        }
+        self.change_id = Some(book.change_id);
+        Ok(())
+    }
+
+    fn to_tick(&self) -> InTick {
+        self.book.to_tick(Exchange::Deribit, |side, price, amount|
+            orderbook::Level::new(side, price, *amount, Exchange::Deribit).with_kind(self.kind))
+    }
+}
+
+/// Subscribes to the book of the instrument of `kind` for the symbol, then asks for heartbeats.
+pub(crate) async fn connect(
+    url: &str,
+    symbol: &str,
+    kind: InstrumentKind,
+    expiry: Option<&str>,
+    tape: &Tape,
+) -> Result<(websocket::WsStream, Vec<Message>), Error>
+{
+    let mut ws_stream = websocket::connect(Exchange::Deribit, symbol, url, tape).await?;
+    websocket::subscribe(&mut ws_stream, Exchange::Deribit, symbol, url, subscription(symbol, kind, expiry)?, tape).await?;
+    let backlog = websocket::await_ack(&mut ws_stream, Exchange::Deribit, symbol, url, ack, tape).await?;
+
+    let heartbeat = Request::new(HEARTBEAT_ID, Method::SetHeartbeat { interval: HEARTBEAT_SECS }).to_text(symbol)?;
+    ws_stream.send(Message::Text(heartbeat)).await
+        .map_err(|e| Error::connect(Exchange::Deribit, symbol, url, e))?;
+    Ok((ws_stream, backlog))
+}
+
+fn ack(text: &str) -> Ack {
+    match deserialize(text) {
+        Ok(Event::Response(Response { id: SUBSCRIBE_ID, error: Some(error), .. })) =>
+            Ack::Rejected(format!("{} ({})", error.message, error.code)),
+        Ok(Event::Response(Response { id: SUBSCRIBE_ID, result: Some(result), .. })) =>
+            match result.as_array().is_some_and(|channels| !channels.is_empty()) {
+                true => Ack::Subscribed,
+                false => Ack::Rejected("no channel subscribed".to_string()),
+            },
+        _ => Ack::Pending,
+    }
+}
+
+fn subscription(symbol: &str, kind: InstrumentKind, expiry: Option<&str>) -> Result<String, Error> {
+    let channel = format!("book.{}.{}", instrument(symbol, kind, expiry), INTERVAL);
+    Request::new(SUBSCRIBE_ID, Method::Subscribe { channels: vec![channel] }).to_text(symbol)
+}
+
+/// Deribit names spot pairs `BTC_USDC`, and derivatives after their base currency when quoted in USD,
+/// e.g. `BTC-PERPETUAL` and `BTC-27DEC24`, after their pair otherwise, e.g. `ETH_USDC-PERPETUAL`.
+fn instrument(symbol: &str, kind: InstrumentKind, expiry: Option<&str>) -> String {
+    let symbol = symbol.to_uppercase();
+    let (base, quote) = symbol.split_once('/').unwrap_or((&symbol, ""));
+    let pair = format!("{}_{}", base, quote);
+    let underlying = match quote {
+        "USD" => base,
+        _ => &pair,
+    };
+    match kind {
+        InstrumentKind::Spot => pair.clone(),
+        InstrumentKind::Perpetual => format!("{}-PERPETUAL", underlying),
+        InstrumentKind::Future => format!("{}-{}", underlying, expiry.unwrap_or_default()),
+    }
+}
+
+/// Kind of instrument of the name, as built by `instrument`.
+fn kind(instrument: &str) -> InstrumentKind {
+    match instrument.split_once('-') {
+        None => InstrumentKind::Spot,
+        Some((_, "PERPETUAL")) => InstrumentKind::Perpetual,
+        Some(_) => InstrumentKind::Future,
+    }
+}
+
+/// The snapshot replaces the book held in `depths`, and changes apply to it as long as they follow on
+/// from it. `test_request` heartbeats are answered.
+pub(crate) fn parse(msg: Message, symbol: &str, depths: &mut Depths) -> Result<Parsed, Error> {
+    metrics::message_received(Exchange::Deribit, &msg);
+    let x = match msg {
+        Message::Text(x) => x,
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); return Ok(Parsed::Nothing) },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); return Ok(Parsed::Nothing) },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); return Ok(Parsed::Nothing) },
+        Message::Close(x) => { info!(frame = ?x, "close"); return Ok(Parsed::Nothing) },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); return Ok(Parsed::Nothing) },
+    };
+    trace!(%x, "text message");
+
+    let e = deserialize(&x).map_err(|e| metrics::parse_error(Exchange::Deribit, symbol, e, &x))?;
+    Ok(match e {
+        Event::Notification(Notification::Subscription { channel, data }) => {
+            trace!(%channel, change_id = data.change_id, "book");
+            if let Some(ts) = Utc.timestamp_millis_opt(data.timestamp).single() {
+                metrics::message_age(Exchange::Deribit, ts);
+            }
+            depths.apply(data, symbol, &x)?;
+            Parsed::Tick(depths.to_tick())
+        },
+        Event::Notification(Notification::Heartbeat { kind: HeartbeatKind::TestRequest }) => {
+            trace!("test request");
+            Parsed::Reply(Message::Text(Request::new(TEST_ID, Method::Test {}).to_text(symbol)?))
+        },
+        Event::Notification(Notification::Heartbeat { kind: HeartbeatKind::Heartbeat }) => {
+            trace!("heartbeat");
+            Parsed::Nothing
+        },
+        Event::Response(Response { error: Some(error), .. }) => Parsed::Error(format!("{} ({})", error.message, error.code)),
+        Event::Response(response) => {
+            info!(?response, "response");
+            Parsed::Nothing
+        },
+    })
+}
+
+fn deserialize(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use crate::deribit::*;
+
+    fn level(side: orderbook::Side, price: Decimal, amount: Decimal) -> orderbook::Level {
+        orderbook::Level::new(side, price, amount, Exchange::Deribit).with_kind(InstrumentKind::Perpetual)
+    }
+
+    #[test]
+    fn should_deserialize_change() -> serde_json::Result<()> {
+        assert_eq!(deserialize(include_str!("../tests/fixtures/deribit/book_change.json"))?,
+                   Event::Notification(Notification::Subscription {
+                       channel: "book.BTC-PERPETUAL.100ms".to_string(),
+                       data: Book {
+                           kind: BookKind::Change,
+                           timestamp: 1656000000200,
+                           instrument_name: "BTC-PERPETUAL".to_string(),
+                           prev_change_id: Some(9000),
+                           change_id: 9001,
+                           bids: vec![
+                               Change(Action::Delete, dec!(20001.5), dec!(0)),
+                               Change(Action::Change, dec!(20001), dec!(5000)),
+                           ],
+                           asks: vec![Change(Action::New, dec!(20001.5), dec!(250))],
+                       },
+                   })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_serialize_requests() {
+        assert_eq!(subscription("btc/usd", InstrumentKind::Perpetual, None).unwrap(),
+                   r#"{"jsonrpc":"2.0","id":1,"method":"public/subscribe","params":{"channels":["book.BTC-PERPETUAL.100ms"]}}"#);
+        assert_eq!(Request::new(HEARTBEAT_ID, Method::SetHeartbeat { interval: 30 }).to_text("BTC/USD").unwrap(),
+                   r#"{"jsonrpc":"2.0","id":2,"method":"public/set_heartbeat","params":{"interval":30}}"#);
+    }
+
+    #[test]
+    fn should_name_instruments() {
+        assert_eq!(instrument("BTC/USDC", InstrumentKind::Spot, None), "BTC_USDC");
+        assert_eq!(instrument("ETH/USD", InstrumentKind::Perpetual, None), "ETH-PERPETUAL");
+        assert_eq!(instrument("ETH/USDC", InstrumentKind::Perpetual, None), "ETH_USDC-PERPETUAL");
+        assert_eq!(instrument("BTC/USD", InstrumentKind::Future, Some("27DEC24")), "BTC-27DEC24");
+
+        assert_eq!(kind("BTC_USDC"), InstrumentKind::Spot);
+        assert_eq!(kind("ETH_USDC-PERPETUAL"), InstrumentKind::Perpetual);
+        assert_eq!(kind("BTC-27DEC24"), InstrumentKind::Future);
+    }
+
+    #[test]
+    fn should_ack_subscription() {
+        assert_eq!(ack(include_str!("../tests/fixtures/deribit/subscribed.json")), Ack::Subscribed);
+        assert_eq!(ack(include_str!("../tests/fixtures/deribit/error.json")), Ack::Rejected("Invalid params (-32602)".to_string()));
+        assert_eq!(ack(r#"{"jsonrpc":"2.0","id":1,"result":[]}"#), Ack::Rejected("no channel subscribed".to_string()));
+        assert_eq!(ack(include_str!("../tests/fixtures/deribit/heartbeat_set.json")), Ack::Pending);
+    }
+
+    #[test]
+    fn should_apply_changes_after_snapshot() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        parse(Message::Text(include_str!("../tests/fixtures/deribit/book_snapshot.json").to_string()), "BTC/USD", &mut depths).unwrap();
+
+        /*
+         * When
+         */
+        let tick = parse(Message::Text(include_str!("../tests/fixtures/deribit/book_change.json").to_string()), "BTC/USD", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(tick, Parsed::Tick(InTick {
+            exchange: Exchange::Deribit,
+            bids: vec![
+                level(orderbook::Side::Bid, dec!(20001), dec!(5000)),
+                level(orderbook::Side::Bid, dec!(20000), dec!(800)),
+            ],
+            asks: vec![
+                level(orderbook::Side::Ask, dec!(20001.5), dec!(250)),
+                level(orderbook::Side::Ask, dec!(20002), dec!(4200)),
+                level(orderbook::Side::Ask, dec!(20003.5), dec!(10)),
+            ],
+        }));
+        assert_eq!(depths.change_id, Some(9001));
+    }
+
+    #[test]
+    fn should_fail_on_change_id_gap() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        parse(Message::Text(include_str!("../tests/fixtures/deribit/book_snapshot.json").to_string()), "BTC/USD", &mut depths).unwrap();
+        let gap = include_str!("../tests/fixtures/deribit/book_change.json").replace(r#""prev_change_id":9000"#, r#""prev_change_id":8990"#);
+
+        /*
+         * When
+         */
+        let parsed = parse(Message::Text(gap), "BTC/USD", &mut depths);
+
+        /*
+         * Then
+         */
+        assert!(matches!(parsed, Err(Error::SequenceGap { expected: 9000, got: 8990, .. })), "{:?}", parsed);
+    }
+
+    #[test]
+    fn should_answer_test_request() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+
+        /*
+         * When
+         */
+        let parsed = parse(Message::Text(include_str!("../tests/fixtures/deribit/test_request.json").to_string()), "BTC/USD", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Reply(Message::Text(r#"{"jsonrpc":"2.0","id":3,"method":"public/test","params":{}}"#.to_string())));
+        assert_eq!(parse(Message::Text(include_str!("../tests/fixtures/deribit/heartbeat_set.json").to_string()), "BTC/USD", &mut depths).unwrap(),
+                   Parsed::Nothing);
+    }
+}
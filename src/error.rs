@@ -1,51 +1,211 @@
-#[derive(Debug)]
+use crate::orderbook::Exchange;
+use thiserror::Error;
+
+/// Longest excerpt of an exchange message kept in an error, in characters.
+const EXCERPT_LEN: usize = 200;
+
+#[derive(Debug, Error)]
 pub enum Error {
-    BadConnection(tungstenite::Error),
+    /// The WebSocket to the exchange could not be opened, or the subscription could not be sent.
+    #[error("{exchange} {symbol}: could not connect to {url}: {source}")]
+    Connect {
+        exchange: Exchange,
+        symbol: String,
+        url: String,
+        #[source]
+        source: Box<tungstenite::Error>,
+    },
 
-    BadData(serde_json::Error),
+    /// A request to the REST API of the exchange failed, e.g. for the WebSocket endpoint or a snapshot.
+    #[error("{exchange} {symbol}: request to {url} failed: {reason}")]
+    Rest {
+        exchange: Exchange,
+        symbol: String,
+        url: String,
+        reason: String,
+    },
 
-    IoError(std::io::Error),
+    /// The exchange refused the subscription, e.g. for an unknown pair.
+    #[error("{exchange} {symbol}: subscription rejected: {reason} in {payload}")]
+    SubscribeRejected {
+        exchange: Exchange,
+        symbol: String,
+        reason: String,
+        payload: String,
+    },
 
-    ServerError(tonic::transport::Error),
+    /// The exchange neither confirmed nor refused the subscription in time.
+    #[error("{exchange} {symbol}: subscription not confirmed within {secs}s")]
+    SubscribeTimeout {
+        exchange: Exchange,
+        symbol: String,
+        secs: u64,
+    },
 
-    BadAddr(std::net::AddrParseError),
-}
+    /// The exchange reported an error after the subscription was confirmed, e.g. for a raw command.
+    /// The connection stays up.
+    #[error("{exchange} {symbol}: exchange reported: {reason}")]
+    Reported {
+        exchange: Exchange,
+        symbol: String,
+        reason: String,
+    },
+
+    /// The exchange sent a message which does not fit its protocol.
+    #[error("{exchange} {symbol}: protocol error: {reason} in {payload}")]
+    Protocol {
+        exchange: Exchange,
+        symbol: String,
+        reason: String,
+        payload: String,
+    },
+
+    /// Updates of the exchange were missed, so the book held for it can no longer be trusted.
+    #[error("{exchange} {symbol}: sequence gap, expected {expected} but got {got} in {payload}")]
+    SequenceGap {
+        exchange: Exchange,
+        symbol: String,
+        expected: u64,
+        got: u64,
+        payload: String,
+    },
+
+    /// The book held for the exchange differs from its own, as told by the checksum it sent along.
+    #[error("{exchange} {symbol}: checksum mismatch, expected {expected} but computed {computed} in {payload}")]
+    ChecksumMismatch {
+        exchange: Exchange,
+        symbol: String,
+        expected: i64,
+        computed: i64,
+        payload: String,
+    },
+
+    /// Ticks were coming in, but none arrived for `secs`.
+    #[error("{exchange} {symbol}: no tick for {secs}s")]
+    Stale {
+        exchange: Exchange,
+        symbol: String,
+        secs: u64,
+    },
+
+    /// The open WebSocket failed or was closed by the exchange.
+    #[error("{exchange} {symbol}: connection lost: {source}")]
+    Disconnected {
+        exchange: Exchange,
+        symbol: String,
+        #[source]
+        source: Box<tungstenite::Error>,
+    },
 
-#[derive(Debug)]
-pub enum ExchangeErr {
-    Bitstamp(Error),
-    Binance(Error),
-    Kraken(Error),
-    Coinbase(Error),
+    /// An error a venue recorded, as replayed.
+    #[error("{0}")]
+    Recorded(String),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("server error: {0}")]
+    Server(#[from] tonic::transport::Error),
+
+    #[error("metrics server error: {0}")]
+    Metrics(#[source] hyper::Error),
+
+    #[error("bad symbol: {0}")]
+    BadSymbol(String),
+
+    #[error("bad config: {0}")]
+    BadConfig(String),
 }
 
-impl From<tungstenite::Error> for Error {
-    fn from(e: tungstenite::Error) -> Self {
-        Self::BadConnection(e)
+impl Error {
+    pub(crate) fn connect(exchange: Exchange, symbol: &str, url: &str, source: tungstenite::Error) -> Error {
+        Error::Connect { exchange, symbol: symbol.to_string(), url: url.to_string(), source: Box::new(source) }
     }
-}
 
-impl From<serde_json::Error> for Error {
-    fn from(e: serde_json::Error) -> Self {
-        Self::BadData(e)
+    pub(crate) fn subscribe_rejected(exchange: Exchange, symbol: &str, reason: impl ToString, payload: &str) -> Error {
+        Error::SubscribeRejected {
+            exchange,
+            symbol: symbol.to_string(),
+            reason: reason.to_string(),
+            payload: excerpt(payload),
+        }
     }
-}
 
-impl From<std::io::Error> for Error {
-    fn from(e: std::io::Error) -> Self {
-        Self::IoError(e)
+    pub(crate) fn protocol(exchange: Exchange, symbol: &str, reason: impl ToString, payload: &str) -> Error {
+        Error::Protocol {
+            exchange,
+            symbol: symbol.to_string(),
+            reason: reason.to_string(),
+            payload: excerpt(payload),
+        }
+    }
+
+    pub(crate) fn disconnected(exchange: Exchange, symbol: &str, source: tungstenite::Error) -> Error {
+        Error::Disconnected { exchange, symbol: symbol.to_string(), source: Box::new(source) }
     }
-}
 
-impl From<tonic::transport::Error> for Error {
-    fn from(e: tonic::transport::Error) -> Self {
-        Self::ServerError(e)
+    /// Whether the book held for the exchange went out of sync with it, which subscribing again
+    /// repairs with a new snapshot.
+    pub(crate) fn is_out_of_sync(&self) -> bool {
+        matches!(self, Error::SequenceGap { .. } | Error::ChecksumMismatch { .. })
+    }
+
+    /// Whether connecting again cannot help, as the exchange refused the subscription or the venue is
+    /// misconfigured. A venue stops on these, and reconnects on the others.
+    pub(crate) fn is_fatal(&self) -> bool {
+        matches!(self, Error::SubscribeRejected { .. } | Error::BadConfig(_))
     }
 }
 
-impl From<std::net::AddrParseError> for Error {
-    fn from(e: std::net::AddrParseError) -> Self {
-        Self::BadAddr(e)
+/// Start of the payload, cut at `EXCERPT_LEN` characters so that a whole book does not end up in the
+/// logs and in the venue status.
+pub(crate) fn excerpt(payload: &str) -> String {
+    match payload.char_indices().nth(EXCERPT_LEN) {
+        Some((end, _)) => format!("{}...", &payload[..end]),
+        None => payload.to_string(),
     }
 }
 
+#[cfg(test)]
+mod test {
+    use crate::error::*;
+
+    #[test]
+    fn should_cut_payload_to_excerpt() {
+        /*
+         * Given
+         */
+        let short = r#"{"event":"heartbeat"}"#;
+        let long = "é".repeat(EXCERPT_LEN + 1);
+
+        /*
+         * When
+         */
+        let short = excerpt(short);
+        let long = excerpt(&long);
+
+        /*
+         * Then
+         */
+        assert_eq!(short, r#"{"event":"heartbeat"}"#);
+        assert_eq!(long, format!("{}...", "é".repeat(EXCERPT_LEN)));
+    }
+
+    #[test]
+    fn should_display_context() {
+        /*
+         * Given
+         */
+        let error = Error::protocol(Exchange::Kraken, "ETH/BTC", "expected value at line 1 column 1", "nonsense");
+
+        /*
+         * When
+         */
+        let message = error.to_string();
+
+        /*
+         * Then
+         */
+        assert_eq!(message, "kraken ETH/BTC: protocol error: expected value at line 1 column 1 in nonsense");
+    }
+}
@@ -0,0 +1,657 @@
+//! Writes the merged books, and optionally those of every venue, to files for offline analysis. The
+//! books are sampled at a fixed interval, or written on every change, and work the same whether they
+//! are aggregated live or from a replayed recording; a replay at any speed writes the same rows, as
+//! they are stamped with the time the book changed at and not the time it was written at.
+//!
+//! A run writes `books_<layout>_<first book at, UTC>.<csv|parquet>` into the export directory, in
+//! one of two layouts:
+//!
+//! - `long`: a row per level, with the columns `timestamp`, `symbol`, `book`, `side`, `level`,
+//!   `price`, `amount` and `exchange`. `book` is `merged` or the exchange of a venue book, and
+//!   `level` counts from 0 at the best price of the side.
+//! - `wide`: a row per book, with the columns `timestamp`, `symbol`, `book` and `spread`, followed
+//!   by `bid_<level>_price`, `bid_<level>_amount`, `bid_<level>_exchange` and the same for `ask`,
+//!   for every level up to the depth the server started with. Missing levels are left empty.
+//!
+//! CSV keeps the prices and amounts as decimals, Parquet stores them as doubles. CSV is flushed every
+//! second, while Parquet writes a row group every `ROW_GROUP_ROWS` rows and is only readable once the
+//! server stopped gracefully.
+
+use crate::config::{ExportConfig, ExportFormat, ExportLayout};
+use crate::error::Error;
+use crate::orderbook::{Exchange, Level, OutTick};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Rows Parquet buffers before it writes them as a row group.
+const ROW_GROUP_ROWS: usize = 10_000;
+
+/// CSV files are flushed at least this often.
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+/// Books of a symbol at the time one of them changed.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Snapshot {
+    pub(crate) at: DateTime<Utc>,
+    pub(crate) symbol: String,
+
+    /// The merged book, without an exchange, followed by the book of every venue when exported.
+    pub(crate) books: Vec<(Option<Exchange>, OutTick)>,
+}
+
+/// Hands snapshots to the thread writing the export. Writing stops once every clone is dropped.
+#[derive(Clone)]
+pub(crate) struct Exporter {
+    tx: Sender<Snapshot>,
+    venues: bool,
+}
+
+impl Exporter {
+    /// Starts writing into the export directory, which is created if missing. `depth` is the number
+    /// of levels of the wide layout. While `live`, samples are also taken when the books do not
+    /// change, by the clock. The returned handle finishes once the file is complete.
+    pub(crate) fn spawn(config: ExportConfig, depth: usize, live: bool) -> Result<(Exporter, JoinHandle<()>), Error> {
+        std::fs::create_dir_all(&config.dir)
+            .map_err(|e| Error::BadConfig(format!("export dir {}: {}", config.dir.display(), e)))?;
+        info!(dir = %config.dir.display(), format = ?config.format, layout = ?config.layout, "exporting");
+
+        let (tx, rx) = mpsc::channel();
+        let venues = config.venues;
+        let handle = std::thread::Builder::new()
+            .name("exporter".to_string())
+            .spawn(move || write(config, depth, live, rx))?;
+        Ok((Exporter { tx, venues }, handle))
+    }
+
+    /// Whether the book of every venue is exported next to the merged one.
+    pub(crate) fn venues(&self) -> bool {
+        self.venues
+    }
+
+    pub(crate) fn send(&self, snapshot: Snapshot) {
+        // the writer only goes away after an I/O error it already logged
+        let _ = self.tx.send(snapshot);
+    }
+}
+
+/// Picks the snapshots which are written.
+struct Sampler {
+    /// Every change is written when not set.
+    interval: Option<i64>,
+
+    /// Latest snapshot of every symbol, and the time its next sample is due at.
+    latest: BTreeMap<String, (Snapshot, DateTime<Utc>)>,
+}
+
+impl Sampler {
+    fn new(interval_ms: Option<u64>) -> Sampler {
+        Sampler { interval: interval_ms.map(|ms| ms as i64 * 1_000_000), latest: BTreeMap::new() }
+    }
+
+    /// Takes a snapshot, returning the samples which were due before it.
+    fn offer(&mut self, snapshot: Snapshot) -> Vec<Snapshot> {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => {
+                let changed = self.latest.get(&snapshot.symbol).is_none_or(|(latest, _)| latest.books != snapshot.books);
+                if !changed {
+                    return vec![];
+                }
+                self.latest.insert(snapshot.symbol.clone(), (snapshot.clone(), snapshot.at));
+                return vec![snapshot];
+            },
+        };
+
+        let mut samples = vec![];
+        match self.latest.get_mut(&snapshot.symbol) {
+            Some((latest, next)) => {
+                // a book which changed right at a sample is part of it
+                sample(latest, next, interval, |next| next < snapshot.at, &mut samples);
+                *latest = snapshot;
+            },
+            None => {
+                // samples are aligned to the interval, so that those of different runs line up
+                let nanos = snapshot.at.timestamp_nanos();
+                let next = Utc.timestamp_nanos(nanos + (interval - nanos.rem_euclid(interval)) % interval);
+                self.latest.insert(snapshot.symbol.clone(), (snapshot, next));
+            },
+        }
+        samples
+    }
+
+    /// Returns the samples due by `now`, as nothing changed since the latest snapshots.
+    fn advance(&mut self, now: DateTime<Utc>) -> Vec<Snapshot> {
+        let mut samples = vec![];
+        if let Some(interval) = self.interval {
+            for (latest, next) in self.latest.values_mut() {
+                sample(latest, next, interval, |next| next <= now, &mut samples);
+            }
+        }
+        samples
+    }
+}
+
+fn sample(
+    latest: &Snapshot,
+    next: &mut DateTime<Utc>,
+    interval: i64,
+    due: impl Fn(DateTime<Utc>) -> bool,
+    samples: &mut Vec<Snapshot>,
+) {
+    while due(*next) {
+        samples.push(Snapshot { at: *next, ..latest.clone() });
+        *next = *next + chrono::Duration::nanoseconds(interval);
+    }
+}
+
+/// Writes the samples of the snapshots until every sender is dropped.
+fn write(config: ExportConfig, depth: usize, live: bool, rx: Receiver<Snapshot>) {
+    let columns = columns(config.layout, depth);
+    let mut sampler = Sampler::new(config.interval_ms);
+    let mut sink: Option<Box<dyn Sink>> = None;
+    let mut flushed = Instant::now();
+
+    loop {
+        let samples = match rx.recv_timeout(FLUSH_EVERY) {
+            Ok(snapshot) => sampler.offer(snapshot),
+            Err(RecvTimeoutError::Timeout) if live => sampler.advance(Utc::now()),
+            Err(RecvTimeoutError::Timeout) => vec![],
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        for snapshot in samples {
+            let rows = rows(config.layout, depth, &snapshot);
+            let res = match &mut sink {
+                Some(sink) => sink.append(rows),
+                None => create(&config, &columns, snapshot.at).and_then(|mut created| {
+                    let res = created.append(rows);
+                    sink = Some(created);
+                    res
+                }),
+            };
+            if let Err(e) = res {
+                // the next sample starts a new file
+                error!(error = %e, "could not export");
+                sink = None;
+            }
+        }
+
+        if flushed.elapsed() >= FLUSH_EVERY {
+            if let Some(Err(e)) = sink.as_mut().map(|sink| sink.flush()) {
+                error!(error = %e, "could not flush export");
+            }
+            flushed = Instant::now();
+        }
+    }
+
+    if let Some(Err(e)) = sink.map(|sink| sink.finish()) {
+        error!(error = %e, "could not finish export");
+    }
+}
+
+fn create(config: &ExportConfig, columns: &[Column], at: DateTime<Utc>) -> io::Result<Box<dyn Sink>> {
+    let layout = match config.layout {
+        ExportLayout::Long => "long",
+        ExportLayout::Wide => "wide",
+    };
+    let extension = match config.format {
+        ExportFormat::Csv => "csv",
+        ExportFormat::Parquet => "parquet",
+    };
+    let name = format!("books_{}_{}.{}", layout, at.format("%Y%m%dT%H%M%S%.6fZ"), extension);
+    let path = config.dir.join(&name);
+    info!(file = %name, "exporting to");
+
+    Ok(match config.format {
+        ExportFormat::Csv => Box::new(CsvSink::create(&path, columns)?),
+        ExportFormat::Parquet => Box::new(ParquetSink::create(&path, columns)?),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Timestamp,
+    Text,
+    Int,
+    Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Column {
+    name: String,
+    kind: Kind,
+
+    /// Whether rows may leave it empty.
+    optional: bool,
+}
+
+impl Column {
+    fn new(name: &str, kind: Kind) -> Column {
+        Column { name: name.to_string(), kind, optional: false }
+    }
+
+    fn optional(name: String, kind: Kind) -> Column {
+        Column { name, kind, optional: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Cell {
+    Timestamp(DateTime<Utc>),
+    Text(String),
+    Int(i32),
+    Decimal(Decimal),
+}
+
+type Row = Vec<Option<Cell>>;
+
+fn columns(layout: ExportLayout, depth: usize) -> Vec<Column> {
+    match layout {
+        ExportLayout::Long => vec![
+            Column::new("timestamp", Kind::Timestamp),
+            Column::new("symbol", Kind::Text),
+            Column::new("book", Kind::Text),
+            Column::new("side", Kind::Text),
+            Column::new("level", Kind::Int),
+            Column::new("price", Kind::Decimal),
+            Column::new("amount", Kind::Decimal),
+            Column::new("exchange", Kind::Text),
+        ],
+        ExportLayout::Wide => {
+            let mut columns = vec![
+                Column::new("timestamp", Kind::Timestamp),
+                Column::new("symbol", Kind::Text),
+                Column::new("book", Kind::Text),
+                Column::new("spread", Kind::Decimal),
+            ];
+            for side in ["bid", "ask"] {
+                for i in 0..depth {
+                    columns.push(Column::optional(format!("{}_{}_price", side, i), Kind::Decimal));
+                    columns.push(Column::optional(format!("{}_{}_amount", side, i), Kind::Decimal));
+                    columns.push(Column::optional(format!("{}_{}_exchange", side, i), Kind::Text));
+                }
+            }
+            columns
+        },
+    }
+}
+
+fn rows(layout: ExportLayout, depth: usize, snapshot: &Snapshot) -> Vec<Row> {
+    let mut rows = vec![];
+    for (exchange, tick) in &snapshot.books {
+        let book = exchange.map_or("merged".to_string(), |exchange| exchange.to_string());
+        let head = vec![
+            Some(Cell::Timestamp(snapshot.at)),
+            Some(Cell::Text(snapshot.symbol.clone())),
+            Some(Cell::Text(book)),
+        ];
+
+        match layout {
+            ExportLayout::Long => {
+                for (side, levels) in [("bid", &tick.bids), ("ask", &tick.asks)] {
+                    for (i, level) in levels.iter().enumerate() {
+                        let mut row = head.clone();
+                        row.push(Some(Cell::Text(side.to_string())));
+                        row.push(Some(Cell::Int(i as i32)));
+                        row.extend(level_cells(Some(level)));
+                        rows.push(row);
+                    }
+                }
+            },
+            ExportLayout::Wide => {
+                let mut row = head;
+                row.push(Some(Cell::Decimal(tick.spread)));
+                for levels in [&tick.bids, &tick.asks] {
+                    for i in 0..depth {
+                        row.extend(level_cells(levels.get(i)));
+                    }
+                }
+                rows.push(row);
+            },
+        }
+    }
+    rows
+}
+
+/// Price, amount and exchange of a level, empty when there is none.
+fn level_cells(level: Option<&Level>) -> [Option<Cell>; 3] {
+    match level {
+        Some(level) => [
+            Some(Cell::Decimal(level.price)),
+            Some(Cell::Decimal(level.amount)),
+            Some(Cell::Text(level.exchange.to_string())),
+        ],
+        None => [None, None, None],
+    }
+}
+
+trait Sink: Send {
+    fn append(&mut self, rows: Vec<Row>) -> io::Result<()>;
+
+    /// Writes what can be written so far.
+    fn flush(&mut self) -> io::Result<()>;
+
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+struct CsvSink {
+    writer: BufWriter<File>,
+}
+
+impl CsvSink {
+    fn create(path: &Path, columns: &[Column]) -> io::Result<CsvSink> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let header: Vec<&str> = columns.iter().map(|column| column.name.as_str()).collect();
+        writeln!(writer, "{}", header.join(","))?;
+        Ok(CsvSink { writer })
+    }
+}
+
+impl Sink for CsvSink {
+    fn append(&mut self, rows: Vec<Row>) -> io::Result<()> {
+        for row in rows {
+            // none of the values contains a comma, quote or line break
+            let cells: Vec<String> = row.into_iter()
+                .map(|cell| match cell {
+                    Some(Cell::Timestamp(at)) => at.to_rfc3339_opts(SecondsFormat::Micros, true),
+                    Some(Cell::Text(text)) => text,
+                    Some(Cell::Int(i)) => i.to_string(),
+                    Some(Cell::Decimal(d)) => d.normalize().to_string(),
+                    None => String::new(),
+                })
+                .collect();
+            writeln!(self.writer, "{}", cells.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+struct ParquetSink {
+    writer: SerializedFileWriter<File>,
+    columns: Vec<Column>,
+    rows: Vec<Row>,
+}
+
+impl ParquetSink {
+    fn create(path: &Path, columns: &[Column]) -> io::Result<ParquetSink> {
+        let fields: Vec<String> = columns.iter()
+            .map(|column| {
+                let repetition = if column.optional { "optional" } else { "required" };
+                let kind = match column.kind {
+                    Kind::Timestamp => "int64",
+                    Kind::Text => "binary",
+                    Kind::Int => "int32",
+                    Kind::Decimal => "double",
+                };
+                let annotation = match column.kind {
+                    Kind::Timestamp => " (TIMESTAMP(MICROS,true))",
+                    Kind::Text => " (UTF8)",
+                    Kind::Int | Kind::Decimal => "",
+                };
+                format!("{} {} {}{};", repetition, kind, column.name, annotation)
+            })
+            .collect();
+        let schema = parse_message_type(&format!("message books {{ {} }}", fields.join(" ")))?;
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+
+        let writer = SerializedFileWriter::new(File::create(path)?, Arc::new(schema), Arc::new(properties))?;
+        Ok(ParquetSink { writer, columns: columns.to_vec(), rows: vec![] })
+    }
+
+    fn write_row_group(&mut self) -> io::Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let rows = std::mem::take(&mut self.rows);
+
+        let mut row_group = self.writer.next_row_group()?;
+        for (i, column) in self.columns.iter().enumerate() {
+            let mut writer = match row_group.next_column()? {
+                Some(writer) => writer,
+                None => break,
+            };
+            let cells = rows.iter().map(|row| row[i].as_ref());
+            let levels: Vec<i16> = cells.clone().map(|cell| cell.is_some() as i16).collect();
+            let levels = if column.optional { Some(levels.as_slice()) } else { None };
+
+            match column.kind {
+                Kind::Timestamp => {
+                    let values: Vec<i64> = cells.filter_map(|cell| match cell {
+                        Some(Cell::Timestamp(at)) => Some(at.timestamp_nanos() / 1000),
+                        _ => None,
+                    }).collect();
+                    writer.typed::<Int64Type>().write_batch(&values, levels, None)?;
+                },
+                Kind::Text => {
+                    let values: Vec<ByteArray> = cells.filter_map(|cell| match cell {
+                        Some(Cell::Text(text)) => Some(ByteArray::from(text.as_str())),
+                        _ => None,
+                    }).collect();
+                    writer.typed::<ByteArrayType>().write_batch(&values, levels, None)?;
+                },
+                Kind::Int => {
+                    let values: Vec<i32> = cells.filter_map(|cell| match cell {
+                        Some(Cell::Int(i)) => Some(*i),
+                        _ => None,
+                    }).collect();
+                    writer.typed::<Int32Type>().write_batch(&values, levels, None)?;
+                },
+                Kind::Decimal => {
+                    let values: Vec<f64> = cells.filter_map(|cell| match cell {
+                        Some(Cell::Decimal(d)) => d.to_f64(),
+                        _ => None,
+                    }).collect();
+                    writer.typed::<DoubleType>().write_batch(&values, levels, None)?;
+                },
+            }
+            writer.close()?;
+        }
+        row_group.close()?;
+        Ok(())
+    }
+}
+
+impl Sink for ParquetSink {
+    fn append(&mut self, rows: Vec<Row>) -> io::Result<()> {
+        self.rows.extend(rows);
+        if self.rows.len() >= ROW_GROUP_ROWS {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // a row group is only written once it is full, as small ones make the file slow to read
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.write_row_group()?;
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::export::*;
+    use crate::orderbook::Side;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use rust_decimal_macros::dec;
+    use std::path::PathBuf;
+
+    fn snapshot(millis: i64, bid: Decimal) -> Snapshot {
+        let tick = OutTick {
+            spread: dec!(0.069001) - bid,
+            bids: vec![Level::new(Side::Bid, bid, dec!(1.5), Exchange::Kraken)],
+            asks: vec![
+                Level::new(Side::Ask, dec!(0.069001), dec!(2), Exchange::Binance),
+                Level::new(Side::Ask, dec!(0.069002), dec!(0.25), Exchange::Kraken),
+            ],
+        };
+        Snapshot {
+            at: Utc.timestamp_millis(1656000000000 + millis),
+            symbol: "ETH/BTC".to_string(),
+            books: vec![(None, tick)],
+        }
+    }
+
+    fn export(format: ExportFormat, layout: ExportLayout, snapshots: Vec<Snapshot>) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ExportConfig { dir: dir.path().to_path_buf(), format, layout, interval_ms: None, venues: false };
+        let (exporter, writer) = Exporter::spawn(config, 2, false).unwrap();
+        for snapshot in snapshots {
+            exporter.send(snapshot);
+        }
+        drop(exporter);
+        writer.join().unwrap();
+
+        let files: Vec<PathBuf> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1, "{:?}", files);
+        let file = files[0].clone();
+        (dir, file)
+    }
+
+    #[test]
+    fn should_sample_at_interval() {
+        /*
+         * Given
+         */
+        let mut sampler = Sampler::new(Some(1000));
+
+        /*
+         * When
+         */
+        let first = sampler.offer(snapshot(300, dec!(0.068)));
+        let at_sample = sampler.offer(snapshot(1000, dec!(0.0681)));
+        let later = sampler.offer(snapshot(2500, dec!(0.0682)));
+        let advanced = sampler.advance(Utc.timestamp_millis(1656000003000));
+
+        /*
+         * Then
+         */
+        assert_eq!(first, vec![]);
+        assert_eq!(at_sample, vec![]);
+        assert_eq!(later, vec![
+            Snapshot { at: Utc.timestamp_millis(1656000001000), ..snapshot(1000, dec!(0.0681)) },
+            Snapshot { at: Utc.timestamp_millis(1656000002000), ..snapshot(1000, dec!(0.0681)) },
+        ]);
+        assert_eq!(advanced, vec![Snapshot { at: Utc.timestamp_millis(1656000003000), ..snapshot(2500, dec!(0.0682)) }]);
+    }
+
+    #[test]
+    fn should_write_every_change() {
+        /*
+         * Given
+         */
+        let mut sampler = Sampler::new(None);
+
+        /*
+         * When
+         */
+        let samples: Vec<Snapshot> = [snapshot(0, dec!(0.068)), snapshot(10, dec!(0.068)), snapshot(20, dec!(0.0681))]
+            .into_iter()
+            .flat_map(|snapshot| sampler.offer(snapshot))
+            .collect();
+
+        /*
+         * Then
+         */
+        assert_eq!(samples, vec![snapshot(0, dec!(0.068)), snapshot(20, dec!(0.0681))]);
+        assert_eq!(sampler.advance(Utc.timestamp_millis(1656000060000)), vec![]);
+    }
+
+    #[test]
+    fn should_write_long_csv() {
+        /*
+         * Given
+         */
+        let mut snapshot = snapshot(0, dec!(0.0680));
+        let venue = snapshot.books[0].1.clone().retain_exchanges(&[Exchange::Kraken]);
+        snapshot.books.push((Some(Exchange::Kraken), venue));
+
+        /*
+         * When
+         */
+        let (_dir, file) = export(ExportFormat::Csv, ExportLayout::Long, vec![snapshot]);
+
+        /*
+         * Then
+         */
+        assert_eq!(file.file_name().unwrap(), "books_long_20220623T160000.000000Z.csv");
+        assert_eq!(std::fs::read_to_string(file).unwrap(), "\
+timestamp,symbol,book,side,level,price,amount,exchange
+2022-06-23T16:00:00.000000Z,ETH/BTC,merged,bid,0,0.068,1.5,kraken
+2022-06-23T16:00:00.000000Z,ETH/BTC,merged,ask,0,0.069001,2,binance
+2022-06-23T16:00:00.000000Z,ETH/BTC,merged,ask,1,0.069002,0.25,kraken
+2022-06-23T16:00:00.000000Z,ETH/BTC,kraken,bid,0,0.068,1.5,kraken
+2022-06-23T16:00:00.000000Z,ETH/BTC,kraken,ask,0,0.069002,0.25,kraken
+");
+    }
+
+    #[test]
+    fn should_write_wide_csv() {
+        /*
+         * When
+         */
+        let (_dir, file) = export(ExportFormat::Csv, ExportLayout::Wide, vec![snapshot(0, dec!(0.068))]);
+
+        /*
+         * Then
+         */
+        assert_eq!(std::fs::read_to_string(file).unwrap(), "\
+timestamp,symbol,book,spread,\
+bid_0_price,bid_0_amount,bid_0_exchange,bid_1_price,bid_1_amount,bid_1_exchange,\
+ask_0_price,ask_0_amount,ask_0_exchange,ask_1_price,ask_1_amount,ask_1_exchange
+2022-06-23T16:00:00.000000Z,ETH/BTC,merged,0.001001,\
+0.068,1.5,kraken,,,,\
+0.069001,2,binance,0.069002,0.25,kraken
+");
+    }
+
+    #[test]
+    fn should_write_parquet() {
+        /*
+         * When
+         */
+        let (_dir, long) = export(ExportFormat::Parquet, ExportLayout::Long, vec![snapshot(0, dec!(0.068)), snapshot(1, dec!(0.0681))]);
+        let (_dir, wide) = export(ExportFormat::Parquet, ExportLayout::Wide, vec![snapshot(0, dec!(0.068))]);
+
+        /*
+         * Then
+         */
+        let long = SerializedFileReader::new(File::open(long).unwrap()).unwrap();
+        let rows: Vec<String> = long.get_row_iter(None).unwrap().map(|row| row.to_string()).collect();
+        assert_eq!(rows.len(), 6);
+        assert_eq!(rows[3], "{timestamp: 2022-06-23 16:00:00 +00:00, symbol: \"ETH/BTC\", book: \"merged\", \
+side: \"bid\", level: 0, price: 0.0681, amount: 1.5, exchange: \"kraken\"}");
+
+        let wide = SerializedFileReader::new(File::open(wide).unwrap()).unwrap();
+        assert_eq!(wide.metadata().file_metadata().schema_descr().num_columns(), 4 + 2 * 2 * 3);
+        let row = wide.get_row_iter(None).unwrap().next().unwrap().to_string();
+        assert!(row.contains("bid_0_price: 0.068, bid_0_amount: 1.5, bid_0_exchange: \"kraken\", bid_1_price: null"), "{}", row);
+    }
+}
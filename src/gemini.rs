@@ -0,0 +1,416 @@
+use crate::error::Error;
+use crate::orderbook::{Exchange, FullBook, InTick, ToLevel};
+use crate::venue::Parsed;
+use crate::record::Tape;
+use crate::trade::{self, TradeSide};
+use crate::websocket::{self, Ack};
+use crate::{metrics, orderbook};
+use chrono::{TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace};
+use tungstenite::protocol::Message;
+
+pub(crate) const GEMINI_WS_URL: &str = "wss://api.gemini.com/v2/marketdata";
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Event {
+    Market(Market),
+
+    /// Response. Sent for a request which failed, e.g. a subscription to an unknown symbol.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "result": "error",
+    ///   "reason": "InvalidSymbol",
+    ///   "message": "Invalid symbol: ETHXYZ"
+    /// }
+    /// ```
+    Error {
+        result: String,
+
+        reason: String,
+
+        message: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Market {
+    /// Publication: Changes to the book of a symbol. The first one after a subscription holds the
+    /// whole book, with the latest trades, and is the only one to list trades.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "type": "l2_updates",
+    ///   "symbol": "BTCUSD",
+    ///   "changes": [
+    ///     ["buy", "9122.04", "0.00121425"],
+    ///     ["sell", "9122.07", "0.98942292"]
+    ///   ],
+    ///   "trades": [
+    ///     {
+    ///       "type": "trade",
+    ///       "symbol": "BTCUSD",
+    ///       "event_id": 169841458,
+    ///       "timestamp": 1560976400428,
+    ///       "price": "9122.04",
+    ///       "quantity": "0.0073173",
+    ///       "side": "sell"
+    ///     }
+    ///   ],
+    ///   "auction_events": []
+    /// }
+    /// ```
+    L2Updates {
+        symbol: String,
+
+        changes: Vec<Change>,
+
+        trades: Option<Vec<Trade>>,
+    },
+
+    /// Publication: A trade on the symbol, sent on the `l2` subscription next to the changes to the book.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "type": "trade",
+    ///   "symbol": "BTCUSD",
+    ///   "event_id": 3575573053,
+    ///   "timestamp": 1560976400428,
+    ///   "price": "9004.21000000",
+    ///   "quantity": "0.09110000",
+    ///   "side": "buy"
+    /// }
+    /// ```
+    Trade(Trade),
+
+    Heartbeat {
+        timestamp: Option<i64>,
+    },
+}
+
+/// Remaining quantity at a price, 0 once the level is gone.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct Change {
+    side: Side,
+
+    price: Decimal,
+
+    quantity: Decimal,
+}
+
+impl ToLevel for Change {
+    /// Converts a `gemini::Change` into a `orderbook::Level`.
+    fn to_level(&self, side: orderbook::Side) -> orderbook::Level {
+        orderbook::Level::new(side, self.price, self.quantity, Exchange::Gemini)
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Trade {
+    symbol: String,
+
+    event_id: u64,
+
+    /// Milliseconds since epoch
+    timestamp: i64,
+
+    price: Decimal,
+
+    quantity: Decimal,
+
+    /// Side of the taker
+    side: Side,
+}
+
+impl Trade {
+    /// Converts a `gemini::Trade` into a `trade::Trade`. `None` if its timestamp is out of range.
+    fn to_trade(&self) -> Option<trade::Trade> {
+        let time = Utc.timestamp_millis_opt(self.timestamp).single()?;
+        let side = match self.side {
+            Side::Buy => TradeSide::Buy,
+            Side::Sell => TradeSide::Sell,
+        };
+        Some(trade::Trade {
+            exchange: Exchange::Gemini,
+            price: self.price,
+            amount: self.quantity,
+            side,
+            trade_id: Some(self.event_id.to_string()),
+            time,
+        })
+    }
+}
+
+/// Request. Subscribes to the books of symbols.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "type": "subscribe",
+///   "subscriptions": [
+///     {
+///       "name": "l2",
+///       "symbols": ["BTCUSD", "ETHBTC"]
+///     }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Serialize, PartialEq)]
+struct Request {
+    #[serde(rename = "type")]
+    kind: String,
+
+    subscriptions: Vec<Subscription>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct Subscription {
+    /// l2|candles_1m|...
+    name: String,
+
+    symbols: Vec<String>,
+}
+
+/// Book of a connection, kept in full as Gemini streams every level of it. Only its top is passed on.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Depths {
+    book: FullBook<Change>,
+}
+
+impl Depths {
+    fn apply(&mut self, changes: Vec<Change>) {
+        for change in changes {
+            let levels = match change.side {
+                Side::Buy => &mut self.book.bids,
+                Side::Sell => &mut self.book.asks,
+            };
+            match change.quantity.is_zero() {
+                true => levels.remove(&change.price),
+                false => levels.insert(change.price, change),
+            };
+        }
+    }
+
+    fn to_tick(&self) -> InTick {
+        self.book.to_tick(Exchange::Gemini, |side, _, change| change.to_level(side))
+    }
+}
+
+/// Returns the WebSocket once the subscription is confirmed, with the messages received until then.
+/// Gemini confirms nothing, the first book stands for the confirmation.
+pub(crate) async fn connect(url: &str, symbol: &str, tape: &Tape) -> Result<(websocket::WsStream, Vec<Message>), Error> {
+    let mut ws_stream = websocket::connect(Exchange::Gemini, symbol, url, tape).await?;
+    websocket::subscribe(&mut ws_stream, Exchange::Gemini, symbol, url, subscription(symbol)?, tape).await?;
+    let backlog = websocket::await_ack(&mut ws_stream, Exchange::Gemini, symbol, url, ack, tape).await?;
+    Ok((ws_stream, backlog))
+}
+
+fn ack(text: &str) -> Ack {
+    match deserialize(text) {
+        Ok(Event::Market(Market::L2Updates { .. })) => Ack::Subscribed,
+        Ok(Event::Error { reason, message, .. }) => Ack::Rejected(describe(reason, message)),
+        _ => Ack::Pending,
+    }
+}
+
+fn describe(reason: String, message: Option<String>) -> String {
+    match message {
+        Some(message) => format!("{}: {}", reason, message),
+        None => reason,
+    }
+}
+
+fn subscription(symbol: &str) -> Result<String, Error> {
+    let sub = Request {
+        kind: "subscribe".to_string(),
+        subscriptions: vec![Subscription { name: "l2".to_string(), symbols: vec![market(symbol)] }],
+    };
+    let payload = format!("{:?}", sub);
+    serde_json::to_string(&sub).map_err(|e| Error::protocol(Exchange::Gemini, symbol, e, &payload))
+}
+
+/// Gemini names markets without a separator, e.g. `ETHBTC`.
+fn market(symbol: &str) -> String {
+    symbol.to_uppercase().replace('/', "")
+}
+
+/// Applies the changes to `depths`, which holds the book of the connection, and passes on its top.
+/// The first changes after a subscription, those listing trades, replace the book. The trades they
+/// list happened before the subscription, so only those sent on their own are passed on.
+pub(crate) fn parse(msg: Message, symbol: &str, depths: &mut Depths) -> Result<Parsed, Error> {
+    metrics::message_received(Exchange::Gemini, &msg);
+    let x = match msg {
+        Message::Text(x) => x,
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); return Ok(Parsed::Nothing) },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); return Ok(Parsed::Nothing) },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); return Ok(Parsed::Nothing) },
+        Message::Close(x) => { info!(frame = ?x, "close"); return Ok(Parsed::Nothing) },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); return Ok(Parsed::Nothing) },
+    };
+    trace!(%x, "text message");
+
+    let e = deserialize(&x).map_err(|e| metrics::parse_error(Exchange::Gemini, symbol, e, &x))?;
+    Ok(match e {
+        Event::Market(Market::L2Updates { changes, trades, .. }) => {
+            if trades.is_some() {
+                trace!(levels = changes.len(), "book");
+                *depths = Depths::default();
+            }
+            depths.apply(changes);
+            Parsed::Tick(depths.to_tick())
+        },
+        Event::Market(Market::Trade(trade)) => {
+            trace!(event_id = trade.event_id, price = %trade.price, quantity = %trade.quantity, "trade");
+            match trade.to_trade() {
+                Some(trade) => {
+                    metrics::message_age(Exchange::Gemini, trade.time);
+                    Parsed::Trades(vec![trade])
+                },
+                None => Parsed::Nothing,
+            }
+        },
+        Event::Market(Market::Heartbeat { .. }) => {
+            trace!("heartbeat");
+            Parsed::Nothing
+        },
+        Event::Error { reason, message, .. } => Parsed::Error(describe(reason, message)),
+    })
+}
+
+fn deserialize(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use crate::gemini::*;
+
+    fn change(side: Side, price: Decimal, quantity: Decimal) -> Change {
+        Change { side, price, quantity }
+    }
+
+    #[test]
+    fn should_deserialize_l2_updates() -> serde_json::Result<()> {
+        assert_eq!(deserialize(include_str!("../tests/fixtures/gemini/l2_snapshot.json"))?,
+                   Event::Market(Market::L2Updates {
+                       symbol: "ETHBTC".to_string(),
+                       changes: vec![
+                           change(Side::Buy, dec!(0.06900100), dec!(3.5)),
+                           change(Side::Buy, dec!(0.06899700), dec!(1.2)),
+                           change(Side::Sell, dec!(0.06900600), dec!(2.25)),
+                           change(Side::Sell, dec!(0.06901100), dec!(8.0)),
+                       ],
+                       trades: Some(vec![Trade {
+                           symbol: "ETHBTC".to_string(),
+                           event_id: 169841458,
+                           timestamp: 1656000000100,
+                           price: dec!(0.06900300),
+                           quantity: dec!(0.41),
+                           side: Side::Sell,
+                       }]),
+                   })
+        );
+        assert!(matches!(deserialize(include_str!("../tests/fixtures/gemini/l2_update.json"))?,
+                         Event::Market(Market::L2Updates { trades: None, .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn should_deserialize_trade_and_error() -> serde_json::Result<()> {
+        assert!(matches!(deserialize(include_str!("../tests/fixtures/gemini/trade.json"))?,
+                         Event::Market(Market::Trade(Trade { event_id: 169841460, side: Side::Buy, .. }))));
+        assert_eq!(deserialize(include_str!("../tests/fixtures/gemini/error.json"))?,
+                   Event::Error {
+                       result: "error".to_string(),
+                       reason: "InvalidSymbol".to_string(),
+                       message: Some("Invalid symbol: ETHXYZ".to_string()),
+                   });
+        Ok(())
+    }
+
+    #[test]
+    fn should_serialize_subscription() {
+        assert_eq!(subscription("eth/btc").unwrap(),
+                   r#"{"type":"subscribe","subscriptions":[{"name":"l2","symbols":["ETHBTC"]}]}"#);
+    }
+
+    #[test]
+    fn should_ack_first_book() {
+        assert_eq!(ack(include_str!("../tests/fixtures/gemini/l2_snapshot.json")), Ack::Subscribed);
+        assert_eq!(ack(include_str!("../tests/fixtures/gemini/error.json")),
+                   Ack::Rejected("InvalidSymbol: Invalid symbol: ETHXYZ".to_string()));
+        assert_eq!(ack(include_str!("../tests/fixtures/gemini/trade.json")), Ack::Pending);
+    }
+
+    #[test]
+    fn should_apply_changes() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+
+        /*
+         * When
+         */
+        parse(Message::Text(include_str!("../tests/fixtures/gemini/l2_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+        let update = parse(Message::Text(include_str!("../tests/fixtures/gemini/l2_update.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+        let trade = parse(Message::Text(include_str!("../tests/fixtures/gemini/trade.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(update, Parsed::Tick(InTick {
+            exchange: Exchange::Gemini,
+            bids: vec![
+                orderbook::Level::new(orderbook::Side::Bid, dec!(0.06899700), dec!(1.2), Exchange::Gemini),
+            ],
+            asks: vec![
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.06900500), dec!(1.75), Exchange::Gemini),
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.06900600), dec!(2.25), Exchange::Gemini),
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.06901100), dec!(8.0), Exchange::Gemini),
+            ],
+        }));
+        assert_eq!(trade, Parsed::Trades(vec![trade::Trade {
+            exchange: Exchange::Gemini,
+            price: dec!(0.06900600),
+            amount: dec!(0.5),
+            side: TradeSide::Buy,
+            trade_id: Some("169841460".to_string()),
+            time: Utc.timestamp_millis_opt(1656000000250).unwrap(),
+        }]));
+    }
+
+    #[test]
+    fn should_replace_book_on_resubscription() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        depths.apply(vec![change(Side::Buy, dec!(0.07), dec!(1))]);
+
+        /*
+         * When
+         */
+        let book = parse(Message::Text(include_str!("../tests/fixtures/gemini/l2_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert!(matches!(book, Parsed::Tick(InTick { ref bids, .. }) if bids.len() == 2 && bids[0].price == dec!(0.06900100)));
+    }
+}
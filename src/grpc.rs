@@ -1,82 +1,266 @@
+// tonic::Status is what services have to return, however large
+#![allow(clippy::result_large_err)]
+
+use crate::auth::{Auth, Client};
+use crate::candle::{self, SeriesKey, Source};
+use crate::config::TlsConfig;
+use crate::console::{self, Command};
 use crate::error::Error;
-use crate::orderbook::{self, OutTick};
-use crate::orderly::OutTickPair;
+use crate::orderbook::{self, Exchange, OutTick};
+use crate::metrics;
+use crate::orderly::{Candles, OutTicks, Published, Trades};
+use crate::trade::{self, TradeSide};
+use crate::venue::{self, TradingStatus, VenueState};
 use futures::Stream;
-use log::info;
 use rust_decimal::prelude::ToPrimitive;
+use std::collections::HashMap;
+use std::path::Path;
 use std::pin::Pin;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use tonic::{transport::Server, Request, Response, Status};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, watch};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic_health::proto::health_server::{Health, HealthServer};
+use tonic::{Request, Response, Status};
+use tracing::{debug, info, warn};
 
 pub mod proto {
     tonic::include_proto!("orderbook");
+
+    pub(crate) const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("orderbook_descriptor");
+}
+
+/// Name of the `OrderbookAggregator` service as reported by the health service.
+pub(crate) const ORDERBOOK_SERVICE: &str = "orderbook.OrderbookAggregator";
+
+/// Name of the `Admin` service as reported by the health service.
+pub(crate) const ADMIN_SERVICE: &str = "orderbook.Admin";
+
+/// Serves the orderbook and admin services behind `auth`, and the standard health and reflection
+/// services to anyone, as load balancers and `grpcurl` carry no token.
+pub(crate) async fn serve(
+    listener: std::net::TcpListener,
+    tls: Option<TlsConfig>,
+    auth: Auth,
+    service: OrderBookService,
+    admin: AdminService,
+    health: HealthServer<impl Health>,
+) -> Result<(), Error>
+{
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET)
+        .build()
+        .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+
+    let mut builder = Server::builder();
+    if let Some(tls) = tls {
+        builder = builder.tls_config(server_tls_config(&tls)?)?;
+    }
+
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    info!(addr = %listener.local_addr()?, "serving grpc");
+    let incoming = async_stream::stream! {
+        loop {
+            yield listener.accept().await.map(|(stream, _)| stream);
+        }
+    };
+
+    builder
+        .add_service(proto::orderbook_aggregator_server::OrderbookAggregatorServer::with_interceptor(service, auth.clone()))
+        .add_service(proto::admin_server::AdminServer::with_interceptor(admin, auth))
+        .add_service(health)
+        .add_service(reflection)
+        .serve_with_incoming(incoming)
+        .await?;
+
+    Ok(())
+}
+
+/// Loads the server identity and, for mutual TLS, the CA which client certificates must be signed by.
+fn server_tls_config(tls: &TlsConfig) -> Result<ServerTlsConfig, Error> {
+    let cert = read_pem(&tls.cert)?;
+    let key = read_pem(&tls.key)?;
+    let config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+
+    match &tls.client_ca {
+        Some(ca) => Ok(config.client_ca_root(Certificate::from_pem(read_pem(ca)?))),
+        None => Ok(config),
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, Error> {
+    std::fs::read(path).map_err(|e| Error::BadConfig(format!("{}: {}", path.display(), e)))
 }
 
 pub struct OrderBookService {
-    out_ticks: Arc<RwLock<OutTickPair>>,
+    out_ticks: OutTicks,
+    trades: Trades,
+
+    /// `None` unless the server builds candles.
+    candles: Option<Candles>,
+
+    /// State of every venue, sorted by exchange and symbol.
+    venue_states: watch::Receiver<Vec<VenueState>>,
+
+    /// Symbol streamed to requests which do not name one.
+    default_symbol: String,
+
+    auth: Auth,
 }
 
 impl OrderBookService {
-    pub(crate) fn new(out_ticks: Arc<RwLock<OutTickPair>>) -> Self {
-        OrderBookService { out_ticks }
+    pub(crate) fn new(
+        out_ticks: OutTicks,
+        trades: Trades,
+        candles: Option<Candles>,
+        venue_states: watch::Receiver<Vec<VenueState>>,
+        default_symbol: String,
+        auth: Auth,
+    ) -> Self
+    {
+        OrderBookService { out_ticks, trades, candles, venue_states, default_symbol, auth }
     }
 
-    pub(crate) async fn serve(self, port: usize) -> Result<(), Error>{
-        let addr = format!("[::1]:{}", port);
-        let addr = addr.parse()?;
+    /// Resolves the requested symbol, defaulting to `default_symbol`, and checks the client may read it.
+    fn symbol(&self, symbol: &str, client: &Client) -> Result<String, Status> {
+        let symbol = match symbol {
+            "" => self.default_symbol.clone(),
+            s => orderbook::parse_symbol(s).map_err(Status::invalid_argument)?,
+        };
+        client.check_symbol(&symbol)?;
+        Ok(symbol)
+    }
 
-        info!("Serving grpc at {}", addr);
+    /// Resolves the requested symbol and subscribes to its orderbook.
+    async fn rx_out_ticks(&self, symbol: &str, client: &Client) -> Result<(String, watch::Receiver<Published>), Status> {
+        let symbol = self.symbol(symbol, client)?;
 
-        Server::builder()
-            .add_service(proto::orderbook_aggregator_server::OrderbookAggregatorServer::new(self))
-            .serve(addr)
-            .await?;
+        let reader = self.out_ticks.read().await;
+        let rx = reader.get(&symbol)
+            .map(|(_, rx)| rx.clone())
+            .ok_or_else(|| Status::not_found(format!("{} is not subscribed", symbol)))?;
+        Ok((symbol, rx))
+    }
+}
 
-        Ok(())
+impl From<trade::Trade> for proto::Trade {
+    fn from(trade: trade::Trade) -> Self {
+        let side = match trade.side {
+            TradeSide::Buy => proto::TradeSide::Buy,
+            TradeSide::Sell => proto::TradeSide::Sell,
+        };
+        proto::Trade {
+            exchange: trade.exchange.to_string(),
+            price: trade.price.to_f64().unwrap_or_default(),
+            amount: trade.amount.to_f64().unwrap_or_default(),
+            side: side as i32,
+            trade_id: trade.trade_id.unwrap_or_default(),
+            time: trade.time.timestamp_millis(),
+        }
     }
+}
 
-    async fn out_tick(&self) -> OutTick {
-        let reader = self.out_ticks.read().await;
-        let out_tick = reader.1.borrow().clone();
-        out_tick
+impl From<proto::CandleSource> for Source {
+    fn from(source: proto::CandleSource) -> Self {
+        match source {
+            proto::CandleSource::Trades => Source::Trades,
+            proto::CandleSource::Mid => Source::Mid,
+        }
+    }
+}
+
+impl From<(SeriesKey, candle::Candle)> for proto::Candle {
+    fn from((key, candle): (SeriesKey, candle::Candle)) -> Self {
+        let source = match key.source {
+            Source::Trades => proto::CandleSource::Trades,
+            Source::Mid => proto::CandleSource::Mid,
+        };
+        proto::Candle {
+            exchange: key.exchange.map(|exchange| exchange.to_string()).unwrap_or_default(),
+            interval_secs: key.interval_secs,
+            source: source as i32,
+            start: candle.start.timestamp_millis(),
+            open: candle.open.to_f64().unwrap_or_default(),
+            high: candle.high.to_f64().unwrap_or_default(),
+            low: candle.low.to_f64().unwrap_or_default(),
+            close: candle.close.to_f64().unwrap_or_default(),
+            volume: candle.volume.to_f64().unwrap_or_default(),
+            count: candle.count,
+            closed: candle.closed,
+        }
     }
 }
 
 impl From<OutTick> for proto::Summary {
     fn from(out_tick: OutTick) -> Self {
-        let spread = out_tick.spread.to_f64().unwrap();
+        let spread = out_tick.spread.to_f64().unwrap_or_default();
         let bids: Vec<proto::Level> = to_levels(&out_tick.bids);
         let asks: Vec<proto::Level> = to_levels(&out_tick.asks);
 
-        proto::Summary{ spread, bids, asks }
+        proto::Summary{ spread, bids, asks, venues: HashMap::new() }
+    }
+}
+
+impl From<OutTick> for proto::BestBidOffer {
+    fn from(out_tick: OutTick) -> Self {
+        proto::BestBidOffer {
+            bid: out_tick.bids.first().map(to_level),
+            ask: out_tick.asks.first().map(to_level),
+            spread: out_tick.spread.to_f64().unwrap_or_default(),
+        }
     }
 }
 
-fn to_levels(levels: &Vec<orderbook::Level>) -> Vec<proto::Level> {
-    levels.iter()
-        .map(|l|
-            proto::Level{
-                exchange: l.exchange.to_string(),
-                price: l.price.to_f64().unwrap(),
-                amount: l.amount.to_f64().unwrap(),
-            })
-        .collect()
+/// Summary of the published orderbook with only the exchanges the client may see.
+fn summary(client: &Client, published: Published) -> proto::Summary {
+    let venues = published.venues.into_iter()
+        .filter(|(exchange, _)| client.sees(exchange))
+        .map(|(exchange, health)| (exchange.to_string(), proto::Health::from(health) as i32))
+        .collect();
+
+    proto::Summary { venues, ..proto::Summary::from(client.filter(published.out_tick)) }
+}
+
+/// Statuses of the venues of the symbol with only the exchanges the client may see.
+fn venue_statuses(client: &Client, symbol: &str, states: &[VenueState]) -> proto::VenueStatuses {
+    let venues = states.iter()
+        .filter(|state| state.symbol == symbol && client.sees(&state.exchange))
+        .cloned()
+        .map(proto::VenueStatus::from)
+        .collect();
+
+    proto::VenueStatuses { venues }
+}
+
+fn to_levels(levels: &[orderbook::Level]) -> Vec<proto::Level> {
+    levels.iter().map(to_level).collect()
+}
+
+fn to_level(l: &orderbook::Level) -> proto::Level {
+    proto::Level{
+        exchange: l.exchange.to_string(),
+        price: l.price.to_f64().unwrap_or_default(),
+        amount: l.amount.to_f64().unwrap_or_default(),
+    }
 }
 
 #[tonic::async_trait]
 impl proto::orderbook_aggregator_server::OrderbookAggregator for OrderBookService {
     async fn check(
         &self,
-        request: Request<proto::Empty>,
+        request: Request<proto::SummaryRequest>,
     ) -> Result<Response<proto::Summary>, Status> {
-        info!("Got a request: {:?}", request);
+        // metadata is left out, as it carries the bearer token
+        info!(request = ?request.get_ref(), "Check");
 
-        let _req = request.into_inner();
+        let client = Client::of(&request)?;
+        let req = request.into_inner();
 
-        let out_tick = self.out_tick().await;
+        let (_, rx_out_ticks) = self.rx_out_ticks(&req.symbol, &client).await?;
+        let published = rx_out_ticks.borrow().clone();
 
-        let reply = proto::Summary::from(out_tick);
+        let reply = summary(&client, published);
 
         Ok(Response::new(reply))
     }
@@ -86,34 +270,376 @@ impl proto::orderbook_aggregator_server::OrderbookAggregator for OrderBookServic
 
     async fn book_summary(
         &self,
-        request: Request<proto::Empty>,
+        request: Request<proto::SummaryRequest>,
     ) -> Result<Response<Self::BookSummaryStream>, Status> {
-        info!("Got a request: {:?}", request);
+        info!(request = ?request.get_ref(), "BookSummary");
 
-        let _req = request.into_inner();
+        let client = Client::of(&request)?;
+        let req = request.into_inner();
 
-        let mut rx_out_ticks = self.out_ticks.read().await.1.clone();
+        let (symbol, mut rx_out_ticks) = self.rx_out_ticks(&req.symbol, &client).await?;
+        let stream_guard = self.auth.open_stream(&client)?;
 
         let output = async_stream::try_stream! {
+            // counts against the client's limit until the stream is dropped
+            let _stream_guard = stream_guard;
+            let _subscriber_guard = metrics::SubscriberGuard::new(&symbol, "book_summary");
+
             // yield the current value
-            let out_tick = rx_out_ticks.borrow().clone();
-            yield proto::Summary::from(out_tick);
+            let published = rx_out_ticks.borrow().clone();
+            yield summary(&client, published);
 
+            // ends once the symbol is removed
             while let Ok(_) = rx_out_ticks.changed().await {
-                let out_tick = rx_out_ticks.borrow().clone();
-                yield proto::Summary::from(out_tick);
+                let published = rx_out_ticks.borrow().clone();
+                metrics::subscriber_lag(client.name(), &symbol, published.at.elapsed());
+                // the venue and seq of the cause match the fields of its merge span
+                debug!(
+                    client = client.name(), %symbol,
+                    venue = ?published.cause.map(|id| id.venue), seq = ?published.cause.map(|id| id.seq),
+                    "sending summary");
+                yield summary(&client, published);
             }
         };
 
         Ok(Response::new(Box::pin(output) as Self::BookSummaryStream))
     }
+
+    type BboStream =
+        Pin<Box<dyn Stream<Item = Result<proto::BestBidOffer, Status>> + Send + 'static>>;
+
+    async fn bbo(
+        &self,
+        request: Request<proto::SummaryRequest>,
+    ) -> Result<Response<Self::BboStream>, Status> {
+        info!(request = ?request.get_ref(), "Bbo");
+
+        let client = Client::of(&request)?;
+        let req = request.into_inner();
+
+        let (symbol, mut rx_out_ticks) = self.rx_out_ticks(&req.symbol, &client).await?;
+        let stream_guard = self.auth.open_stream(&client)?;
+
+        let output = async_stream::try_stream! {
+            let _stream_guard = stream_guard;
+            let _subscriber_guard = metrics::SubscriberGuard::new(&symbol, "bbo");
+
+            let out_tick = rx_out_ticks.borrow().out_tick.clone();
+            let mut last = proto::BestBidOffer::from(client.filter(out_tick));
+            yield last.clone();
+
+            // ends once the symbol is removed. Changes below the top are skipped
+            while let Ok(_) = rx_out_ticks.changed().await {
+                let published = rx_out_ticks.borrow().clone();
+                let bbo = proto::BestBidOffer::from(client.filter(published.out_tick));
+                if bbo != last {
+                    metrics::subscriber_lag(client.name(), &symbol, published.at.elapsed());
+                    last = bbo.clone();
+                    yield bbo;
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::BboStream))
+    }
+
+    type VenueStatusStream =
+        Pin<Box<dyn Stream<Item = Result<proto::VenueStatuses, Status>> + Send + 'static>>;
+
+    async fn venue_status(
+        &self,
+        request: Request<proto::SummaryRequest>,
+    ) -> Result<Response<Self::VenueStatusStream>, Status> {
+        info!(request = ?request.get_ref(), "VenueStatus");
+
+        let client = Client::of(&request)?;
+        let req = request.into_inner();
+
+        let symbol = self.symbol(&req.symbol, &client)?;
+        let stream_guard = self.auth.open_stream(&client)?;
+        let mut rx_venue_states = self.venue_states.clone();
+
+        let output = async_stream::try_stream! {
+            let _stream_guard = stream_guard;
+            let _subscriber_guard = metrics::SubscriberGuard::new(&symbol, "venue_status");
+
+            let statuses = venue_statuses(&client, &symbol, &rx_venue_states.borrow());
+            yield statuses;
+
+            // ends once the server shuts down
+            while let Ok(_) = rx_venue_states.changed().await {
+                let statuses = venue_statuses(&client, &symbol, &rx_venue_states.borrow());
+                yield statuses;
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::VenueStatusStream))
+    }
+
+    type TradesStream =
+        Pin<Box<dyn Stream<Item = Result<proto::Trade, Status>> + Send + 'static>>;
+
+    async fn trades(
+        &self,
+        request: Request<proto::TradesRequest>,
+    ) -> Result<Response<Self::TradesStream>, Status> {
+        info!(request = ?request.get_ref(), "Trades");
+
+        let client = Client::of(&request)?;
+        let req = request.into_inner();
+
+        let symbol = self.symbol(&req.symbol, &client)?;
+        let exchanges: Vec<Exchange> = req.exchanges.iter()
+            .map(|exchange| exchange.parse().map_err(Status::invalid_argument))
+            .collect::<Result<_, _>>()?;
+        for exchange in &exchanges {
+            client.check_exchange(Some(exchange))?;
+        }
+        let mut rx_trades = self.trades.read().await.get(&symbol)
+            .map(|tx| tx.subscribe())
+            .ok_or_else(|| Status::not_found(format!("{} is not subscribed", symbol)))?;
+        let stream_guard = self.auth.open_stream(&client)?;
+
+        let output = async_stream::try_stream! {
+            let _stream_guard = stream_guard;
+            let _subscriber_guard = metrics::SubscriberGuard::new(&symbol, "trades");
+
+            // ends once the symbol is removed
+            loop {
+                let trade = match rx_trades.recv().await {
+                    Ok(trade) => trade,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(client = client.name(), %symbol, skipped, "trades stream behind, skipped");
+                        continue;
+                    },
+                    Err(RecvError::Closed) => break,
+                };
+                if (exchanges.is_empty() || exchanges.contains(&trade.exchange)) && client.sees(&trade.exchange) {
+                    yield proto::Trade::from(trade);
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::TradesStream))
+    }
+
+    type CandlesStream =
+        Pin<Box<dyn Stream<Item = Result<proto::Candle, Status>> + Send + 'static>>;
+
+    async fn candles(
+        &self,
+        request: Request<proto::CandlesRequest>,
+    ) -> Result<Response<Self::CandlesStream>, Status> {
+        info!(request = ?request.get_ref(), "Candles");
+
+        let client = Client::of(&request)?;
+        let req = request.into_inner();
+
+        let candles = self.candles.as_ref()
+            .ok_or_else(|| Status::failed_precondition("candles are not built, see [candles] in the configuration"))?;
+        let symbol = self.symbol(&req.symbol, &client)?;
+        let exchange: Option<Exchange> = match req.exchange.as_str() {
+            "" => None,
+            exchange => Some(exchange.parse().map_err(Status::invalid_argument)?),
+        };
+        client.check_exchange(exchange.as_ref())?;
+        let source = proto::CandleSource::from_i32(req.source)
+            .ok_or_else(|| Status::invalid_argument(format!("unknown source: {}", req.source)))?;
+        let key = SeriesKey { source: source.into(), exchange, interval_secs: req.interval_secs };
+
+        // the backfill and the subscription are taken together, so that no change falls in between
+        let (backfill, mut rx_candles) = {
+            let reader = candles.read().await;
+            let engine = reader.get(&symbol)
+                .ok_or_else(|| Status::not_found(format!("{} is not subscribed", symbol)))?;
+            if !engine.intervals_secs().contains(&key.interval_secs) {
+                return Err(Status::invalid_argument(format!(
+                    "candles are built every {:?} seconds, got: {}", engine.intervals_secs(), key.interval_secs)));
+            }
+            (engine.backfill(&key, req.backfill as usize), engine.subscribe())
+        };
+        let stream_guard = self.auth.open_stream(&client)?;
+
+        let output = async_stream::try_stream! {
+            let _stream_guard = stream_guard;
+            let _subscriber_guard = metrics::SubscriberGuard::new(&symbol, "candles");
+
+            for candle in backfill {
+                yield proto::Candle::from((key, candle));
+            }
+
+            // ends once the symbol is removed
+            loop {
+                let (candle_key, candle) = match rx_candles.recv().await {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(client = client.name(), %symbol, skipped, "candles stream behind, skipped");
+                        continue;
+                    },
+                    Err(RecvError::Closed) => break,
+                };
+                if candle_key == key {
+                    yield proto::Candle::from((key, candle));
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::CandlesStream))
+    }
+}
+
+/// Adds and removes exchanges and symbols at runtime through the same commands as the console.
+pub struct AdminService {
+    tx_requests: mpsc::Sender<console::Request>,
+}
+
+impl AdminService {
+    pub(crate) fn new(tx_requests: mpsc::Sender<console::Request>) -> Self {
+        AdminService { tx_requests }
+    }
+
+    /// Executes the command, then replies with the subscriptions as they are afterwards.
+    async fn execute(&self, command: Command) -> Result<Response<proto::Subscriptions>, Status> {
+        match console::request(&self.tx_requests, command).await {
+            console::Response::Error { message } => Err(Status::failed_precondition(message)),
+            _ => self.subscriptions().await,
+        }
+    }
+
+    async fn subscriptions(&self) -> Result<Response<proto::Subscriptions>, Status> {
+        match console::request(&self.tx_requests, Command::Status).await {
+            console::Response::Status { exchanges, symbols, venues, .. } =>
+                Ok(Response::new(proto::Subscriptions {
+                    exchanges: exchanges.iter().map(Exchange::to_string).collect(),
+                    symbols,
+                    subscriptions: venues.into_iter().map(proto::Subscription::from).collect(),
+                })),
+            console::Response::Error { message } => Err(Status::unavailable(message)),
+            r => Err(Status::internal(format!("unexpected response: {:?}", r))),
+        }
+    }
+}
+
+impl From<venue::Health> for proto::Health {
+    fn from(health: venue::Health) -> Self {
+        match health {
+            venue::Health::Connecting => proto::Health::Connecting,
+            venue::Health::Live => proto::Health::Live,
+            venue::Health::Disconnected => proto::Health::Disconnected,
+        }
+    }
+}
+
+impl From<Option<TradingStatus>> for proto::TradingStatus {
+    fn from(status: Option<TradingStatus>) -> Self {
+        match status {
+            None => proto::TradingStatus::Unknown,
+            Some(TradingStatus::Online) => proto::TradingStatus::Online,
+            Some(TradingStatus::Maintenance) => proto::TradingStatus::Maintenance,
+            Some(TradingStatus::CancelOnly) => proto::TradingStatus::CancelOnly,
+            Some(TradingStatus::LimitOnly) => proto::TradingStatus::LimitOnly,
+            Some(TradingStatus::PostOnly) => proto::TradingStatus::PostOnly,
+            Some(TradingStatus::Offline) => proto::TradingStatus::Offline,
+        }
+    }
+}
+
+impl From<VenueState> for proto::VenueStatus {
+    fn from(state: VenueState) -> Self {
+        proto::VenueStatus {
+            exchange: state.exchange.to_string(),
+            symbol: state.symbol,
+            health: proto::Health::from(state.health) as i32,
+            last_message: state.last_message.map(|t| t.timestamp_millis()).unwrap_or_default(),
+            trading_status: proto::TradingStatus::from(state.trading_status) as i32,
+            trading_status_message: state.trading_status_message.unwrap_or_default(),
+            last_error: state.last_error.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<VenueState> for proto::Subscription {
+    fn from(state: VenueState) -> Self {
+        proto::Subscription {
+            exchange: state.exchange.to_string(),
+            symbol: state.symbol,
+            health: proto::Health::from(state.health) as i32,
+            ticks: state.ticks as u64,
+            last_tick: state.last_tick.map(|t| t.timestamp_millis()).unwrap_or_default(),
+            last_error: state.last_error.unwrap_or_default(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl proto::admin_server::Admin for AdminService {
+    async fn list_subscriptions(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<proto::Subscriptions>, Status> {
+        info!(request = ?request.get_ref(), "ListSubscriptions");
+        Client::of(&request)?.check_admin()?;
+
+        self.subscriptions().await
+    }
+
+    async fn add_exchange(
+        &self,
+        request: Request<proto::ExchangeRequest>,
+    ) -> Result<Response<proto::Subscriptions>, Status> {
+        info!(request = ?request.get_ref(), "AddExchange");
+        Client::of(&request)?.check_admin()?;
+
+        let exchange = request.into_inner().exchange.parse().map_err(Status::invalid_argument)?;
+        self.execute(Command::Enable(exchange)).await
+    }
+
+    async fn remove_exchange(
+        &self,
+        request: Request<proto::ExchangeRequest>,
+    ) -> Result<Response<proto::Subscriptions>, Status> {
+        info!(request = ?request.get_ref(), "RemoveExchange");
+        Client::of(&request)?.check_admin()?;
+
+        let exchange = request.into_inner().exchange.parse().map_err(Status::invalid_argument)?;
+        self.execute(Command::Disable(exchange)).await
+    }
+
+    async fn add_symbol(
+        &self,
+        request: Request<proto::SymbolRequest>,
+    ) -> Result<Response<proto::Subscriptions>, Status> {
+        info!(request = ?request.get_ref(), "AddSymbol");
+        Client::of(&request)?.check_admin()?;
+
+        let symbol = orderbook::parse_symbol(&request.into_inner().symbol).map_err(Status::invalid_argument)?;
+        self.execute(Command::AddSymbol(symbol)).await
+    }
+
+    async fn remove_symbol(
+        &self,
+        request: Request<proto::SymbolRequest>,
+    ) -> Result<Response<proto::Subscriptions>, Status> {
+        info!(request = ?request.get_ref(), "RemoveSymbol");
+        Client::of(&request)?.check_admin()?;
+
+        let symbol = orderbook::parse_symbol(&request.into_inner().symbol).map_err(Status::invalid_argument)?;
+        self.execute(Command::RemoveSymbol(symbol)).await
+    }
 }
 
 #[cfg(test)]
 mod test {
     use rust_decimal_macros::dec;
-    use crate::grpc::proto;
+    use crate::candle::{self, SeriesKey, Source};
+    use crate::grpc::{proto, AdminService, OrderBookService, ADMIN_SERVICE, ORDERBOOK_SERVICE};
+    use tonic::transport::NamedService;
+    use crate::config::InstrumentKind;
     use crate::orderbook::{Exchange, Level, OutTick, Side};
+    use crate::trade::{self, TradeSide};
+    use crate::venue::{Health, TradingStatus, VenueState};
+    use chrono::{TimeZone, Utc};
+    use std::collections::HashMap;
 
     #[test]
     fn should_convert_to_summary() {
@@ -123,28 +649,28 @@ mod test {
         let out_tick = OutTick {
             spread: dec!(0.00000010), 
             bids: vec![
-                Level { side: Side::Bid, price: dec!(0.00018688), amount: dec!(610014.67000000), exchange: Exchange::Binance },
-                Level { side: Side::Bid, price: dec!(0.00018687), amount: dec!(2205276.09000000), exchange: Exchange::Binance },
-                Level { side: Side::Bid, price: dec!(0.00018686), amount: dec!(4959229.21000000), exchange: Exchange::Binance },
-                Level { side: Side::Bid, price: dec!(0.00018685), amount: dec!(13520849.56000000), exchange: Exchange::Binance },
-                Level { side: Side::Bid, price: dec!(0.00018683), amount: dec!(2697439.72000000), exchange: Exchange::Binance },
-                Level { side: Side::Bid, price: dec!(0.00018682), amount: dec!(1575744.75000000), exchange: Exchange::Binance },
-                Level { side: Side::Bid, price: dec!(0.00018681), amount: dec!(6302978.66000000), exchange: Exchange::Binance },
-                Level { side: Side::Bid, price: dec!(0.00018680), amount: dec!(5954547.05000000), exchange: Exchange::Binance },
-                Level { side: Side::Bid, price: dec!(0.00018679), amount: dec!(10776354.35000000), exchange: Exchange::Binance },
-                Level { side: Side::Bid, price: dec!(0.00018678), amount: dec!(15388083.16000000), exchange: Exchange::Binance },
+                Level { side: Side::Bid, price: dec!(0.00018688), amount: dec!(610014.67000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018687), amount: dec!(2205276.09000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018686), amount: dec!(4959229.21000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018685), amount: dec!(13520849.56000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018683), amount: dec!(2697439.72000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018682), amount: dec!(1575744.75000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018681), amount: dec!(6302978.66000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018680), amount: dec!(5954547.05000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018679), amount: dec!(10776354.35000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018678), amount: dec!(15388083.16000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
             ],
             asks: vec![
-                Level { side: Side::Ask, price: dec!(0.00018698), amount: dec!(595429.87000000), exchange: Exchange::Binance },
-                Level { side: Side::Ask, price: dec!(0.00018699), amount: dec!(123707.71000000), exchange: Exchange::Binance },
-                Level { side: Side::Ask, price: dec!(0.00018700), amount: dec!(44033903.92000000), exchange: Exchange::Binance },
-                Level { side: Side::Ask, price: dec!(0.00018705), amount: dec!(4278646.87000000), exchange: Exchange::Binance },
-                Level { side: Side::Ask, price: dec!(0.00018706), amount: dec!(12777847.03000000), exchange: Exchange::Binance },
-                Level { side: Side::Ask, price: dec!(0.00018707), amount: dec!(11137472.05000000), exchange: Exchange::Binance },
-                Level { side: Side::Ask, price: dec!(0.00018708), amount: dec!(380833.80000000), exchange: Exchange::Binance },
-                Level { side: Side::Ask, price: dec!(0.00018710), amount: dec!(2938703.50000000), exchange: Exchange::Binance },
-                Level { side: Side::Ask, price: dec!(0.00018711), amount: dec!(73753.41000000), exchange: Exchange::Binance },
-                Level { side: Side::Ask, price: dec!(0.00018712), amount: dec!(566911.25000000), exchange: Exchange::Binance },
+                Level { side: Side::Ask, price: dec!(0.00018698), amount: dec!(595429.87000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Ask, price: dec!(0.00018699), amount: dec!(123707.71000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Ask, price: dec!(0.00018700), amount: dec!(44033903.92000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Ask, price: dec!(0.00018705), amount: dec!(4278646.87000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Ask, price: dec!(0.00018706), amount: dec!(12777847.03000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Ask, price: dec!(0.00018707), amount: dec!(11137472.05000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Ask, price: dec!(0.00018708), amount: dec!(380833.80000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Ask, price: dec!(0.00018710), amount: dec!(2938703.50000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Ask, price: dec!(0.00018711), amount: dec!(73753.41000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+                Level { side: Side::Ask, price: dec!(0.00018712), amount: dec!(566911.25000000), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
             ],
         };
         
@@ -158,6 +684,7 @@ mod test {
          */
         assert_eq!(summary, proto::Summary{
             spread: 0.0000001,
+            venues: HashMap::new(),
             bids: vec![
                 proto::Level { price: 0.00018688, amount: 610014.67, exchange: "binance".to_string() },
                 proto::Level { price: 0.00018687, amount: 2205276.09, exchange: "binance".to_string() },
@@ -184,4 +711,201 @@ mod test {
             ],
         });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn should_convert_to_best_bid_offer() {
+        /*
+         * Given
+         */
+        let out_tick = OutTick {
+            spread: dec!(0.00000010),
+            bids: vec![
+                Level { side: Side::Bid, price: dec!(0.00018688), amount: dec!(610014.67), exchange: Exchange::Kraken, kind: InstrumentKind::Spot },
+                Level { side: Side::Bid, price: dec!(0.00018687), amount: dec!(2205276.09), exchange: Exchange::Binance, kind: InstrumentKind::Spot },
+            ],
+            asks: vec![
+                Level { side: Side::Ask, price: dec!(0.00018698), amount: dec!(595429.87), exchange: Exchange::Coinbase, kind: InstrumentKind::Spot },
+            ],
+        };
+        let one_sided = OutTick { spread: dec!(0), asks: vec![], ..out_tick.clone() };
+
+        /*
+         * When
+         */
+        let (bbo, one_sided) = (proto::BestBidOffer::from(out_tick), proto::BestBidOffer::from(one_sided));
+
+        /*
+         * Then
+         */
+        assert_eq!(bbo, proto::BestBidOffer {
+            bid: Some(proto::Level { price: 0.00018688, amount: 610014.67, exchange: "kraken".to_string() }),
+            ask: Some(proto::Level { price: 0.00018698, amount: 595429.87, exchange: "coinbase".to_string() }),
+            spread: 0.0000001,
+        });
+        assert_eq!(one_sided.ask, None);
+        assert_eq!(one_sided.spread, 0.0);
+    }
+
+    #[test]
+    fn should_convert_to_trade() {
+        /*
+         * Given
+         */
+        let trade = trade::Trade {
+            exchange: Exchange::Coinbase,
+            price: dec!(0.06900500),
+            amount: dec!(0.25),
+            side: TradeSide::Sell,
+            trade_id: Some("39502561".to_string()),
+            time: Utc.timestamp_millis(1656000001123),
+        };
+        let unnumbered = trade::Trade { exchange: Exchange::Kraken, trade_id: None, ..trade.clone() };
+
+        /*
+         * When
+         */
+        let (trade, unnumbered) = (proto::Trade::from(trade), proto::Trade::from(unnumbered));
+
+        /*
+         * Then
+         */
+        assert_eq!(trade, proto::Trade {
+            exchange: "coinbase".to_string(),
+            price: 0.069005,
+            amount: 0.25,
+            side: proto::TradeSide::Sell as i32,
+            trade_id: "39502561".to_string(),
+            time: 1656000001123,
+        });
+        assert_eq!(unnumbered.trade_id, "");
+    }
+
+    #[test]
+    fn should_convert_to_candle() {
+        /*
+         * Given
+         */
+        let candle = candle::Candle {
+            start: Utc.timestamp_millis(1656000000000),
+            open: dec!(0.069005),
+            high: dec!(0.06901),
+            low: dec!(0.069),
+            close: dec!(0.069006),
+            volume: dec!(3.5),
+            count: 3,
+            closed: true,
+        };
+        let consolidated = SeriesKey { source: Source::Trades, exchange: None, interval_secs: 60 };
+        let mid = SeriesKey { source: Source::Mid, exchange: Some(Exchange::Kraken), interval_secs: 300 };
+
+        /*
+         * When
+         */
+        let (consolidated, mid) = (proto::Candle::from((consolidated, candle.clone())), proto::Candle::from((mid, candle)));
+
+        /*
+         * Then
+         */
+        assert_eq!(consolidated, proto::Candle {
+            exchange: "".to_string(),
+            interval_secs: 60,
+            source: proto::CandleSource::Trades as i32,
+            start: 1656000000000,
+            open: 0.069005,
+            high: 0.06901,
+            low: 0.069,
+            close: 0.069006,
+            volume: 3.5,
+            count: 3,
+            closed: true,
+        });
+        assert_eq!((mid.exchange.as_str(), mid.source, mid.interval_secs), ("kraken", proto::CandleSource::Mid as i32, 300));
+    }
+
+    #[test]
+    fn should_convert_to_subscription() {
+        /*
+         * Given
+         */
+        let live = VenueState {
+            exchange: Exchange::Kraken,
+            symbol: "ETH/BTC".to_string(),
+            health: Health::Live,
+            ticks: 42,
+            last_tick: Some(Utc.timestamp_millis(1656000000123)),
+            last_message: Some(Utc.timestamp_millis(1656000000456)),
+            trading_status: Some(TradingStatus::Online),
+            trading_status_message: None,
+            last_error: None,
+            connecting_since: None,
+        };
+        let mut disconnected = VenueState::new(Exchange::Coinbase, "ETH/BTC".to_string());
+        disconnected.health = Health::Disconnected;
+        disconnected.last_error = Some("bad connection: connection closed normally".to_string());
+
+        /*
+         * When
+         */
+        let live = proto::Subscription::from(live);
+        let disconnected = proto::Subscription::from(disconnected);
+
+        /*
+         * Then
+         */
+        assert_eq!(live, proto::Subscription {
+            exchange: "kraken".to_string(),
+            symbol: "ETH/BTC".to_string(),
+            health: proto::Health::Live as i32,
+            ticks: 42,
+            last_tick: 1656000000123,
+            last_error: "".to_string(),
+        });
+        assert_eq!(disconnected, proto::Subscription {
+            exchange: "coinbase".to_string(),
+            symbol: "ETH/BTC".to_string(),
+            health: proto::Health::Disconnected as i32,
+            ticks: 0,
+            last_tick: 0,
+            last_error: "bad connection: connection closed normally".to_string(),
+        });
+    }
+
+    #[test]
+    fn should_convert_to_venue_status() {
+        /*
+         * Given
+         */
+        let mut state = VenueState::new(Exchange::Kraken, "ETH/BTC".to_string());
+        state.health = Health::Live;
+        state.last_message = Some(Utc.timestamp_millis(1656000000456));
+        state.on_status(TradingStatus::CancelOnly, Some("wallet maintenance".to_string()));
+        let unknown = VenueState::new(Exchange::Binance, "ETH/BTC".to_string());
+
+        /*
+         * When
+         */
+        let status = proto::VenueStatus::from(state);
+        let unknown = proto::VenueStatus::from(unknown);
+
+        /*
+         * Then
+         */
+        assert_eq!(status, proto::VenueStatus {
+            exchange: "kraken".to_string(),
+            symbol: "ETH/BTC".to_string(),
+            health: proto::Health::Live as i32,
+            last_message: 1656000000456,
+            trading_status: proto::TradingStatus::CancelOnly as i32,
+            trading_status_message: "wallet maintenance".to_string(),
+            last_error: "".to_string(),
+        });
+        assert_eq!(unknown.trading_status, proto::TradingStatus::Unknown as i32);
+        assert_eq!(unknown.health, proto::Health::Connecting as i32);
+    }
+
+    #[test]
+    fn should_name_services_for_health() {
+        assert_eq!(ORDERBOOK_SERVICE, <proto::orderbook_aggregator_server::OrderbookAggregatorServer<OrderBookService> as NamedService>::NAME);
+        assert_eq!(ADMIN_SERVICE, <proto::admin_server::AdminServer<AdminService> as NamedService>::NAME);
+    }
+}
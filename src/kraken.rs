@@ -1,13 +1,18 @@
 use crate::error::Error;
 use crate::orderbook::{Exchange, InTick, ToLevel, ToLevels, ToTick};
-use crate::{orderbook, websocket};
-use futures::SinkExt;
-use log::{debug, info};
+use crate::venue::{Parsed, TradingStatus};
+use crate::record::Tape;
+use crate::trade::{self, TradeSide};
+use crate::websocket::{self, Ack};
+use crate::{metrics, orderbook};
+use chrono::{DateTime, TimeZone, Utc};
 use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
 use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace};
 use tungstenite::protocol::Message;
 
-const KRAKEN_WS_URL: &str = "wss://ws.kraken.com";
+pub(crate) const KRAKEN_WS_URL: &str = "wss://ws.kraken.com";
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(untagged)]
@@ -17,8 +22,38 @@ enum Event {
     PublicMessage(PublicMessage),
 }
 
+impl Event {
+    /// Latest timestamp of the levels of a book update. Snapshots are left out, as their levels keep
+    /// the time they were last updated at.
+    fn updated_at(&self) -> Option<DateTime<Utc>> {
+        let updates: Vec<&Option<Vec<Level>>> = match self {
+            Event::PublicMessage(PublicMessage::SinglePayload(SinglePayload {
+                payload: Payload::Book(Book::Update { bids, asks, .. }), ..
+            })) => vec![bids, asks],
+            Event::PublicMessage(PublicMessage::DoublePayload(DoublePayload {
+                payload1: Payload::Book(Book::Update { bids: b1, asks: a1, .. }),
+                payload2: Payload::Book(Book::Update { bids: b2, asks: a2, .. }),
+                ..
+            })) => vec![b1, a1, b2, a2],
+            _ => vec![],
+        };
+
+        updates.into_iter()
+            .flatten()
+            .flatten()
+            .map(|level| level.timestamp)
+            .max()
+            .and_then(to_time)
+    }
+}
+
+/// Converts seconds since epoch, with their fraction, into a time.
+fn to_time(secs: Decimal) -> Option<DateTime<Utc>> {
+    (secs * Decimal::from(1_000_000_000)).to_i64().map(|nanos| Utc.timestamp_nanos(nanos))
+}
+
 impl ToTick for Event {
-    /// Converts the `Event` into a `Option<InTick>`. Only keep the top ten levels of bids and asks.
+    /// Converts the `Event` into a `Option<InTick>`. Only keep the top `MAX_DEPTH` levels of bids and asks.
     fn maybe_to_tick(&self) -> Option<InTick> {
         match self {
             Event::PublicMessage(
@@ -27,8 +62,8 @@ impl ToTick for Event {
                         payload: Payload::Book(Book::Snapshot {bids, asks}),
                         ..
                     })) => {
-                let bids = bids.to_levels(orderbook::Side::Bid, 10);
-                let asks = asks.to_levels(orderbook::Side::Ask, 10);
+                let bids = bids.to_levels(orderbook::Side::Bid, orderbook::MAX_DEPTH);
+                let asks = asks.to_levels(orderbook::Side::Ask, orderbook::MAX_DEPTH);
                 Some(InTick { exchange: Exchange::Kraken, bids, asks })
             },
             Event::PublicMessage(
@@ -38,8 +73,8 @@ impl ToTick for Event {
                         ..
                     })) => {
                 let mut tick = InTick{ exchange: Exchange::Kraken, bids: vec![], asks: vec![] };
-                bids.as_ref().map(|bids| tick.bids = bids.to_levels(orderbook::Side::Bid, 10) );
-                asks.as_ref().map(|asks| tick.asks = asks.to_levels(orderbook::Side::Ask, 10) );
+                if let Some(bids) = bids { tick.bids = bids.to_levels(orderbook::Side::Bid, orderbook::MAX_DEPTH); }
+                if let Some(asks) = asks { tick.asks = asks.to_levels(orderbook::Side::Ask, orderbook::MAX_DEPTH); }
                 Some(tick)
             },
             Event::PublicMessage(
@@ -50,10 +85,10 @@ impl ToTick for Event {
                         ..
                     })) => {
                 let mut tick = InTick{ exchange: Exchange::Kraken, bids: vec![], asks: vec![] };
-                b1.as_ref().map(|bids| tick.bids = bids.to_levels(orderbook::Side::Bid, 10) );
-                b2.as_ref().map(|bids| tick.bids = bids.to_levels(orderbook::Side::Bid, 10) );
-                a1.as_ref().map(|asks| tick.asks = asks.to_levels(orderbook::Side::Ask, 10) );
-                a2.as_ref().map(|asks| tick.asks = asks.to_levels(orderbook::Side::Ask, 10) );
+                if let Some(bids) = b1 { tick.bids = bids.to_levels(orderbook::Side::Bid, orderbook::MAX_DEPTH); }
+                if let Some(bids) = b2 { tick.bids = bids.to_levels(orderbook::Side::Bid, orderbook::MAX_DEPTH); }
+                if let Some(asks) = a1 { tick.asks = asks.to_levels(orderbook::Side::Ask, orderbook::MAX_DEPTH); }
+                if let Some(asks) = a2 { tick.asks = asks.to_levels(orderbook::Side::Ask, orderbook::MAX_DEPTH); }
                 Some(tick)
             },
             _ => None,
@@ -284,6 +319,18 @@ enum Status {
     PostOnly,
 }
 
+impl From<&Status> for TradingStatus {
+    fn from(status: &Status) -> Self {
+        match status {
+            Status::Online => TradingStatus::Online,
+            Status::Maintenance => TradingStatus::Maintenance,
+            Status::CancelOnly => TradingStatus::CancelOnly,
+            Status::LimitOnly => TradingStatus::LimitOnly,
+            Status::PostOnly => TradingStatus::PostOnly,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 struct Subscription {
     /// Optional - depth associated with book subscription in number of levels each side, default 10. Valid Options are: 10, 25, 100, 500, 1000
@@ -387,6 +434,49 @@ struct DoublePayload {
 #[serde(untagged)]
 enum Payload {
     Book(Book),
+
+    /// Publication: Trades, grouped when they were matched together.
+    ///
+    /// **Example of payload**
+    ///
+    /// ```json
+    /// [
+    ///   0,
+    ///   [
+    ///     [
+    ///       "5541.20000",
+    ///       "0.15850568",
+    ///       "1534614057.321597",
+    ///       "s",
+    ///       "l",
+    ///       ""
+    ///     ]
+    ///   ],
+    ///   "trade",
+    ///   "XBT/USD"
+    /// ]
+    /// ```
+    Trade(Vec<Trade>),
+
+    /// Publication: Best bid and offer, whenever either changes.
+    ///
+    /// **Example of payload**
+    ///
+    /// ```json
+    /// [
+    ///   0,
+    ///   [
+    ///     "5698.40000",
+    ///     "5700.00000",
+    ///     "1542057299.545897",
+    ///     "1.01234567",
+    ///     "0.98765432"
+    ///   ],
+    ///   "spread",
+    ///   "XBT/USD"
+    /// ]
+    /// ```
+    Spread(Spread),
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -589,6 +679,80 @@ impl ToLevel for Level {
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct Trade {
+    price: Decimal,
+
+    volume: Decimal,
+
+    /// Time of the trade, seconds since epoch
+    time: Decimal,
+
+    /// Side of the taker
+    side: TradeSideCode,
+
+    /// m for market, l for limit
+    order_type: String,
+
+    /// Miscellaneous info
+    misc: String,
+}
+
+impl Trade {
+    /// Converts a `kraken::Trade` into a `trade::Trade`. `None` if its time is out of range.
+    fn to_trade(&self) -> Option<trade::Trade> {
+        Some(trade::Trade {
+            exchange: Exchange::Kraken,
+            price: self.price,
+            amount: self.volume,
+            side: match self.side {
+                TradeSideCode::Buy => TradeSide::Buy,
+                TradeSideCode::Sell => TradeSide::Sell,
+            },
+            trade_id: None,
+            time: to_time(self.time)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct Spread {
+    bid: Decimal,
+
+    ask: Decimal,
+
+    /// Time of the last change, seconds since epoch
+    timestamp: Decimal,
+
+    bid_volume: Decimal,
+
+    ask_volume: Decimal,
+}
+
+impl Spread {
+    /// Converts a `kraken::Spread` into an `InTick` of the best bid and offer. An empty side is left out.
+    fn to_tick(&self) -> InTick {
+        let bids = match self.bid_volume.is_zero() {
+            true => vec![],
+            false => vec![orderbook::Level::new(orderbook::Side::Bid, self.bid, self.bid_volume, Exchange::Kraken)],
+        };
+        let asks = match self.ask_volume.is_zero() {
+            true => vec![],
+            false => vec![orderbook::Level::new(orderbook::Side::Ask, self.ask, self.ask_volume, Exchange::Kraken)],
+        };
+        InTick { exchange: Exchange::Kraken, bids, asks }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+enum TradeSideCode {
+    #[serde(rename = "b")]
+    Buy,
+
+    #[serde(rename = "s")]
+    Sell,
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 enum SubscriptionType {
@@ -603,23 +767,54 @@ enum SubscriptionType {
     AllAvailable,
 }
 
-pub(crate) async fn connect(symbol: &String) -> Result<websocket::WsStream, Error> {
-    let mut ws_stream = websocket::connect(KRAKEN_WS_URL).await?;
-    subscribe(&mut ws_stream, symbol).await?;
-    Ok(ws_stream)
+/// Returns the WebSocket once the subscription is confirmed, with the messages received until then.
+/// The system status is sent before the confirmation. With `bbo`, the spread is subscribed instead of
+/// the book. The trades are subscribed once the book is, so that the confirmation awaited is the one
+/// of the book.
+pub(crate) async fn connect(
+    url: &str,
+    symbol: &str,
+    depth: usize,
+    bbo: bool,
+    trades: bool,
+    tape: &Tape,
+) -> Result<(websocket::WsStream, Vec<Message>), Error>
+{
+    let sub = match bbo {
+        true => channel_subscription(symbol, SubscriptionType::Spread)?,
+        false => subscription(symbol, depth)?,
+    };
+    let mut ws_stream = websocket::connect(Exchange::Kraken, symbol, url, tape).await?;
+    websocket::subscribe(&mut ws_stream, Exchange::Kraken, symbol, url, sub, tape).await?;
+    let backlog = websocket::await_ack(&mut ws_stream, Exchange::Kraken, symbol, url, ack, tape).await?;
+    if trades {
+        websocket::subscribe(&mut ws_stream, Exchange::Kraken, symbol, url, channel_subscription(symbol, SubscriptionType::Trade)?, tape).await?;
+    }
+    Ok((ws_stream, backlog))
 }
 
-async fn subscribe (
-    rx: &mut websocket::WsStream,
-    symbol: &String,
-) -> Result<(), Error>
-{
+/// Kraken answers an unknown pair with a `subscriptionStatus` of status `error`, or with an `error`
+/// event for a malformed request.
+fn ack(text: &str) -> Ack {
+    match deserialize_event(text) {
+        Ok(Event::GeneralMessage(GeneralMessage::SubscriptionStatus { status, error_message, .. })) => match status.as_str() {
+            "subscribed" => Ack::Subscribed,
+            "error" => Ack::Rejected(error_message.unwrap_or(status)),
+            _ => Ack::Pending,
+        },
+        Ok(Event::GeneralMessage(GeneralMessage::Error { error_message, .. })) => Ack::Rejected(error_message),
+        _ => Ack::Pending,
+    }
+}
+
+fn subscription(symbol: &str, depth: usize) -> Result<String, Error> {
     let pair = symbol.to_uppercase();
+    let depth = book_depth(depth);
     let sub = GeneralMessage::Subscribe{
         reqid: None,
         pair: vec![pair],
         subscription: Subscription {
-            depth: Some(10),
+            depth: Some(depth),
             name: SubscriptionType::Book,
             interval: None,
             ratecounter: None,
@@ -627,39 +822,96 @@ async fn subscribe (
             token: None,
         },
     };
-    let msg = serialize(sub)?;
-    rx.send(Message::Text(msg)).await?;
-    Ok(())
+    let payload = format!("{:?}", sub);
+    serialize(sub).map_err(|e| Error::protocol(Exchange::Kraken, symbol, e, &payload))
 }
 
-pub(crate) fn parse(msg: Message) -> Result<Option<InTick>, Error> {
+/// Levels per side of the book subscribed for `depth`. Kraken only streams 10, 25, 100, 500 or 1000
+/// levels, so the depth is rounded up to the next of these.
+pub(crate) fn book_depth(depth: usize) -> usize {
+    [10, 25, 100, 500, 1000].into_iter().find(|d| *d >= depth).unwrap_or(1000)
+}
+
+/// Subscribes a channel which takes no options, such as the trades or the spread.
+fn channel_subscription(symbol: &str, name: SubscriptionType) -> Result<String, Error> {
+    let sub = GeneralMessage::Subscribe{
+        reqid: None,
+        pair: vec![symbol.to_uppercase()],
+        subscription: Subscription {
+            depth: None,
+            name,
+            interval: None,
+            ratecounter: None,
+            snapshot: None,
+            token: None,
+        },
+    };
+    let payload = format!("{:?}", sub);
+    serialize(sub).map_err(|e| Error::protocol(Exchange::Kraken, symbol, e, &payload))
+}
+
+/// The system status applies to every pair, so it is reported to the venue of `symbol` as is.
+pub(crate) fn parse(msg: Message, symbol: &str) -> Result<Parsed, Error> {
+    metrics::message_received(Exchange::Kraken, &msg);
     let e = match msg {
-        Message::Binary(x) => { info!("binary {:?}", x); None },
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); None },
         Message::Text(x) => {
-            debug!("{:?}", x);
+            trace!(%x, "text message");
 
-            let e = deserialize_event(x)?;
+            let e = deserialize_event(&x).map_err(|e| metrics::parse_error(Exchange::Kraken, symbol, e, &x))?;
             match e {
-                Event::GeneralMessage(_) => info!("{:?}", e),
-                Event::PublicMessage(_) => debug!("{:?}", e),
+                // heartbeats arrive every second when nothing else is sent
+                Event::GeneralMessage(GeneralMessage::Heartbeat {}) => trace!("heartbeat"),
+                Event::GeneralMessage(_) => info!(event = ?e, "control message"),
+                Event::PublicMessage(PublicMessage::SinglePayload(SinglePayload { payload: Payload::Trade(ref trades), .. })) =>
+                    trace!(trades = trades.len(), "trade"),
+                Event::PublicMessage(PublicMessage::SinglePayload(SinglePayload { payload: Payload::Spread(ref spread), .. })) => {
+                    trace!(bid = %spread.bid, ask = %spread.ask, "spread");
+                    if let Some(time) = to_time(spread.timestamp) {
+                        metrics::message_age(Exchange::Kraken, time);
+                    }
+                },
+                Event::PublicMessage(_) => trace!("book"),
+            }
+            if let Some(updated_at) = e.updated_at() {
+                metrics::message_age(Exchange::Kraken, updated_at);
             }
 
             Some(e)
         },
-        Message::Ping(x) => { info!("Ping {:?}", x); None },
-        Message::Pong(x) => { info!("Pong {:?}", x); None },
-        Message::Close(x) => { info!("Close {:?}", x); None },
-        Message::Frame(x) => { info!("Frame {:?}", x); None },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); None },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); None },
+        Message::Close(x) => { info!(frame = ?x, "close"); None },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); None },
     };
-    Ok(e.map(|e| e.maybe_to_tick()).flatten())
+    match e {
+        Some(Event::GeneralMessage(GeneralMessage::SystemStatus { status, .. })) =>
+            return Ok(Parsed::Status(TradingStatus::from(&status), None)),
+        Some(Event::GeneralMessage(GeneralMessage::SubscriptionStatus { status, error_message: Some(message), .. }))
+            if status == "error" => return Ok(Parsed::Error(message)),
+        Some(Event::GeneralMessage(GeneralMessage::Error { error_message, .. })) =>
+            return Ok(Parsed::Error(error_message)),
+        Some(Event::PublicMessage(PublicMessage::SinglePayload(SinglePayload { payload: Payload::Trade(trades), .. }))) => {
+            let trades: Vec<trade::Trade> = trades.iter().filter_map(Trade::to_trade).collect();
+            if let Some(last) = trades.last() {
+                metrics::message_age(Exchange::Kraken, last.time);
+            }
+            return Ok(Parsed::Trades(trades));
+        },
+        // the best bid and offer replace the book, as nothing else is subscribed with them
+        Some(Event::PublicMessage(PublicMessage::SinglePayload(SinglePayload { payload: Payload::Spread(spread), .. }))) =>
+            return Ok(Parsed::Snapshot(spread.to_tick())),
+        _ => {},
+    }
+    Ok(e.and_then(|e| e.maybe_to_tick()).into())
 }
 
-fn deserialize_event(s: String) -> serde_json::Result<Event> {
-    Ok(serde_json::from_str(&s)?)
+fn deserialize_event(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
 }
 
 fn serialize(msg: GeneralMessage) -> serde_json::Result<String> {
-    Ok(serde_json::to_string(&Event::GeneralMessage(msg))?)
+    serde_json::to_string(&Event::GeneralMessage(msg))
 }
 
 #[cfg(test)]
@@ -668,7 +920,7 @@ mod test {
     use crate::kraken::*;
 
     #[test]
-    fn should_deserialize_book_snapshot() -> Result<(), Error> {
+    fn should_deserialize_book_snapshot() -> serde_json::Result<()> {
         assert_eq!(deserialize_event(r#"
         [
             640,
@@ -700,7 +952,7 @@ mod test {
             },
             "book-10",
             "ETH/XBT"
-        ]"#.to_string())?, Event::PublicMessage(PublicMessage::SinglePayload(SinglePayload {
+        ]"#)?, Event::PublicMessage(PublicMessage::SinglePayload(SinglePayload {
             channel_id: 640,
                 payload: Payload::Book(Book::Snapshot {
                     bids: vec![
@@ -736,7 +988,7 @@ mod test {
     }
 
     #[test]
-    fn should_deserialize_book_update_single_payload() -> Result<(), Error> {
+    fn should_deserialize_book_update_single_payload() -> serde_json::Result<()> {
         assert_eq!(deserialize_event(r#"
         [
             640,
@@ -748,7 +1000,7 @@ mod test {
             },
             "book-10",
             "ETH/XBT"
-        ]"#.to_string())?, Event::PublicMessage(PublicMessage::SinglePayload(SinglePayload {
+        ]"#)?, Event::PublicMessage(PublicMessage::SinglePayload(SinglePayload {
             channel_id: 640,
             payload: Payload::Book(Book::Update {
                 asks: None,
@@ -764,7 +1016,7 @@ mod test {
     }
 
     #[test]
-    fn should_deserialize_book_update_double_payload() -> Result<(), Error> {
+    fn should_deserialize_book_update_double_payload() -> serde_json::Result<()> {
         assert_eq!(deserialize_event(r#"
         [
             640,
@@ -789,7 +1041,7 @@ mod test {
             },
             "book-10",
             "ETH/XBT"
-        ]"#.to_string())?, Event::PublicMessage(PublicMessage::DoublePayload(DoublePayload {
+        ]"#)?, Event::PublicMessage(PublicMessage::DoublePayload(DoublePayload {
             channel_id: 640,
             payload1: Payload::Book(Book::Update {
                 asks: Some(vec![
@@ -812,7 +1064,30 @@ mod test {
     }
 
     #[test]
-    fn should_deserialize_subscription() -> Result<(), Error> {
+    fn should_take_latest_update_timestamp() -> serde_json::Result<()> {
+        let update = deserialize_event(r#"
+        [
+            640,
+            {"a": [["0.067390", "31.09081272", "1652905268.998332"]]},
+            {"b": [["0.067290", "53.27428999", "1652905268.998444"]], "c": "201829889"},
+            "book-10",
+            "ETH/XBT"
+        ]"#)?;
+        let snapshot = deserialize_event(r#"
+        [
+            640,
+            {"as": [["0.068010", "2.61547960", "1652817781.572052"]], "bs": []},
+            "book-10",
+            "ETH/XBT"
+        ]"#)?;
+
+        assert_eq!(update.updated_at(), Some(Utc.timestamp_nanos(1652905268998444000)));
+        assert_eq!(snapshot.updated_at(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn should_deserialize_subscription() -> serde_json::Result<()> {
         assert_eq!(deserialize_event(r#"
         {
             "channelID":640,
@@ -824,7 +1099,7 @@ mod test {
                 "depth":10,
                 "name":"book"
             }
-        }"#.to_string())?, Event::GeneralMessage(GeneralMessage::SubscriptionStatus{
+        }"#)?, Event::GeneralMessage(GeneralMessage::SubscriptionStatus{
             channel_name: Some("book-10".to_string()),
             reqid: None, 
             pair: Some("ETH/XBT".to_string()),
@@ -844,13 +1119,13 @@ mod test {
     }
 
     #[test]
-    fn should_deserialize_subscription_error() -> Result<(), Error> {
+    fn should_deserialize_subscription_error() -> serde_json::Result<()> {
         assert_eq!(deserialize_event(r#"
         {
             "errorMessage": "Event(s) not found",
             "event": "subscriptionStatus",
             "status": "error"
-        }"#.to_string())?, Event::GeneralMessage(GeneralMessage::SubscriptionStatus{
+        }"#)?, Event::GeneralMessage(GeneralMessage::SubscriptionStatus{
             channel_name: None,
             reqid: None,
             pair: None,
@@ -864,7 +1139,32 @@ mod test {
     }
 
     #[test]
-    fn should_serialize_subscription() -> Result<(), Error> {
+    fn should_report_unparsable_message_with_context() {
+        /*
+         * Given
+         */
+        let msg = Message::Text(r#"{"event":"unheard of"}"#.to_string());
+
+        /*
+         * When
+         */
+        let parsed = parse(msg, "ETH/BTC");
+
+        /*
+         * Then
+         */
+        match parsed {
+            Err(Error::Protocol { exchange, symbol, payload, .. }) => {
+                assert_eq!(exchange, Exchange::Kraken);
+                assert_eq!(symbol, "ETH/BTC");
+                assert_eq!(payload, r#"{"event":"unheard of"}"#);
+            },
+            other => panic!("expected a protocol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_serialize_subscription() -> serde_json::Result<()> {
         let mut serialized = r#"
         {
             "event": "subscribe",
@@ -895,7 +1195,7 @@ mod test {
     }
 
     #[test]
-    fn should_convert_to_tick() -> Result<(), Error> {
+    fn should_convert_to_tick() -> serde_json::Result<()> {
         /*
          * Given
          */
@@ -970,5 +1270,110 @@ mod test {
         Ok(())
     }
 
-}
+    #[test]
+    fn should_ack_subscription() {
+        /*
+         * Given
+         */
+        let status = include_str!("../tests/fixtures/kraken/system_status.json");
+        let subscribed = include_str!("../tests/fixtures/kraken/subscribed.json");
+        let rejected = include_str!("../tests/fixtures/kraken/subscription_error.json");
+
+        /*
+         * When
+         */
+        let (status, subscribed, rejected) = (ack(status), ack(subscribed), ack(rejected));
+
+        /*
+         * Then
+         */
+        assert_eq!(status, Ack::Pending);
+        assert_eq!(subscribed, Ack::Subscribed);
+        assert_eq!(rejected, Ack::Rejected("Currency pair not supported ETH/XYZ".to_string()));
+    }
+
+    #[test]
+    fn should_pass_on_trades() -> Result<(), Error> {
+        /*
+         * Given
+         */
+        let msg = Message::Text(include_str!("../tests/fixtures/kraken/trade.json").to_string());
+
+        /*
+         * When
+         */
+        let parsed = parse(msg, "ETH/BTC")?;
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Trades(vec![
+            trade::Trade {
+                exchange: Exchange::Kraken,
+                price: dec!(0.06900500),
+                amount: dec!(0.15850568),
+                side: TradeSide::Buy,
+                trade_id: None,
+                time: Utc.timestamp_nanos(1656000001321597000),
+            },
+            trade::Trade {
+                exchange: Exchange::Kraken,
+                price: dec!(0.06900600),
+                amount: dec!(0.5),
+                side: TradeSide::Buy,
+                trade_id: None,
+                time: Utc.timestamp_nanos(1656000001325000000),
+            },
+        ]));
+        assert_eq!(channel_subscription("eth/btc", SubscriptionType::Trade)?,
+                   r#"{"event":"subscribe","pair":["ETH/BTC"],"subscription":{"name":"trade"}}"#);
+
+        Ok(())
+    }
 
+    #[test]
+    fn should_replace_with_spread() -> Result<(), Error> {
+        /*
+         * Given
+         */
+        let msg = Message::Text(include_str!("../tests/fixtures/kraken/spread.json").to_string());
+
+        /*
+         * When
+         */
+        let parsed = parse(msg, "ETH/BTC")?;
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Snapshot(InTick {
+            exchange: Exchange::Kraken,
+            bids: vec![orderbook::Level::new(orderbook::Side::Bid, dec!(0.06900350), dec!(1.01234567), Exchange::Kraken)],
+            asks: vec![orderbook::Level::new(orderbook::Side::Ask, dec!(0.06900450), dec!(0.98765432), Exchange::Kraken)],
+        }));
+        assert_eq!(channel_subscription("eth/btc", SubscriptionType::Spread)?,
+                   r#"{"event":"subscribe","pair":["ETH/BTC"],"subscription":{"name":"spread"}}"#);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_error_after_subscription() -> Result<(), Error> {
+        /*
+         * Given
+         */
+        let msg = Message::Text(include_str!("../tests/fixtures/kraken/error.json").to_string());
+
+        /*
+         * When
+         */
+        let parsed = parse(msg, "ETH/BTC")?;
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Error("Exceeded msg rate".to_string()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,646 @@
+use crate::error::{self, Error};
+use crate::orderbook::{Exchange, FullBook, InTick, ToLevel};
+use crate::venue::Parsed;
+use crate::record::Tape;
+use crate::websocket::{self, Ack};
+use crate::{metrics, orderbook, rest};
+use chrono::{TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, info, trace};
+use tungstenite::protocol::Message;
+
+/// REST API, which hands out the WebSocket endpoint and the snapshots of the book.
+pub(crate) const KUCOIN_REST_URL: &str = "https://api.kucoin.com";
+
+/// KuCoin closes connections on which the client sent nothing for `pingInterval` plus `pingTimeout`,
+/// 18 and 10 seconds for the public endpoints.
+pub(crate) const PING_EVERY: Duration = Duration::from_secs(18);
+
+/// Code of a successful REST response.
+const SUCCESS: &str = "200000";
+
+/// Levels per side of the `level2_100` snapshot, the deepest one KuCoin hands out without an API key.
+const SNAPSHOT_LEVELS: usize = 100;
+
+/// Response of the REST API.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "code": "200000",
+///   "data": {
+///     "token": "2neAiuYvAU61ZDXANAGAsiL4-iAExhsBXZxftpOeh_55i3Ysy2q2LEsEWU64mdzUOPusi34M_wGoSf7iNyEWJ4aBZXpWhrmY9jKtqkdWoFa75w3istPvPtiYB9J6i9GjsxUuhPw3BlrzazF6ghq4L_Lh4A3QYq2qvMk9kmZ2NO-UBgfhRUXQrA==.mQaFXyXxlC9bxRbOE18lDw==",
+///     "instanceServers": [
+///       {
+///         "endpoint": "wss://ws-api-spot.kucoin.com/",
+///         "encrypt": true,
+///         "protocol": "websocket",
+///         "pingInterval": 18000,
+///         "pingTimeout": 10000
+///       }
+///     ]
+///   }
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct Response<T> {
+    code: String,
+
+    data: Option<T>,
+
+    /// Explanation of a failure
+    msg: Option<String>,
+}
+
+/// Token and endpoints handed out by `/api/v1/bullet-public`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Bullet {
+    token: String,
+
+    instance_servers: Vec<InstanceServer>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct InstanceServer {
+    endpoint: String,
+
+    protocol: String,
+
+    /// Milliseconds between the pings the server expects
+    ping_interval: u64,
+}
+
+/// Book of `/api/v1/market/orderbook/level2_100`, as of a sequence of the level-2 updates.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "code": "200000",
+///   "data": {
+///     "time": 1656000000050,
+///     "sequence": "3262786978",
+///     "bids": [["6500.12", "0.45054140"], ["6500.11", "0.45054140"]],
+///     "asks": [["6500.16", "0.57753524"], ["6500.15", "0.57753524"]]
+///   }
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct Snapshot {
+    /// Milliseconds since epoch
+    time: i64,
+
+    #[serde(deserialize_with = "from_str")]
+    sequence: u64,
+
+    /// Bids, descending from best bid
+    bids: Vec<Level>,
+
+    /// Asks, ascending from best ask
+    asks: Vec<Level>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct Level {
+    price: Decimal,
+    size: Decimal,
+}
+
+impl ToLevel for Level {
+    /// Converts a `kucoin::Level` into a `orderbook::Level`.
+    fn to_level(&self, side: orderbook::Side) -> orderbook::Level {
+        orderbook::Level::new(side, self.price, self.size, Exchange::Kucoin)
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Event {
+    WebSocket(WsEvent),
+
+    /// The REST snapshot, passed on to the parser in between the WebSocket messages.
+    Snapshot(Response<Snapshot>),
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum WsEvent {
+    /// Publication: Sent once the connection is open.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "id": "hQvf8jkno",
+    ///   "type": "welcome"
+    /// }
+    /// ```
+    Welcome {
+        id: String,
+    },
+
+    /// Response. Confirms the request of the same ID, e.g. a subscription.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "id": "1545910660739",
+    ///   "type": "ack"
+    /// }
+    /// ```
+    Ack {
+        id: String,
+    },
+
+    Pong {
+        id: String,
+    },
+
+    /// Response. Sent for a request which failed, e.g. a subscription to an unknown symbol.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "id": "1545910660739",
+    ///   "type": "error",
+    ///   "code": 404,
+    ///   "data": "topic /market/level2:ETH-XYZ is not found"
+    /// }
+    /// ```
+    Error {
+        id: Option<String>,
+
+        code: u32,
+
+        data: String,
+    },
+
+    /// Publication: Changes to the book of a `/market/level2` topic, each numbered by a sequence
+    /// which goes on from the one of the previous message.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "type": "message",
+    ///   "topic": "/market/level2:BTC-USDT",
+    ///   "subject": "trade.l2update",
+    ///   "data": {
+    ///     "changes": {
+    ///       "asks": [["18906", "0.00331", "14103845"]],
+    ///       "bids": [["18891.9", "0.15688", "14103847"]]
+    ///     },
+    ///     "sequenceEnd": 14103847,
+    ///     "sequenceStart": 14103844,
+    ///     "symbol": "BTC-USDT",
+    ///     "time": 1663747970273
+    ///   }
+    /// }
+    /// ```
+    Message {
+        topic: String,
+
+        subject: String,
+
+        data: Level2,
+    },
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Level2 {
+    changes: Changes,
+
+    sequence_start: u64,
+
+    sequence_end: u64,
+
+    symbol: String,
+
+    /// Milliseconds since epoch
+    time: i64,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Changes {
+    asks: Vec<Change>,
+    bids: Vec<Change>,
+}
+
+/// Size left at a price, 0 once the level is gone. A price of 0 only moves the sequence on.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct Change {
+    price: Decimal,
+
+    size: Decimal,
+
+    #[serde(deserialize_with = "from_str")]
+    sequence: u64,
+}
+
+/// Request. Subscribes to a topic, or pings the server.
+///
+/// **Examples of payload**
+/// ```json
+/// {
+///   "id": 1545910660739,
+///   "type": "subscribe",
+///   "topic": "/market/level2:BTC-USDT",
+///   "privateChannel": false,
+///   "response": true
+/// }
+///
+/// {
+///   "id": "1545910590801",
+///   "type": "ping"
+/// }
+/// ```
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Request {
+    Subscribe {
+        id: String,
+
+        topic: String,
+
+        #[serde(rename = "privateChannel")]
+        private_channel: bool,
+
+        /// Asks for an `ack`
+        response: bool,
+    },
+
+    Ping {
+        id: String,
+    },
+}
+
+/// Book of a connection, kept as updates change any level of it, with the sequence it is at. The
+/// snapshot only holds the top `SNAPSHOT_LEVELS` of each side, so the book is capped to its prices:
+/// the levels beyond were never known, and the book thins out as the top is consumed rather than
+/// show the few of them which happened to change.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Depths {
+    book: FullBook<Level>,
+
+    /// `None` until the snapshot arrived.
+    sequence: Option<u64>,
+
+    /// Worst bid and ask of the snapshot, beyond which changes are left out. `None` if the side was
+    /// shorter than `SNAPSHOT_LEVELS`, i.e. whole.
+    bid_floor: Option<Decimal>,
+    ask_ceiling: Option<Decimal>,
+}
+
+impl Depths {
+    fn reset(&mut self, snapshot: Snapshot) {
+        self.bid_floor = snapshot.bids.iter().map(|level| level.price).min()
+            .filter(|_| snapshot.bids.len() >= SNAPSHOT_LEVELS);
+        self.ask_ceiling = snapshot.asks.iter().map(|level| level.price).max()
+            .filter(|_| snapshot.asks.len() >= SNAPSHOT_LEVELS);
+        self.book.bids = snapshot.bids.into_iter().map(|level| (level.price, level)).collect();
+        self.book.asks = snapshot.asks.into_iter().map(|level| (level.price, level)).collect();
+        self.sequence = Some(snapshot.sequence);
+    }
+
+    /// Applies the changes the book is not at yet. Fails if changes were missed since.
+    fn apply(&mut self, update: Level2, symbol: &str, payload: &str) -> Result<(), Error> {
+        let sequence = match self.sequence {
+            Some(sequence) => sequence,
+            None => {
+                debug!(sequence_end = update.sequence_end, "update before the snapshot");
+                return Ok(());
+            },
+        };
+        if update.sequence_start > sequence + 1 {
+            return Err(Error::SequenceGap {
+                exchange: Exchange::Kucoin,
+                symbol: symbol.to_string(),
+                expected: sequence + 1,
+                got: update.sequence_start,
+                payload: error::excerpt(payload),
+            });
+        }
+
+        let bids: Vec<Change> = update.changes.bids.into_iter()
+            .filter(|change| self.bid_floor.is_none_or(|floor| change.price >= floor))
+            .collect();
+        let asks: Vec<Change> = update.changes.asks.into_iter()
+            .filter(|change| self.ask_ceiling.is_none_or(|ceiling| change.price <= ceiling))
+            .collect();
+        for (levels, changes) in [(&mut self.book.bids, bids), (&mut self.book.asks, asks)] {
+            for change in changes.into_iter().filter(|change| change.sequence > sequence && !change.price.is_zero()) {
+                match change.size.is_zero() {
+                    true => levels.remove(&change.price),
+                    false => levels.insert(change.price, Level { price: change.price, size: change.size }),
+                };
+            }
+        }
+        self.sequence = Some(sequence.max(update.sequence_end));
+        Ok(())
+    }
+
+    fn to_tick(&self) -> InTick {
+        self.book.to_tick(Exchange::Kucoin, |side, _, level| level.to_level(side))
+    }
+}
+
+/// Asks `url`, the REST API, for a WebSocket endpoint and a token, subscribes there, then fetches the
+/// snapshot of the book. Returns the WebSocket with the messages received until then, the snapshot
+/// last, so that the updates which follow can be synced against it.
+pub(crate) async fn connect(url: &str, symbol: &str, tape: &Tape) -> Result<(websocket::WsStream, Vec<Message>), Error> {
+    let url = url.trim_end_matches('/');
+    let bullet_url = format!("{}/api/v1/bullet-public", url);
+    let body = rest::post(Exchange::Kucoin, symbol, &bullet_url).await?;
+    let bullet: Bullet = data(&body, symbol)?;
+    let server = bullet.instance_servers.iter()
+        .find(|server| server.protocol == "websocket")
+        .ok_or_else(|| Error::protocol(Exchange::Kucoin, symbol, "no websocket endpoint", &body))?;
+    debug!(endpoint = %server.endpoint, ping_interval = server.ping_interval, "bullet");
+
+    let ws_url = format!("{}?token={}&connectId={}", server.endpoint, bullet.token, request_id());
+    let mut ws_stream = websocket::connect(Exchange::Kucoin, symbol, &ws_url, tape).await?;
+    websocket::subscribe(&mut ws_stream, Exchange::Kucoin, symbol, &ws_url, subscription(symbol)?, tape).await?;
+    let mut backlog = websocket::await_ack(&mut ws_stream, Exchange::Kucoin, symbol, &ws_url, ack, tape).await?;
+
+    // the full book needs an API key
+    let snapshot_url = format!("{}/api/v1/market/orderbook/level2_100?symbol={}", url, market(symbol));
+    let snapshot = Message::Text(rest::get(Exchange::Kucoin, symbol, &snapshot_url).await?);
+    tape.message(&snapshot);
+    backlog.push(snapshot);
+    Ok((ws_stream, backlog))
+}
+
+/// Data of a successful REST response.
+fn data<T: serde::de::DeserializeOwned>(body: &str, symbol: &str) -> Result<T, Error> {
+    let response: Response<T> = serde_json::from_str(body)
+        .map_err(|e| Error::protocol(Exchange::Kucoin, symbol, e, body))?;
+    match response {
+        Response { code, data: Some(data), .. } if code == SUCCESS => Ok(data),
+        Response { code, msg, .. } => Err(Error::protocol(Exchange::Kucoin, symbol, format!("{} {}", code, msg.unwrap_or_default()), body)),
+    }
+}
+
+fn ack(text: &str) -> Ack {
+    match deserialize(text) {
+        Ok(Event::WebSocket(WsEvent::Ack { .. })) => Ack::Subscribed,
+        Ok(Event::WebSocket(WsEvent::Error { code, data, .. })) => Ack::Rejected(format!("{} ({})", data, code)),
+        _ => Ack::Pending,
+    }
+}
+
+fn subscription(symbol: &str) -> Result<String, Error> {
+    let sub = Request::Subscribe {
+        id: request_id(),
+        topic: format!("/market/level2:{}", market(symbol)),
+        private_channel: false,
+        response: true,
+    };
+    let payload = format!("{:?}", sub);
+    serde_json::to_string(&sub).map_err(|e| Error::protocol(Exchange::Kucoin, symbol, e, &payload))
+}
+
+/// Application level ping, sent every `PING_EVERY`.
+pub(crate) fn ping() -> Message {
+    // cannot fail, as the request holds nothing but strings
+    Message::Text(serde_json::to_string(&Request::Ping { id: request_id() }).unwrap_or_default())
+}
+
+/// Requests are told apart by the time they were sent at.
+fn request_id() -> String {
+    Utc::now().timestamp_millis().to_string()
+}
+
+/// KuCoin names markets with a dash, e.g. `ETH-BTC`.
+fn market(symbol: &str) -> String {
+    symbol.to_uppercase().replace('/', "-")
+}
+
+/// The snapshot replaces the book held in `depths`, and updates change it from its sequence on. An
+/// update which does not follow on fails, as the book can no longer be trusted until the venue
+/// subscribes again and fetches a new snapshot.
+pub(crate) fn parse(msg: Message, symbol: &str, depths: &mut Depths) -> Result<Parsed, Error> {
+    metrics::message_received(Exchange::Kucoin, &msg);
+    let x = match msg {
+        Message::Text(x) => x,
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); return Ok(Parsed::Nothing) },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); return Ok(Parsed::Nothing) },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); return Ok(Parsed::Nothing) },
+        Message::Close(x) => { info!(frame = ?x, "close"); return Ok(Parsed::Nothing) },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); return Ok(Parsed::Nothing) },
+    };
+    trace!(%x, "text message");
+
+    let e = deserialize(&x).map_err(|e| metrics::parse_error(Exchange::Kucoin, symbol, e, &x))?;
+    Ok(match e {
+        Event::Snapshot(Response { code, data: Some(snapshot), .. }) if code == SUCCESS => {
+            trace!(sequence = snapshot.sequence, time = snapshot.time, "snapshot");
+            depths.reset(snapshot);
+            Parsed::Tick(depths.to_tick())
+        },
+        Event::Snapshot(Response { code, msg, .. }) => Parsed::Error(format!("snapshot: {} {}", code, msg.unwrap_or_default())),
+        Event::WebSocket(WsEvent::Message { data, .. }) => {
+            trace!(sequence_start = data.sequence_start, sequence_end = data.sequence_end, "update");
+            if let Some(ts) = Utc.timestamp_millis_opt(data.time).single() {
+                metrics::message_age(Exchange::Kucoin, ts);
+            }
+            depths.apply(data, symbol, &x)?;
+            Parsed::Tick(depths.to_tick())
+        },
+        Event::WebSocket(WsEvent::Pong { .. }) => {
+            trace!("pong");
+            Parsed::Nothing
+        },
+        Event::WebSocket(WsEvent::Error { code, data, .. }) => Parsed::Error(format!("{} ({})", data, code)),
+        Event::WebSocket(event) => {
+            info!(?event, "control message");
+            Parsed::Nothing
+        },
+    })
+}
+
+fn deserialize(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
+}
+
+/// KuCoin sends some numbers as strings.
+fn from_str<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use crate::kucoin::*;
+
+    fn level(price: Decimal, size: Decimal) -> Level {
+        Level { price, size }
+    }
+
+    #[test]
+    fn should_deserialize_rest_responses() {
+        let bullet: Bullet = data(include_str!("../tests/fixtures/kucoin/bullet_public.json"), "ETH/BTC").unwrap();
+        assert_eq!(bullet.instance_servers, vec![InstanceServer {
+            endpoint: "{endpoint}".to_string(),
+            protocol: "websocket".to_string(),
+            ping_interval: 18000,
+        }]);
+
+        let snapshot: Snapshot = data(include_str!("../tests/fixtures/kucoin/level2_snapshot.json"), "ETH/BTC").unwrap();
+        assert_eq!(snapshot, Snapshot {
+            time: 1656000000050,
+            sequence: 1000,
+            bids: vec![level(dec!(0.069002), dec!(1.5)), level(dec!(0.068996), dec!(2.0))],
+            asks: vec![level(dec!(0.069008), dec!(3.0)), level(dec!(0.069012), dec!(0.75))],
+        });
+
+        let failed = data::<Bullet>(r#"{"code":"400100","msg":"Too many requests"}"#, "ETH/BTC");
+        assert!(matches!(failed, Err(Error::Protocol { ref reason, .. }) if reason == "400100 Too many requests"), "{:?}", failed);
+    }
+
+    #[test]
+    fn should_deserialize_update() -> serde_json::Result<()> {
+        assert_eq!(deserialize(include_str!("../tests/fixtures/kucoin/level2_update.json"))?,
+                   Event::WebSocket(WsEvent::Message {
+                       topic: "/market/level2:ETH-BTC".to_string(),
+                       subject: "trade.l2update".to_string(),
+                       data: Level2 {
+                           changes: Changes {
+                               asks: vec![Change { price: dec!(0.069006), size: dec!(1.25), sequence: 1002 }],
+                               bids: vec![
+                                   Change { price: dec!(0.068996), size: dec!(9), sequence: 1000 },
+                                   Change { price: dec!(0.069002), size: dec!(0), sequence: 1001 },
+                               ],
+                           },
+                           sequence_start: 1000,
+                           sequence_end: 1002,
+                           symbol: "ETH-BTC".to_string(),
+                           time: 1656000000100,
+                       },
+                   })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_serialize_requests() {
+        let sub: serde_json::Value = serde_json::from_str(&subscription("eth/btc").unwrap()).unwrap();
+        assert_eq!(sub["type"], "subscribe");
+        assert_eq!(sub["topic"], "/market/level2:ETH-BTC");
+        assert_eq!(sub["response"], true);
+        assert!(matches!(ping(), Message::Text(ping) if ping.starts_with(r#"{"type":"ping","id":""#)));
+    }
+
+    #[test]
+    fn should_ack_subscription() {
+        assert_eq!(ack(include_str!("../tests/fixtures/kucoin/welcome.json")), Ack::Pending);
+        assert_eq!(ack(include_str!("../tests/fixtures/kucoin/ack.json")), Ack::Subscribed);
+        assert_eq!(ack(include_str!("../tests/fixtures/kucoin/error.json")),
+                   Ack::Rejected("topic /market/level2:ETH-XYZ is not found (404)".to_string()));
+    }
+
+    #[test]
+    fn should_apply_updates_after_snapshot() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        parse(Message::Text(include_str!("../tests/fixtures/kucoin/level2_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * When
+         */
+        let tick = parse(Message::Text(include_str!("../tests/fixtures/kucoin/level2_update.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(tick, Parsed::Tick(InTick {
+            exchange: Exchange::Kucoin,
+            bids: vec![
+                orderbook::Level::new(orderbook::Side::Bid, dec!(0.068996), dec!(2.0), Exchange::Kucoin),
+            ],
+            asks: vec![
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.069006), dec!(1.25), Exchange::Kucoin),
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.069008), dec!(3.0), Exchange::Kucoin),
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.069012), dec!(0.75), Exchange::Kucoin),
+            ],
+        }));
+        assert_eq!(depths.sequence, Some(1002));
+        assert_eq!((depths.bid_floor, depths.ask_ceiling), (None, None));
+    }
+
+    #[test]
+    fn should_leave_out_changes_beyond_snapshot() {
+        /*
+         * Given
+         */
+        let side = |best: i64, step: i64| (0..SNAPSHOT_LEVELS as i64)
+            .map(|i| format!(r#"["{}","1"]"#, Decimal::new(best + step * i, 6)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let snapshot = format!(
+            r#"{{"code":"200000","data":{{"time":1656000000050,"sequence":"1000","bids":[{}],"asks":[{}]}}}}"#,
+            side(69002, -1), side(69008, 1));
+        let mut depths = Depths::default();
+        parse(Message::Text(snapshot), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * When
+         */
+        let tick = parse(Message::Text(
+            r#"{"type":"message","topic":"/market/level2:ETH-BTC","subject":"trade.l2update","data":{"changes":{"asks":[["0.069200","5","1003"]],"bids":[["0.069002","0","1001"],["0.068900","5","1002"]]},"sequenceEnd":1003,"sequenceStart":1001,"symbol":"ETH-BTC","time":1656000000100}}"#.to_string()),
+            "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        let tick = match tick {
+            Parsed::Tick(tick) => tick,
+            parsed => panic!("{:?}", parsed),
+        };
+        assert_eq!((tick.bids.len(), tick.bids.last().map(|l| l.price)), (99, Some(dec!(0.068903))));
+        assert_eq!((tick.asks.len(), tick.asks.last().map(|l| l.price)), (100, Some(dec!(0.069107))));
+        assert_eq!((depths.bid_floor, depths.ask_ceiling), (Some(dec!(0.068903)), Some(dec!(0.069107))));
+    }
+
+    #[test]
+    fn should_skip_updates_before_snapshot() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+
+        /*
+         * When
+         */
+        parse(Message::Text(include_str!("../tests/fixtures/kucoin/level2_update.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(depths, Depths::default());
+    }
+
+    #[test]
+    fn should_fail_on_sequence_gap() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        parse(Message::Text(include_str!("../tests/fixtures/kucoin/level2_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+        let gap = include_str!("../tests/fixtures/kucoin/level2_update_next.json").replace("1003", "1005");
+
+        /*
+         * When
+         */
+        let parsed = parse(Message::Text(gap), "ETH/BTC", &mut depths);
+
+        /*
+         * Then
+         */
+        assert!(matches!(parsed, Err(Error::SequenceGap { expected: 1001, got: 1005, .. })), "{:?}", parsed);
+    }
+}
@@ -1,10 +1,26 @@
+mod auth;
 mod binance;
+mod bitfinex;
 mod bitstamp;
+mod bybit;
+mod candle;
 mod coinbase;
-mod error;
+pub mod config;
+mod console;
+mod deribit;
+pub mod error;
+mod export;
+mod gemini;
 mod grpc;
 mod kraken;
+mod kucoin;
+mod metrics;
+mod okx;
 mod orderbook;
-mod stdin;
+mod record;
+mod replay;
+mod rest;
+mod trade;
+mod venue;
 mod websocket;
-pub mod orderly;
\ No newline at end of file
+pub mod orderly;
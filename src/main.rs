@@ -1,15 +1,33 @@
-use clap::Parser;
-use orderly::orderly;
+use clap::{ArgEnum, Parser};
+use ::orderly::{config::{Config, ExportConfig, ExportFormat, ExportLayout, ReplayConfig, Speed, TlsConfig}, error::Error, orderly};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tracing_subscriber::EnvFilter;
 
 /// Pulls order depths for the given currency pair from the WebSocket feeds of multiple exchanges.
 /// Publishes a merged order book as a gRPC stream.
 #[derive(Parser)]
 struct Cli {
+    #[clap(short, long, help = "(Optional) TOML or YAML configuration file. Options below override it")]
+    config: Option<PathBuf>,
+
     #[clap(short, long, help = "(Optional) Currency pair to subscribe to. Default: ETH/BTC")]
     symbol: Option<String>,
 
     #[clap(short, long, help = "(Optional) Port number on which the the gRPC server will be hosted. Default: 50051")]
-    port: Option<usize>,
+    port: Option<u16>,
+
+    #[clap(short, long, conflicts_with = "port", help = "(Optional) Address on which the gRPC server will be hosted, e.g. 0.0.0.0:50051. Default: [::1]:50051")]
+    listen: Option<SocketAddr>,
+
+    #[clap(long, requires = "tls-key", help = "(Optional) PEM certificate chain. Serves gRPC over TLS")]
+    tls_cert: Option<PathBuf>,
+
+    #[clap(long, requires = "tls-cert", help = "(Optional) PEM private key of --tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    #[clap(long, requires = "tls-cert", help = "(Optional) PEM CA certificate. Requires clients to present a certificate signed by it")]
+    tls_client_ca: Option<PathBuf>,
 
     #[clap(long, help = "(Optional) Disable Bitstamp. Default: false")]
     no_bitstamp: bool,
@@ -23,20 +41,206 @@ struct Cli {
     #[clap(long, help = "(Optional) Disable Coinbase. Default: false")]
     no_coinbase: bool,
 
+    #[clap(long, help = "(Optional) Disable OKX. Default: false")]
+    no_okx: bool,
+
+    #[clap(long, help = "(Optional) Disable Bybit. Default: false")]
+    no_bybit: bool,
+
+    #[clap(long, help = "(Optional) Disable Bitfinex. Default: false")]
+    no_bitfinex: bool,
+
+    #[clap(long, help = "(Optional) Disable Gemini. Default: false")]
+    no_gemini: bool,
+
+    #[clap(long, help = "(Optional) Disable KuCoin. Default: false")]
+    no_kucoin: bool,
+
+    #[clap(long, help = "(Optional) Disable Deribit. Default: false")]
+    no_deribit: bool,
+
+    #[clap(long, help = "(Optional) Disable Binance USDⓈ-M futures. Default: false")]
+    no_binance_futures: bool,
+
+    #[clap(long, help = "(Optional) Disable Binance.US. Default: false")]
+    no_binance_us: bool,
+
+    #[clap(long, help = "(Optional) TOML or YAML file of the clients allowed to use the gRPC services. Default: anyone")]
+    auth_file: Option<PathBuf>,
+
+    #[clap(long, help = "(Optional) Also accept console commands on a Unix socket at this path")]
+    console_socket: Option<PathBuf>,
+
+    #[clap(long, help = "(Optional) Address on which Prometheus metrics are served at /metrics, e.g. 0.0.0.0:9100")]
+    metrics_listen: Option<SocketAddr>,
+
+    #[clap(long, help = "(Optional) Record the raw messages of every exchange below this directory, as compressed JSON lines")]
+    record: Option<PathBuf>,
+
+    #[clap(long, conflicts_with = "record", help = "(Optional) Play the recording in this directory back instead of connecting to the exchanges")]
+    replay: Option<PathBuf>,
+
+    #[clap(long, help = "(Optional) Pace of the replay: a factor of the recorded pace, e.g. 10, or max. Default: 1")]
+    replay_speed: Option<Speed>,
+
+    #[clap(long, help = "(Optional) Stop once the replay finished, e.g. to export a recording offline")]
+    replay_exit: bool,
+
+    #[clap(long, help = "(Optional) Write the merged books to files in this directory")]
+    export: Option<PathBuf>,
+
+    #[clap(long, help = "(Optional) File format of the export: csv or parquet. Default: csv")]
+    export_format: Option<ExportFormat>,
+
+    #[clap(long, help = "(Optional) Layout of the export: long, a row per level, or wide, a row per book. Default: long")]
+    export_layout: Option<ExportLayout>,
+
+    #[clap(long, help = "(Optional) Sample the exported books every this many milliseconds. Default: every change")]
+    export_interval_ms: Option<u64>,
+
+    #[clap(long, help = "(Optional) Also export the book of every venue. Default: false")]
+    export_venues: bool,
+
+    #[clap(long, arg_enum, default_value = "text", help = "(Optional) Log as human readable text or as one JSON object per line. Levels are set with RUST_LOG")]
+    log_format: LogFormat,
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Logs to stderr, filtered per module by `RUST_LOG`, e.g. `info,orderly::kraken=debug`. Only
+/// errors are logged when it is unset.
+fn init_logging(format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).init(),
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
     let args = Cli::parse();
-    let symbol: String = args.symbol.unwrap_or("ETH/BTC".to_string());
-    let port: usize = args.port.unwrap_or(50051);
-    let no_bitstamp: bool = args.no_bitstamp;
-    let no_binance: bool = args.no_binance;
-    let no_kraken: bool = args.no_kraken;
-    let no_coinbase: bool = args.no_coinbase;
-
-    orderly::run(&symbol, port,
-                 no_bitstamp, no_binance, no_kraken, no_coinbase).await.unwrap();
+    init_logging(args.log_format);
+
+    if let Err(e) = serve(args).await {
+        eprintln!("orderbook-server: {}", e);
+        std::process::exit(1);
+    }
 }
 
+/// Applies the command line over the configuration file and runs the aggregator until it exits.
+async fn serve(args: Cli) -> Result<(), Error> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    if let Some(symbol) = args.symbol {
+        config.symbols = vec![symbol];
+    }
+    if let Some(port) = args.port {
+        config.listen.set_port(port);
+    }
+    if let Some(listen) = args.listen {
+        config.listen = listen;
+    }
+    if let (Some(cert), Some(key)) = (args.tls_cert, args.tls_key) {
+        config.tls = Some(TlsConfig { cert, key, client_ca: args.tls_client_ca });
+    }
+    if args.no_bitstamp {
+        config.venues.bitstamp.enabled = false;
+    }
+    if args.no_binance {
+        config.venues.binance.enabled = false;
+    }
+    if args.no_kraken {
+        config.venues.kraken.enabled = false;
+    }
+    if args.no_coinbase {
+        config.venues.coinbase.enabled = false;
+    }
+    if args.no_okx {
+        config.venues.okx.enabled = false;
+    }
+    if args.no_bybit {
+        config.venues.bybit.enabled = false;
+    }
+    if args.no_bitfinex {
+        config.venues.bitfinex.enabled = false;
+    }
+    if args.no_gemini {
+        config.venues.gemini.enabled = false;
+    }
+    if args.no_kucoin {
+        config.venues.kucoin.enabled = false;
+    }
+    if args.no_deribit {
+        config.venues.deribit.enabled = false;
+    }
+    if args.no_binance_futures {
+        config.venues.binance_futures.enabled = false;
+    }
+    if args.no_binance_us {
+        config.venues.binance_us.enabled = false;
+    }
+    if args.auth_file.is_some() {
+        config.auth_file = args.auth_file;
+    }
+    if args.console_socket.is_some() {
+        config.console_socket = args.console_socket;
+    }
+    if args.metrics_listen.is_some() {
+        config.metrics_listen = args.metrics_listen;
+    }
+    if args.record.is_some() {
+        config.record = args.record;
+    }
+    if let Some(dir) = args.replay {
+        match &mut config.replay {
+            Some(replay) => replay.dir = dir,
+            None => config.replay = Some(ReplayConfig { dir, speed: Speed::default(), exit: false }),
+        }
+    }
+    if args.replay_speed.is_some() || args.replay_exit {
+        let replay = config.replay.as_mut()
+            .ok_or_else(|| Error::BadConfig("--replay-speed and --replay-exit need --replay or a [replay] section".to_string()))?;
+        if let Some(speed) = args.replay_speed {
+            replay.speed = speed;
+        }
+        replay.exit |= args.replay_exit;
+    }
+    if let Some(dir) = args.export {
+        match &mut config.export {
+            Some(export) => export.dir = dir,
+            None => config.export = Some(ExportConfig {
+                dir,
+                format: ExportFormat::default(),
+                layout: ExportLayout::default(),
+                interval_ms: None,
+                venues: false,
+            }),
+        }
+    }
+    if args.export_format.is_some() || args.export_layout.is_some() || args.export_interval_ms.is_some() || args.export_venues {
+        let export = config.export.as_mut()
+            .ok_or_else(|| Error::BadConfig("--export-* options need --export or an [export] section".to_string()))?;
+        if let Some(format) = args.export_format {
+            export.format = format;
+        }
+        if let Some(layout) = args.export_layout {
+            export.layout = layout;
+        }
+        if args.export_interval_ms.is_some() {
+            export.interval_ms = args.export_interval_ms;
+        }
+        export.venues |= args.export_venues;
+    }
+
+    orderly::run(config).await
+}
@@ -0,0 +1,264 @@
+use crate::error::Error;
+use crate::orderbook::{Exchange, OutTick};
+use chrono::{DateTime, Utc};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Response, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use rust_decimal::prelude::ToPrimitive;
+use std::cell::Cell;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::info;
+use tungstenite::Message;
+
+lazy_static! {
+    static ref MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "orderly_messages_received_total", "WebSocket messages received from the exchange", &["exchange"]).unwrap();
+
+    static ref BYTES: IntCounterVec = register_int_counter_vec!(
+        "orderly_bytes_received_total", "Payload bytes received from the exchange", &["exchange"]).unwrap();
+
+    static ref PARSE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "orderly_parse_errors_total", "Messages of the exchange which could not be parsed", &["exchange"]).unwrap();
+
+    static ref SKIPPED: IntCounterVec = register_int_counter_vec!(
+        "orderly_messages_skipped_total", "Messages of the exchange skipped after a protocol error", &["exchange"]).unwrap();
+
+    static ref TRADES: IntCounterVec = register_int_counter_vec!(
+        "orderly_trades_received_total", "Trades received from the exchange", &["exchange"]).unwrap();
+
+    static ref RECONNECTS: IntCounterVec = register_int_counter_vec!(
+        "orderly_reconnects_total", "Connections to the exchange made again after the first one", &["exchange"]).unwrap();
+
+    static ref MESSAGE_AGE: HistogramVec = register_histogram_vec!(
+        "orderly_message_age_seconds", "Time from the exchange timestamp of an update to its receipt", &["exchange"],
+        vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]).unwrap();
+
+    static ref MERGE_DURATION: HistogramVec = register_histogram_vec!(
+        "orderly_merge_duration_seconds", "Time to apply a tick and merge the orderbooks of a symbol", &["symbol"],
+        vec![0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01]).unwrap();
+
+    static ref SPREAD: GaugeVec = register_gauge_vec!(
+        "orderly_spread", "Spread of the merged orderbook", &["symbol"]).unwrap();
+
+    static ref BEST_BID: GaugeVec = register_gauge_vec!(
+        "orderly_best_bid", "Best bid price of the merged orderbook", &["symbol"]).unwrap();
+
+    static ref BEST_ASK: GaugeVec = register_gauge_vec!(
+        "orderly_best_ask", "Best ask price of the merged orderbook", &["symbol"]).unwrap();
+
+    static ref SUBSCRIBERS: IntGaugeVec = register_int_gauge_vec!(
+        "orderly_grpc_subscribers", "Open gRPC streams of the symbol, by RPC", &["symbol", "stream"]).unwrap();
+
+    static ref SUBSCRIBER_LAG: HistogramVec = register_histogram_vec!(
+        "orderly_subscriber_lag_seconds", "Time from publishing a merged orderbook to a stream sending it",
+        &["client", "symbol"],
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]).unwrap();
+}
+
+thread_local! {
+    /// Set while recorded messages are parsed, whose receipt was observed when they were recorded.
+    static REPLAYING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f`, the parsing of a recorded message, without observing the receipt of what it parses: its
+/// count, bytes, parse errors, trades and age are of the live feeds only.
+pub(crate) fn unobserved<T>(f: impl FnOnce() -> T) -> T {
+    REPLAYING.with(|replaying| replaying.set(true));
+    let result = f();
+    REPLAYING.with(|replaying| replaying.set(false));
+    result
+}
+
+fn observed() -> bool {
+    !REPLAYING.with(|replaying| replaying.get())
+}
+
+/// Counts a WebSocket message and its payload bytes.
+pub(crate) fn message_received(exchange: Exchange, msg: &Message) {
+    if !observed() {
+        return;
+    }
+    let exchange = exchange.to_string();
+    MESSAGES.with_label_values(&[&exchange]).inc();
+    BYTES.with_label_values(&[&exchange]).inc_by(msg.len() as u64);
+}
+
+/// Counts the error, and turns it into a protocol error so that it can be used within `map_err`.
+pub(crate) fn parse_error(exchange: Exchange, symbol: &str, e: serde_json::Error, payload: &str) -> Error {
+    if observed() {
+        PARSE_ERRORS.with_label_values(&[&exchange.to_string()]).inc();
+    }
+    Error::protocol(exchange, symbol, e, payload)
+}
+
+pub(crate) fn message_skipped(exchange: Exchange) {
+    SKIPPED.with_label_values(&[&exchange.to_string()]).inc();
+}
+
+pub(crate) fn trades_received(exchange: Exchange, count: usize) {
+    if !observed() {
+        return;
+    }
+    TRADES.with_label_values(&[&exchange.to_string()]).inc_by(count as u64);
+}
+
+pub(crate) fn reconnected(exchange: Exchange) {
+    RECONNECTS.with_label_values(&[&exchange.to_string()]).inc();
+}
+
+/// Observes how long ago the exchange timestamped an update. Clock skew can make it negative, which
+/// is recorded as zero.
+pub(crate) fn message_age(exchange: Exchange, timestamp: DateTime<Utc>) {
+    if !observed() {
+        return;
+    }
+    let age = (Utc::now() - timestamp).to_std().unwrap_or_default();
+    MESSAGE_AGE.with_label_values(&[&exchange.to_string()]).observe(age.as_secs_f64());
+}
+
+pub(crate) fn merged(symbol: &str, duration: Duration) {
+    MERGE_DURATION.with_label_values(&[symbol]).observe(duration.as_secs_f64());
+}
+
+pub(crate) fn published(symbol: &str, out_tick: &OutTick) {
+    SPREAD.with_label_values(&[symbol]).set(out_tick.spread.to_f64().unwrap_or_default());
+    match out_tick.bids.first() {
+        Some(level) => BEST_BID.with_label_values(&[symbol]).set(level.price.to_f64().unwrap_or_default()),
+        None => { let _ = BEST_BID.remove_label_values(&[symbol]); },
+    }
+    match out_tick.asks.first() {
+        Some(level) => BEST_ASK.with_label_values(&[symbol]).set(level.price.to_f64().unwrap_or_default()),
+        None => { let _ = BEST_ASK.remove_label_values(&[symbol]); },
+    }
+}
+
+/// Drops the gauges of a symbol which is no longer subscribed.
+pub(crate) fn symbol_removed(symbol: &str) {
+    let _ = SPREAD.remove_label_values(&[symbol]);
+    let _ = BEST_BID.remove_label_values(&[symbol]);
+    let _ = BEST_ASK.remove_label_values(&[symbol]);
+}
+
+pub(crate) fn subscriber_lag(client: &str, symbol: &str, lag: Duration) {
+    SUBSCRIBER_LAG.with_label_values(&[client, symbol]).observe(lag.as_secs_f64());
+}
+
+/// Counts an open stream of the symbol until dropped, under the RPC it was opened with, e.g.
+/// `book_summary` or `bbo`.
+pub(crate) struct SubscriberGuard {
+    symbol: String,
+    stream: &'static str,
+}
+
+impl SubscriberGuard {
+    pub(crate) fn new(symbol: &str, stream: &'static str) -> SubscriberGuard {
+        SUBSCRIBERS.with_label_values(&[symbol, stream]).inc();
+        SubscriberGuard { symbol: symbol.to_string(), stream }
+    }
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        SUBSCRIBERS.with_label_values(&[&self.symbol, self.stream]).dec();
+    }
+}
+
+/// Renders every metric in the Prometheus text format.
+fn render() -> prometheus::Result<Vec<u8>> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(buffer)
+}
+
+fn respond(method: &Method, path: &str) -> Response<Body> {
+    match (method, path) {
+        (&Method::GET, "/metrics") => match render() {
+            Ok(text) => {
+                let mut response = Response::new(Body::from(text));
+                response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(prometheus::TEXT_FORMAT));
+                response
+            },
+            Err(e) => with_status(StatusCode::INTERNAL_SERVER_ERROR, Body::from(e.to_string())),
+        },
+        _ => with_status(StatusCode::NOT_FOUND, Body::empty()),
+    }
+}
+
+fn with_status(status: StatusCode, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
+
+/// Serves the metrics at `/metrics` over plain HTTP.
+pub(crate) async fn serve(addr: SocketAddr) -> Result<(), Error> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|request| async move {
+            Ok::<_, Infallible>(respond(request.method(), request.uri().path()))
+        }))
+    });
+
+    info!(%addr, "serving metrics at /metrics");
+    hyper::Server::try_bind(&addr)
+        .map_err(|e| Error::BadConfig(format!("metrics_listen {}: {}", addr, e)))?
+        .serve(make_service)
+        .await
+        .map_err(Error::Metrics)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::*;
+    use crate::orderbook::{Level, Side};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn should_render_metrics() {
+        /*
+         * Given
+         */
+        let out_tick = OutTick {
+            spread: dec!(1),
+            bids: vec![Level::new(Side::Bid, dec!(100), dec!(1), Exchange::Kraken)],
+            asks: vec![Level::new(Side::Ask, dec!(101), dec!(1), Exchange::Binance)],
+        };
+
+        /*
+         * When
+         */
+        message_received(Exchange::Kraken, &Message::Text("{}".to_string()));
+        published("METRICS/TEST", &out_tick);
+        let guard = SubscriberGuard::new("METRICS/TEST", "book_summary");
+        let text = String::from_utf8(render().unwrap()).unwrap();
+        drop(guard);
+
+        /*
+         * Then
+         */
+        assert!(text.contains("orderly_messages_received_total{exchange=\"kraken\"}"));
+        assert!(text.contains("orderly_best_bid{symbol=\"METRICS/TEST\"} 100"));
+        assert!(text.contains("orderly_best_ask{symbol=\"METRICS/TEST\"} 101"));
+        assert!(text.contains("orderly_grpc_subscribers{stream=\"book_summary\",symbol=\"METRICS/TEST\"} 1"));
+    }
+
+    #[test]
+    fn should_not_observe_replayed_messages() {
+        /*
+         * When
+         */
+        let replaying = unobserved(observed);
+
+        /*
+         * Then
+         */
+        assert!(!replaying);
+        assert!(observed());
+    }
+}
@@ -0,0 +1,529 @@
+use crate::error::{self, Error};
+use crate::orderbook::{Exchange, FullBook, InTick, ToLevel};
+use crate::venue::Parsed;
+use crate::record::Tape;
+use crate::websocket::{self, Ack};
+use crate::{metrics, orderbook};
+use chrono::{TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, info, trace};
+use tungstenite::protocol::Message;
+
+pub(crate) const OKX_WS_URL: &str = "wss://ws.okx.com:8443/ws/v5/public";
+
+/// OKX closes connections on which nothing was received for 30 seconds, and recommends a `ping` text
+/// once the client has been waiting for less than that.
+pub(crate) const PING_EVERY: Duration = Duration::from_secs(20);
+
+/// Levels per side the checksum of a book is computed over.
+const CHECKSUM_LEVELS: usize = 25;
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Event {
+    Control(Control),
+
+    Push(Push),
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Control {
+    /// Response. Confirms a subscription.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "event": "subscribe",
+    ///   "arg": {
+    ///     "channel": "books",
+    ///     "instId": "BTC-USDT"
+    ///   },
+    ///   "connId": "a4d3ae55"
+    /// }
+    /// ```
+    Subscribe {
+        arg: Arg,
+
+        /// Connection ID
+        #[serde(rename = "connId")]
+        conn_id: Option<String>,
+    },
+
+    Unsubscribe {
+        arg: Arg,
+
+        #[serde(rename = "connId")]
+        conn_id: Option<String>,
+    },
+
+    /// Response. Sent for a request which failed, e.g. a subscription to an unknown instrument.
+    ///
+    /// **Example of payload**
+    /// ```json
+    /// {
+    ///   "event": "error",
+    ///   "code": "60012",
+    ///   "msg": "Invalid request: {\"op\": \"subscribe\", \"argss\":[{ \"channel\" : \"books\", \"instId\" : \"BTC-USDT\"}]}",
+    ///   "connId": "a4d3ae55"
+    /// }
+    /// ```
+    Error {
+        code: String,
+
+        msg: String,
+
+        #[serde(rename = "connId")]
+        conn_id: Option<String>,
+    },
+}
+
+/// Request. Subscribes to or unsubscribes from channels.
+///
+/// **Example of payload**
+/// ```json
+/// {
+///   "op": "subscribe",
+///   "args": [
+///     {
+///       "channel": "books",
+///       "instId": "BTC-USDT"
+///     }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Serialize, PartialEq)]
+struct Request {
+    op: Op,
+
+    args: Vec<Arg>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Op {
+    Subscribe,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct Arg {
+    /// books|books5|bbo-tbt|books50-l2-tbt|books-l2-tbt|...
+    channel: String,
+
+    /// Instrument ID, e.g. `BTC-USDT`
+    #[serde(rename = "instId")]
+    inst_id: String,
+}
+
+/// Publication: Order book levels of the `books` channel. On subscription a snapshot of 400 levels
+/// per side is pushed, followed by updates of the levels which changed, every 100 ms at most.
+///
+/// **Example of snapshot payload**
+///
+/// ```json
+/// {
+///   "arg": {
+///     "channel": "books",
+///     "instId": "BTC-USDT"
+///   },
+///   "action": "snapshot",
+///   "data": [
+///     {
+///       "asks": [
+///         ["8476.98", "415", "0", "13"],
+///         ["8477", "7", "0", "2"]
+///       ],
+///       "bids": [
+///         ["8476.97", "256", "0", "12"],
+///         ["8475.55", "101", "0", "1"]
+///       ],
+///       "ts": "1597026383085",
+///       "checksum": -855196043,
+///       "prevSeqId": -1,
+///       "seqId": 123456
+///     }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+struct Push {
+    arg: Arg,
+
+    action: Action,
+
+    data: Vec<Book>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    /// The whole book, replacing what was held.
+    Snapshot,
+
+    /// Levels which changed, a size of 0 removing the level.
+    Update,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Book {
+    /// Ask levels, ascending from best ask
+    asks: Vec<Level>,
+
+    /// Bid levels, descending from best bid
+    bids: Vec<Level>,
+
+    /// Time the book was generated at, milliseconds since epoch
+    ts: String,
+
+    /// CRC32 of the book after the message, as a signed 32-bit integer
+    checksum: i32,
+
+    /// Sequence ID of the previous message, -1 for a snapshot
+    #[serde(rename = "prevSeqId")]
+    prev_seq_id: i64,
+
+    /// Sequence ID of the message. Equal to `prevSeqId` when nothing changed
+    #[serde(rename = "seqId")]
+    seq_id: i64,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct Level {
+    /// Price level. A `Decimal` keeps the scale it was parsed with, so it prints as the exchange sent it
+    price: Decimal,
+
+    /// Quantity at the price, 0 for a level which was removed
+    size: Decimal,
+
+    /// Deprecated, always "0"
+    #[serde(default)]
+    liquidated_orders: String,
+
+    /// Number of orders at the price
+    #[serde(default)]
+    orders: String,
+}
+
+impl ToLevel for Level {
+    /// Converts a `okx::Level` into a `orderbook::Level`.
+    fn to_level(&self, side: orderbook::Side) -> orderbook::Level {
+        orderbook::Level::new(side, self.price, self.size, Exchange::Okx)
+    }
+}
+
+/// Book of a connection, kept in full, as updates and checksums refer to levels beyond those merged.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Depths {
+    book: FullBook<Level>,
+
+    /// Sequence ID of the last message applied. `None` until the snapshot arrived.
+    seq_id: Option<i64>,
+}
+
+impl Depths {
+    /// Applies the levels of `book`, then checks that the sequence and the checksum still agree with
+    /// those of the exchange.
+    fn apply(&mut self, action: Action, book: &Book, symbol: &str, payload: &str) -> Result<(), Error> {
+        match (action, self.seq_id) {
+            (Action::Snapshot, _) => self.book.clear(),
+            (Action::Update, Some(seq_id)) if seq_id == book.prev_seq_id => {},
+            (Action::Update, seq_id) => return Err(Error::SequenceGap {
+                exchange: Exchange::Okx,
+                symbol: symbol.to_string(),
+                expected: seq_id.unwrap_or_default() as u64,
+                got: book.prev_seq_id as u64,
+                payload: error::excerpt(payload),
+            }),
+        }
+        self.seq_id = Some(book.seq_id);
+
+        for (levels, side) in [(&mut self.book.bids, &book.bids), (&mut self.book.asks, &book.asks)] {
+            for level in side {
+                match level.size.is_zero() {
+                    true => levels.remove(&level.price),
+                    false => levels.insert(level.price, level.clone()),
+                };
+            }
+        }
+
+        let computed = self.checksum();
+        if computed != book.checksum {
+            return Err(Error::ChecksumMismatch {
+                exchange: Exchange::Okx,
+                symbol: symbol.to_string(),
+                expected: book.checksum as i64,
+                computed: computed as i64,
+                payload: error::excerpt(payload),
+            });
+        }
+        Ok(())
+    }
+
+    /// CRC32 of the best `CHECKSUM_LEVELS` bids and asks, alternating from the best bid as
+    /// `bid price:bid size:ask price:ask size:...`. The longer side goes on alone once the other ends.
+    fn checksum(&self) -> i32 {
+        let bids: Vec<&Level> = self.book.bids.values().rev().take(CHECKSUM_LEVELS).collect();
+        let asks: Vec<&Level> = self.book.asks.values().take(CHECKSUM_LEVELS).collect();
+
+        let mut fields = vec![];
+        for i in 0..CHECKSUM_LEVELS {
+            for side in [&bids, &asks] {
+                if let Some(level) = side.get(i) {
+                    fields.push(format!("{}:{}", level.price, level.size));
+                }
+            }
+        }
+        crc32fast::hash(fields.join(":").as_bytes()) as i32
+    }
+
+
+    fn to_tick(&self) -> InTick {
+        self.book.to_tick(Exchange::Okx, |side, _, level| level.to_level(side))
+    }
+}
+
+/// Returns the WebSocket once the subscription is confirmed, with the messages received until then.
+/// The `books` channel always streams 400 levels per side.
+pub(crate) async fn connect(url: &str, symbol: &str, tape: &Tape) -> Result<(websocket::WsStream, Vec<Message>), Error> {
+    let mut ws_stream = websocket::connect(Exchange::Okx, symbol, url, tape).await?;
+    websocket::subscribe(&mut ws_stream, Exchange::Okx, symbol, url, subscription(symbol)?, tape).await?;
+    let backlog = websocket::await_ack(&mut ws_stream, Exchange::Okx, symbol, url, ack, tape).await?;
+    Ok((ws_stream, backlog))
+}
+
+/// OKX answers an unknown instrument with an `error` event.
+fn ack(text: &str) -> Ack {
+    match deserialize(text) {
+        Ok(Event::Control(Control::Subscribe { .. })) => Ack::Subscribed,
+        Ok(Event::Control(Control::Error { code, msg, .. })) => Ack::Rejected(format!("{} ({})", msg, code)),
+        _ => Ack::Pending,
+    }
+}
+
+fn subscription(symbol: &str) -> Result<String, Error> {
+    let sub = Request {
+        op: Op::Subscribe,
+        args: vec![Arg { channel: "books".to_string(), inst_id: inst_id(symbol) }],
+    };
+    let payload = format!("{:?}", sub);
+    serde_json::to_string(&sub).map_err(|e| Error::protocol(Exchange::Okx, symbol, e, &payload))
+}
+
+/// Application level ping, sent every `PING_EVERY`. OKX answers it with a `pong` text.
+pub(crate) fn ping() -> Message {
+    Message::Text("ping".to_string())
+}
+
+/// OKX names instruments with a dash, e.g. `ETH-BTC`.
+fn inst_id(symbol: &str) -> String {
+    symbol.to_uppercase().replace('/', "-")
+}
+
+/// Applies every book message to `depths`, which holds the book of the connection, and passes on the
+/// whole book. A sequence gap or a checksum mismatch fail the message, as the book can no longer be
+/// trusted until the venue subscribes again.
+pub(crate) fn parse(msg: Message, symbol: &str, depths: &mut Depths) -> Result<Parsed, Error> {
+    metrics::message_received(Exchange::Okx, &msg);
+    let x = match msg {
+        Message::Text(x) => x,
+        Message::Binary(x) => { debug!(bytes = x.len(), "binary message"); return Ok(Parsed::Nothing) },
+        Message::Ping(x) => { trace!(bytes = x.len(), "ping"); return Ok(Parsed::Nothing) },
+        Message::Pong(x) => { trace!(bytes = x.len(), "pong"); return Ok(Parsed::Nothing) },
+        Message::Close(x) => { info!(frame = ?x, "close"); return Ok(Parsed::Nothing) },
+        Message::Frame(x) => { debug!(bytes = x.len(), "raw frame"); return Ok(Parsed::Nothing) },
+    };
+    trace!(%x, "text message");
+    // the answer to a `ping` text, which is not JSON
+    if x == "pong" {
+        trace!("pong");
+        return Ok(Parsed::Nothing);
+    }
+
+    let e = deserialize(&x).map_err(|e| metrics::parse_error(Exchange::Okx, symbol, e, &x))?;
+    match e {
+        Event::Control(Control::Error { code, msg, .. }) => Ok(Parsed::Error(format!("{} ({})", msg, code))),
+        Event::Control(control) => {
+            info!(event = ?control, "control message");
+            Ok(Parsed::Nothing)
+        },
+        Event::Push(push) => {
+            for book in &push.data {
+                trace!(action = ?push.action, seq_id = book.seq_id, "book");
+                depths.apply(push.action, book, symbol, &x)?;
+                if let Some(ts) = book.ts.parse().ok().and_then(|millis| Utc.timestamp_millis_opt(millis).single()) {
+                    if push.action == Action::Update {
+                        metrics::message_age(Exchange::Okx, ts);
+                    }
+                }
+            }
+            Ok(Parsed::Tick(depths.to_tick()))
+        },
+    }
+}
+
+fn deserialize(s: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(s)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use crate::okx::*;
+
+    fn level(price: Decimal, size: Decimal, orders: &str) -> Level {
+        Level { price, size, liquidated_orders: "0".to_string(), orders: orders.to_string() }
+    }
+
+    #[test]
+    fn should_deserialize_snapshot() -> serde_json::Result<()> {
+        assert_eq!(deserialize(include_str!("../tests/fixtures/okx/books_snapshot.json"))?,
+                   Event::Push(Push {
+                       arg: Arg { channel: "books".to_string(), inst_id: "ETH-BTC".to_string() },
+                       action: Action::Snapshot,
+                       data: vec![Book {
+                           asks: vec![level(dec!(0.06901), dec!(3.0), "2"), level(dec!(0.06902), dec!(6.5), "4")],
+                           bids: vec![level(dec!(0.06900), dec!(5.1), "3"), level(dec!(0.06899), dec!(1.2), "1")],
+                           ts: "1656000000100".to_string(),
+                           checksum: 1828829918,
+                           prev_seq_id: -1,
+                           seq_id: 1000,
+                       }],
+                   })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_deserialize_control_messages() -> serde_json::Result<()> {
+        assert_eq!(deserialize(include_str!("../tests/fixtures/okx/subscribe.json"))?,
+                   Event::Control(Control::Subscribe {
+                       arg: Arg { channel: "books".to_string(), inst_id: "ETH-BTC".to_string() },
+                       conn_id: Some("a4d3ae55".to_string()),
+                   })
+        );
+        assert!(matches!(deserialize(include_str!("../tests/fixtures/okx/error.json"))?,
+                         Event::Control(Control::Error { code, .. }) if code == "60018"));
+        Ok(())
+    }
+
+    #[test]
+    fn should_serialize_subscription() {
+        assert_eq!(subscription("eth/btc").unwrap(),
+                   r#"{"op":"subscribe","args":[{"channel":"books","instId":"ETH-BTC"}]}"#);
+        assert_eq!(ping(), Message::Text("ping".to_string()));
+    }
+
+    #[test]
+    fn should_ack_subscription() {
+        assert_eq!(ack(include_str!("../tests/fixtures/okx/subscribe.json")), Ack::Subscribed);
+        assert!(matches!(ack(include_str!("../tests/fixtures/okx/error.json")), Ack::Rejected(reason) if reason.ends_with("(60018)")));
+        assert_eq!(ack(include_str!("../tests/fixtures/okx/books_snapshot.json")), Ack::Pending);
+    }
+
+    #[test]
+    fn should_compute_checksum() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        for (price, size) in [(dec!(3366.1), dec!(7)), (dec!(3366), dec!(6))] {
+            depths.book.bids.insert(price, level(price, size, "1"));
+        }
+        for (price, size) in [(dec!(3366.8), dec!(9)), (dec!(3368), dec!(8))] {
+            depths.book.asks.insert(price, level(price, size, "1"));
+        }
+
+        /*
+         * When
+         */
+        let checksum = depths.checksum();
+
+        /*
+         * Then
+         */
+        // of "3366.1:7:3366.8:9:3366:6:3368:8"
+        assert_eq!(checksum, -1881014294);
+    }
+
+    #[test]
+    fn should_apply_updates_to_snapshot() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+
+        /*
+         * When
+         */
+        parse(Message::Text(include_str!("../tests/fixtures/okx/books_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+        let parsed = parse(Message::Text(include_str!("../tests/fixtures/okx/books_update.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(parsed, Parsed::Tick(InTick {
+            exchange: Exchange::Okx,
+            bids: vec![
+                orderbook::Level::new(orderbook::Side::Bid, dec!(0.06899), dec!(1.2), Exchange::Okx),
+                orderbook::Level::new(orderbook::Side::Bid, dec!(0.06897), dec!(2), Exchange::Okx),
+            ],
+            asks: vec![
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.06901), dec!(2.5), Exchange::Okx),
+                orderbook::Level::new(orderbook::Side::Ask, dec!(0.06902), dec!(6.5), Exchange::Okx),
+            ],
+        }));
+        assert_eq!(depths.seq_id, Some(1001));
+    }
+
+    #[test]
+    fn should_fail_on_checksum_mismatch() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        parse(Message::Text(include_str!("../tests/fixtures/okx/books_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+
+        /*
+         * When
+         */
+        let res = parse(Message::Text(include_str!("../tests/fixtures/okx/books_update_bad_checksum.json").to_string()), "ETH/BTC", &mut depths);
+
+        /*
+         * Then
+         */
+        assert!(matches!(res, Err(Error::ChecksumMismatch { expected: 828352711, computed: 828352710, .. })), "{:?}", res);
+    }
+
+    #[test]
+    fn should_fail_on_sequence_gap() {
+        /*
+         * Given
+         */
+        let mut depths = Depths::default();
+        let update = include_str!("../tests/fixtures/okx/books_update.json");
+
+        /*
+         * When
+         */
+        let before_snapshot = parse(Message::Text(update.to_string()), "ETH/BTC", &mut depths);
+        parse(Message::Text(include_str!("../tests/fixtures/okx/books_snapshot.json").to_string()), "ETH/BTC", &mut depths).unwrap();
+        parse(Message::Text(update.to_string()), "ETH/BTC", &mut depths).unwrap();
+        let replayed = parse(Message::Text(update.to_string()), "ETH/BTC", &mut depths);
+
+        /*
+         * Then
+         */
+        assert!(matches!(before_snapshot, Err(Error::SequenceGap { got: 1000, .. })), "{:?}", before_snapshot);
+        assert!(matches!(replayed, Err(Error::SequenceGap { expected: 1001, got: 1000, .. })), "{:?}", replayed);
+    }
+
+    #[test]
+    fn should_report_errors_and_ignore_pong() {
+        let mut depths = Depths::default();
+        assert!(matches!(parse(Message::Text(include_str!("../tests/fixtures/okx/error.json").to_string()), "ETH/BTC", &mut depths),
+                         Ok(Parsed::Error(reason)) if reason.starts_with("Wrong URL or channel")));
+        assert_eq!(parse(Message::Text("pong".to_string()), "ETH/BTC", &mut depths).unwrap(), Parsed::Nothing);
+    }
+}
@@ -1,7 +1,12 @@
+use crate::config::InstrumentKind;
+use crate::kraken;
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct InTick {
@@ -29,38 +34,129 @@ impl OutTick {
             asks: vec![],
         }
     }
+
+    /// Builds an `OutTick` from ascending bids and asks, keeping the best `depth` levels of each side.
+    fn from_sorted(bids: Vec<Level>, asks: Vec<Level>, depth: usize) -> OutTick {
+        let bids: Vec<Level> = bids.into_iter().rev().take(depth).collect();
+        let asks: Vec<Level> = asks.into_iter().take(depth).collect();
+
+        OutTick::from_best(bids, asks)
+    }
+
+    /// Builds an `OutTick` from bids and asks which are both sorted best first.
+    fn from_best(bids: Vec<Level>, asks: Vec<Level>) -> OutTick {
+        let spread = match (bids.first(), asks.first()) {
+            (Some(b), Some(a)) => a.price - b.price,
+            (_, _) => dec!(0),
+        };
+
+        OutTick { spread, bids, asks }
+    }
+
+    /// Keeps only the levels of the given exchanges.
+    pub(crate) fn retain_exchanges(self, exchanges: &[Exchange]) -> OutTick {
+        let bids = self.bids.into_iter().filter(|l| exchanges.contains(&l.exchange)).collect();
+        let asks = self.asks.into_iter().filter(|l| exchanges.contains(&l.exchange)).collect();
+
+        OutTick::from_best(bids, asks)
+    }
 }
 
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
-pub(crate) enum Exchange {
+#[derive(Debug, Clone, Copy, Hash, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Exchange {
     Bitstamp,
     Binance,
     Kraken,
     Coinbase,
+    Okx,
+    Bybit,
+    Bitfinex,
+    Gemini,
+    Kucoin,
+    Deribit,
+    #[serde(rename = "binance_futures")]
+    BinanceFutures,
+    #[serde(rename = "binance_us")]
+    BinanceUs,
+}
+
+impl Exchange {
+    pub(crate) const ALL: [Exchange; 12] = [
+        Exchange::Bitstamp,
+        Exchange::Binance,
+        Exchange::Kraken,
+        Exchange::Coinbase,
+        Exchange::Okx,
+        Exchange::Bybit,
+        Exchange::Bitfinex,
+        Exchange::Gemini,
+        Exchange::Kucoin,
+        Exchange::Deribit,
+        Exchange::BinanceFutures,
+        Exchange::BinanceUs,
+    ];
 }
 
-impl ToString for Exchange {
-    fn to_string(&self) -> String {
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Exchange::Bitstamp => "bitstamp".to_string(),
-            Exchange::Binance => "binance".to_string(),
-            Exchange::Kraken => "kraken".to_string(),
-            Exchange::Coinbase => "coinbase".to_string(),
+            Exchange::Bitstamp => write!(f, "bitstamp"),
+            Exchange::Binance => write!(f, "binance"),
+            Exchange::Kraken => write!(f, "kraken"),
+            Exchange::Coinbase => write!(f, "coinbase"),
+            Exchange::Okx => write!(f, "okx"),
+            Exchange::Bybit => write!(f, "bybit"),
+            Exchange::Bitfinex => write!(f, "bitfinex"),
+            Exchange::Gemini => write!(f, "gemini"),
+            Exchange::Kucoin => write!(f, "kucoin"),
+            Exchange::Deribit => write!(f, "deribit"),
+            Exchange::BinanceFutures => write!(f, "binance_futures"),
+            Exchange::BinanceUs => write!(f, "binance_us"),
         }
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+impl FromStr for Exchange {
+    type Err = String;
+
+    /// Parses the lowercase exchange name as printed by `Display`, case-insensitively.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Exchange::ALL.iter()
+            .find(|e| e.to_string().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| format!("unknown exchange: {}", s))
+    }
+}
+
+/// Normalizes a currency pair such as `eth/btc` into the `ETH/BTC` form the exchange adapters expect.
+pub(crate) fn parse_symbol(s: &str) -> Result<String, String> {
+    match s.trim().split_once('/') {
+        Some((base, quote))
+            if !base.is_empty() && !quote.is_empty()
+                && base.chars().chain(quote.chars()).all(|c| c.is_ascii_alphanumeric()) =>
+            Ok(format!("{}/{}", base, quote).to_uppercase()),
+        _ => Err(format!("symbol must look like ETH/BTC, got: {:?}", s)),
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub(crate) struct Level {
     pub(crate) side: Side,
     pub(crate) price: Decimal,
     pub(crate) amount: Decimal,
     pub(crate) exchange: Exchange,
+    pub(crate) kind: InstrumentKind,
 }
 
 impl Level {
+    /// A level of a spot book.
     pub(crate) fn new(side: Side, price: Decimal, amount: Decimal, exchange: Exchange) -> Level {
-        Level{side, price, amount, exchange}
+        Level{side, price, amount, exchange, kind: InstrumentKind::Spot}
+    }
+
+    pub(crate) fn with_kind(self, kind: InstrumentKind) -> Level {
+        Level{kind, ..self}
     }
 }
 
@@ -76,20 +172,20 @@ impl Ord for Level {
 
 impl PartialOrd for Level {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match (self.price.partial_cmp(&other.price), &self.side) {
-            (Some(Ordering::Equal), Side::Bid) => self.amount.partial_cmp(&other.amount),
-            (Some(Ordering::Equal), Side::Ask) => self.amount.partial_cmp(&other.amount).map(Ordering::reverse),
-            (ord, _) => ord,
-        }
+        Some(self.cmp(other))
     }
 }
 
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub(crate) enum Side {
     Bid,
     Ask,
 }
 
+/// Most levels per side an adapter passes on from a single message.
+pub(crate) const MAX_DEPTH: usize = 100;
+
 pub(crate) trait ToLevel {
     fn to_level(&self, side: Side) -> Level;
 }
@@ -103,7 +199,7 @@ impl<T> ToLevels for Vec<T>
 {
     fn to_levels(&self, side: Side, depth: usize) -> Vec<Level> {
         let levels = match self.len() > depth {
-            true => self.split_at(depth).0.to_vec(), // only keep 10
+            true => self.split_at(depth).0.to_vec(), // only keep the first levels
             false => self.clone(),
         };
 
@@ -113,9 +209,49 @@ impl<T> ToLevels for Vec<T>
     }
 }
 
+/// Whole book of an adapter whose messages change any of its levels, by price, of which only the top
+/// `MAX_DEPTH` levels of each side are passed on. What a level holds is left to the adapter, which
+/// applies the changes and checks the sequence.
+#[derive(Debug, PartialEq)]
+pub(crate) struct FullBook<T> {
+    pub(crate) bids: BTreeMap<Decimal, T>,
+    pub(crate) asks: BTreeMap<Decimal, T>,
+}
+
+impl<T> Default for FullBook<T> {
+    fn default() -> Self {
+        FullBook {
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> FullBook<T> {
+    pub(crate) fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    pub(crate) fn side_mut(&mut self, side: Side) -> &mut BTreeMap<Decimal, T> {
+        match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        }
+    }
+
+    /// The top `MAX_DEPTH` bids and asks of the exchange, best first, as converted by `to_level`.
+    pub(crate) fn to_tick(&self, exchange: Exchange, to_level: impl Fn(Side, Decimal, &T) -> Level) -> InTick {
+        InTick {
+            exchange,
+            bids: self.bids.iter().rev().take(MAX_DEPTH).map(|(price, l)| to_level(Side::Bid, *price, l)).collect(),
+            asks: self.asks.iter().take(MAX_DEPTH).map(|(price, l)| to_level(Side::Ask, *price, l)).collect(),
+        }
+    }
+}
+
 trait Merge {
     fn merge(self, other: Vec<Level>) -> Vec<Level>;
-    fn merge_map(self, other: LevelsMap) -> Vec<Level>;
 }
 
 impl Merge for Vec<Level> {
@@ -127,43 +263,50 @@ impl Merge for Vec<Level> {
         levels.sort_unstable();
         levels
     }
-
-    fn merge_map(self, other: LevelsMap) -> Vec<Level> {
-        let levels: Vec<Level> = other.values().cloned().collect();
-        self.merge(levels)
-    }
 }
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct Exchanges {
-    bitstamp: OrderDepths,
-    binance: OrderDepths,
+    /// Book of each exchange which passes on its top levels whole with every tick.
+    books: BTreeMap<Exchange, OrderDepths>,
     kraken: OrderDepthsMap,
     coinbase: OrderDepthsMap,
+    depth: usize,
+
+    /// Kind of instrument merged, spot unless set.
+    kind: InstrumentKind,
+
+    /// Levels per side kept from each exchange, 10 unless set.
+    venue_depths: BTreeMap<Exchange, usize>,
+
+    /// Taker fee of each exchange, none unless set.
+    fees: BTreeMap<Exchange, Decimal>,
+
+    /// Whether the merged prices are adjusted by the fees, or left as quoted.
+    fee_adjusted: bool,
 }
 
 impl Exchanges {
     pub(crate) fn new() -> Exchanges {
         Exchanges {
-            bitstamp: OrderDepths::new(),
-            binance: OrderDepths::new(),
+            books: BTreeMap::new(),
             kraken: OrderDepthsMap::new(),
             coinbase: OrderDepthsMap::new(),
+            depth: 10,
+            kind: InstrumentKind::Spot,
+            venue_depths: BTreeMap::new(),
+            fees: BTreeMap::new(),
+            fee_adjusted: false,
         }
     }
 
     /// Extracts the bids and asks from the `InTick`, then adds into its corresponding
     /// orderbook of the exchange.
     pub(crate) fn update(&mut self, t: InTick) {
+        let depth = self.venue_depth(&t.exchange);
         match t.exchange {
-            Exchange::Bitstamp => {
-                self.bitstamp.bids = t.bids;
-                self.bitstamp.asks = t.asks;
-            },
-            Exchange::Binance => {
-                self.binance.bids = t.bids;
-                self.binance.asks = t.asks;
-            },
+            // Kraken streams the book subscribed, rounded up from the depth, and only sends a level
+            // beyond the top as it enters it: the whole book is kept, and its top merged
             Exchange::Kraken => {
                 let bids = t.bids.into_iter()
                     .map(|l| (l.price, l))
@@ -172,8 +315,8 @@ impl Exchanges {
                     .map(|l| (l.price, l))
                     .collect::<LevelsMap>();
 
-                self.kraken.bids.extend_and_keep(bids, 10);
-                self.kraken.asks.extend_and_keep(asks, 10);
+                self.kraken.bids.extend_and_keep(bids, kraken::book_depth(depth), Side::Bid);
+                self.kraken.asks.extend_and_keep(asks, kraken::book_depth(depth), Side::Ask);
             },
             Exchange::Coinbase => {
                 let bids = t.bids.into_iter()
@@ -183,36 +326,147 @@ impl Exchanges {
                     .map(|l| (l.price, l))
                     .collect::<LevelsMap>();
 
-                self.coinbase.bids.extend_and_keep(bids, 10);
-                self.coinbase.asks.extend_and_keep(asks, 10);
-            }
+                self.coinbase.bids.extend_and_keep(bids, depth, Side::Bid);
+                self.coinbase.asks.extend_and_keep(asks, depth, Side::Ask);
+            },
+            // the other adapters pass on the top of their book whole, having applied any changes to
+            // a book of their own
+            _ => {
+                let book = self.books.entry(t.exchange).or_insert_with(OrderDepths::new);
+                book.bids = t.bids.into_iter().take(depth).collect();
+                book.asks = t.asks.into_iter().take(depth).collect();
+            },
         }
     }
 
-    /// Returns a new `OutTick` containing the merge bids and asks from both orderbooks.
+    /// Drops the orderbook of the exchange, then adds the bids and asks of the `InTick`, which hold
+    /// its whole book.
+    pub(crate) fn replace(&mut self, t: InTick) {
+        self.clear(&t.exchange);
+        self.update(t);
+    }
+
+    /// Returns a new `OutTick` containing the merge bids and asks from all orderbooks of the kind
+    /// merged, with prices adjusted by the fee of their exchange if fee adjusted.
     pub(crate) fn to_tick(&self) -> OutTick {
-        let bids: Vec<Level> =
-            self.bitstamp.bids.clone()
-                .merge(self.binance.bids.clone())
-                .merge_map(self.kraken.bids.clone())
-                .merge_map(self.coinbase.bids.clone())
-                .into_iter().rev().take(10)
-                .collect();
+        let (bids, asks) = Exchange::ALL.iter()
+            .fold((vec![], vec![]), |(bids, asks), exchange| {
+                let (venue_bids, venue_asks) = self.levels(exchange);
+                let (venue_bids, venue_asks) = (self.of_kind(venue_bids), self.of_kind(venue_asks));
+                let fee = self.fee(exchange);
+                (bids.merge(venue_bids.with_fee(fee)), asks.merge(venue_asks.with_fee(fee)))
+            });
 
-        let asks: Vec<Level> =
-            self.bitstamp.asks.clone()
-                .merge(self.binance.asks.clone())
-                .merge_map(self.kraken.asks.clone())
-                .merge_map(self.coinbase.asks.clone())
-                .into_iter().take(10)
-                .collect();
+        OutTick::from_sorted(bids, asks, self.depth)
+    }
 
-        let spread = match (bids.first(), asks.first()) {
-            (Some(b), Some(a)) => a.price - b.price,
-            (_, _) => dec!(0),
-        };
+    /// Best bid and best ask across the orderbooks of the kind merged, as quoted by their exchange
+    /// whether the merge is fee adjusted or not.
+    pub(crate) fn best_unadjusted(&self) -> (Option<Level>, Option<Level>) {
+        let (bids, asks) = Exchange::ALL.iter()
+            .fold((vec![], vec![]), |(mut bids, mut asks), exchange| {
+                let (venue_bids, venue_asks) = self.levels(exchange);
+                bids.extend(self.of_kind(venue_bids));
+                asks.extend(self.of_kind(venue_asks));
+                (bids, asks)
+            });
 
-        OutTick { spread, bids, asks }
+        (bids.into_iter().max_by_key(|l| l.price), asks.into_iter().min_by_key(|l| l.price))
+    }
+
+    /// Returns a new `OutTick` containing only the bids and asks of the given exchange, as quoted by it.
+    pub(crate) fn to_venue_tick(&self, exchange: &Exchange) -> OutTick {
+        let (bids, asks) = self.levels(exchange);
+
+        OutTick::from_sorted(vec![].merge(bids), vec![].merge(asks), self.depth)
+    }
+
+    /// Unsorted bids and asks of the exchange, at most the venue depth of each.
+    fn levels(&self, exchange: &Exchange) -> (Vec<Level>, Vec<Level>) {
+        match exchange {
+            Exchange::Kraken => (
+                self.kraken.bids.values().rev().take(self.venue_depth(exchange)).cloned().collect(),
+                self.kraken.asks.values().take(self.venue_depth(exchange)).cloned().collect(),
+            ),
+            Exchange::Coinbase => (
+                self.coinbase.bids.values().cloned().collect(),
+                self.coinbase.asks.values().cloned().collect(),
+            ),
+            _ => self.books.get(exchange)
+                .map(|book| (book.bids.clone(), book.asks.clone()))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Drops the orderbook of the exchange, so it no longer takes part in the merge.
+    pub(crate) fn clear(&mut self, exchange: &Exchange) {
+        match exchange {
+            Exchange::Kraken => self.kraken = OrderDepthsMap::new(),
+            Exchange::Coinbase => self.coinbase = OrderDepthsMap::new(),
+            _ => {
+                self.books.remove(exchange);
+            },
+        }
+    }
+
+    /// Sets the number of levels per side published in the merged `OutTick`.
+    pub(crate) fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+    }
+
+    /// Sets the number of levels per side kept from the exchange, and its taker fee as a fraction.
+    pub(crate) fn set_venue(&mut self, exchange: Exchange, depth: usize, fee: Decimal) {
+        self.venue_depths.insert(exchange, depth);
+        self.fees.insert(exchange, fee);
+    }
+
+    fn venue_depth(&self, exchange: &Exchange) -> usize {
+        self.venue_depths.get(exchange).copied().unwrap_or(10)
+    }
+
+    /// Adjusts the merged prices by the fee of their exchange from now on, or leaves them as quoted.
+    pub(crate) fn set_fee_adjusted(&mut self, fee_adjusted: bool) {
+        self.fee_adjusted = fee_adjusted;
+    }
+
+    /// Fee the prices of the exchange are adjusted by in the merge, none unless fee adjusted.
+    fn fee(&self, exchange: &Exchange) -> Decimal {
+        match self.fee_adjusted {
+            true => self.fees.get(exchange).copied().unwrap_or_default(),
+            false => Decimal::ZERO,
+        }
+    }
+
+    /// Sets the kind of instrument whose books are merged.
+    pub(crate) fn set_kind(&mut self, kind: InstrumentKind) {
+        self.kind = kind;
+    }
+
+    fn of_kind(&self, levels: Vec<Level>) -> Vec<Level> {
+        levels.into_iter().filter(|l| l.kind == self.kind).collect()
+    }
+}
+
+trait WithFee {
+    fn with_fee(self, fee: Decimal) -> Vec<Level>;
+}
+
+impl WithFee for Vec<Level> {
+    /// Adjusts the prices by the taker fee: bids are worth less and asks cost more.
+    fn with_fee(self, fee: Decimal) -> Vec<Level> {
+        if fee.is_zero() {
+            return self;
+        }
+
+        self.into_iter()
+            .map(|mut l| {
+                l.price = match l.side {
+                    Side::Bid => l.price * (dec!(1) - fee),
+                    Side::Ask => l.price * (dec!(1) + fee),
+                };
+                l
+            })
+            .collect()
     }
 }
 
@@ -252,18 +506,31 @@ trait ExtendAndKeep {
     fn extend_and_keep(
         &mut self,
         other: LevelsMap,
-        index: usize,
+        depth: usize,
+        side: Side,
     );
 }
 
 impl ExtendAndKeep for LevelsMap {
-    /// Merges two `BTreeMap`. Returns everything before the given index.
-    fn extend_and_keep(&mut self, other: LevelsMap, i: usize) {
+    /// Merges two `BTreeMap`, then keeps the best `depth` levels of the side: the highest bids and
+    /// the lowest asks.
+    fn extend_and_keep(&mut self, other: LevelsMap, depth: usize, side: Side) {
         self.extend(other);
         self.retain(|_k, v| !v.amount.eq(&dec!(0))); // remove where volume is 0
-        if self.len() > i {
-            let key = self.keys().collect::<Vec<&Decimal>>()[i].clone();
-            self.split_off(&key);
+        if self.len() <= depth {
+            return;
+        }
+        match side {
+            Side::Bid => {
+                if let Some(key) = self.keys().nth(self.len() - depth).copied() {
+                    *self = self.split_off(&key);
+                }
+            },
+            Side::Ask => {
+                if let Some(key) = self.keys().nth(depth).copied() {
+                    self.split_off(&key);
+                }
+            },
         }
     }
 }
@@ -316,7 +583,7 @@ mod test {
          * Then
          */
         assert_eq!(exchanges, Exchanges {
-            bitstamp: OrderDepths {
+            books: BTreeMap::from([(Exchange::Bitstamp, OrderDepths {
                 bids: vec![
                     Level::new(Side::Bid, dec!(0.07358322), dec!(0.46500000), Exchange::Bitstamp),
                     Level::new(Side::Bid, dec!(0.07357954), dec!(8.50000000), Exchange::Bitstamp),
@@ -341,10 +608,14 @@ mod test {
                     Level::new(Side::Ask, dec!(0.07375736), dec!(0.00275804), Exchange::Bitstamp),
                     Level::new(Side::Ask, dec!(0.07377938), dec!(0.00275807), Exchange::Bitstamp),
                 ],
-            },
-            binance: OrderDepths::new(),
+            })]),
             kraken: OrderDepthsMap::new(),
             coinbase: OrderDepthsMap::new(),
+            depth: 10,
+            kind: InstrumentKind::Spot,
+            venue_depths: BTreeMap::new(),
+            fees: BTreeMap::new(),
+            fee_adjusted: false,
         });
     }
 
@@ -661,4 +932,242 @@ mod test {
             }
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn should_keep_venue_depth() {
+        /*
+         * Given
+         */
+        let mut exchanges = Exchanges::new();
+        exchanges.set_venue(Exchange::Binance, 2, dec!(0));
+        let t = InTick {
+            exchange: Exchange::Binance,
+            bids: vec![
+                Level::new(Side::Bid, dec!(10), dec!(1), Exchange::Binance),
+                Level::new(Side::Bid, dec!(9), dec!(1), Exchange::Binance),
+                Level::new(Side::Bid, dec!(8), dec!(1), Exchange::Binance),
+            ],
+            asks: vec![
+                Level::new(Side::Ask, dec!(11), dec!(1), Exchange::Binance),
+                Level::new(Side::Ask, dec!(12), dec!(1), Exchange::Binance),
+                Level::new(Side::Ask, dec!(13), dec!(1), Exchange::Binance),
+            ],
+        };
+
+        /*
+         * When
+         */
+        exchanges.update(t);
+
+        /*
+         * Then
+         */
+        assert_eq!(exchanges.to_tick(), OutTick {
+            spread: dec!(1),
+            bids: vec![
+                Level::new(Side::Bid, dec!(10), dec!(1), Exchange::Binance),
+                Level::new(Side::Bid, dec!(9), dec!(1), Exchange::Binance),
+            ],
+            asks: vec![
+                Level::new(Side::Ask, dec!(11), dec!(1), Exchange::Binance),
+                Level::new(Side::Ask, dec!(12), dec!(1), Exchange::Binance),
+            ],
+        });
+    }
+
+    #[test]
+    fn should_keep_best_levels_of_kraken_updates() {
+        /*
+         * Given
+         */
+        let mut exchanges = Exchanges::new();
+        exchanges.set_venue(Exchange::Kraken, 2, dec!(0));
+        exchanges.update(InTick {
+            exchange: Exchange::Kraken,
+            bids: vec![
+                Level::new(Side::Bid, dec!(10), dec!(1), Exchange::Kraken),
+                Level::new(Side::Bid, dec!(9), dec!(1), Exchange::Kraken),
+            ],
+            asks: vec![
+                Level::new(Side::Ask, dec!(11), dec!(1), Exchange::Kraken),
+                Level::new(Side::Ask, dec!(12), dec!(1), Exchange::Kraken),
+            ],
+        });
+
+        /*
+         * When
+         */
+        exchanges.update(InTick {
+            exchange: Exchange::Kraken,
+            bids: vec![Level::new(Side::Bid, dec!(10.5), dec!(2), Exchange::Kraken)],
+            asks: vec![Level::new(Side::Ask, dec!(10.8), dec!(2), Exchange::Kraken)],
+        });
+
+        /*
+         * Then
+         */
+        assert_eq!(exchanges.to_tick(), OutTick {
+            spread: dec!(0.3),
+            bids: vec![
+                Level::new(Side::Bid, dec!(10.5), dec!(2), Exchange::Kraken),
+                Level::new(Side::Bid, dec!(10), dec!(1), Exchange::Kraken),
+            ],
+            asks: vec![
+                Level::new(Side::Ask, dec!(10.8), dec!(2), Exchange::Kraken),
+                Level::new(Side::Ask, dec!(11), dec!(1), Exchange::Kraken),
+            ],
+        });
+    }
+
+    #[test]
+    fn should_merge_kraken_levels_beyond_venue_depth_once_top_deleted() {
+        /*
+         * Given
+         */
+        let mut exchanges = Exchanges::new();
+        exchanges.set_venue(Exchange::Kraken, 2, dec!(0));
+        exchanges.update(InTick {
+            exchange: Exchange::Kraken,
+            bids: vec![
+                Level::new(Side::Bid, dec!(10), dec!(1), Exchange::Kraken),
+                Level::new(Side::Bid, dec!(9), dec!(1), Exchange::Kraken),
+                Level::new(Side::Bid, dec!(8), dec!(1), Exchange::Kraken),
+            ],
+            asks: vec![Level::new(Side::Ask, dec!(11), dec!(1), Exchange::Kraken)],
+        });
+
+        /*
+         * When
+         */
+        exchanges.update(InTick {
+            exchange: Exchange::Kraken,
+            bids: vec![Level::new(Side::Bid, dec!(10), dec!(0), Exchange::Kraken)],
+            asks: vec![],
+        });
+
+        /*
+         * Then
+         */
+        assert_eq!(exchanges.to_tick().bids, vec![
+            Level::new(Side::Bid, dec!(9), dec!(1), Exchange::Kraken),
+            Level::new(Side::Bid, dec!(8), dec!(1), Exchange::Kraken),
+        ]);
+    }
+
+    #[test]
+    fn should_merge_with_fees() {
+        /*
+         * Given
+         */
+        let mut exchanges = Exchanges::new();
+        exchanges.set_venue(Exchange::Kraken, 10, dec!(0.01));
+        exchanges.update(InTick {
+            exchange: Exchange::Bitstamp,
+            bids: vec![Level::new(Side::Bid, dec!(100), dec!(1), Exchange::Bitstamp)],
+            asks: vec![Level::new(Side::Ask, dec!(102), dec!(1), Exchange::Bitstamp)],
+        });
+        exchanges.update(InTick {
+            exchange: Exchange::Kraken,
+            bids: vec![Level::new(Side::Bid, dec!(100.5), dec!(2), Exchange::Kraken)],
+            asks: vec![Level::new(Side::Ask, dec!(101), dec!(2), Exchange::Kraken)],
+        });
+
+        /*
+         * When
+         */
+        let quoted = exchanges.to_tick();
+        exchanges.set_fee_adjusted(true);
+        let out_tick = exchanges.to_tick();
+
+        /*
+         * Then
+         */
+        assert_eq!(quoted, OutTick {
+            spread: dec!(0.5),
+            bids: vec![
+                Level::new(Side::Bid, dec!(100.5), dec!(2), Exchange::Kraken),
+                Level::new(Side::Bid, dec!(100), dec!(1), Exchange::Bitstamp),
+            ],
+            asks: vec![
+                Level::new(Side::Ask, dec!(101), dec!(2), Exchange::Kraken),
+                Level::new(Side::Ask, dec!(102), dec!(1), Exchange::Bitstamp),
+            ],
+        });
+        assert_eq!(out_tick, OutTick {
+            spread: dec!(2),
+            bids: vec![
+                Level::new(Side::Bid, dec!(100), dec!(1), Exchange::Bitstamp),
+                Level::new(Side::Bid, dec!(99.495), dec!(2), Exchange::Kraken),
+            ],
+            asks: vec![
+                Level::new(Side::Ask, dec!(102), dec!(1), Exchange::Bitstamp),
+                Level::new(Side::Ask, dec!(102.01), dec!(2), Exchange::Kraken),
+            ],
+        });
+        assert_eq!(exchanges.to_venue_tick(&Exchange::Kraken).bids[0].price, dec!(100.5));
+        assert_eq!(exchanges.best_unadjusted(), (
+            Some(Level::new(Side::Bid, dec!(100.5), dec!(2), Exchange::Kraken)),
+            Some(Level::new(Side::Ask, dec!(101), dec!(2), Exchange::Kraken)),
+        ));
+    }
+
+    #[test]
+    fn should_merge_only_kind_merged() {
+        /*
+         * Given
+         */
+        let mut exchanges = Exchanges::new();
+        exchanges.set_kind(InstrumentKind::Perpetual);
+        exchanges.update(InTick {
+            exchange: Exchange::Bitstamp,
+            bids: vec![Level::new(Side::Bid, dec!(100), dec!(1), Exchange::Bitstamp)],
+            asks: vec![Level::new(Side::Ask, dec!(102), dec!(1), Exchange::Bitstamp)],
+        });
+        let perpetual = |side, price| Level::new(side, price, dec!(5), Exchange::Deribit).with_kind(InstrumentKind::Perpetual);
+        exchanges.update(InTick {
+            exchange: Exchange::Deribit,
+            bids: vec![perpetual(Side::Bid, dec!(100.5))],
+            asks: vec![perpetual(Side::Ask, dec!(101))],
+        });
+
+        /*
+         * When
+         */
+        let out_tick = exchanges.to_tick();
+
+        /*
+         * Then
+         */
+        assert_eq!(out_tick, OutTick {
+            spread: dec!(0.5),
+            bids: vec![perpetual(Side::Bid, dec!(100.5))],
+            asks: vec![perpetual(Side::Ask, dec!(101))],
+        });
+        assert_eq!(exchanges.to_venue_tick(&Exchange::Bitstamp).bids.len(), 1);
+    }
+
+    #[test]
+    fn should_pass_on_top_of_full_book() {
+        /*
+         * Given
+         */
+        let mut book = FullBook::default();
+        for i in 0..(MAX_DEPTH as i64 + 5) {
+            book.bids.insert(Decimal::from(100 - i), dec!(1));
+            book.asks.insert(Decimal::from(101 + i), dec!(1));
+        }
+
+        /*
+         * When
+         */
+        let tick = book.to_tick(Exchange::Okx, |side, price, amount| Level::new(side, price, *amount, Exchange::Okx));
+
+        /*
+         * Then
+         */
+        assert_eq!(tick.bids.len(), MAX_DEPTH);
+        assert_eq!(tick.asks.len(), MAX_DEPTH);
+        assert_eq!((tick.bids[0].price, tick.bids[MAX_DEPTH - 1].price), (dec!(100), dec!(1)));
+        assert_eq!((tick.asks[0].price, tick.asks[MAX_DEPTH - 1].price), (dec!(101), dec!(200)));
+    }
+}
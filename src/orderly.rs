@@ -1,216 +1,856 @@
-use crate::error::{Error, ExchangeErr};
-use crate::grpc::OrderBookService;
-use crate::orderbook::{Exchanges, InTick, OutTick};
-use crate::{bitstamp, stdin, binance, websocket, kraken, coinbase};
-use futures::channel::mpsc::UnboundedSender;
-use futures::{join, SinkExt, StreamExt};
-use log::{debug, error, info};
+use crate::auth::Auth;
+use crate::candle;
+use crate::config::{CandlesConfig, Config, InstrumentKind, ReplayConfig, Venues};
+use crate::console::{self, Command, Response};
+use crate::error::Error;
+use crate::export::{Exporter, Snapshot};
+use crate::grpc::{self, AdminService, OrderBookService};
+use crate::metrics;
+use crate::orderbook::{Exchange, Exchanges, InTick, Level, OutTick};
+use crate::record::{self, Entry, Recorder};
+use crate::replay;
+use crate::trade::Trade;
+use crate::venue::{self, MessageId, Parsed, Parser, Venue, VenueId, VenueState};
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use tokio::sync::{RwLock, watch};
-use tungstenite::protocol::Message;
-
-pub async fn run(
-    symbol: &String,
-    port: usize,
-    no_bitstamp: bool,
-    no_binance: bool,
-    no_kraken: bool,
-    no_coinbase: bool,
-) -> Result<(), Error>
-{
-    let connector = Connector::new();
-    let service = OrderBookService::new(connector.out_ticks.clone());
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, RwLock, watch};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::{debug, debug_span, error, info, instrument, warn};
 
+pub async fn run(config: Config) -> Result<(), Error> {
+    let listener = std::net::TcpListener::bind(config.listen)?;
+    run_with_listener(config, listener).await
+}
+
+/// Runs as `run` but serves gRPC on the given listener instead of binding `listen`, so that a caller
+/// which bound port 0 keeps the port it was given.
+pub async fn run_with_listener(mut config: Config, listener: std::net::TcpListener) -> Result<(), Error> {
+    config.validate()?;
+    let mut exchanges: Vec<Exchange> = Exchange::ALL.iter()
+        .filter(|exchange| config.venues.get(exchange).enabled)
+        .copied()
+        .collect();
+
+    // a replay subscribes what was recorded
+    let replay = match &config.replay {
+        Some(replay) => Some((replay::tracks(&replay.dir)?, replay.speed)),
+        None => None,
+    };
+    if let Some((tracks, _)) = &replay {
+        exchanges.retain(|exchange| tracks.iter().any(|track| track.exchange == *exchange));
+        let mut symbols: Vec<String> = tracks.iter().map(|track| track.symbol.clone()).collect();
+        symbols.sort();
+        symbols.dedup();
+        // clients which do not ask for a symbol still get the configured one, if it was recorded
+        symbols.sort_by_key(|symbol| *symbol != config.symbols[0]);
+        info!(?exchanges, ?symbols, "replaying");
+        config.symbols = symbols;
+    }
+
+    for exchange in &exchanges {
+        let kind = config.venues.kind(exchange);
+        if kind != config.kind {
+            warn!(%exchange, %kind, merged = %config.kind, "venue left out of the merge, its kind is not the one merged");
+        }
+    }
+
+    let auth = Auth::load(config.auth_file.clone())?;
+
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    health.set_service_status("", ServingStatus::NotServing).await;
+    health.set_service_status(grpc::ORDERBOOK_SERVICE, ServingStatus::NotServing).await;
+    health.set_service_status(grpc::ADMIN_SERVICE, ServingStatus::Serving).await;
+
+    let (recorder, writer) = match config.record.clone() {
+        Some(dir) => Recorder::spawn(dir).map(|(recorder, writer)| (Some(recorder), Some(writer)))?,
+        None => (None, None),
+    };
+    let (exporter, export_writer) = match config.export.clone() {
+        Some(export) => Exporter::spawn(export, config.depth, replay.is_none())
+            .map(|(exporter, writer)| (Some(exporter), Some(writer)))?,
+        None => (None, None),
+    };
+
+    let (connector, rx_in_ticks, rx_events) = Connector::new(
+        config.depth, config.kind, config.fee_adjusted, config.venues.clone(), config.candles.clone(), auth.clone(), health, recorder, exporter,
+        config.replay.as_ref());
+    let (tx_requests, rx_requests) = mpsc::channel::<console::Request>(10);
+
+    let service = OrderBookService::new(
+        connector.out_ticks.clone(),
+        connector.trades.clone(),
+        config.candles.is_some().then(|| connector.candles.clone()),
+        connector.tx_venue_states.subscribe(),
+        config.symbols[0].clone(),
+        auth.clone());
+    let admin = AdminService::new(tx_requests.clone());
+
+    // a server which fails, e.g. because its port is taken, stops the aggregator with its error
+    let (tx_fatal, rx_fatal) = mpsc::channel::<Error>(2);
+
+    let tls = config.tls.clone();
+    let tx = tx_fatal.clone();
+    tokio::spawn(async move {
+        if let Err(e) = grpc::serve(listener, tls, auth, service, admin, health_service).await {
+            let _ = tx.send(e).await;
+        }
+    });
+
+    if let Some(metrics_listen) = config.metrics_listen {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics_listen).await {
+                let _ = tx_fatal.send(e).await;
+            }
+        });
+    }
+
+    if config.auth_file.is_some() {
+        reload_auth_on_sighup(tx_requests.clone())?;
+    }
+    stop_on_signals(tx_requests.clone())?;
+    console::spawn(config.console_socket.clone(), tx_requests);
+
+    let rx_replay = match replay {
+        Some((tracks, speed)) => {
+            let (tx_replay, rx_replay) = mpsc::channel(1);
+            replay::spawn(tracks, speed, tx_replay)?;
+            Some(rx_replay)
+        },
+        None => None,
+    };
+
+    let result = connector.run(exchanges, config.symbols, rx_in_ticks, rx_events, rx_requests, rx_fatal, rx_replay).await;
+
+    // the venues are closed, so the recording and the export can be completed
+    for writer in [writer, export_writer].into_iter().flatten() {
+        let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+    }
+
+    result
+}
+
+fn reload_auth_on_sighup(tx_requests: mpsc::Sender<console::Request>) -> Result<(), Error> {
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
     tokio::spawn(async move {
-        service.serve(port).await.expect("Failed to serve grpc");
+        while sighup.recv().await.is_some() {
+            let response = console::request(&tx_requests, Command::ReloadAuth).await;
+            info!(?response, "SIGHUP");
+        }
     });
+    Ok(())
+}
 
-    connector.run(symbol,
-                  no_bitstamp, no_binance, no_kraken, no_coinbase).await?;
+/// Stops the aggregator gracefully on SIGINT or SIGTERM, so that the recording and the export are
+/// complete. A second signal stops it at once.
+fn stop_on_signals(tx_requests: mpsc::Sender<console::Request>) -> Result<(), Error> {
+    use tokio::signal::unix::{signal, SignalKind};
 
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigint.recv() => {},
+            _ = sigterm.recv() => {},
+        }
+        info!("stopping on signal");
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sigint.recv() => {},
+                _ = sigterm.recv() => {},
+            }
+            std::process::exit(130);
+        });
+        let _ = console::request(&tx_requests, Command::Exit).await;
+    });
     Ok(())
 }
 
-pub(crate) type OutTickPair = (watch::Sender<OutTick>, watch::Receiver<OutTick>);
+/// Merged orderbook as handed to the gRPC streams.
+#[derive(Debug, Clone)]
+pub(crate) struct Published {
+    pub(crate) out_tick: OutTick,
+
+    /// When it was published, so that streams can tell how far behind they are.
+    pub(crate) at: Instant,
+
+    /// Exchange message which caused the update. `None` when a venue or the depth changed.
+    pub(crate) cause: Option<MessageId>,
+
+    /// Health of every exchange subscribed for the symbol.
+    pub(crate) venues: BTreeMap<Exchange, venue::Health>,
+}
+
+impl Published {
+    fn new(out_tick: OutTick, cause: Option<MessageId>, venues: BTreeMap<Exchange, venue::Health>) -> Published {
+        Published { out_tick, at: Instant::now(), cause, venues }
+    }
+}
+
+pub(crate) type OutTickPair = (watch::Sender<Published>, watch::Receiver<Published>);
+
+/// Merged orderbook of every subscribed symbol.
+pub(crate) type OutTicks = Arc<RwLock<BTreeMap<String, OutTickPair>>>;
+
+/// Trades of every subscribed symbol, sent to the `Trades` streams as they come in.
+pub(crate) type Trades = Arc<RwLock<BTreeMap<String, broadcast::Sender<Trade>>>>;
+
+/// Trades held for a `Trades` stream which falls behind, beyond which it skips the oldest.
+const TRADES_CAPACITY: usize = 1024;
+
+/// Candles of every subscribed symbol, built from its trades and books.
+pub(crate) type Candles = Arc<RwLock<BTreeMap<String, candle::Engine>>>;
 
 struct Connector {
-    out_ticks: Arc<RwLock<OutTickPair>>,
+    out_ticks: OutTicks,
+    trades: Trades,
+    candles: Candles,
+
+    /// Intervals and history of the candles built. None are built unless set.
+    candle_config: Option<CandlesConfig>,
+
+    /// Exchanges connected for every symbol.
+    exchanges: Vec<Exchange>,
+
+    /// Symbols subscribed on every exchange.
+    symbols: Vec<String>,
+
+    books: BTreeMap<String, Exchanges>,
+    venues: BTreeMap<VenueId, (Venue, VenueState)>,
+    depth: usize,
+
+    /// Kind of instrument merged into the books.
+    kind: InstrumentKind,
+
+    /// Whether the merged prices are adjusted by the fee of their exchange.
+    fee_adjusted: bool,
+
+    /// Endpoint, depth, fee and staleness threshold of each exchange.
+    venue_configs: Venues,
+
+    auth: Auth,
+
+    /// Reports the orderbook service as serving while any venue is live and not stale.
+    health: HealthReporter,
+    serving: bool,
+
+    /// State of every venue, sent to the `VenueStatus` streams whenever more than a counter changes,
+    /// and every second to refresh the timestamps.
+    tx_venue_states: watch::Sender<Vec<VenueState>>,
+
+    tx_in_ticks: UnboundedSender<(MessageId, Parsed)>,
+    tx_events: mpsc::UnboundedSender<venue::Event>,
+
+    /// Records the raw messages of every venue when set.
+    recorder: Option<Recorder>,
+
+    /// Writes the published books to files when set.
+    exporter: Option<Exporter>,
+
+    /// Takes the messages of the venues from a recording instead of the exchanges.
+    replaying: bool,
+
+    /// Stops the aggregator once the replay finished.
+    exit_after_replay: bool,
+
+    /// Counts the replayed messages of every venue.
+    replayed: BTreeMap<VenueId, u64>,
+
+    /// Parses the replayed messages of every venue, as its task would have.
+    replay_parsers: BTreeMap<VenueId, Parser>,
+
+    /// Time the latest replayed message was received at, which is the time of the books it changed.
+    replayed_at: Option<DateTime<Utc>>,
 }
 
 impl Connector {
-    fn new() -> Connector {
-        let out_ticks = Arc::new(RwLock::new(watch::channel(OutTick::new())));
-        Connector { out_ticks }
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        depth: usize,
+        kind: InstrumentKind,
+        fee_adjusted: bool,
+        venue_configs: Venues,
+        candle_config: Option<CandlesConfig>,
+        auth: Auth,
+        health: HealthReporter,
+        recorder: Option<Recorder>,
+        exporter: Option<Exporter>,
+        replay: Option<&ReplayConfig>,
+    ) -> (Connector, UnboundedReceiver<(MessageId, Parsed)>, mpsc::UnboundedReceiver<venue::Event>)
+    {
+        let (tx_in_ticks, rx_in_ticks) = futures::channel::mpsc::unbounded();
+        let (tx_events, rx_events) = mpsc::unbounded_channel();
+
+        let connector = Connector {
+            out_ticks: Arc::new(RwLock::new(BTreeMap::new())),
+            trades: Arc::new(RwLock::new(BTreeMap::new())),
+            candles: Arc::new(RwLock::new(BTreeMap::new())),
+            candle_config,
+            exchanges: vec![],
+            symbols: vec![],
+            books: BTreeMap::new(),
+            venues: BTreeMap::new(),
+            depth,
+            kind,
+            fee_adjusted,
+            venue_configs,
+            auth,
+            health,
+            serving: false,
+            tx_venue_states: watch::channel(vec![]).0,
+            tx_in_ticks,
+            tx_events,
+            recorder,
+            exporter,
+            replaying: replay.is_some(),
+            exit_after_replay: replay.is_some_and(|replay| replay.exit),
+            replayed: BTreeMap::new(),
+            replay_parsers: BTreeMap::new(),
+            replayed_at: None,
+        };
+
+        (connector, rx_in_ticks, rx_events)
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn run(
-        &self,
-        symbol: &String,
-        no_bitstamp: bool,
-        no_binance: bool,
-        no_kraken: bool,
-        no_coinbase: bool,
+        mut self,
+        exchanges: Vec<Exchange>,
+        symbols: Vec<String>,
+        mut rx_in_ticks: UnboundedReceiver<(MessageId, Parsed)>,
+        mut rx_events: mpsc::UnboundedReceiver<venue::Event>,
+        mut rx_requests: mpsc::Receiver<console::Request>,
+        mut rx_fatal: mpsc::Receiver<Error>,
+        mut rx_replay: Option<mpsc::Receiver<Entry>>,
     ) -> Result<(), Error>
     {
-        let (
-            ws_bitstamp,
-            ws_binance,
-            ws_kraken,
-            ws_coinbase,
-        ) = join!(
-            bitstamp::connect(symbol),
-            binance::connect(symbol),
-            kraken::connect(symbol),
-            coinbase::connect(symbol),
-        );
-        let mut ws_bitstamp = ws_bitstamp?;
-        let mut ws_binance = ws_binance?;
-        let mut ws_kraken = ws_kraken?;
-        let mut ws_coinbase = ws_coinbase?;
-
-        let mut rx_stdin = stdin::rx();
-        let (tx_in_ticks, mut rx_in_ticks) = futures::channel::mpsc::unbounded();
-
-        let mut exchanges = Exchanges::new();
-
-        // handle websocket messages
-        loop {
+        for symbol in symbols {
+            let _ = self.add_symbol(symbol).await;
+        }
+        for exchange in exchanges {
+            let _ = self.enable(exchange).await;
+        }
+
+        let mut stale_check = tokio::time::interval(Duration::from_secs(1));
+
+        let result = loop {
+            let mut refresh = false;
             tokio::select! {
-                ws_msg = ws_coinbase.next() => {
-                    let tx = tx_in_ticks.clone();
-
-                    let res = handle(ws_msg)
-                        .and_then(|msg| {
-                            if no_coinbase { Ok(()) }
-                            else { msg.parse_and_send(coinbase::parse, tx) }
-                        })
-                        .map_err(ExchangeErr::Coinbase);
-
-                    if let Err(e) = res {
-                        error!("Err: {:?}", e);
-                        break
-                    }
+                Some((id, parsed)) = rx_in_ticks.next() => self.on_message(id, parsed).await,
+                Some(event) = rx_events.recv() => self.on_event(event).await,
+                entry = next_replayed(&mut rx_replay) => match entry {
+                    Some(entry) => self.on_replayed(entry).await,
+                    None => {
+                        rx_replay = None;
+                        if self.exit_after_replay {
+                            info!("replay finished, stopping");
+                            break Ok(())
+                        }
+                    },
                 },
-                ws_msg = ws_kraken.next() => {
-                    let tx = tx_in_ticks.clone();
-
-                    let res = handle(ws_msg)
-                        .and_then(|msg| {
-                            if no_kraken { Ok(()) }
-                            else { msg.parse_and_send(kraken::parse, tx) }
-                        })
-                        .map_err(ExchangeErr::Kraken);
-
-                    if let Err(e) = res {
-                        error!("Err: {:?}", e);
-                        break
+                Some((command, tx_response)) = rx_requests.recv() => {
+                    info!(?command, "console command");
+                    let exit = command == Command::Exit;
+                    let response = self.execute(command).await;
+                    let _ = tx_response.send(response);
+                    if exit {
+                        break Ok(())
                     }
                 },
-                ws_msg = ws_bitstamp.next() => {
-                    let tx = tx_in_ticks.clone();
-
-                    let res = handle(ws_msg)
-                        .and_then(|msg| {
-                            if no_bitstamp { Ok(()) }
-                            else { msg.parse_and_send(bitstamp::parse, tx) }
-                        })
-                        .map_err(ExchangeErr::Bitstamp);
-
-                    if let Err(e) = res {
-                        error!("Err: {:?}", e);
-                        break
-                    }
+                Some(e) = rx_fatal.recv() => {
+                    error!(error = %e, "stopping");
+                    break Err(e)
                 },
-                ws_msg = ws_binance.next() => {
-                    let tx = tx_in_ticks.clone();
-
-                    let res = handle(ws_msg)
-                        .and_then(|msg| {
-                            if no_binance { Ok(()) }
-                            else { msg.parse_and_send(binance::parse, tx) }
-                        })
-                        .map_err(ExchangeErr::Binance);
-
-                    if let Err(e) = res {
-                        error!("Err: {:?}", e);
-                        break
+                _ = stale_check.tick() => {
+                    // a replay goes at its own pace, and its venues resubscribed as recorded
+                    if !self.replaying {
+                        self.resubscribe_stale();
                     }
+                    self.close_candles().await;
+                    refresh = true;
                 },
-                stdin_msg = rx_stdin.recv() => {
-                    match stdin_msg {
-                        Some(msg) => {
-                            info!("Sent to WS: {:?}", msg);
-                            let _ = ws_coinbase.send(Message::Text(msg)).await;
-                        },
-                        None => break,
-                    }
-                },
-                in_tick = rx_in_ticks.next() => {
-                    match in_tick {
-                        Some(t) => {
-                            debug!("{:?}", t);
-                            exchanges.update(t);
+            };
+            self.report_health().await;
+            self.publish_venue_states(refresh);
+        };
 
-                            let out_tick = exchanges.to_tick();
-                            debug!("{:?}", out_tick);
+        // Gracefully close connections by Close-handshake procedure
+        let venues = std::mem::take(&mut self.venues);
+        futures::future::join_all(venues.into_values().map(|(venue, _)| venue.close())).await;
 
-                            let writer = self.out_ticks.write().await;
-                            let tx = &writer.0;
+        result
+    }
 
-                            tx.send(out_tick).expect("channel should not be closed");
-                        },
-                        _ => {},
-                    }
-                },
-            };
+    async fn on_event(&mut self, event: venue::Event) {
+        let (venue::Event::Connected(id) | venue::Event::Disconnected(id, _)) = &event;
+        let (id, disconnected) = (*id, matches!(event, venue::Event::Disconnected(..)));
+        let (exchange, symbol) = match self.venues.get_mut(&id) {
+            Some((_, state)) => {
+                state.on_event(&event);
+                (state.exchange, state.symbol.clone())
+            },
+            None => return,
+        };
+        if let venue::Event::Disconnected(_, Some(e)) = &event {
+            error!(%exchange, %symbol, error = %e, "disconnected");
+        }
+        if disconnected {
+            if let Some(book) = self.books.get_mut(&symbol) {
+                book.clear(&exchange);
+            }
+        }
+        // the health of the venue is part of the summary
+        self.publish(&symbol, None).await;
+    }
+
+    /// Applies a recorded entry as if the venue of its exchange and symbol had just received it.
+    async fn on_replayed(&mut self, entry: Entry) {
+        let id = match self.venues.iter().find(|(_, (_, state))| state.exchange == entry.exchange && state.symbol == entry.symbol) {
+            Some((id, _)) => *id,
+            None => return,
+        };
+        self.replayed_at = Some(entry.at);
+
+        match entry.event {
+            record::Event::Connect { .. } => self.on_event(venue::Event::Connected(id)).await,
+            record::Event::Closed => self.on_event(venue::Event::Disconnected(id, None)).await,
+            record::Event::Error { message } => {
+                self.on_event(venue::Event::Disconnected(id, Some(Error::Recorded(message)))).await
+            },
+            record::Event::Subscribe { .. } => {},
+            event => {
+                let msg = match event.to_message() {
+                    Some(msg) => msg,
+                    None => return,
+                };
+                let seq = self.replayed.entry(id).or_default();
+                *seq += 1;
+                let message_id = MessageId { venue: id, seq: *seq };
+
+                let parse = self.replay_parsers.entry(id).or_insert_with(|| venue::parser(&entry.exchange, self.venue_configs.get(&entry.exchange)));
+                match debug_span!("message", venue = id, seq = *seq).in_scope(|| metrics::unobserved(|| parse(msg, &entry.symbol))) {
+                    Ok(parsed) => self.on_message(message_id, parsed).await,
+                    // as the venue would have skipped the message
+                    Err(e @ Error::Protocol { .. }) => warn!(error = %e, "skipping message"),
+                    // as the venue would have resubscribed or stopped
+                    Err(e) => self.on_event(venue::Event::Disconnected(id, Some(e))).await,
+                }
+            },
         }
+    }
+
+    async fn on_message(&mut self, id: MessageId, parsed: Parsed) {
+        // messages still in flight from a venue which was closed or stopped in the meantime are
+        // dropped, as the two travel on separate channels
+        let state = match self.venues.get_mut(&id.venue) {
+            Some((_, state)) if state.health != venue::Health::Disconnected => state,
+            _ => return,
+        };
+        state.on_message();
+
+        match parsed {
+            Parsed::Tick(t) => self.on_tick(id, t, false).await,
+            Parsed::Snapshot(t) => self.on_tick(id, t, true).await,
+            Parsed::Status(status, message) => {
+                if state.trading_status != Some(status) {
+                    info!(exchange = %state.exchange, symbol = %state.symbol, ?status, ?message, "trading status");
+                }
+                state.on_status(status, message);
+            },
+            Parsed::Trades(trades) => {
+                let symbol = state.symbol.clone();
+                self.on_trades(&symbol, trades).await;
+            },
+            Parsed::Error(reason) => {
+                let e = Error::Reported { exchange: state.exchange, symbol: state.symbol.clone(), reason };
+                warn!(error = %e, "exchange error");
+                state.last_error = Some(e.to_string());
+            },
+            Parsed::Nothing | Parsed::Reply(_) => {},
+        }
+    }
+
+    /// Applies the tick to the orderbook of its symbol, or replaces the book of its exchange with it,
+    /// and publishes the merged orderbook.
+    #[instrument(name = "merge", level = "debug", skip_all, fields(venue = id.venue, seq = id.seq, exchange = %t.exchange))]
+    async fn on_tick(&mut self, id: MessageId, t: InTick, replace: bool) {
+        debug!(bids = t.bids.len(), asks = t.asks.len(), replace, "tick");
+        let symbol = match self.venues.get_mut(&id.venue) {
+            Some((_, state)) => {
+                state.on_tick();
+                state.symbol.clone()
+            },
+            None => return,
+        };
+        let started = Instant::now();
+        let exchange = t.exchange;
+        if let Some(book) = self.books.get_mut(&symbol) {
+            match replace {
+                true => book.replace(t),
+                false => book.update(t),
+            }
+        }
+        // the books of the venues are only sorted for their candles
+        if self.candle_config.is_some() {
+            let venue_tick = self.books.get(&symbol).map(|book| book.to_venue_tick(&exchange));
+            if let Some(mid) = venue_tick.and_then(|tick| mid(tick.bids.first(), tick.asks.first())) {
+                self.on_mid(&symbol, Some(exchange), mid).await;
+            }
+        }
+        self.publish(&symbol, Some(id)).await;
+        metrics::merged(&symbol, started.elapsed());
+    }
+
+    /// Sends the trades to the `Trades` streams of their symbol, and adds them to its candles.
+    async fn on_trades(&self, symbol: &str, trades: Vec<Trade>) {
+        if let Some(trade) = trades.first() {
+            metrics::trades_received(trade.exchange, trades.len());
+        }
+        if self.candle_config.is_some() {
+            if let Some(engine) = self.candles.write().await.get_mut(symbol) {
+                for trade in &trades {
+                    engine.on_trade(trade, self.venue_kind(&trade.exchange) == self.kind);
+                }
+            }
+        }
+        let reader = self.trades.read().await;
+        if let Some(tx) = reader.get(symbol) {
+            for trade in trades {
+                debug!(%symbol, exchange = %trade.exchange, price = %trade.price, amount = %trade.amount, "trade");
+                // no stream being open is fine
+                let _ = tx.send(trade);
+            }
+        }
+    }
+
+    /// Adds the mid price of the exchange, or of the merged book for `None`, to the candles of the symbol.
+    async fn on_mid(&self, symbol: &str, exchange: Option<Exchange>, mid: Decimal) {
+        if self.candle_config.is_none() {
+            return;
+        }
+        if let Some(engine) = self.candles.write().await.get_mut(symbol) {
+            engine.on_mid(exchange, mid, self.replayed_at.unwrap_or_else(Utc::now));
+        }
+    }
+
+    /// Closes the candles whose interval is over, at the time of the replay when replaying.
+    async fn close_candles(&self) {
+        let now = self.replayed_at.unwrap_or_else(Utc::now);
+        for engine in self.candles.write().await.values_mut() {
+            engine.close_until(now);
+        }
+    }
+
+    /// Kind of instrument the exchange is subscribed to.
+    fn venue_kind(&self, exchange: &Exchange) -> InstrumentKind {
+        self.venue_configs.kind(exchange)
+    }
+
+    async fn execute(&mut self, command: Command) -> Response {
+        let res = match command {
+            Command::Status => return self.status(),
+            Command::Book(exchange, symbol) => {
+                let symbol = symbol.or_else(|| self.symbols.first().cloned()).unwrap_or_default();
+                return match self.books.get(&symbol) {
+                    Some(book) => Response::book(exchange, symbol, book.to_venue_tick(&exchange)),
+                    None => Response::error(format!("{} is not subscribed", symbol)),
+                };
+            },
+            Command::Disable(exchange) => self.disable(exchange).await
+                .map(|_| format!("{} disabled", exchange)),
+            Command::Enable(exchange) => self.enable(exchange).await
+                .map(|_| format!("{} enabled", exchange)),
+            Command::AddSymbol(symbol) => self.add_symbol(symbol.clone()).await
+                .map(|_| format!("{} added", symbol)),
+            Command::RemoveSymbol(symbol) => self.remove_symbol(&symbol).await
+                .map(|_| format!("{} removed", symbol)),
+            Command::Resubscribe(exchange) => self.resubscribe(exchange).await
+                .map(|_| format!("resubscribing to {}", exchange)),
+            Command::Depth(depth) => {
+                self.depth = depth;
+                self.books.values_mut().for_each(|book| book.set_depth(depth));
+                for symbol in self.symbols.clone() {
+                    self.publish(&symbol, None).await;
+                }
+                Ok(format!("depth set to {}", depth))
+            },
+            Command::Raw(exchange, json) => {
+                let sent = self.venues.values()
+                    .filter(|(_, state)| state.exchange == exchange)
+                    .filter(|(venue, _)| venue.send(venue::Command::Raw(json.clone())))
+                    .count();
+                match sent {
+                    0 => Err(format!("{} is not connected", exchange)),
+                    n => Ok(format!("sent to {} connection(s) to {}", n, exchange)),
+                }
+            },
+            Command::ReloadAuth => self.auth.reload()
+                .map(|n| format!("{} client(s) loaded", n))
+                .map_err(|e| e.to_string()),
+            Command::Exit => Ok("shutting down".to_string()),
+        };
+
+        match res {
+            Ok(message) => Response::ok(message),
+            Err(message) => Response::error(message),
+        }
+    }
+
+    fn status(&self) -> Response {
+        Response::Status {
+            exchanges: self.exchanges.clone(),
+            symbols: self.symbols.clone(),
+            depth: self.depth,
+            venues: self.venue_states(),
+        }
+    }
+
+    /// States of all venues, by exchange and symbol.
+    fn venue_states(&self) -> Vec<VenueState> {
+        let mut venues: Vec<VenueState> = self.venues.values()
+            .map(|(_, state)| state.clone())
+            .collect();
+        venues.sort_by(|a, b| (a.exchange, &a.symbol).cmp(&(b.exchange, &b.symbol)));
+        venues
+    }
+
+    /// Sends the venue states to the `VenueStatus` streams if any changed, or anyway on `refresh`.
+    fn publish_venue_states(&self, refresh: bool) {
+        let venues = self.venue_states();
+        let changed = {
+            let current = self.tx_venue_states.borrow();
+            current.len() != venues.len() || current.iter().zip(&venues).any(|(a, b)| a.differs(b))
+        };
+        if changed || refresh {
+            // no stream being open is fine
+            let _ = self.tx_venue_states.send(venues);
+        }
+    }
 
-        // Gracefully close connection by Close-handshake procedure
-        join!(
-            websocket::close(&mut ws_bitstamp),
-            websocket::close(&mut ws_binance),
-            websocket::close(&mut ws_kraken),
-            websocket::close(&mut ws_coinbase)
-        );
+    /// Connects to the exchange for every symbol.
+    async fn enable(&mut self, exchange: Exchange) -> Result<(), String> {
+        if self.exchanges.contains(&exchange) {
+            return Err(format!("{} is already enabled", exchange));
+        }
+        self.exchanges.push(exchange);
+        for symbol in self.symbols.clone() {
+            self.spawn(exchange, symbol);
+        }
+        Ok(())
+    }
 
+    /// Disconnects from the exchange and drops its orderbooks.
+    async fn disable(&mut self, exchange: Exchange) -> Result<(), String> {
+        if !self.exchanges.contains(&exchange) {
+            return Err(format!("{} is not enabled", exchange));
+        }
+        self.exchanges.retain(|e| *e != exchange);
+        self.close_where(|state| state.exchange == exchange);
+        for symbol in self.symbols.clone() {
+            if let Some(book) = self.books.get_mut(&symbol) {
+                book.clear(&exchange);
+            }
+            self.publish(&symbol, None).await;
+        }
         Ok(())
     }
-}
 
-fn handle(
-    ws_msg: Option<Result<Message, tungstenite::Error>>,
-) -> Result<Message, Error>
-{
-    let msg = ws_msg.unwrap_or_else(|| {
-        info!("no message");
-        Err(tungstenite::Error::ConnectionClosed)
-    })?;
+    /// Connects to every enabled exchange for the symbol.
+    async fn add_symbol(&mut self, symbol: String) -> Result<(), String> {
+        if self.symbols.contains(&symbol) {
+            return Err(format!("{} is already subscribed", symbol));
+        }
+        self.symbols.push(symbol.clone());
 
-    Ok(msg)
+        let mut book = Exchanges::new();
+        book.set_depth(self.depth);
+        book.set_kind(self.kind);
+        book.set_fee_adjusted(self.fee_adjusted);
+        for exchange in Exchange::ALL {
+            let config = self.venue_configs.get(&exchange);
+            book.set_venue(exchange, config.depth, config.fee);
+        }
+        self.books.insert(symbol.clone(), book);
+        self.out_ticks.write().await.insert(symbol.clone(), watch::channel(Published::new(OutTick::new(), None, BTreeMap::new())));
+        self.trades.write().await.insert(symbol.clone(), broadcast::channel(TRADES_CAPACITY).0);
+        if let Some(config) = &self.candle_config {
+            self.candles.write().await.insert(symbol.clone(), candle::Engine::new(config.intervals_secs.clone(), config.history));
+        }
+
+        for exchange in self.exchanges.clone() {
+            self.spawn(exchange, symbol.clone());
+        }
+        Ok(())
+    }
+
+    /// Disconnects every exchange from the symbol. Streams of the symbol's orderbook, trades and candles end.
+    /// The first symbol is streamed to requests which do not name one, and cannot be removed.
+    async fn remove_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if !self.symbols.iter().any(|s| s == symbol) {
+            return Err(format!("{} is not subscribed", symbol));
+        }
+        if self.symbols.first().is_some_and(|s| s == symbol) {
+            return Err(format!("{} is the default symbol and cannot be removed", symbol));
+        }
+        self.symbols.retain(|s| s != symbol);
+        self.close_where(|state| state.symbol == symbol);
+        self.books.remove(symbol);
+        self.out_ticks.write().await.remove(symbol);
+        self.trades.write().await.remove(symbol);
+        self.candles.write().await.remove(symbol);
+        metrics::symbol_removed(symbol);
+        Ok(())
+    }
+
+    /// Reconnects every connection to the exchange, restarting those which stopped after an error.
+    async fn resubscribe(&mut self, exchange: Exchange) -> Result<(), String> {
+        if !self.exchanges.contains(&exchange) {
+            return Err(format!("{} is not enabled", exchange));
+        }
+
+        let ids: Vec<VenueId> = self.venues.iter()
+            .filter(|(_, (_, state))| state.exchange == exchange)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            if let Some((venue, state)) = self.venues.get(&id) {
+                let symbol = state.symbol.clone();
+                if !venue.send(venue::Command::Resubscribe) {
+                    metrics::reconnected(exchange);
+                    self.venues.remove(&id);
+                    self.spawn(exchange, symbol.clone());
+                }
+                if let Some(book) = self.books.get_mut(&symbol) {
+                    book.clear(&exchange);
+                }
+                self.publish(&symbol, None).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resubscribes the venues which stopped ticking for longer than the threshold of their exchange,
+    /// or have not ticked for as long since they connected or were resubscribed.
+    fn resubscribe_stale(&mut self) {
+        let now = Utc::now();
+        for (venue, state) in self.venues.values_mut() {
+            let stale_after = match self.venue_configs.get(&state.exchange).stale_after_secs {
+                Some(secs) => chrono::Duration::seconds(secs as i64),
+                None => continue,
+            };
+            if !state.is_stale(now, stale_after) {
+                continue;
+            }
+
+            warn!(exchange = %state.exchange, symbol = %state.symbol, "stale, resubscribing");
+            state.on_resubscribe(now);
+            let secs = stale_after.num_seconds() as u64;
+            state.last_error = Some(Error::Stale { exchange: state.exchange, symbol: state.symbol.clone(), secs }.to_string());
+            // the book is dropped from the merge once the venue reports that it disconnected
+            venue.send(venue::Command::Resubscribe);
+        }
+    }
+
+    /// Flips the health of the orderbook service, and of the server as a whole, once no venue is
+    /// live anymore or the first one is again.
+    async fn report_health(&mut self) {
+        let now = Utc::now();
+        let serving = self.venues.values().any(|(_, state)| {
+            let stale_after = self.venue_configs.get(&state.exchange).stale_after_secs;
+            state.health == venue::Health::Live
+                && !stale_after.is_some_and(|secs| state.is_stale(now, chrono::Duration::seconds(secs as i64)))
+        });
+        if serving == self.serving {
+            return;
+        }
+        self.serving = serving;
+
+        let status = if serving { ServingStatus::Serving } else { ServingStatus::NotServing };
+        info!(%status, "health");
+        self.health.set_service_status("", status).await;
+        self.health.set_service_status(grpc::ORDERBOOK_SERVICE, status).await;
+    }
+
+    fn spawn(&mut self, exchange: Exchange, symbol: String) {
+        let config = self.venue_configs.get(&exchange);
+        let venue = if self.replaying {
+            Venue::idle()
+        } else {
+            Venue::spawn(
+                exchange, symbol.clone(), config, self.tx_in_ticks.clone(), self.tx_events.clone(), self.recorder.as_ref())
+        };
+        self.venues.insert(venue.id, (venue, VenueState::new(exchange, symbol)));
+    }
+
+    /// Closes the venues matching the predicate without waiting for them to finish.
+    fn close_where(&mut self, predicate: impl Fn(&VenueState) -> bool) {
+        let ids: Vec<VenueId> = self.venues.iter()
+            .filter(|(_, (_, state))| predicate(state))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            if let Some((venue, _)) = self.venues.remove(&id) {
+                venue.send(venue::Command::Close);
+            }
+        }
+    }
+
+    async fn publish(&self, symbol: &str, cause: Option<MessageId>) {
+        let book = match self.books.get(symbol) {
+            Some(book) => book,
+            None => return,
+        };
+        let out_tick = book.to_tick();
+        debug!(
+            %symbol, spread = %out_tick.spread,
+            best_bid = ?out_tick.bids.first().map(|l| l.price), best_ask = ?out_tick.asks.first().map(|l| l.price),
+            "published");
+        metrics::published(symbol, &out_tick);
+        // of the quotes, as the venue mids, even if the merge is fee adjusted
+        let (best_bid, best_ask) = book.best_unadjusted();
+        if let Some(mid) = mid(best_bid.as_ref(), best_ask.as_ref()) {
+            self.on_mid(symbol, None, mid).await;
+        }
+
+        let venues: BTreeMap<Exchange, venue::Health> = self.venues.values()
+            .filter(|(_, state)| state.symbol == symbol)
+            .map(|(_, state)| (state.exchange, state.health))
+            .collect();
+
+        if let Some(exporter) = &self.exporter {
+            let mut books = vec![(None, out_tick.clone())];
+            if exporter.venues() {
+                books.extend(venues.keys().map(|exchange| (Some(*exchange), book.to_venue_tick(exchange))));
+            }
+            let at = self.replayed_at.unwrap_or_else(Utc::now);
+            exporter.send(Snapshot { at, symbol: symbol.to_string(), books });
+        }
+
+        let reader = self.out_ticks.read().await;
+        if let Some((tx, _)) = reader.get(symbol) {
+            // cannot fail, as the receiver next to it keeps the channel open
+            let _ = tx.send(Published::new(out_tick, cause, venues));
+        }
+    }
 }
 
-trait ParseAndSend {
-    fn parse_and_send(
-        self,
-        parse: fn(Message) -> Result<Option<InTick>, Error>,
-        tx: UnboundedSender<InTick>,
-    ) -> Result<(), Error>;
+/// Middle of the best bid and offer, `None` unless both sides are quoted.
+fn mid(bid: Option<&Level>, ask: Option<&Level>) -> Option<Decimal> {
+    match (bid, ask) {
+        (Some(bid), Some(ask)) => Some((bid.price + ask.price) / Decimal::TWO),
+        _ => None,
+    }
 }
 
-impl ParseAndSend for Message {
-    fn parse_and_send(
-        self,
-        parse: fn(Message) -> Result<Option<InTick>, Error>,
-        tx: UnboundedSender<InTick>,
-    ) -> Result<(), Error>
-    {
-        parse(self).and_then(|t| {
-            t.map(|tick| {
-                tokio::spawn(async move {
-                    tx.unbounded_send(tick).expect("Failed to send");
-                });
-            });
-            Ok(())
-        })
-    }
-}
\ No newline at end of file
+/// Next entry of the replay. Never ready when not replaying, and `None` once the replay finished.
+async fn next_replayed(rx_replay: &mut Option<mpsc::Receiver<Entry>>) -> Option<Entry> {
+    match rx_replay {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,467 @@
+//! Records the raw WebSocket messages of every venue to disk, as received and before any adapter
+//! parses them, together with the connection events of the venue.
+//!
+//! Each venue writes to its own files in the recording directory, named
+//! `<exchange>_<base>-<quote>_<opened at, UTC>.jsonl.gz`, e.g. `kraken_ETH-BTC_20220623T160000.123456Z.jsonl.gz`.
+//! A file is gzip compressed JSON Lines, one `Entry` per line, and is only ever appended to. A new
+//! file is started once the current one holds `ROTATE_BYTES` uncompressed or is `ROTATE_AFTER` old,
+//! so a session of a venue spans every file of its exchange and symbol in the order of their names.
+//! Files are flushed every second; the last ones of a crashed server may end in a truncated gzip
+//! block, which most decoders read up to the last flush.
+//!
+//! ```json
+//! {"at":"2022-06-23T16:00:00.123456Z","venue":3,"exchange":"kraken","symbol":"ETH/BTC","event":"connect","url":"wss://ws.kraken.com"}
+//! {"at":"2022-06-23T16:00:00.123501Z","venue":3,"exchange":"kraken","symbol":"ETH/BTC","event":"subscribe","text":"{\"event\":\"subscribe\",...}"}
+//! {"at":"2022-06-23T16:00:00.201930Z","venue":3,"exchange":"kraken","symbol":"ETH/BTC","event":"text","data":"{\"event\":\"systemStatus\",...}"}
+//! {"at":"2022-06-23T16:00:05.000012Z","venue":3,"exchange":"kraken","symbol":"ETH/BTC","event":"ping","hex":"01ff"}
+//! {"at":"2022-06-23T16:05:00.300000Z","venue":3,"exchange":"kraken","symbol":"ETH/BTC","event":"close","code":1000,"reason":""}
+//! {"at":"2022-06-23T16:05:00.300100Z","venue":3,"exchange":"kraken","symbol":"ETH/BTC","event":"error","message":"kraken ETH/BTC: connection lost: ..."}
+//! ```
+//!
+//! `at` is the time the message was received, or the event happened, in RFC 3339 with microseconds.
+//! `venue` tells apart the venues started one after the other for the same exchange and symbol.
+
+use crate::error::Error;
+use crate::orderbook::Exchange;
+use crate::venue::VenueId;
+use chrono::{DateTime, Utc};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+
+/// Uncompressed size after which a venue starts a new file.
+pub(crate) const ROTATE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Age after which a venue starts a new file.
+pub(crate) const ROTATE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Files are flushed at least this often, so that a crash loses little.
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+/// One line of a recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    #[serde(with = "micros")]
+    pub(crate) at: DateTime<Utc>,
+    pub(crate) venue: VenueId,
+    pub(crate) exchange: Exchange,
+    pub(crate) symbol: String,
+    #[serde(flatten)]
+    pub(crate) event: Event,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    /// The WebSocket to `url` was opened.
+    Connect { url: String },
+
+    /// The subscription was sent.
+    Subscribe { text: String },
+
+    /// A text message was received.
+    Text { data: String },
+
+    /// A binary message was received, hex encoded.
+    Binary { hex: String },
+
+    Ping { hex: String },
+
+    Pong { hex: String },
+
+    /// The exchange sent a close frame. Without a code if the frame was empty.
+    Close { code: Option<u16>, reason: String },
+
+    /// The aggregator closed the WebSocket, to resubscribe or to stop the venue.
+    Closed,
+
+    /// The venue failed to connect or stopped after an error.
+    Error { message: String },
+}
+
+impl From<&Message> for Event {
+    fn from(msg: &Message) -> Self {
+        match msg {
+            Message::Text(data) => Event::Text { data: data.clone() },
+            Message::Binary(data) => Event::Binary { hex: to_hex(data) },
+            Message::Ping(data) => Event::Ping { hex: to_hex(data) },
+            Message::Pong(data) => Event::Pong { hex: to_hex(data) },
+            Message::Close(frame) => Event::Close {
+                code: frame.as_ref().map(|f| f.code.into()),
+                reason: frame.as_ref().map(|f| f.reason.to_string()).unwrap_or_default(),
+            },
+            Message::Frame(frame) => Event::Binary { hex: to_hex(frame.payload()) },
+        }
+    }
+}
+
+impl Event {
+    /// The message as it was received, for the events which are one.
+    pub(crate) fn to_message(&self) -> Option<Message> {
+        match self {
+            Event::Text { data } => Some(Message::Text(data.clone())),
+            Event::Binary { hex } => from_hex(hex).map(Message::Binary),
+            Event::Ping { hex } => from_hex(hex).map(Message::Ping),
+            Event::Pong { hex } => from_hex(hex).map(Message::Pong),
+            Event::Close { code, reason } => Some(Message::Close(code.map(|code| CloseFrame {
+                code: CloseCode::from(code),
+                reason: reason.clone().into(),
+            }))),
+            Event::Connect { .. } | Event::Subscribe { .. } | Event::Closed | Event::Error { .. } => None,
+        }
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/// Timestamps in RFC 3339 with microseconds, which is what the exchanges resolve to.
+mod micros {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(at: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&at.to_rfc3339_opts(SecondsFormat::Micros, true))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&s)
+            .map(|at| at.with_timezone(&Utc))
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Hands entries to the thread writing the recording. Writing stops once every clone, and every
+/// `Tape` made from them, is dropped.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    tx: Sender<Entry>,
+}
+
+impl Recorder {
+    /// Starts writing below `dir`, which is created if missing. The returned handle finishes once the
+    /// last file is complete.
+    pub(crate) fn spawn(dir: PathBuf) -> Result<(Recorder, JoinHandle<()>), Error> {
+        Recorder::spawn_rotating(dir, ROTATE_BYTES, ROTATE_AFTER, FLUSH_EVERY)
+    }
+
+    fn spawn_rotating(
+        dir: PathBuf,
+        rotate_bytes: u64,
+        rotate_after: Duration,
+        flush_every: Duration,
+    ) -> Result<(Recorder, JoinHandle<()>), Error>
+    {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| Error::BadConfig(format!("record dir {}: {}", dir.display(), e)))?;
+        info!(dir = %dir.display(), "recording");
+
+        let (tx, rx) = mpsc::channel();
+        let handle = std::thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || write(&dir, rx, rotate_bytes, rotate_after, flush_every))?;
+        Ok((Recorder { tx }, handle))
+    }
+
+    pub(crate) fn tape(&self, venue: VenueId, exchange: Exchange, symbol: &str) -> Tape {
+        Tape { tx: Some(self.tx.clone()), venue, exchange, symbol: symbol.to_string() }
+    }
+}
+
+/// Records the events of a single venue, or nothing when the server is not recording.
+pub(crate) struct Tape {
+    tx: Option<Sender<Entry>>,
+    venue: VenueId,
+    exchange: Exchange,
+    symbol: String,
+}
+
+impl Tape {
+    pub(crate) fn new(recorder: Option<&Recorder>, venue: VenueId, exchange: Exchange, symbol: &str) -> Tape {
+        match recorder {
+            Some(recorder) => recorder.tape(venue, exchange, symbol),
+            None => Tape { tx: None, venue, exchange, symbol: symbol.to_string() },
+        }
+    }
+
+    pub(crate) fn record(&self, event: Event) {
+        if let Some(tx) = &self.tx {
+            let entry = Entry { at: Utc::now(), venue: self.venue, exchange: self.exchange, symbol: self.symbol.clone(), event };
+            // the writer only goes away after an I/O error it already logged
+            let _ = tx.send(entry);
+        }
+    }
+
+    pub(crate) fn message(&self, msg: &Message) {
+        if self.tx.is_some() {
+            self.record(Event::from(msg));
+        }
+    }
+
+    pub(crate) fn error(&self, e: &Error) {
+        self.record(Event::Error { message: e.to_string() });
+    }
+}
+
+/// File a venue is currently appending to.
+struct LogFile {
+    encoder: GzEncoder<File>,
+    opened: Instant,
+    bytes: u64,
+}
+
+impl LogFile {
+    fn create(dir: &Path, entry: &Entry) -> io::Result<LogFile> {
+        let name = format!(
+            "{}_{}_{}.jsonl.gz",
+            entry.exchange,
+            entry.symbol.replace('/', "-"),
+            entry.at.format("%Y%m%dT%H%M%S%.6fZ"));
+        let file = OpenOptions::new().create(true).append(true).open(dir.join(&name))?;
+        info!(file = %name, "recording to");
+        Ok(LogFile { encoder: GzEncoder::new(file, Compression::default()), opened: Instant::now(), bytes: 0 })
+    }
+
+    fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.encoder.write_all(&line)?;
+        self.bytes += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Appends every entry to the file of its venue until every sender is dropped, flushing the files
+/// at least every `flush_every` however busy the venues are.
+fn write(dir: &Path, rx: Receiver<Entry>, rotate_bytes: u64, rotate_after: Duration, flush_every: Duration) {
+    let mut files: HashMap<(Exchange, String), LogFile> = HashMap::new();
+    let mut flushed = Instant::now();
+    loop {
+        match rx.recv_timeout(flush_every) {
+            Ok(entry) => append(dir, &mut files, entry, rotate_bytes, rotate_after),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if flushed.elapsed() >= flush_every {
+            for file in files.values_mut() {
+                if let Err(e) = file.encoder.flush() {
+                    error!(error = %e, "could not flush recording");
+                }
+            }
+            flushed = Instant::now();
+        }
+    }
+
+    for file in files.into_values() {
+        finish(file);
+    }
+}
+
+/// Appends the entry to the file of its venue, starting a new one if it is due.
+fn append(
+    dir: &Path,
+    files: &mut HashMap<(Exchange, String), LogFile>,
+    entry: Entry,
+    rotate_bytes: u64,
+    rotate_after: Duration,
+) {
+    let key = (entry.exchange, entry.symbol.clone());
+    let due = files.get(&key).is_some_and(|f| f.bytes >= rotate_bytes || f.opened.elapsed() >= rotate_after);
+    if due {
+        if let Some(file) = files.remove(&key) {
+            finish(file);
+        }
+    }
+
+    let res = match files.get_mut(&key) {
+        Some(file) => file.append(&entry),
+        None => LogFile::create(dir, &entry).and_then(|mut file| {
+            let res = file.append(&entry);
+            files.insert(key.clone(), file);
+            res
+        }),
+    };
+    if let Err(e) = res {
+        // the next entry of the venue starts a new file
+        error!(exchange = %entry.exchange, symbol = %entry.symbol, error = %e, "could not record");
+        files.remove(&key);
+    }
+}
+
+fn finish(file: LogFile) {
+    if let Err(e) = file.encoder.finish() {
+        error!(error = %e, "could not finish recording");
+    }
+}
+
+/// Reads the entries of a recorded file. A file which was not finished, e.g. as the server crashed
+/// or is still recording, is read up to its last flush. Lines which are not entries are skipped.
+pub(crate) fn read(path: &Path) -> io::Result<impl Iterator<Item = Entry>> {
+    let reader = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+    let path = path.to_path_buf();
+    let entries = reader.lines()
+        .map_while(Result::ok)
+        .filter_map(move |line| match serde_json::from_str(&line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!(file = %path.display(), error = %e, "skipping line");
+                None
+            },
+        });
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::record::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn should_serialize_entry() {
+        /*
+         * Given
+         */
+        let entry = Entry {
+            at: Utc.timestamp(1656000000, 123456000),
+            venue: 3,
+            exchange: Exchange::Kraken,
+            symbol: "ETH/BTC".to_string(),
+            event: Event::Close { code: Some(1000), reason: "".to_string() },
+        };
+
+        /*
+         * When
+         */
+        let line = serde_json::to_string(&entry).unwrap();
+
+        /*
+         * Then
+         */
+        assert_eq!(line, r#"{"at":"2022-06-23T16:00:00.123456Z","venue":3,"exchange":"kraken","symbol":"ETH/BTC","event":"close","code":1000,"reason":""}"#);
+        assert_eq!(serde_json::from_str::<Entry>(&line).unwrap(), entry);
+    }
+
+    #[test]
+    fn should_keep_messages_as_received() {
+        /*
+         * Given
+         */
+        let messages = [
+            Message::Text(r#"{"event":"heartbeat"}"#.to_string()),
+            Message::Binary(vec![0, 1, 254, 255]),
+            Message::Ping(vec![]),
+            Message::Close(None),
+            Message::Close(Some(CloseFrame { code: CloseCode::Away, reason: "bye".into() })),
+        ];
+
+        /*
+         * When
+         */
+        let events: Vec<Event> = messages.iter().map(Event::from).collect();
+
+        /*
+         * Then
+         */
+        assert_eq!(events, vec![
+            Event::Text { data: r#"{"event":"heartbeat"}"#.to_string() },
+            Event::Binary { hex: "0001feff".to_string() },
+            Event::Ping { hex: "".to_string() },
+            Event::Close { code: None, reason: "".to_string() },
+            Event::Close { code: Some(1001), reason: "bye".to_string() },
+        ]);
+        assert_eq!(events.iter().map(|e| e.to_message().unwrap()).collect::<Vec<_>>(), messages);
+        assert_eq!(Event::Closed.to_message(), None);
+    }
+
+    #[test]
+    fn should_write_and_rotate_per_venue() {
+        /*
+         * Given
+         */
+        let dir = tempfile::tempdir().unwrap();
+        let (recorder, writer) = Recorder::spawn_rotating(dir.path().to_path_buf(), 100, ROTATE_AFTER, FLUSH_EVERY).unwrap();
+        let kraken = recorder.tape(0, Exchange::Kraken, "ETH/BTC");
+        let binance = Tape::new(Some(&recorder), 1, Exchange::Binance, "ETH/BTC");
+        let off = Tape::new(None, 2, Exchange::Coinbase, "ETH/BTC");
+
+        /*
+         * When
+         */
+        kraken.record(Event::Connect { url: "wss://ws.kraken.com".to_string() });
+        binance.message(&Message::Text("{}".to_string()));
+        // rotates, as the connect entry alone is over 100 bytes
+        std::thread::sleep(Duration::from_millis(2));
+        kraken.record(Event::Closed);
+        off.record(Event::Closed);
+        drop((recorder, kraken, binance, off));
+        writer.join().unwrap();
+
+        /*
+         * Then
+         */
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().path()).collect();
+        paths.sort();
+        let names: Vec<String> = paths.iter().map(|p| p.file_name().unwrap().to_string_lossy().to_string()).collect();
+        assert_eq!(names.len(), 3, "{:?}", names);
+        assert!(names[0].starts_with("binance_ETH-BTC_") && names[0].ends_with(".jsonl.gz"));
+        assert!(names[1].starts_with("kraken_ETH-BTC_"));
+        assert!(names[2].starts_with("kraken_ETH-BTC_"));
+
+        let events = |path| read(path).unwrap().map(|e| e.event).collect::<Vec<_>>();
+        assert_eq!(events(&paths[0]), vec![Event::Text { data: "{}".to_string() }]);
+        assert_eq!(events(&paths[1]), vec![Event::Connect { url: "wss://ws.kraken.com".to_string() }]);
+        assert_eq!(events(&paths[2]), vec![Event::Closed]);
+    }
+
+    #[test]
+    fn should_flush_while_entries_keep_arriving() {
+        /*
+         * Given
+         */
+        let dir = tempfile::tempdir().unwrap();
+        let flush_every = Duration::from_millis(20);
+        let (recorder, writer) = Recorder::spawn_rotating(dir.path().to_path_buf(), ROTATE_BYTES, ROTATE_AFTER, flush_every).unwrap();
+        let kraken = recorder.tape(0, Exchange::Kraken, "ETH/BTC");
+
+        /*
+         * When
+         */
+        // never long enough apart for the writer to wait a whole flush_every
+        for _ in 0..20 {
+            kraken.message(&Message::Text("{}".to_string()));
+            std::thread::sleep(flush_every / 4);
+        }
+        let path = std::fs::read_dir(dir.path()).unwrap().next().unwrap().unwrap().path();
+        let while_open = read(&path).unwrap().count();
+        kraken.message(&Message::Text("{}".to_string()));
+        drop((recorder, kraken));
+        writer.join().unwrap();
+
+        /*
+         * Then
+         */
+        assert!(while_open > 0);
+        assert_eq!(read(&path).unwrap().count(), 21);
+    }
+}
@@ -0,0 +1,232 @@
+//! Plays a recording made with `record` back through the aggregator. The recorded messages go through
+//! the same `parse` and merge as live ones, in the order they were received across every venue, so
+//! that a replay at any speed publishes the same books.
+
+use crate::config::Speed;
+use crate::error::Error;
+use crate::orderbook::Exchange;
+use crate::record::{self, Entry};
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Recorded files of one exchange and symbol, in the order they were written.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Track {
+    pub(crate) exchange: Exchange,
+    pub(crate) symbol: String,
+    files: Vec<PathBuf>,
+}
+
+impl Track {
+    fn entries(self) -> Box<dyn Iterator<Item = Entry> + Send> {
+        Box::new(self.files.into_iter().flat_map(|path| {
+            let entries = record::read(&path)
+                .map_err(|e| warn!(file = %path.display(), error = %e, "skipping file"))
+                .ok();
+            entries.into_iter().flatten()
+        }))
+    }
+}
+
+/// Finds the tracks recorded in `dir`, ordered by exchange and symbol.
+pub(crate) fn tracks(dir: &Path) -> Result<Vec<Track>, Error> {
+    let bad_dir = |e: std::io::Error| Error::BadConfig(format!("replay dir {}: {}", dir.display(), e));
+
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir).map_err(bad_dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.to_string_lossy().ends_with(".jsonl.gz"))
+        .collect();
+    // the names of the files of a track start alike and end with the time they were opened at
+    paths.sort();
+
+    let mut tracks: BTreeMap<(Exchange, String), Vec<PathBuf>> = BTreeMap::new();
+    for path in paths {
+        // every entry of a file is of the same exchange and symbol
+        match record::read(&path).map_err(bad_dir)?.next() {
+            Some(entry) => tracks.entry((entry.exchange, entry.symbol)).or_default().push(path),
+            None => warn!(file = %path.display(), "skipping empty file"),
+        }
+    }
+    if tracks.is_empty() {
+        return Err(Error::BadConfig(format!("replay dir {}: no recording found", dir.display())));
+    }
+
+    Ok(tracks.into_iter()
+        .map(|((exchange, symbol), files)| Track { exchange, symbol, files })
+        .collect())
+}
+
+/// Entries of every track, earliest first. Entries received at the same time are taken in the order
+/// of their tracks, and those of a track always in the order they were written.
+struct Merge {
+    tracks: Vec<Box<dyn Iterator<Item = Entry> + Send>>,
+    heads: Vec<Option<Entry>>,
+    next: BinaryHeap<Reverse<(DateTime<Utc>, usize)>>,
+}
+
+impl Merge {
+    fn new(tracks: Vec<Track>) -> Merge {
+        let mut merge = Merge {
+            heads: tracks.iter().map(|_| None).collect(),
+            tracks: tracks.into_iter().map(Track::entries).collect(),
+            next: BinaryHeap::new(),
+        };
+        for i in 0..merge.tracks.len() {
+            merge.advance(i);
+        }
+        merge
+    }
+
+    fn advance(&mut self, i: usize) {
+        if let Some(entry) = self.tracks[i].next() {
+            self.next.push(Reverse((entry.at, i)));
+            self.heads[i] = Some(entry);
+        }
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let Reverse((_, i)) = self.next.pop()?;
+        let entry = self.heads[i].take();
+        self.advance(i);
+        entry
+    }
+}
+
+/// Sends the entries of the tracks to `tx` in the order they were received, spaced as they were
+/// divided by `speed`. Reading is blocking, so it runs on a thread of its own, which finishes once
+/// every entry was taken or the receiver is dropped.
+pub(crate) fn spawn(tracks: Vec<Track>, speed: Speed, tx: mpsc::Sender<Entry>) -> Result<JoinHandle<()>, Error> {
+    let handle = std::thread::Builder::new()
+        .name("replay".to_string())
+        .spawn(move || {
+            let started = Instant::now();
+            let mut first: Option<DateTime<Utc>> = None;
+            let mut count = 0;
+
+            for entry in Merge::new(tracks) {
+                if let Speed::Times(factor) = speed {
+                    let first = *first.get_or_insert(entry.at);
+                    let offset = (entry.at - first).to_std().unwrap_or_default().div_f64(factor);
+                    if let Some(wait) = (started + offset).checked_duration_since(Instant::now()) {
+                        std::thread::sleep(wait);
+                    }
+                }
+                if tx.blocking_send(entry).is_err() {
+                    return;
+                }
+                count += 1;
+            }
+            info!(entries = count, elapsed = ?started.elapsed(), "replay finished");
+        })?;
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::replay::*;
+    use crate::record::Event;
+    use chrono::TimeZone;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn entry(secs: i64, exchange: Exchange, data: &str) -> Entry {
+        Entry {
+            at: Utc.timestamp(1656000000 + secs, 0),
+            venue: 0,
+            exchange,
+            symbol: "ETH/BTC".to_string(),
+            event: Event::Text { data: data.to_string() },
+        }
+    }
+
+    fn write(dir: &Path, name: &str, entries: &[Entry]) {
+        let mut encoder = GzEncoder::new(std::fs::File::create(dir.join(name)).unwrap(), Compression::default());
+        for entry in entries {
+            writeln!(encoder, "{}", serde_json::to_string(entry).unwrap()).unwrap();
+        }
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn should_merge_tracks_in_time_order() {
+        /*
+         * Given
+         */
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "kraken_ETH-BTC_20220623T160000.000000Z.jsonl.gz", &[
+            entry(0, Exchange::Kraken, "k1"),
+            entry(2, Exchange::Kraken, "k2"),
+        ]);
+        write(dir.path(), "kraken_ETH-BTC_20220623T160003.000000Z.jsonl.gz", &[
+            entry(3, Exchange::Kraken, "k3"),
+        ]);
+        write(dir.path(), "binance_ETH-BTC_20220623T160001.000000Z.jsonl.gz", &[
+            entry(1, Exchange::Binance, "b1"),
+            entry(2, Exchange::Binance, "b2"),
+        ]);
+        std::fs::write(dir.path().join("notes.txt"), "not a recording").unwrap();
+
+        /*
+         * When
+         */
+        let tracks = tracks(dir.path()).unwrap();
+        let (tx, mut rx) = mpsc::channel(10);
+        spawn(tracks.clone(), Speed::Max, tx).unwrap().join().unwrap();
+        let mut replayed = vec![];
+        while let Ok(entry) = rx.try_recv() {
+            replayed.push(entry.event);
+        }
+
+        /*
+         * Then
+         */
+        assert_eq!(tracks.iter().map(|t| (t.exchange, t.files.len())).collect::<Vec<_>>(),
+                   vec![(Exchange::Binance, 1), (Exchange::Kraken, 2)]);
+        let text = |data: &str| Event::Text { data: data.to_string() };
+        assert_eq!(replayed, vec![text("k1"), text("b1"), text("b2"), text("k2"), text("k3")]);
+    }
+
+    #[test]
+    fn should_pace_at_speed() {
+        /*
+         * Given
+         */
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "kraken_ETH-BTC_20220623T160000.000000Z.jsonl.gz", &[
+            entry(0, Exchange::Kraken, "k1"),
+            entry(1, Exchange::Kraken, "k2"),
+        ]);
+        let tracks = tracks(dir.path()).unwrap();
+
+        /*
+         * When
+         */
+        let started = Instant::now();
+        let (tx, _rx) = mpsc::channel(10);
+        spawn(tracks, Speed::Times(5.0), tx).unwrap().join().unwrap();
+
+        /*
+         * Then
+         */
+        let elapsed = started.elapsed();
+        assert!(elapsed.as_millis() >= 200 && elapsed.as_millis() < 1000, "{:?}", elapsed);
+    }
+
+    #[test]
+    fn should_reject_dir_without_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(tracks(dir.path()).is_err());
+        assert!(tracks(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use crate::error::{self, Error};
+use crate::orderbook::Exchange;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Longest wait for a whole response, body included.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends a GET to the REST API of the exchange, and returns the body of a successful response.
+pub(crate) async fn get(exchange: Exchange, symbol: &str, url: &str) -> Result<String, Error> {
+    request(exchange, symbol, Method::GET, url).await
+}
+
+/// Sends a POST without a body to the REST API of the exchange, and returns the body of a successful
+/// response.
+pub(crate) async fn post(exchange: Exchange, symbol: &str, url: &str) -> Result<String, Error> {
+    request(exchange, symbol, Method::POST, url).await
+}
+
+async fn request(exchange: Exchange, symbol: &str, method: Method, url: &str) -> Result<String, Error> {
+    let fail = |reason: String| Error::Rest { exchange, symbol: symbol.to_string(), url: url.to_string(), reason };
+
+    let mut request = Request::builder()
+        .method(method.clone())
+        .uri(url)
+        .body(Body::empty())
+        .map_err(|e| fail(e.to_string()))?;
+    request.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    // plain HTTP is allowed, for local stand-ins of the exchange
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let client: Client<_, Body> = Client::builder().build(HttpsConnector::new_with_connector(http));
+
+    let response = async {
+        let response = client.request(request).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok::<_, hyper::Error>((status, body))
+    };
+    let (status, body) = match tokio::time::timeout(TIMEOUT, response).await {
+        Ok(response) => response.map_err(|e| fail(e.to_string()))?,
+        Err(_) => return Err(fail(format!("no response within {}s", TIMEOUT.as_secs()))),
+    };
+
+    let body = String::from_utf8_lossy(&body).into_owned();
+    debug!(%method, url, %status, bytes = body.len(), "response");
+    if !status.is_success() {
+        info!(%method, url, %status, "request failed");
+        return Err(fail(format!("{} {}", status, error::excerpt(&body))));
+    }
+    Ok(body)
+}
@@ -0,0 +1,27 @@
+use crate::orderbook::Exchange;
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+/// Side of the taker of a trade, i.e. `Buy` when a buy order lifted an ask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TradeSide {
+    Buy,
+    Sell,
+}
+
+/// Trade on an exchange, as published on its public trade channel.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Trade {
+    pub(crate) exchange: Exchange,
+    pub(crate) price: Decimal,
+    pub(crate) amount: Decimal,
+    pub(crate) side: TradeSide,
+
+    /// As numbered by the exchange. `None` for Kraken, which numbers none.
+    pub(crate) trade_id: Option<String>,
+
+    /// When the exchange matched the orders.
+    pub(crate) time: DateTime<Utc>,
+}
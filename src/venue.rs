@@ -0,0 +1,753 @@
+use crate::config::VenueConfig;
+use crate::error::Error;
+use crate::orderbook::{Exchange, InTick};
+use crate::record::{Recorder, Tape};
+use crate::trade::Trade;
+use crate::{binance, bitfinex, bitstamp, bybit, coinbase, deribit, gemini, kraken, kucoin, metrics, okx, websocket};
+use chrono::{DateTime, Duration, Utc};
+use futures::channel::mpsc::UnboundedSender;
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tracing::{debug_span, error, info, info_span, warn, Instrument};
+use tungstenite::protocol::Message;
+
+/// Instructions sent from the `Connector` to a running venue.
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Closes the WebSocket and connects and subscribes again from scratch.
+    Resubscribe,
+
+    /// Sends the text frame as-is to the exchange.
+    Raw(String),
+
+    /// Closes the WebSocket and stops the venue.
+    Close,
+}
+
+/// Identifies a running venue. A venue started again for the same exchange and symbol gets a new id,
+/// so that ticks and events still in flight from the previous one can be told apart.
+pub(crate) type VenueId = usize;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Wait before connecting again after the first failed attempt, doubled after each further one.
+const RECONNECT_BACKOFF_MIN: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Longest wait between two attempts to connect.
+const RECONNECT_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Identifies a message received by a venue, so that the merge and the gRPC emissions it causes can be
+/// traced back to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct MessageId {
+    pub(crate) venue: VenueId,
+
+    /// Counts the messages of the venue, starting at 1.
+    pub(crate) seq: u64,
+}
+
+/// What an adapter makes of a message of its exchange.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Parsed {
+    /// Levels which changed, merged into what is held for the exchange.
+    Tick(InTick),
+
+    /// A whole book, replacing what was held for the exchange.
+    Snapshot(InTick),
+
+    /// Trading status reported by the exchange, with its explanation if it gave one.
+    Status(TradingStatus, Option<String>),
+
+    /// Trades of the exchange, passed on to the `Trades` streams without touching the book.
+    Trades(Vec<Trade>),
+
+    /// Error reported by the exchange after the subscription was confirmed. The connection stays up.
+    Error(String),
+
+    /// Nothing to act on, such as a heartbeat or a subscription acknowledgement. It still shows that
+    /// the exchange is sending.
+    Nothing,
+
+    /// Request of the exchange, answered on the same connection with the message, such as a Deribit
+    /// `test_request`. Left unanswered when replaying.
+    Reply(Message),
+}
+
+impl From<Option<InTick>> for Parsed {
+    fn from(tick: Option<InTick>) -> Self {
+        match tick {
+            Some(tick) => Parsed::Tick(tick),
+            None => Parsed::Nothing,
+        }
+    }
+}
+
+/// Trading status of the market as reported by the exchange. Only Kraken, Coinbase and Bitfinex report one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TradingStatus {
+    Online,
+    Maintenance,
+    CancelOnly,
+    LimitOnly,
+    PostOnly,
+
+    /// Not trading at all, e.g. delisted.
+    Offline,
+}
+
+/// Lifecycle notifications sent from a running venue back to the `Connector`.
+#[derive(Debug)]
+pub(crate) enum Event {
+    Connected(VenueId),
+
+    Disconnected(VenueId, Option<Error>),
+}
+
+/// Handle to the task streaming the orderbook of a single symbol from a single exchange.
+pub(crate) struct Venue {
+    pub(crate) id: VenueId,
+    tx_command: mpsc::UnboundedSender<Command>,
+    handle: JoinHandle<()>,
+}
+
+impl Venue {
+    /// Spawns the task connecting to the exchange. Parsed ticks are sent to `tx_in_ticks`. Raw messages
+    /// and connection events go to `recorder` first, if the server is recording.
+    pub(crate) fn spawn(
+        exchange: Exchange,
+        symbol: String,
+        config: &VenueConfig,
+        tx_in_ticks: UnboundedSender<(MessageId, Parsed)>,
+        tx_events: mpsc::UnboundedSender<Event>,
+        recorder: Option<&Recorder>,
+    ) -> Venue
+    {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let url = config.url.clone().unwrap_or_else(|| default_url(&exchange).to_string());
+        let tape = Tape::new(recorder, id, exchange, &symbol);
+        let (tx_command, rx_command) = mpsc::unbounded_channel();
+        let span = info_span!("venue", id, %exchange, %symbol);
+        let handle = tokio::spawn(
+            run(id, exchange, symbol, url, config.clone(), rx_command, tx_in_ticks, tx_events, tape).instrument(span));
+        Venue { id, tx_command, handle }
+    }
+
+    /// Stands in for a venue during a replay, where the `Connector` is fed the recorded messages
+    /// instead. Commands other than `Close` are ignored.
+    pub(crate) fn idle() -> Venue {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let (tx_command, mut rx_command) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(async move {
+            while let Some(command) = rx_command.recv().await {
+                match command {
+                    Command::Close => break,
+                    command => info!(?command, "replaying, ignored"),
+                }
+            }
+        });
+        Venue { id, tx_command, handle }
+    }
+
+    /// Returns `false` if the venue is no longer running.
+    pub(crate) fn send(&self, command: Command) -> bool {
+        self.tx_command.send(command).is_ok()
+    }
+
+    /// Closes the venue and waits until the close handshake with the exchange is done.
+    pub(crate) async fn close(self) {
+        self.send(Command::Close);
+        let _ = self.handle.await;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Health {
+    /// Connecting, or connected and waiting for the first tick.
+    Connecting,
+
+    /// Ticks are coming in.
+    Live,
+
+    /// Stopped after an error connecting again cannot fix, see `last_error`.
+    Disconnected,
+}
+
+/// Bookkeeping of a venue as reported by the `status` command, the `Admin` service and the
+/// `VenueStatus` stream.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct VenueState {
+    pub(crate) exchange: Exchange,
+    pub(crate) symbol: String,
+    pub(crate) health: Health,
+    pub(crate) ticks: usize,
+    pub(crate) last_tick: Option<DateTime<Utc>>,
+
+    /// Time of the last message of any kind, heartbeats included.
+    pub(crate) last_message: Option<DateTime<Utc>>,
+
+    /// `None` until the exchange reports it.
+    pub(crate) trading_status: Option<TradingStatus>,
+    pub(crate) trading_status_message: Option<String>,
+
+    pub(crate) last_error: Option<String>,
+
+    /// Time the venue connected or was asked to resubscribe, until its first tick since.
+    #[serde(skip)]
+    pub(crate) connecting_since: Option<DateTime<Utc>>,
+}
+
+impl VenueState {
+    pub(crate) fn new(exchange: Exchange, symbol: String) -> VenueState {
+        VenueState {
+            exchange,
+            symbol,
+            health: Health::Connecting,
+            ticks: 0,
+            last_tick: None,
+            last_message: None,
+            trading_status: None,
+            trading_status_message: None,
+            last_error: None,
+            connecting_since: None,
+        }
+    }
+
+    pub(crate) fn on_message(&mut self) {
+        self.last_message = Some(Utc::now());
+    }
+
+    pub(crate) fn on_tick(&mut self) {
+        self.health = Health::Live;
+        self.ticks += 1;
+        self.last_tick = Some(Utc::now());
+        self.connecting_since = None;
+    }
+
+    /// The venue was asked to resubscribe, and waits for its first tick again.
+    pub(crate) fn on_resubscribe(&mut self, now: DateTime<Utc>) {
+        self.health = Health::Connecting;
+        self.connecting_since = Some(now);
+    }
+
+    pub(crate) fn on_status(&mut self, status: TradingStatus, message: Option<String>) {
+        self.trading_status = Some(status);
+        self.trading_status_message = message;
+    }
+
+    /// Whether the states differ in more than their counters and timestamps.
+    pub(crate) fn differs(&self, other: &VenueState) -> bool {
+        (self.exchange, &self.symbol, self.health, self.trading_status, &self.trading_status_message, &self.last_error)
+            != (other.exchange, &other.symbol, other.health, other.trading_status, &other.trading_status_message, &other.last_error)
+    }
+
+    /// Whether ticks were coming in but none arrived for longer than `after`, or the venue connected
+    /// or was asked to resubscribe longer than `after` ago and has not ticked since.
+    pub(crate) fn is_stale(&self, now: DateTime<Utc>, after: Duration) -> bool {
+        match (self.health, self.last_tick, self.connecting_since) {
+            (Health::Live, Some(last_tick), _) => now - last_tick > after,
+            (Health::Connecting, _, Some(since)) => now - since > after,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn on_event(&mut self, event: &Event) {
+        match event {
+            // the first tick may overtake the event, as the two travel on separate channels
+            Event::Connected(_) => if self.health != Health::Live {
+                self.health = Health::Connecting;
+                self.connecting_since = Some(Utc::now());
+            },
+            Event::Disconnected(_, None) => self.health = Health::Connecting,
+            Event::Disconnected(_, Some(e)) if e.is_fatal() => {
+                self.health = Health::Disconnected;
+                self.last_error = Some(e.to_string());
+            },
+            // the venue reconnects, and its first ticks may overtake the `Connected` event as well
+            Event::Disconnected(_, Some(e)) => {
+                self.health = Health::Connecting;
+                self.last_error = Some(e.to_string());
+            },
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run(
+    id: VenueId,
+    exchange: Exchange,
+    symbol: String,
+    url: String,
+    config: VenueConfig,
+    mut rx_command: mpsc::UnboundedReceiver<Command>,
+    tx_in_ticks: UnboundedSender<(MessageId, Parsed)>,
+    tx_events: mpsc::UnboundedSender<Event>,
+    tape: Tape,
+)
+{
+    let (mut ws, mut backlog) = match reconnect(id, &exchange, &url, &symbol, &config, &mut rx_command, &tx_events, &tape).await {
+        Some(connected) => connected,
+        None => return,
+    };
+
+    let mut keepalive = keepalive(&exchange).map(|(period, ping)| {
+        let mut interval = time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        (interval, ping)
+    });
+
+    let mut seq = 0;
+    let mut parse = parser(&exchange, &config);
+    // returns the reply to the message, if the exchange asked for one
+    let mut receive = |ws_msg: Option<Result<Message, tungstenite::Error>>| {
+        seq += 1;
+        let message_id = MessageId { venue: id, seq };
+        debug_span!("message", seq).in_scope(|| handle(exchange, &symbol, ws_msg)
+            .and_then(|msg| msg.parse_and_send(&mut parse, &symbol, message_id, &tx_in_ticks)))
+    };
+
+    let err = loop {
+        // messages received while the subscription was being confirmed come first, and were
+        // recorded then
+        let res = match backlog.pop_front() {
+            Some(msg) => receive(Some(Ok(msg))).map_err(Some),
+            None => tokio::select! {
+                ws_msg = ws.next() => {
+                    if let Some(Ok(msg)) = &ws_msg {
+                        tape.message(msg);
+                    }
+                    receive(ws_msg).map_err(Some)
+                },
+                command = rx_command.recv() => {
+                    match command {
+                        Some(Command::Resubscribe) => {
+                            info!("resubscribing");
+                            Err(None)
+                        },
+                        Some(Command::Raw(text)) => {
+                            info!(%text, "sending raw message");
+                            Ok(Some(Message::Text(text)))
+                        },
+                        Some(Command::Close) | None => break None,
+                    }
+                },
+                ping = next_ping(&mut keepalive) => Ok(Some(ping())),
+            },
+        };
+        let res = match res {
+            Ok(Some(msg)) => ws.send(msg).await.map_err(|e| Some(Error::disconnected(exchange, &symbol, e))),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        // `None` asks to resubscribe, as does an error after which the book is no longer in sync
+        let cause = match res {
+            Ok(()) => continue,
+            Err(Some(e)) if e.is_out_of_sync() => {
+                warn!(error = %e, "out of sync, resubscribing");
+                tape.error(&e);
+                Some(e)
+            },
+            // the message is lost, but the book does not depend on it
+            Err(Some(e @ Error::Protocol { .. })) => {
+                warn!(error = %e, "skipping message");
+                metrics::message_skipped(exchange);
+                continue;
+            },
+            Err(None) => None,
+            Err(Some(e)) if e.is_fatal() => {
+                error!(error = %e, "stopping");
+                tape.error(&e);
+                break Some(e)
+            },
+            Err(Some(e)) => {
+                warn!(error = %e, "reconnecting");
+                tape.error(&e);
+                Some(e)
+            },
+        };
+
+        metrics::reconnected(exchange);
+        websocket::close(&mut ws, &tape).await;
+        let _ = tx_events.send(Event::Disconnected(id, cause));
+
+        (ws, backlog) = match reconnect(id, &exchange, &url, &symbol, &config, &mut rx_command, &tx_events, &tape).await {
+            Some(connected) => connected,
+            None => return,
+        };
+        if let Some((interval, _)) = &mut keepalive {
+            interval.reset();
+        }
+    };
+
+    // Gracefully close connection by Close-handshake procedure
+    websocket::close(&mut ws, &tape).await;
+    let _ = tx_events.send(Event::Disconnected(id, err));
+}
+
+/// Connects until the exchange confirms the subscription, waiting `RECONNECT_BACKOFF_MIN` after the
+/// first failed attempt and twice as long after each further one, up to `RECONNECT_BACKOFF_MAX`. Every
+/// failure is reported as a disconnection. Returns `None` once an error is fatal or the venue is closed
+/// while waiting; a resubscribe while waiting tries again at once.
+#[allow(clippy::too_many_arguments)]
+async fn reconnect(
+    id: VenueId,
+    exchange: &Exchange,
+    url: &str,
+    symbol: &str,
+    config: &VenueConfig,
+    rx_command: &mut mpsc::UnboundedReceiver<Command>,
+    tx_events: &mpsc::UnboundedSender<Event>,
+    tape: &Tape,
+) -> Option<(websocket::WsStream, VecDeque<Message>)>
+{
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    loop {
+        let e = match connect(exchange, url, symbol, config, tape).await {
+            Ok((ws, backlog)) => {
+                let _ = tx_events.send(Event::Connected(id));
+                return Some((ws, VecDeque::from(backlog)));
+            },
+            Err(e) => e,
+        };
+        tape.error(&e);
+        let fatal = e.is_fatal();
+        match fatal {
+            true => error!(error = %e, "stopping"),
+            false => warn!(error = %e, secs = backoff.as_secs(), "connecting again after a while"),
+        }
+        let _ = tx_events.send(Event::Disconnected(id, Some(e)));
+        if fatal {
+            return None;
+        }
+
+        let wait = time::sleep(backoff);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                command = rx_command.recv() => match command {
+                    Some(Command::Resubscribe) => {
+                        info!("resubscribing");
+                        break;
+                    },
+                    Some(Command::Raw(text)) => warn!(%text, "not connected, raw message dropped"),
+                    Some(Command::Close) | None => return None,
+                },
+            }
+        }
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+        metrics::reconnected(*exchange);
+    }
+}
+
+/// Returns the WebSocket once the exchange confirmed the subscription, with the messages received until then.
+async fn connect(
+    exchange: &Exchange,
+    url: &str,
+    symbol: &str,
+    config: &VenueConfig,
+    tape: &Tape,
+) -> Result<(websocket::WsStream, Vec<Message>), Error>
+{
+    let depth = config.depth;
+    match exchange {
+        Exchange::Bitstamp => bitstamp::connect(url, symbol, config.trades, tape).await,
+        Exchange::Binance | Exchange::BinanceFutures | Exchange::BinanceUs =>
+            binance::connect(*exchange, url, symbol, config, tape).await,
+        Exchange::Kraken => kraken::connect(url, symbol, depth, config.bbo, config.trades, tape).await,
+        Exchange::Coinbase => coinbase::connect(url, symbol, config.bbo, config.trades, tape).await,
+        Exchange::Okx => okx::connect(url, symbol, tape).await,
+        Exchange::Bybit => bybit::connect(url, symbol, depth, tape).await,
+        Exchange::Bitfinex => bitfinex::connect(url, symbol, depth, config.precision(symbol), config.checksum, tape).await,
+        Exchange::Gemini => gemini::connect(url, symbol, tape).await,
+        Exchange::Kucoin => kucoin::connect(url, symbol, tape).await,
+        Exchange::Deribit => deribit::connect(url, symbol, config.kind.unwrap_or_default(), config.expiry.as_deref(), tape).await,
+    }
+}
+
+/// Public WebSocket endpoint of the exchange.
+fn default_url(exchange: &Exchange) -> &'static str {
+    match exchange {
+        Exchange::Bitstamp => bitstamp::BITSTAMP_WS_URL,
+        Exchange::Binance => binance::BINANCE_WS_URL,
+        Exchange::Kraken => kraken::KRAKEN_WS_URL,
+        Exchange::Coinbase => coinbase::COINBASE_WS_URL,
+        Exchange::Okx => okx::OKX_WS_URL,
+        Exchange::Bybit => bybit::BYBIT_WS_URL,
+        Exchange::Bitfinex => bitfinex::BITFINEX_WS_URL,
+        Exchange::Gemini => gemini::GEMINI_WS_URL,
+        Exchange::Kucoin => kucoin::KUCOIN_REST_URL,
+        Exchange::Deribit => deribit::DERIBIT_WS_URL,
+        Exchange::BinanceFutures => binance::BINANCE_FUTURES_WS_URL,
+        Exchange::BinanceUs => binance::BINANCE_US_WS_URL,
+    }
+}
+
+/// Builds the text of an application level ping.
+type Ping = fn() -> Message;
+
+/// Waits until the next ping is due. Never completes for exchanges without an application level ping.
+async fn next_ping(keepalive: &mut Option<(time::Interval, Ping)>) -> Ping {
+    match keepalive {
+        Some((interval, ping)) => {
+            interval.tick().await;
+            *ping
+        },
+        None => futures::future::pending().await,
+    }
+}
+
+/// Period and message of the application level ping of exchanges which close connections on which the
+/// client stays silent. The others rely on WebSocket pings of the server, which tungstenite answers.
+fn keepalive(exchange: &Exchange) -> Option<(std::time::Duration, Ping)> {
+    match exchange {
+        Exchange::Okx => Some((okx::PING_EVERY, okx::ping)),
+        Exchange::Bybit => Some((bybit::PING_EVERY, bybit::ping)),
+        Exchange::Kucoin => Some((kucoin::PING_EVERY, kucoin::ping)),
+        _ => None,
+    }
+}
+
+/// Parses the messages of a venue, in the order they were received. Adapters which validate the book
+/// against the exchange keep a copy of it in between.
+pub(crate) type Parser = Box<dyn FnMut(Message, &str) -> Result<Parsed, Error> + Send + Sync>;
+
+pub(crate) fn parser(exchange: &Exchange, config: &VenueConfig) -> Parser {
+    match exchange {
+        Exchange::Bitstamp => Box::new(bitstamp::parse),
+        Exchange::Binance | Exchange::BinanceFutures | Exchange::BinanceUs => {
+            let mut depths = binance::Depths::new(*exchange, config.diff);
+            Box::new(move |msg, symbol| binance::parse(msg, symbol, &mut depths))
+        },
+        Exchange::Kraken => Box::new(kraken::parse),
+        Exchange::Coinbase => Box::new(coinbase::parse),
+        Exchange::Okx => {
+            let mut depths = okx::Depths::default();
+            Box::new(move |msg, symbol| okx::parse(msg, symbol, &mut depths))
+        },
+        Exchange::Bybit => {
+            let mut depths = bybit::Depths::default();
+            Box::new(move |msg, symbol| bybit::parse(msg, symbol, &mut depths))
+        },
+        Exchange::Bitfinex => {
+            let mut channels = bitfinex::Channels::default();
+            Box::new(move |msg, symbol| bitfinex::parse(msg, symbol, &mut channels))
+        },
+        Exchange::Gemini => {
+            let mut depths = gemini::Depths::default();
+            Box::new(move |msg, symbol| gemini::parse(msg, symbol, &mut depths))
+        },
+        Exchange::Kucoin => {
+            let mut depths = kucoin::Depths::default();
+            Box::new(move |msg, symbol| kucoin::parse(msg, symbol, &mut depths))
+        },
+        Exchange::Deribit => {
+            let mut depths = deribit::Depths::default();
+            Box::new(move |msg, symbol| deribit::parse(msg, symbol, &mut depths))
+        },
+    }
+}
+
+fn handle(
+    exchange: Exchange,
+    symbol: &str,
+    ws_msg: Option<Result<Message, tungstenite::Error>>,
+) -> Result<Message, Error>
+{
+    match ws_msg {
+        Some(msg) => msg.map_err(|e| Error::disconnected(exchange, symbol, e)),
+        None => {
+            info!("stream ended");
+            Err(Error::disconnected(exchange, symbol, tungstenite::Error::ConnectionClosed))
+        },
+    }
+}
+
+trait ParseAndSend {
+    fn parse_and_send(
+        self,
+        parse: &mut Parser,
+        symbol: &str,
+        id: MessageId,
+        tx: &UnboundedSender<(MessageId, Parsed)>,
+    ) -> Result<Option<Message>, Error>;
+}
+
+impl ParseAndSend for Message {
+    fn parse_and_send(
+        self,
+        parse: &mut Parser,
+        symbol: &str,
+        id: MessageId,
+        tx: &UnboundedSender<(MessageId, Parsed)>,
+    ) -> Result<Option<Message>, Error>
+    {
+        let parsed = parse(self, symbol)?;
+        let reply = match &parsed {
+            Parsed::Reply(msg) => Some(msg.clone()),
+            _ => None,
+        };
+        // the receiver only goes away when the server is shutting down
+        let _ = tx.unbounded_send((id, parsed));
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::venue::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn should_be_stale() {
+        /*
+         * Given
+         */
+        let mut state = VenueState::new(Exchange::Kraken, "ETH/BTC".to_string());
+        let now = Utc.timestamp(1656000060, 0);
+        let after = Duration::seconds(30);
+
+        /*
+         * Then
+         */
+        assert!(!state.is_stale(now, after));
+
+        state.health = Health::Live;
+        state.last_tick = Some(Utc.timestamp(1656000040, 0));
+        assert!(!state.is_stale(now, after));
+
+        state.last_tick = Some(Utc.timestamp(1656000020, 0));
+        assert!(state.is_stale(now, after));
+
+        state.health = Health::Disconnected;
+        assert!(!state.is_stale(now, after));
+    }
+
+    #[test]
+    fn should_be_stale_when_no_tick_since_resubscribe() {
+        /*
+         * Given
+         */
+        let mut state = VenueState::new(Exchange::Kraken, "ETH/BTC".to_string());
+        let now = Utc.timestamp(1656000060, 0);
+        let after = Duration::seconds(30);
+
+        /*
+         * When
+         */
+        state.on_resubscribe(Utc.timestamp(1656000020, 0));
+
+        /*
+         * Then
+         */
+        assert_eq!(state.health, Health::Connecting);
+        assert!(state.is_stale(now, after));
+        assert!(!state.is_stale(Utc.timestamp(1656000040, 0), after));
+
+        state.on_tick();
+        assert!(state.connecting_since.is_none());
+    }
+
+    #[test]
+    fn should_differ_in_more_than_counters() {
+        /*
+         * Given
+         */
+        let state = VenueState::new(Exchange::Kraken, "ETH/BTC".to_string());
+
+        /*
+         * When
+         */
+        let mut ticked = state.clone();
+        ticked.on_message();
+        ticked.on_tick();
+        let mut ticked_again = ticked.clone();
+        ticked_again.on_message();
+        ticked_again.on_tick();
+        let mut halted = ticked.clone();
+        halted.on_status(TradingStatus::Maintenance, None);
+
+        /*
+         * Then
+         */
+        assert!(state.differs(&ticked));
+        assert!(!ticked.differs(&ticked_again));
+        assert!(ticked.differs(&halted));
+    }
+
+    #[test]
+    fn should_stay_live_when_tick_overtakes_connected() {
+        /*
+         * Given
+         */
+        let mut state = VenueState::new(Exchange::Binance, "ETH/BTC".to_string());
+
+        /*
+         * When
+         */
+        state.on_tick();
+        state.on_event(&Event::Connected(0));
+
+        /*
+         * Then
+         */
+        assert_eq!(state.health, Health::Live);
+    }
+
+    #[test]
+    fn should_keep_connecting_unless_error_is_fatal() {
+        /*
+         * Given
+         */
+        let mut lost = VenueState::new(Exchange::Kraken, "ETH/BTC".to_string());
+        let mut rejected = VenueState::new(Exchange::Kraken, "ETH/XYZ".to_string());
+
+        /*
+         * When
+         */
+        lost.on_tick();
+        lost.on_event(&Event::Disconnected(0, Some(Error::disconnected(Exchange::Kraken, "ETH/BTC", tungstenite::Error::ConnectionClosed))));
+        rejected.on_event(&Event::Disconnected(1, Some(Error::subscribe_rejected(Exchange::Kraken, "ETH/XYZ", "Currency pair not supported", "{}"))));
+
+        /*
+         * Then
+         */
+        assert_eq!(lost.health, Health::Connecting);
+        assert!(lost.last_error.is_some());
+        assert_eq!(rejected.health, Health::Disconnected);
+    }
+
+    #[test]
+    fn should_keep_connecting_when_out_of_sync() {
+        /*
+         * Given
+         */
+        let mut state = VenueState::new(Exchange::BinanceFutures, "BTC/USDT".to_string());
+        let gap = Error::SequenceGap {
+            exchange: Exchange::BinanceFutures,
+            symbol: "BTC/USDT".to_string(),
+            expected: 1005,
+            got: 1003,
+            payload: "{}".to_string(),
+        };
+
+        /*
+         * When
+         */
+        state.on_tick();
+        state.on_event(&Event::Disconnected(0, Some(gap)));
+
+        /*
+         * Then
+         */
+        assert_eq!(state.health, Health::Connecting);
+        assert!(state.last_error.is_some());
+    }
+}
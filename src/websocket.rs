@@ -1,25 +1,148 @@
 use crate::error::Error;
+use crate::orderbook::Exchange;
+use crate::record::{self, Tape};
 use futures::{SinkExt, StreamExt};
-use log::info;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{info, warn};
 use tungstenite::Message;
 use url::Url;
 
 pub(crate) type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
-pub(crate) async fn connect(s: &str) -> Result<WsStream, Error> {
-    let url = Url::parse(s).unwrap();
-    let (ws_stream, _) =
-        tokio_tungstenite::connect_async(url).await?;
-    info!("Successfully connected to {}", s);
+/// Time the exchange has to confirm or refuse a subscription.
+pub(crate) const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time the exchange has to acknowledge the close of the WebSocket, after which it is dropped as is.
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a text message received while waiting for the subscription to be confirmed amounts to.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Ack {
+    Subscribed,
+
+    /// Refused for the given reason, e.g. an unknown pair.
+    Rejected(String),
+
+    /// Anything else, such as a status sent before the confirmation.
+    Pending,
+}
+
+pub(crate) async fn connect(exchange: Exchange, symbol: &str, s: &str, tape: &Tape) -> Result<WsStream, Error> {
+    let url = Url::parse(s).map_err(|e| Error::BadConfig(format!("{} url {:?}: {}", exchange, s, e)))?;
+    let (ws_stream, _) = tokio_tungstenite::connect_async(url).await
+        .map_err(|e| Error::connect(exchange, symbol, s, e))?;
+    info!(url = s, "connected");
+    tape.record(record::Event::Connect { url: s.to_string() });
     Ok(ws_stream)
 }
 
-pub(crate) async fn close(ws_stream: &mut WsStream) {
-    let _ = ws_stream.send(Message::Close(None)).await;
-    let close = ws_stream.next().await;
-    info!("server close msg: {:?}", close);
-    assert!(ws_stream.next().await.is_none());
-    let _ = ws_stream.close(None).await;
-}
\ No newline at end of file
+/// Sends the subscription over the WebSocket just opened to `url`. Failing to counts as failing to connect.
+pub(crate) async fn subscribe(
+    ws_stream: &mut WsStream,
+    exchange: Exchange,
+    symbol: &str,
+    url: &str,
+    text: String,
+    tape: &Tape,
+) -> Result<(), Error>
+{
+    tape.record(record::Event::Subscribe { text: text.clone() });
+    ws_stream.send(Message::Text(text)).await
+        .map_err(|e| Error::connect(exchange, symbol, url, e))
+}
+
+/// Closes the WebSocket, for at most `CLOSE_TIMEOUT`: a silent or half-open peer never acknowledges it.
+pub(crate) async fn close(ws_stream: &mut WsStream, tape: &Tape) {
+    tape.record(record::Event::Closed);
+    let closing = async {
+        let _ = ws_stream.send(Message::Close(None)).await;
+        // drain whatever the server sent before acknowledging the close
+        while let Some(Ok(msg)) = ws_stream.next().await {
+            tape.message(&msg);
+            if let Message::Close(close) = msg {
+                info!(frame = ?close, "closed by server");
+            }
+        }
+        let _ = ws_stream.close(None).await;
+    };
+    if tokio::time::timeout(CLOSE_TIMEOUT, closing).await.is_err() {
+        warn!(secs = CLOSE_TIMEOUT.as_secs(), "close not acknowledged, dropped");
+    }
+}
+
+/// Reads until `ack` tells that the exchange confirmed or refused the subscription, for at most
+/// `ACK_TIMEOUT`. Returns every message read, the confirmation included, as they still need to be parsed.
+pub(crate) async fn await_ack(
+    ws_stream: &mut WsStream,
+    exchange: Exchange,
+    symbol: &str,
+    url: &str,
+    ack: fn(&str) -> Ack,
+    tape: &Tape,
+) -> Result<Vec<Message>, Error>
+{
+    let mut backlog = vec![];
+    let wait = async {
+        while let Some(msg) = ws_stream.next().await {
+            let msg = msg.map_err(|e| Error::connect(exchange, symbol, url, e))?;
+            tape.message(&msg);
+            let acked = match &msg {
+                Message::Text(text) => ack(text),
+                _ => Ack::Pending,
+            };
+            match acked {
+                Ack::Subscribed => {
+                    backlog.push(msg);
+                    return Ok(());
+                },
+                Ack::Rejected(reason) => {
+                    let payload = msg.to_text().unwrap_or_default();
+                    return Err(Error::subscribe_rejected(exchange, symbol, reason, payload));
+                },
+                Ack::Pending => backlog.push(msg),
+            }
+        }
+        Err(Error::connect(exchange, symbol, url, tungstenite::Error::ConnectionClosed))
+    };
+
+    match tokio::time::timeout(ACK_TIMEOUT, wait).await {
+        Ok(result) => result.map(|()| backlog),
+        Err(_) => Err(Error::SubscribeTimeout { exchange, symbol: symbol.to_string(), secs: ACK_TIMEOUT.as_secs() }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+    use crate::websocket::*;
+
+    #[tokio::test]
+    async fn should_drop_when_close_not_acknowledged() {
+        /*
+         * Given
+         */
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            // accepts, then never reads again, as a half-open peer
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+        let tape = Tape::new(None, 1, Exchange::Kraken, "ETH/BTC");
+        let mut ws_stream = connect(Exchange::Kraken, "ETH/BTC", &url, &tape).await.unwrap();
+
+        /*
+         * When
+         */
+        let started = Instant::now();
+        close(&mut ws_stream, &tape).await;
+
+        /*
+         * Then
+         */
+        assert!(started.elapsed() < CLOSE_TIMEOUT + Duration::from_secs(1), "{:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,312 @@
+//! Local WebSocket servers which play scripted sessions in the protocol of an exchange, so that the
+//! aggregator can be run end-to-end without leaving the machine. Exchanges which hand out their
+//! WebSocket endpoint over REST get a local HTTP server as well.
+
+use futures::{SinkExt, StreamExt};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Exchange whose protocol a mock speaks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Binance,
+    Bitstamp,
+    Kraken,
+    Coinbase,
+    Okx,
+    Bybit,
+    Bitfinex,
+    Gemini,
+    Kucoin,
+    Deribit,
+    BinanceFutures,
+}
+
+impl Protocol {
+    /// Directory of the fixtures recorded from the exchange.
+    fn name(&self) -> &'static str {
+        match self {
+            Protocol::Binance => "binance",
+            Protocol::Bitstamp => "bitstamp",
+            Protocol::Kraken => "kraken",
+            Protocol::Coinbase => "coinbase",
+            Protocol::Okx => "okx",
+            Protocol::Bybit => "bybit",
+            Protocol::Bitfinex => "bitfinex",
+            Protocol::Gemini => "gemini",
+            Protocol::Kucoin => "kucoin",
+            Protocol::Deribit => "deribit",
+            Protocol::BinanceFutures => "binance_futures",
+        }
+    }
+
+    /// Steps up to and including the confirmation of the subscription. Binance confirms nothing, as the
+    /// stream is named in the URL, nor does Gemini, whose first book stands for the confirmation. KuCoin
+    /// greets the connection before anything else, and Deribit is asked for heartbeats once subscribed.
+    fn handshake(&self) -> Vec<Step> {
+        match self {
+            Protocol::Binance | Protocol::BinanceFutures => vec![],
+            Protocol::Bitstamp => vec![Step::Receive, self.send("subscription_succeeded.json")],
+            Protocol::Kraken => vec![self.send("system_status.json"), Step::Receive, self.send("subscribed.json")],
+            Protocol::Coinbase => vec![Step::Receive, self.send("subscriptions.json")],
+            Protocol::Okx => vec![Step::Receive, self.send("subscribe.json")],
+            Protocol::Bybit => vec![Step::Receive, self.send("subscribed.json")],
+            Protocol::Bitfinex => vec![self.send("info.json"), Step::Receive, self.send("subscribed.json")],
+            Protocol::Gemini => vec![Step::Receive],
+            Protocol::Kucoin => vec![self.send("welcome.json"), Step::Receive, self.send("ack.json")],
+            Protocol::Deribit => vec![Step::Receive, self.send("subscribed.json"), Step::Receive, self.send("heartbeat_set.json")],
+        }
+    }
+
+    /// First book sent after the handshake. KuCoin sends its book over REST, see `rest_routes`, and
+    /// changes to it over the WebSocket.
+    fn snapshot(&self) -> Step {
+        match self {
+            Protocol::Binance => self.send("depth.json"),
+            Protocol::Bitstamp => self.send("data.json"),
+            Protocol::Kraken => self.send("book_snapshot.json"),
+            Protocol::Coinbase => self.send("snapshot.json"),
+            Protocol::Okx => self.send("books_snapshot.json"),
+            Protocol::Bybit => self.send("orderbook_snapshot.json"),
+            Protocol::Bitfinex => self.send("book_snapshot.json"),
+            Protocol::Gemini => self.send("l2_snapshot.json"),
+            Protocol::Kucoin => self.send("level2_update.json"),
+            Protocol::Deribit => self.send("book_snapshot.json"),
+            Protocol::BinanceFutures => self.send("depth_update.json"),
+        }
+    }
+
+    fn send(&self, fixture_name: &str) -> Step {
+        Step::Send(fixture(&format!("{}/{}", self.name(), fixture_name)))
+    }
+
+    /// Confirms the subscription and sends a book.
+    pub fn snapshot_session(&self) -> Vec<Step> {
+        let mut steps = self.handshake();
+        steps.push(self.snapshot());
+        steps
+    }
+
+    /// Confirms the subscription, sends a book and then changes to it. Bitstamp and Binance send whole
+    /// books every time, the others changes. Deribit only sends them once its `test_request` was answered.
+    pub fn updates_session(&self) -> Vec<Step> {
+        let mut steps = self.snapshot_session();
+        match self {
+            Protocol::Kraken => steps.push(self.send("book_update.json")),
+            Protocol::Coinbase => steps.push(self.send("l2update.json")),
+            Protocol::Okx => steps.push(self.send("books_update.json")),
+            Protocol::Bybit => steps.push(self.send("orderbook_delta.json")),
+            Protocol::Bitfinex => steps.push(self.send("book_update.json")),
+            Protocol::Gemini => steps.extend([self.send("trade.json"), self.send("l2_update.json")]),
+            Protocol::Kucoin => steps.push(self.send("level2_update_next.json")),
+            Protocol::Deribit => steps.extend([self.send("test_request.json"), Step::Receive, self.send("book_change.json")]),
+            Protocol::BinanceFutures => steps.push(self.send("depth_update_next.json")),
+            Protocol::Binance | Protocol::Bitstamp => steps.push(self.snapshot()),
+        }
+        steps
+    }
+
+    /// Confirms the subscription, sends a book and then a trade every 100 ms for 10 seconds, so that
+    /// streams opened in the meantime get some. Only exchanges with a trade channel send trades.
+    pub fn trades_session(&self) -> Vec<Step> {
+        let trade = match self {
+            Protocol::Binance => self.send("agg_trade.json"),
+            Protocol::Bitstamp | Protocol::Kraken | Protocol::Gemini => self.send("trade.json"),
+            Protocol::Coinbase => self.send("match.json"),
+            _ => panic!("{:?} sends no trades", self),
+        };
+        let mut steps = self.snapshot_session();
+        for _ in 0..100 {
+            steps.extend([Step::Sleep(Duration::from_millis(100)), trade.clone()]);
+        }
+        steps
+    }
+
+    /// Confirms the subscription and sends the best bid and offer instead of a book. Only Binance, Kraken
+    /// and Coinbase have a channel of their own for them.
+    pub fn bbo_session(&self) -> Vec<Step> {
+        let bbo = match self {
+            Protocol::Binance => self.send("book_ticker.json"),
+            Protocol::Kraken => self.send("spread.json"),
+            Protocol::Coinbase => self.send("ticker.json"),
+            _ => panic!("{:?} sends no best bid and offer", self),
+        };
+        let mut steps = self.handshake();
+        steps.push(bbo);
+        steps
+    }
+
+    /// Refuses the subscription as the exchange does for an unknown pair.
+    pub fn error_session(&self) -> Vec<Step> {
+        match self {
+            Protocol::Binance | Protocol::BinanceFutures => vec![],
+            Protocol::Bitstamp => vec![Step::Receive, self.send("error.json")],
+            Protocol::Kraken => vec![self.send("system_status.json"), Step::Receive, self.send("subscription_error.json")],
+            Protocol::Coinbase => vec![Step::Receive, self.send("error.json")],
+            Protocol::Okx => vec![Step::Receive, self.send("error.json")],
+            Protocol::Bybit => vec![Step::Receive, self.send("subscription_error.json")],
+            Protocol::Bitfinex => vec![self.send("info.json"), Step::Receive, self.send("error.json")],
+            Protocol::Gemini => vec![Step::Receive, self.send("error.json")],
+            Protocol::Kucoin => vec![self.send("welcome.json"), Step::Receive, self.send("error.json")],
+            Protocol::Deribit => vec![Step::Receive, self.send("error.json")],
+        }
+    }
+
+    /// Bodies of the REST API by path, which hand out `ws_url` as the WebSocket endpoint or the snapshots
+    /// of the book. Empty for the exchanges which need no REST.
+    pub fn rest_routes(&self, ws_url: &str) -> Vec<(String, String)> {
+        match self {
+            Protocol::Kucoin => vec![
+                ("/api/v1/bullet-public".to_string(), fixture("kucoin/bullet_public.json").replace("{endpoint}", ws_url)),
+                ("/api/v1/market/orderbook/level2_100".to_string(), fixture("kucoin/level2_snapshot.json")),
+            ],
+            Protocol::BinanceFutures => vec![
+                ("/fapi/v1/depth".to_string(), fixture("binance_futures/depth_snapshot.json")),
+            ],
+            _ => vec![],
+        }
+    }
+
+    /// Sends a book, then a change to it whose checksum does not match. Only OKX sends checksums
+    /// which the aggregator validates.
+    pub fn bad_checksum_session(&self) -> Vec<Step> {
+        let mut steps = self.snapshot_session();
+        steps.push(self.send("books_update_bad_checksum.json"));
+        steps
+    }
+
+    /// Confirms the subscription, sends a message which does not parse, then a book.
+    pub fn garbled_session(&self) -> Vec<Step> {
+        let mut steps = self.handshake();
+        steps.extend([Step::Send("nonsense".to_string()), self.snapshot()]);
+        steps
+    }
+
+    /// Sends a book, then closes the connection a moment later, so that the book is streamed first.
+    pub fn disconnect_session(&self) -> Vec<Step> {
+        let mut steps = self.snapshot_session();
+        steps.extend([Step::Sleep(Duration::from_millis(500)), Step::Close]);
+        steps
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Waits for the next text message of the aggregator, e.g. its subscription.
+    Receive,
+
+    Send(String),
+
+    Sleep(Duration),
+
+    /// Closes the connection from the exchange side.
+    Close,
+}
+
+/// WebSocket server on a local port playing one session per connection.
+pub struct MockExchange {
+    pub url: String,
+    connections: Arc<AtomicUsize>,
+}
+
+impl MockExchange {
+    /// Plays `sessions` to successive connections, the last one again to any further connection. A
+    /// session which does not close keeps the connection open once played.
+    pub async fn start(sessions: Vec<Vec<Step>>) -> MockExchange {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let count = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                let session = sessions[n.min(sessions.len() - 1)].clone();
+                tokio::spawn(async move {
+                    if let Ok(ws) = tokio_tungstenite::accept_async(stream).await {
+                        play(ws, session).await;
+                    }
+                });
+            }
+        });
+
+        MockExchange { url, connections }
+    }
+
+    /// Number of connections the aggregator made so far.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+async fn play(mut ws: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, session: Vec<Step>) {
+    for step in session {
+        match step {
+            Step::Receive => loop {
+                match ws.next().await {
+                    Some(Ok(Message::Text(_))) => break,
+                    Some(Ok(_)) => continue,
+                    _ => return,
+                }
+            },
+            Step::Send(text) => {
+                if ws.send(Message::Text(text)).await.is_err() {
+                    return;
+                }
+            },
+            Step::Sleep(duration) => tokio::time::sleep(duration).await,
+            Step::Close => {
+                let _ = ws.close(None).await;
+                return;
+            },
+        }
+    }
+    // answer pings and the close handshake until the aggregator goes away
+    while let Some(Ok(_)) = ws.next().await {}
+}
+
+/// HTTP server on a local port answering any method with the body routed to the path of the request,
+/// whatever its query, and with 404 to other paths.
+pub struct MockRest {
+    pub url: String,
+}
+
+impl MockRest {
+    pub async fn start(routes: Vec<(String, String)>) -> MockRest {
+        let routes = Arc::new(routes);
+        let make_service = make_service_fn(move |_| {
+            let routes = routes.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                    let body = routes.iter()
+                        .find(|(path, _)| path == request.uri().path())
+                        .map(|(_, body)| body.clone());
+                    async move {
+                        Ok::<_, Infallible>(match body {
+                            Some(body) => Response::new(Body::from(body)),
+                            None => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+                        })
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        MockRest { url }
+    }
+}
+
+/// Contents of a file below `tests/fixtures`, e.g. `kraken/subscribed.json`.
+pub fn fixture(path: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(path);
+    std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,56 @@
+//! Helpers shared by the integration tests, which start a server in-process.
+
+use orderly::config::Config;
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+use tonic::transport::{Channel, ClientTlsConfig};
+
+#[allow(dead_code)]
+pub mod mock;
+
+/// Starts a server with only the exchanges given a `url`, i.e. a mock, so that no connection leaves
+/// the machine. A replay connects nowhere, so it keeps them all.
+pub fn serve(config: Config) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let listen = listener.local_addr().unwrap();
+
+    let mut config = Config { listen, ..config };
+    let replay = config.replay.is_some();
+    for venue in [
+        &mut config.venues.bitstamp,
+        &mut config.venues.binance,
+        &mut config.venues.kraken,
+        &mut config.venues.coinbase,
+        &mut config.venues.okx,
+        &mut config.venues.bybit,
+        &mut config.venues.bitfinex,
+        &mut config.venues.gemini,
+        &mut config.venues.kucoin,
+        &mut config.venues.deribit,
+        &mut config.venues.binance_futures,
+        &mut config.venues.binance_us,
+    ] {
+        venue.enabled = venue.enabled && (venue.url.is_some() || replay);
+    }
+
+    tokio::spawn(orderly::orderly::run_with_listener(config, listener));
+    listen
+}
+
+/// Connects to the server, retrying while it is starting up.
+pub async fn connect(uri: String, tls: Option<ClientTlsConfig>) -> Result<Channel, String> {
+    let mut endpoint = Channel::from_shared(uri).unwrap();
+    if let Some(tls) = tls {
+        endpoint = endpoint.tls_config(tls).unwrap();
+    }
+
+    let mut last_error = String::new();
+    for _ in 0..50 {
+        match endpoint.connect().await {
+            Ok(channel) => return Ok(channel),
+            Err(e) => last_error = format!("{:?}", e),
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Err(last_error)
+}
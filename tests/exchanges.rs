@@ -0,0 +1,769 @@
+//! Streams books merged from mock exchanges, which play scripted sessions recorded from the real ones,
+//! and checks what a gRPC client receives.
+
+use common::mock::{fixture, MockExchange, MockRest, Protocol, Step};
+use flate2::read::MultiGzDecoder;
+use orderly::config::{CandlesConfig, Config, ExportConfig, ExportFormat, ExportLayout, InstrumentKind, ReplayConfig, Speed};
+use proto::orderbook_aggregator_client::OrderbookAggregatorClient;
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+mod common;
+
+mod proto {
+    tonic::include_proto!("orderbook");
+}
+
+/// Longest wait for the stream to show what a test expects.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves with a mock for each of the given exchanges, which plays the given sessions.
+async fn serve(mocks: Vec<(Protocol, Vec<Vec<Step>>)>) -> (OrderbookAggregatorClient<Channel>, Vec<MockExchange>) {
+    serve_with(Config::default(), mocks).await
+}
+
+/// Same as `serve`, with the settings of `config` other than the venues played by the mocks.
+async fn serve_with(
+    mut config: Config,
+    mocks: Vec<(Protocol, Vec<Vec<Step>>)>,
+) -> (OrderbookAggregatorClient<Channel>, Vec<MockExchange>)
+{
+    let mut exchanges = vec![];
+    for (protocol, sessions) in mocks {
+        let mock = MockExchange::start(sessions).await;
+        let venue = match protocol {
+            Protocol::Binance => &mut config.venues.binance,
+            Protocol::Bitstamp => &mut config.venues.bitstamp,
+            Protocol::Kraken => &mut config.venues.kraken,
+            Protocol::Coinbase => &mut config.venues.coinbase,
+            Protocol::Okx => &mut config.venues.okx,
+            Protocol::Bybit => &mut config.venues.bybit,
+            Protocol::Bitfinex => &mut config.venues.bitfinex,
+            Protocol::Gemini => &mut config.venues.gemini,
+            Protocol::Kucoin => &mut config.venues.kucoin,
+            Protocol::Deribit => &mut config.venues.deribit,
+            Protocol::BinanceFutures => &mut config.venues.binance_futures,
+        };
+        venue.url = Some(mock.url.clone());
+        let routes = protocol.rest_routes(&mock.url);
+        if !routes.is_empty() {
+            let rest = MockRest::start(routes).await;
+            // KuCoin is only told its REST endpoint, which hands out the WebSocket one
+            match protocol {
+                Protocol::Kucoin => venue.url = Some(rest.url),
+                _ => venue.rest_url = Some(rest.url),
+            }
+        }
+        exchanges.push(mock);
+    }
+
+    let addr = common::serve(config);
+    let channel = common::connect(format!("http://{}", addr), None).await.unwrap();
+    (OrderbookAggregatorClient::new(channel), exchanges)
+}
+
+async fn book_summary(client: &mut OrderbookAggregatorClient<Channel>) -> Streaming<proto::Summary> {
+    let request = proto::SummaryRequest { symbol: "".to_string() };
+    client.book_summary(request).await.unwrap().into_inner()
+}
+
+/// Reads the stream until a message satisfies `predicate`, and returns it with the number of messages read.
+async fn until<T>(stream: &mut Streaming<T>, predicate: impl Fn(&T) -> bool) -> (T, usize) {
+    let read = async {
+        let mut count = 0;
+        loop {
+            let message = stream.message().await.unwrap().expect("stream ended");
+            count += 1;
+            if predicate(&message) {
+                return (message, count);
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, read).await.expect("timed out waiting for the expected message")
+}
+
+/// Levels as exchange and price, the price rounded so that it compares exactly.
+fn levels(levels: &[proto::Level]) -> Vec<(String, String)> {
+    levels.iter().map(|l| (l.exchange.clone(), format!("{:.8}", l.price))).collect()
+}
+
+fn level(exchange: &str, price: &str) -> (String, String) {
+    (exchange.to_string(), price.to_string())
+}
+
+fn health(summary: &proto::Summary, exchange: &str) -> Option<proto::Health> {
+    summary.venues.get(exchange).and_then(|h| proto::Health::from_i32(*h))
+}
+
+fn is_live(summary: &proto::Summary, exchanges: &[&str]) -> bool {
+    exchanges.iter().all(|e| health(summary, e) == Some(proto::Health::Live))
+}
+
+#[tokio::test]
+async fn should_merge_snapshots_of_all_exchanges() {
+    /*
+     * Given
+     */
+    let (mut client, _mocks) = serve(vec![
+        (Protocol::Binance, vec![Protocol::Binance.snapshot_session()]),
+        (Protocol::Bitstamp, vec![Protocol::Bitstamp.snapshot_session()]),
+        (Protocol::Kraken, vec![Protocol::Kraken.snapshot_session()]),
+        (Protocol::Coinbase, vec![Protocol::Coinbase.snapshot_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| is_live(s, &["binance", "bitstamp", "kraken", "coinbase"])).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(levels(&summary.bids), vec![
+        level("binance", "0.06900300"),
+        level("bitstamp", "0.06900200"),
+        level("binance", "0.06900100"),
+        level("kraken", "0.06900000"),
+        level("coinbase", "0.06899900"),
+        level("kraken", "0.06899500"),
+        level("bitstamp", "0.06899000"),
+    ]);
+    assert_eq!(levels(&summary.asks), vec![
+        level("binance", "0.06900400"),
+        level("coinbase", "0.06900450"),
+        level("binance", "0.06900500"),
+        level("bitstamp", "0.06900600"),
+        level("kraken", "0.06900700"),
+        level("bitstamp", "0.06901000"),
+        level("kraken", "0.06901200"),
+    ]);
+    assert_eq!(format!("{:.8}", summary.spread), "0.00000100");
+}
+
+#[tokio::test]
+async fn should_apply_updates() {
+    /*
+     * Given
+     */
+    let (mut client, _mocks) = serve(vec![
+        (Protocol::Kraken, vec![Protocol::Kraken.updates_session()]),
+        (Protocol::Coinbase, vec![Protocol::Coinbase.updates_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| {
+        let bids = levels(&s.bids);
+        bids.contains(&level("kraken", "0.06899800"))
+            && !bids.contains(&level("kraken", "0.06900000")) && !bids.contains(&level("coinbase", "0.06899900"))
+    }).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(levels(&summary.bids), vec![
+        level("kraken", "0.06899800"),
+        level("kraken", "0.06899500"),
+    ]);
+    assert_eq!(levels(&summary.asks), vec![
+        level("coinbase", "0.06900420"),
+        level("coinbase", "0.06900450"),
+        level("kraken", "0.06900700"),
+        level("kraken", "0.06901200"),
+    ]);
+}
+
+#[tokio::test]
+async fn should_report_rejected_subscription() {
+    /*
+     * Given
+     */
+    let (mut client, _mocks) = serve(vec![
+        (Protocol::Kraken, vec![Protocol::Kraken.error_session()]),
+    ]).await;
+    let request = proto::SummaryRequest { symbol: "".to_string() };
+    let mut stream = client.venue_status(request).await.unwrap().into_inner();
+
+    /*
+     * When
+     */
+    let (statuses, _) = until(&mut stream, |s| {
+        s.venues.iter().any(|v| v.exchange == "kraken" && v.health == proto::Health::Disconnected as i32)
+    }).await;
+
+    /*
+     * Then
+     */
+    let kraken = statuses.venues.iter().find(|v| v.exchange == "kraken").unwrap();
+    assert_eq!(kraken.symbol, "ETH/BTC");
+    assert!(kraken.last_error.contains("Currency pair not supported ETH/XYZ"), "{}", kraken.last_error);
+}
+
+#[tokio::test]
+async fn should_drop_levels_of_disconnected_exchange() {
+    /*
+     * Given
+     */
+    // the exchange then never confirms the subscription again
+    let (mut client, _mocks) = serve(vec![
+        (Protocol::Binance, vec![Protocol::Binance.snapshot_session()]),
+        (Protocol::Bitstamp, vec![Protocol::Bitstamp.disconnect_session(), vec![]]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+    let was_live = Cell::new(false);
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| {
+        was_live.set(was_live.get() || is_live(s, &["bitstamp"]));
+        was_live.get() && health(s, "bitstamp") == Some(proto::Health::Connecting) && is_live(s, &["binance"])
+    }).await;
+
+    /*
+     * Then
+     */
+    assert!(summary.bids.iter().chain(&summary.asks).all(|l| l.exchange == "binance"));
+    assert_eq!(summary.bids.len(), 2);
+    assert_eq!(summary.asks.len(), 2);
+}
+
+#[tokio::test]
+async fn should_reconnect_after_disconnect() {
+    /*
+     * Given
+     */
+    let (mut client, mocks) = serve(vec![
+        (Protocol::Bitstamp, vec![Protocol::Bitstamp.disconnect_session(), Protocol::Bitstamp.snapshot_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| is_live(s, &["bitstamp"]) && mocks[0].connections() == 2).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(summary.bids.len(), 2);
+}
+
+#[tokio::test]
+async fn should_skip_message_which_does_not_parse() {
+    /*
+     * Given
+     */
+    let (mut client, mocks) = serve(vec![(Protocol::Bitstamp, vec![Protocol::Bitstamp.garbled_session()])]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| is_live(s, &["bitstamp"])).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(summary.bids.len(), 2);
+    assert_eq!(mocks[0].connections(), 1);
+}
+
+#[tokio::test]
+async fn should_skip_to_latest_book_for_slow_consumer() {
+    /*
+     * Given
+     */
+    const DEPTHS: usize = 500;
+    let depth = fixture("binance/depth.json");
+    let mut session = vec![Step::Sleep(Duration::from_millis(500))];
+    for i in 1..=DEPTHS {
+        session.push(Step::Send(depth.replace("14.80480000", &format!("{}.00000000", i))));
+    }
+    let (mut client, _mocks) = serve(vec![(Protocol::Binance, vec![session])]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    // busy elsewhere while the exchange sends every depth
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let (summary, received) = until(&mut stream, |s| {
+        s.bids.first().map(|l| l.amount) == Some(DEPTHS as f64)
+    }).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(levels(&summary.bids)[0], level("binance", "0.06900300"));
+    assert!(received < DEPTHS, "received {} summaries for {} depths", received, DEPTHS);
+}
+
+#[tokio::test]
+async fn should_record_raw_messages() {
+    /*
+     * Given
+     */
+    let dir = tempfile::tempdir().unwrap();
+    let mock = MockExchange::start(vec![Protocol::Kraken.snapshot_session()]).await;
+    let mut config = Config { record: Some(dir.path().to_path_buf()), ..Default::default() };
+    config.venues.kraken.url = Some(mock.url.clone());
+    let addr = common::serve(config);
+    let mut client = OrderbookAggregatorClient::new(common::connect(format!("http://{}", addr), None).await.unwrap());
+    let mut stream = book_summary(&mut client).await;
+    until(&mut stream, |s| is_live(s, &["kraken"])).await;
+
+    /*
+     * When
+     */
+    // the recording is flushed every second while the server runs
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let path = std::fs::read_dir(dir.path()).unwrap().next().unwrap().unwrap().path();
+    let mut lines = vec![];
+    for line in BufReader::new(MultiGzDecoder::new(File::open(&path).unwrap())).lines() {
+        match line {
+            Ok(line) => lines.push(serde_json::from_str::<serde_json::Value>(&line).unwrap()),
+            // the file is not finished yet
+            Err(_) => break,
+        }
+    }
+
+    /*
+     * Then
+     */
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    assert!(name.starts_with("kraken_ETH-BTC_") && name.ends_with(".jsonl.gz"), "{}", name);
+    let events: Vec<&str> = lines.iter().map(|l| l["event"].as_str().unwrap()).collect();
+    assert_eq!(events, vec!["connect", "subscribe", "text", "text", "text"]);
+    assert_eq!(lines[0]["url"], mock.url.as_str());
+    assert_eq!(lines[2]["data"], fixture("kraken/system_status.json"));
+    assert_eq!(lines[4]["data"], fixture("kraken/book_snapshot.json"));
+    assert!(lines.iter().all(|l| l["exchange"] == "kraken" && l["symbol"] == "ETH/BTC"));
+}
+
+#[tokio::test]
+async fn should_replay_recording() {
+    /*
+     * Given
+     */
+    let dir = tempfile::tempdir().unwrap();
+    let kraken = MockExchange::start(vec![Protocol::Kraken.updates_session()]).await;
+    let coinbase = MockExchange::start(vec![Protocol::Coinbase.updates_session()]).await;
+    let mut config = Config { record: Some(dir.path().to_path_buf()), ..Default::default() };
+    config.venues.kraken.url = Some(kraken.url.clone());
+    config.venues.coinbase.url = Some(coinbase.url.clone());
+    let addr = common::serve(config);
+    let mut client = OrderbookAggregatorClient::new(common::connect(format!("http://{}", addr), None).await.unwrap());
+    let (live, _) = until(&mut book_summary(&mut client).await, |s| {
+        levels(&s.bids).contains(&level("kraken", "0.06899800")) && levels(&s.asks).contains(&level("coinbase", "0.06900420"))
+    }).await;
+    // the recording is flushed every second while the server runs
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    /*
+     * When
+     */
+    let replay = ReplayConfig { dir: dir.path().to_path_buf(), speed: Speed::Max, exit: false };
+    let addr = common::serve(Config { replay: Some(replay), ..Default::default() });
+    let mut client = OrderbookAggregatorClient::new(common::connect(format!("http://{}", addr), None).await.unwrap());
+    let (replayed, _) = until(&mut book_summary(&mut client).await, |s| {
+        levels(&s.bids) == levels(&live.bids) && levels(&s.asks) == levels(&live.asks)
+    }).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(replayed, live);
+}
+
+#[tokio::test]
+async fn should_export_replayed_books() {
+    /*
+     * Given
+     */
+    let dir = tempfile::tempdir().unwrap();
+    let kraken = MockExchange::start(vec![Protocol::Kraken.updates_session()]).await;
+    let mut config = Config { record: Some(dir.path().join("feeds")), ..Default::default() };
+    config.venues.kraken.url = Some(kraken.url.clone());
+    let addr = common::serve(config);
+    let mut client = OrderbookAggregatorClient::new(common::connect(format!("http://{}", addr), None).await.unwrap());
+    until(&mut book_summary(&mut client).await, |s| levels(&s.bids).contains(&level("kraken", "0.06899800"))).await;
+    // the recording is flushed every second while the server runs
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    /*
+     * When
+     */
+    let replay = ReplayConfig { dir: dir.path().join("feeds"), speed: Speed::Max, exit: true };
+    let export = ExportConfig {
+        dir: dir.path().join("books"),
+        format: ExportFormat::Csv,
+        layout: ExportLayout::Long,
+        interval_ms: None,
+        venues: true,
+    };
+    common::serve(Config { replay: Some(replay), export: Some(export), ..Default::default() });
+    // the replay stops the server once it finished, which completes the export
+    let mut rows = String::new();
+    let started = std::time::Instant::now();
+    while !rows.contains("ETH/BTC,kraken,bid,0,0.068998,2,kraken") && started.elapsed() < TIMEOUT {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if let Some(Ok(entry)) = std::fs::read_dir(dir.path().join("books")).ok().and_then(|mut files| files.next()) {
+            rows = std::fs::read_to_string(entry.path()).unwrap();
+        }
+    }
+
+    /*
+     * Then
+     */
+    let mut lines = rows.lines();
+    assert_eq!(lines.next(), Some("timestamp,symbol,book,side,level,price,amount,exchange"));
+    assert!(rows.contains("ETH/BTC,merged,ask,0,0.069007,3,kraken"), "{}", rows);
+    assert!(rows.contains("ETH/BTC,kraken,bid,0,0.068998,2,kraken"), "{}", rows);
+}
+
+#[tokio::test]
+async fn should_resubscribe_on_checksum_mismatch() {
+    /*
+     * Given
+     */
+    let (mut client, mocks) = serve(vec![
+        (Protocol::Okx, vec![Protocol::Okx.bad_checksum_session(), Protocol::Okx.updates_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| levels(&s.bids).contains(&level("okx", "0.06897000"))).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(mocks[0].connections(), 2);
+    assert_eq!(levels(&summary.bids), vec![level("okx", "0.06899000"), level("okx", "0.06897000")]);
+    assert_eq!(levels(&summary.asks), vec![level("okx", "0.06901000"), level("okx", "0.06902000")]);
+}
+
+#[tokio::test]
+async fn should_resync_on_update_id_gap() {
+    /*
+     * Given
+     */
+    let mut gap = Protocol::Bybit.snapshot_session();
+    gap.push(Step::Send(fixture("bybit/orderbook_delta.json").replace(r#""u":501"#, r#""u":600"#)));
+    let (mut client, mocks) = serve(vec![
+        (Protocol::Bybit, vec![gap, Protocol::Bybit.updates_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| levels(&s.bids).contains(&level("bybit", "0.06898000"))).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(mocks[0].connections(), 2);
+    assert_eq!(levels(&summary.bids), vec![level("bybit", "0.06899000"), level("bybit", "0.06898000")]);
+    assert_eq!(levels(&summary.asks), vec![level("bybit", "0.06901000"), level("bybit", "0.06902000")]);
+}
+
+#[tokio::test]
+async fn should_delete_levels_of_zero_count() {
+    /*
+     * Given
+     */
+    let (mut client, _mocks) = serve(vec![
+        (Protocol::Bitfinex, vec![Protocol::Bitfinex.updates_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| {
+        is_live(s, &["bitfinex"]) && !levels(&s.bids).contains(&level("bitfinex", "0.06900000"))
+    }).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(levels(&summary.bids), vec![level("bitfinex", "0.06899000")]);
+    assert_eq!(levels(&summary.asks), vec![level("bitfinex", "0.06901000"), level("bitfinex", "0.06902000")]);
+}
+
+#[tokio::test]
+async fn should_apply_changes_streamed_with_trades() {
+    /*
+     * Given
+     */
+    let (mut client, _mocks) = serve(vec![
+        (Protocol::Gemini, vec![Protocol::Gemini.updates_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| levels(&s.asks).contains(&level("gemini", "0.06900500"))).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(levels(&summary.bids), vec![level("gemini", "0.06899700")]);
+    assert_eq!(levels(&summary.asks), vec![
+        level("gemini", "0.06900500"),
+        level("gemini", "0.06900600"),
+        level("gemini", "0.06901100"),
+    ]);
+}
+
+#[tokio::test]
+async fn should_resync_on_sequence_gap() {
+    /*
+     * Given
+     */
+    let mut gap = Protocol::Kucoin.snapshot_session();
+    gap.push(Step::Send(fixture("kucoin/level2_update_next.json").replace("1003", "1005")));
+    let (mut client, mocks) = serve(vec![
+        (Protocol::Kucoin, vec![gap, Protocol::Kucoin.updates_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| levels(&s.bids).contains(&level("kucoin", "0.06899900"))).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(mocks[0].connections(), 2);
+    assert_eq!(levels(&summary.bids), vec![
+        level("kucoin", "0.06899900"),
+        level("kucoin", "0.06899600"),
+    ]);
+    assert_eq!(levels(&summary.asks), vec![
+        level("kucoin", "0.06900600"),
+        level("kucoin", "0.06900800"),
+        level("kucoin", "0.06901200"),
+    ]);
+}
+
+#[tokio::test]
+async fn should_merge_only_perpetuals_and_answer_test_requests() {
+    /*
+     * Given
+     */
+    let mut config = Config { symbols: vec!["BTC/USD".to_string()], kind: InstrumentKind::Perpetual, ..Default::default() };
+    config.venues.deribit.kind = Some(InstrumentKind::Perpetual);
+    let (mut client, _mocks) = serve_with(config, vec![
+        (Protocol::Deribit, vec![Protocol::Deribit.updates_session()]),
+        (Protocol::Bybit, vec![Protocol::Bybit.snapshot_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s|
+        is_live(s, &["bybit"]) && levels(&s.asks).contains(&level("deribit", "20001.50000000"))).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(levels(&summary.bids), vec![
+        level("deribit", "20001.00000000"),
+        level("deribit", "20000.00000000"),
+    ]);
+    assert_eq!(levels(&summary.asks), vec![
+        level("deribit", "20001.50000000"),
+        level("deribit", "20002.00000000"),
+        level("deribit", "20003.50000000"),
+    ]);
+}
+
+#[tokio::test]
+async fn should_resync_futures_diff_on_previous_update_id_gap() {
+    /*
+     * Given
+     */
+    let mut config = Config { symbols: vec!["BTC/USDT".to_string()], kind: InstrumentKind::Perpetual, ..Default::default() };
+    config.venues.binance_futures.diff = true;
+    let mut gap = Protocol::BinanceFutures.snapshot_session();
+    gap.push(Step::Send(fixture("binance_futures/depth_update_next.json").replace(r#""pu":1005"#, r#""pu":1003"#)));
+    let (mut client, mocks) = serve_with(config, vec![
+        (Protocol::BinanceFutures, vec![gap, Protocol::BinanceFutures.updates_session()]),
+    ]).await;
+    let mut stream = book_summary(&mut client).await;
+
+    /*
+     * When
+     */
+    let (summary, _) = until(&mut stream, |s| levels(&s.asks).contains(&level("binance_futures", "20000.30000000"))).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(mocks[0].connections(), 2);
+    assert_eq!(levels(&summary.bids), vec![
+        level("binance_futures", "20000.00000000"),
+        level("binance_futures", "19999.90000000"),
+    ]);
+    assert_eq!(levels(&summary.asks), vec![
+        level("binance_futures", "20000.20000000"),
+        level("binance_futures", "20000.30000000"),
+        level("binance_futures", "20000.50000000"),
+    ]);
+}
+
+#[tokio::test]
+async fn should_stream_trades_of_requested_exchanges() {
+    /*
+     * Given
+     */
+    let mut config = Config::default();
+    config.venues.coinbase.trades = true;
+    let (mut client, _mocks) = serve_with(config, vec![
+        (Protocol::Coinbase, vec![Protocol::Coinbase.trades_session()]),
+        (Protocol::Gemini, vec![Protocol::Gemini.trades_session()]),
+    ]).await;
+    let request = proto::TradesRequest { symbol: "".to_string(), exchanges: vec!["coinbase".to_string()] };
+    let mut stream = client.trades(request).await.unwrap().into_inner();
+    let all = proto::TradesRequest { symbol: "".to_string(), exchanges: vec![] };
+    let mut all = client.trades(all).await.unwrap().into_inner();
+
+    /*
+     * When
+     */
+    let mut trades = vec![];
+    for _ in 0..5 {
+        trades.push(until(&mut stream, |_| true).await.0);
+    }
+    let mut exchanges = BTreeSet::new();
+    while exchanges.len() < 2 {
+        exchanges.insert(until(&mut all, |_| true).await.0.exchange);
+    }
+
+    /*
+     * Then
+     */
+    assert!(trades.iter().all(|t| t.exchange == "coinbase"), "{:?}", trades);
+    assert_eq!(trades[0], proto::Trade {
+        exchange: "coinbase".to_string(),
+        price: 0.069005,
+        amount: 0.25,
+        side: proto::TradeSide::Buy as i32,
+        trade_id: "39502561".to_string(),
+        time: 1656000001123,
+    });
+    assert_eq!(exchanges, BTreeSet::from(["coinbase".to_string(), "gemini".to_string()]));
+}
+
+#[tokio::test]
+async fn should_deny_trades_of_exchanges_not_allowed() {
+    /*
+     * Given
+     */
+    let dir = tempfile::tempdir().unwrap();
+    let auth_file = dir.path().join("auth.toml");
+    std::fs::write(&auth_file, "[clients.desk]\ntokens = [\"d3sk\"]\nexchanges = [\"gemini\"]\n").unwrap();
+    let (mut client, _mocks) = serve_with(Config { auth_file: Some(auth_file), ..Default::default() }, vec![]).await;
+    let request = |exchanges: &[&str]| {
+        let mut request = tonic::Request::new(proto::TradesRequest {
+            symbol: "".to_string(),
+            exchanges: exchanges.iter().map(|exchange| exchange.to_string()).collect(),
+        });
+        request.metadata_mut().insert("authorization", "Bearer d3sk".parse().unwrap());
+        request
+    };
+
+    /*
+     * When
+     */
+    let denied = client.trades(request(&["gemini", "coinbase"])).await;
+    let allowed = client.trades(request(&["gemini"])).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(denied.err().map(|status| status.code()), Some(tonic::Code::PermissionDenied));
+    assert!(allowed.is_ok(), "{:?}", allowed.err());
+}
+
+#[tokio::test]
+async fn should_stream_best_bid_and_offer_across_exchanges() {
+    /*
+     * Given
+     */
+    let mut config = Config::default();
+    for venue in [&mut config.venues.binance, &mut config.venues.kraken, &mut config.venues.coinbase] {
+        venue.bbo = true;
+    }
+    let (mut client, _mocks) = serve_with(config, vec![
+        (Protocol::Binance, vec![Protocol::Binance.bbo_session()]),
+        (Protocol::Kraken, vec![Protocol::Kraken.bbo_session()]),
+        (Protocol::Coinbase, vec![Protocol::Coinbase.bbo_session()]),
+    ]).await;
+    let request = proto::SummaryRequest { symbol: "".to_string() };
+    let mut stream = client.bbo(request).await.unwrap().into_inner();
+
+    /*
+     * When
+     */
+    let (bbo, _) = until(&mut stream, |bbo| {
+        bbo.bid.as_ref().is_some_and(|l| l.exchange == "kraken") && bbo.ask.as_ref().is_some_and(|l| l.exchange == "coinbase")
+    }).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(levels(&[bbo.bid.unwrap(), bbo.ask.unwrap()]), vec![level("kraken", "0.06900350"), level("coinbase", "0.06900380")]);
+    assert_eq!(format!("{:.8}", bbo.spread), "0.00000030");
+}
+
+#[tokio::test]
+async fn should_stream_candles_of_trades_and_mid_prices() {
+    /*
+     * Given
+     */
+    let mut config = Config { candles: Some(CandlesConfig { intervals_secs: vec![60], history: 10 }), ..Default::default() };
+    config.venues.coinbase.trades = true;
+    let (mut client, _mocks) = serve_with(config, vec![
+        (Protocol::Coinbase, vec![Protocol::Coinbase.trades_session()]),
+    ]).await;
+    let request = |exchange: &str, source: proto::CandleSource| proto::CandlesRequest {
+        symbol: "".to_string(),
+        exchange: exchange.to_string(),
+        interval_secs: 60,
+        source: source as i32,
+        backfill: 10,
+    };
+    let mut trades = client.candles(request("", proto::CandleSource::Trades)).await.unwrap().into_inner();
+    let mut mid = client.candles(request("coinbase", proto::CandleSource::Mid)).await.unwrap().into_inner();
+    let unbuilt = client.candles(proto::CandlesRequest { interval_secs: 30, ..request("", proto::CandleSource::Trades) }).await;
+
+    /*
+     * When
+     */
+    let (trades, _) = until(&mut trades, |candle| candle.count > 0).await;
+    let (mid, _) = until(&mut mid, |candle| candle.count > 0).await;
+
+    /*
+     * Then
+     */
+    assert_eq!((trades.exchange.as_str(), trades.start, format!("{:.8}", trades.open)), ("", 1656000000000, "0.06900500".to_string()));
+    assert!(trades.volume >= 0.25, "{:?}", trades);
+    assert_eq!((mid.exchange.as_str(), format!("{:.8}", mid.open), mid.volume), ("coinbase", "0.06900175".to_string(), 0.0));
+    assert_eq!(unbuilt.unwrap_err().code(), tonic::Code::InvalidArgument);
+}
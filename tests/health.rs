@@ -0,0 +1,60 @@
+//! Reports health through the standard `grpc.health.v1.Health` service.
+
+use orderly::config::Config;
+use tonic_health::proto::health_check_response::ServingStatus;
+use tonic_health::proto::health_client::HealthClient;
+use tonic_health::proto::HealthCheckRequest;
+
+mod common;
+
+async fn health(client: &mut HealthClient<tonic::transport::Channel>, service: &str) -> ServingStatus {
+    let request = HealthCheckRequest { service: service.to_string() };
+    let response = client.check(request).await.unwrap().into_inner();
+    ServingStatus::from_i32(response.status).unwrap()
+}
+
+#[tokio::test]
+async fn should_not_serve_before_any_book() {
+    /*
+     * Given
+     */
+    let addr = common::serve(Config::default());
+    let mut client = HealthClient::new(common::connect(format!("http://{}", addr), None).await.unwrap());
+
+    /*
+     * When
+     */
+    let server = health(&mut client, "").await;
+    let orderbook = health(&mut client, "orderbook.OrderbookAggregator").await;
+    let admin = health(&mut client, "orderbook.Admin").await;
+
+    /*
+     * Then
+     */
+    assert_eq!(server, ServingStatus::NotServing);
+    assert_eq!(orderbook, ServingStatus::NotServing);
+    assert_eq!(admin, ServingStatus::Serving);
+}
+
+#[tokio::test]
+async fn should_check_health_without_token() {
+    /*
+     * Given
+     */
+    let dir = tempfile::tempdir().unwrap();
+    let auth_file = dir.path().join("auth.toml");
+    std::fs::write(&auth_file, "[clients.dashboard]\ntokens = [\"d4shb0ard\"]\n").unwrap();
+
+    let addr = common::serve(Config { auth_file: Some(auth_file), ..Default::default() });
+    let channel = common::connect(format!("http://{}", addr), None).await.unwrap();
+
+    /*
+     * When
+     */
+    let status = HealthClient::new(channel).check(HealthCheckRequest { service: "".to_string() }).await;
+
+    /*
+     * Then
+     */
+    assert!(status.is_ok(), "{:?}", status);
+}
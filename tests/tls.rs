@@ -0,0 +1,145 @@
+//! Serves gRPC in plain text, over TLS and over mutual TLS, with certificates generated at test time.
+
+use orderly::config::{Config, TlsConfig};
+use proto::orderbook_aggregator_client::OrderbookAggregatorClient;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tempfile::TempDir;
+use tonic::transport::{self, ClientTlsConfig, Identity};
+
+mod common;
+
+mod proto {
+    tonic::include_proto!("orderbook");
+}
+
+/// PEM files of a CA, and of a server and a client certificate signed by it.
+struct Certs {
+    _dir: TempDir,
+    ca: PathBuf,
+    server_cert: PathBuf,
+    server_key: PathBuf,
+    client_cert: PathBuf,
+    client_key: PathBuf,
+}
+
+impl Certs {
+    fn generate() -> Certs {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, pem: String| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, pem).unwrap();
+            path
+        };
+
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "orderly test CA");
+        let ca = Certificate::from_params(params).unwrap();
+
+        let server = Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()])).unwrap();
+        let client = Certificate::from_params(CertificateParams::new(vec!["client".to_string()])).unwrap();
+
+        Certs {
+            ca: write("ca.pem", ca.serialize_pem().unwrap()),
+            server_cert: write("server.pem", server.serialize_pem_with_signer(&ca).unwrap()),
+            server_key: write("server.key", server.serialize_private_key_pem()),
+            client_cert: write("client.pem", client.serialize_pem_with_signer(&ca).unwrap()),
+            client_key: write("client.key", client.serialize_private_key_pem()),
+            _dir: dir,
+        }
+    }
+
+    fn ca(&self) -> transport::Certificate {
+        transport::Certificate::from_pem(std::fs::read(&self.ca).unwrap())
+    }
+
+    fn client_identity(&self) -> Identity {
+        Identity::from_pem(std::fs::read(&self.client_cert).unwrap(), std::fs::read(&self.client_key).unwrap())
+    }
+}
+
+/// Serves with the given TLS settings.
+fn serve(tls: Option<TlsConfig>) -> SocketAddr {
+    common::serve(Config { tls, ..Default::default() })
+}
+
+/// Calls `Check`.
+async fn check(uri: String, tls: Option<ClientTlsConfig>) -> Result<proto::Summary, String> {
+    let channel = common::connect(uri, tls).await?;
+    let request = proto::SummaryRequest { symbol: "".to_string() };
+    OrderbookAggregatorClient::new(channel).check(request).await
+        .map(|response| response.into_inner())
+        .map_err(|status| status.to_string())
+}
+
+#[tokio::test]
+async fn should_serve_on_listen_address() {
+    /*
+     * Given
+     */
+    let addr = serve(None);
+
+    /*
+     * When
+     */
+    let summary = check(format!("http://{}", addr), None).await;
+
+    /*
+     * Then
+     */
+    assert_eq!(summary, Ok(proto::Summary { spread: 0.0, bids: vec![], asks: vec![], venues: Default::default() }));
+}
+
+#[tokio::test]
+async fn should_serve_over_tls() {
+    /*
+     * Given
+     */
+    let certs = Certs::generate();
+    let addr = serve(Some(TlsConfig {
+        cert: certs.server_cert.clone(),
+        key: certs.server_key.clone(),
+        client_ca: None,
+    }));
+    let tls = ClientTlsConfig::new().ca_certificate(certs.ca()).domain_name("localhost");
+
+    /*
+     * When
+     */
+    let summary = check(format!("https://{}", addr), Some(tls)).await;
+    let plain_text = check(format!("http://{}", addr), None).await;
+
+    /*
+     * Then
+     */
+    assert!(summary.is_ok(), "{:?}", summary);
+    assert!(plain_text.is_err());
+}
+
+#[tokio::test]
+async fn should_require_client_certificate() {
+    /*
+     * Given
+     */
+    let certs = Certs::generate();
+    let addr = serve(Some(TlsConfig {
+        cert: certs.server_cert.clone(),
+        key: certs.server_key.clone(),
+        client_ca: Some(certs.ca.clone()),
+    }));
+    let tls = ClientTlsConfig::new().ca_certificate(certs.ca()).domain_name("localhost");
+
+    /*
+     * When
+     */
+    let summary = check(format!("https://{}", addr), Some(tls.clone().identity(certs.client_identity()))).await;
+    let anonymous = check(format!("https://{}", addr), Some(tls)).await;
+
+    /*
+     * Then
+     */
+    assert!(summary.is_ok(), "{:?}", summary);
+    assert!(anonymous.is_err());
+}